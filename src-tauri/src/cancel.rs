@@ -0,0 +1,106 @@
+//! Cooperative cancellation for long-running commands. A job registers under
+//! an id (e.g. the document being embedded), polls its flag between units of
+//! work, and another command flips the flag by id.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+pub struct CancelRegistry {
+    flags: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl CancelRegistry {
+    /// Start tracking `id`. The job stays cancellable until the guard drops.
+    pub fn register(&self, id: &str) -> CancelGuard<'_> {
+        let flag = Arc::new(AtomicBool::new(false));
+        self.flags
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), flag.clone());
+        CancelGuard {
+            registry: self,
+            id: id.to_string(),
+            flag,
+        }
+    }
+
+    /// Like `register`, but only if no job is running under `id` yet, so two
+    /// jobs never work on the same id at once.
+    pub fn try_register(&self, id: &str) -> Option<CancelGuard<'_>> {
+        let mut flags = self.flags.lock().unwrap();
+        if flags.contains_key(id) {
+            return None;
+        }
+        let flag = Arc::new(AtomicBool::new(false));
+        flags.insert(id.to_string(), flag.clone());
+        Some(CancelGuard {
+            registry: self,
+            id: id.to_string(),
+            flag,
+        })
+    }
+
+    /// Ask the job running under `id` to stop. Returns false if none is.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.flags.lock().unwrap().get(id) {
+            Some(flag) => {
+                flag.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct CancelGuard<'a> {
+    registry: &'a CancelRegistry,
+    id: String,
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelGuard<'_> {
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        let mut flags = self.registry.flags.lock().unwrap();
+        // A newer job may have re-registered the same id
+        if flags.get(&self.id).is_some_and(|f| Arc::ptr_eq(f, &self.flag)) {
+            flags.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_reaches_registered_job_only_while_running() {
+        let registry = CancelRegistry::default();
+        assert!(!registry.cancel("doc"));
+
+        let guard = registry.register("doc");
+        assert!(!guard.is_cancelled());
+        assert!(registry.cancel("doc"));
+        assert!(guard.is_cancelled());
+
+        drop(guard);
+        assert!(!registry.cancel("doc"));
+    }
+
+    #[test]
+    fn test_try_register_refuses_running_id() {
+        let registry = CancelRegistry::default();
+        let guard = registry.try_register("doc").unwrap();
+        assert!(registry.try_register("doc").is_none());
+        assert!(registry.try_register("other").is_some());
+        drop(guard);
+        assert!(registry.try_register("doc").is_some());
+    }
+}
@@ -1,46 +1,85 @@
+use crate::commands::settings::{
+    base_url_setting, generation_params, ollama_host, openai_extra_headers, stream_save_interval,
+};
+use crate::commands::knowledge::rag_context;
+use crate::commands::templates::{render_template_ref, TemplateRef};
 use crate::db::models::{Conversation, Message};
 use crate::db::Database;
-use crate::llm::{ChatMessage, ChatRequest, Provider, StreamChunk};
+use crate::debug_log::InspectedRequest;
+use crate::doc_processor;
+use crate::error::AppError;
+use crate::llm::{
+    Attachment, ChatMessage, ChatRequest, Provider, StreamChunk, TokenUsage,
+    STREAM_CHANNEL_CAPACITY,
+};
+use crate::tokens;
 use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tauri::{Emitter, State};
+use tokio::sync::mpsc;
 
 #[derive(Clone, Serialize)]
-struct ChatStreamEvent {
-    conversation_id: String,
-    delta: String,
-    done: bool,
+pub(crate) struct ChatStreamEvent {
+    /// Empty for knowledge-base answers from `rag_query`, which aren't saved.
+    pub(crate) conversation_id: String,
+    /// Id the assistant message is saved under. With `stream_save_interval_ms`
+    /// set, the row exists (partially filled) while the stream is running.
+    pub(crate) message_id: String,
+    pub(crate) model: String,
+    pub(crate) delta: String,
+    pub(crate) done: bool,
+    /// Set on the final event when the reply failed and nothing was saved.
+    pub(crate) error: bool,
+    /// Set on the final event when the reply stopped at the token limit and
+    /// can be extended with `continue_message`.
+    pub(crate) truncated: bool,
+    /// Token counts on the final event, for providers that report them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) usage: Option<TokenUsage>,
 }
 
-/// Resolve an LLM provider from a model string like "openai/gpt-4o", "claude/...", "ollama/..."
-fn resolve_provider(model: &str, db: &Database) -> Result<(Provider, String), String> {
+/// Resolve an LLM provider from a model string like "openai/gpt-4o", "claude/...", "ollama/...",
+/// "openrouter/<vendor>/<model>"
+pub(crate) fn resolve_provider(model: &str, db: &Database) -> Result<(Provider, String), AppError> {
     if let Some(model_id) = model.strip_prefix("ollama/") {
-        let host = db
-            .get_setting("ollama_host")
-            .ok()
-            .flatten()
-            .unwrap_or_else(|| "http://localhost:11434".to_string());
-        Ok((Provider::ollama(host), model_id.to_string()))
+        Ok((Provider::ollama(ollama_host(db)), model_id.to_string()))
     } else if let Some(model_id) = model.strip_prefix("claude/") {
         let api_key = db
             .get_setting("claude_api_key")
             .ok()
             .flatten()
-            .ok_or("Claude API key not configured")?;
-        let base_url = db
-            .get_setting("claude_base_url")
+            .ok_or(AppError::NotConfigured("Claude API key not configured".into()))?;
+        let base_url = base_url_setting(db, "claude_base_url")
+            .unwrap_or_else(|| "https://api.anthropic.com".to_string());
+        let prompt_caching = db
+            .get_setting("claude_prompt_caching")
             .ok()
             .flatten()
-            .unwrap_or_else(|| "https://api.anthropic.com".to_string());
+            .is_some_and(|v| v == "true");
         Ok((
-            Provider::Claude(crate::llm::claude::ClaudeConfig { api_key, base_url }),
+            Provider::Claude(crate::llm::claude::ClaudeConfig {
+                api_key,
+                base_url,
+                prompt_caching,
+            }),
             model_id.to_string(),
         ))
+    } else if let Some(model_id) = model.strip_prefix("openrouter/") {
+        let api_key = db
+            .get_setting("openrouter_api_key")
+            .ok()
+            .flatten()
+            .ok_or(AppError::NotConfigured("OpenRouter API key not configured".into()))?;
+        Ok((Provider::openrouter(api_key), model_id.to_string()))
     } else if let Some(model_id) = model.strip_prefix("copilot/") {
         let oauth_token = db
             .get_setting("copilot_oauth_token")
             .ok()
             .flatten()
-            .ok_or("GitHub Copilot not logged in")?;
+            .ok_or(AppError::NotConfigured("GitHub Copilot not logged in".into()))?;
         Ok((Provider::copilot(oauth_token), model_id.to_string()))
     } else {
         let model_id = model.strip_prefix("openai/").unwrap_or(model);
@@ -48,37 +87,156 @@ fn resolve_provider(model: &str, db: &Database) -> Result<(Provider, String), St
             .get_setting("openai_api_key")
             .ok()
             .flatten()
-            .ok_or("OpenAI API key not configured")?;
-        let base_url = db
-            .get_setting("openai_base_url")
-            .ok()
-            .flatten()
+            .ok_or(AppError::NotConfigured("OpenAI API key not configured".into()))?;
+        let base_url = base_url_setting(db, "openai_base_url")
             .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
         Ok((
-            Provider::OpenAi(crate::llm::openai::OpenAiConfig { api_key, base_url }),
+            Provider::OpenAi(crate::llm::openai::OpenAiConfig {
+                api_key,
+                base_url,
+                extra_headers: openai_extra_headers(db),
+            }),
             model_id.to_string(),
         ))
     }
 }
 
+/// Create a conversation. Without a model it starts on the `default_model`
+/// setting, so it can be sent to straight away; the returned conversation
+/// carries the effective model. Its system prompt starts as the
+/// `default_system_prompt` setting, stored on the conversation so later
+/// changes to the default leave it alone.
 #[tauri::command]
 pub fn create_conversation(
     db: State<'_, Database>,
     title: String,
     model: Option<String>,
-) -> Result<Conversation, String> {
-    db.create_conversation(&title, model.as_deref())
-        .map_err(|e| e.to_string())
+) -> Result<Conversation, AppError> {
+    new_conversation(&db, &title, model)
+}
+
+fn new_conversation(
+    db: &Database,
+    title: &str,
+    model: Option<String>,
+) -> Result<Conversation, AppError> {
+    let model = match model.filter(|m| !m.is_empty()) {
+        Some(model) => Some(model),
+        None => default_model(db)?,
+    };
+    let mut conversation = db.create_conversation(title, model.as_deref())?;
+    if let Some(prompt) = default_system_prompt(db)? {
+        db.update_conversation_system_prompt(&conversation.id, Some(&prompt))?;
+        conversation.system_prompt = Some(prompt);
+    }
+    Ok(conversation)
+}
+
+/// The `default_model` setting, treating an empty value as unset.
+fn default_model(db: &Database) -> Result<Option<String>, AppError> {
+    Ok(db.get_setting("default_model")?.filter(|m| !m.is_empty()))
+}
+
+/// The `default_system_prompt` setting, treating a blank value as unset.
+fn default_system_prompt(db: &Database) -> Result<Option<String>, AppError> {
+    Ok(db
+        .get_setting("default_system_prompt")?
+        .filter(|p| !p.trim().is_empty()))
+}
+
+#[tauri::command]
+pub fn list_conversations(
+    db: State<'_, Database>,
+    include_tags: Option<bool>,
+) -> Result<Vec<Conversation>, AppError> {
+    let mut conversations = db.list_conversations()?;
+    if include_tags.unwrap_or(false) {
+        db.attach_tags(&mut conversations)?;
+    }
+    Ok(conversations)
+}
+
+#[tauri::command]
+pub fn add_tag(db: State<'_, Database>, conversation_id: String, tag: String) -> Result<(), AppError> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err(AppError::InvalidInput("Tag cannot be empty".into()));
+    }
+    db.add_tag(&conversation_id, tag).map_err(AppError::from)
 }
 
 #[tauri::command]
-pub fn list_conversations(db: State<'_, Database>) -> Result<Vec<Conversation>, String> {
-    db.list_conversations().map_err(|e| e.to_string())
+pub fn remove_tag(
+    db: State<'_, Database>,
+    conversation_id: String,
+    tag: String,
+) -> Result<(), AppError> {
+    db.remove_tag(&conversation_id, tag.trim()).map_err(AppError::from)
+}
+
+#[tauri::command]
+pub fn list_tags(db: State<'_, Database>) -> Result<Vec<String>, AppError> {
+    db.list_tags().map_err(AppError::from)
+}
+
+#[tauri::command]
+pub fn list_conversations_by_tag(
+    db: State<'_, Database>,
+    tag: String,
+) -> Result<Vec<Conversation>, AppError> {
+    db.list_conversations_by_tag(&tag).map_err(AppError::from)
+}
+
+/// Moves the conversation to the trash unless `permanent` is set.
+#[tauri::command]
+pub fn delete_conversation(
+    db: State<'_, Database>,
+    id: String,
+    permanent: Option<bool>,
+) -> Result<(), AppError> {
+    if permanent.unwrap_or(false) {
+        db.delete_conversation(&id)
+    } else {
+        db.trash_conversation(&id)
+    }.map_err(AppError::from)
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletedConversations {
+    pub conversations: usize,
+    pub messages: usize,
+}
+
+/// Permanently delete every conversation, trash included, leaving the
+/// knowledge base alone.
+#[tauri::command]
+pub fn delete_all_conversations(db: State<'_, Database>) -> Result<DeletedConversations, AppError> {
+    let (conversations, messages) = db.delete_all_conversations()?;
+    Ok(DeletedConversations {
+        conversations,
+        messages,
+    })
+}
+
+#[tauri::command]
+pub fn list_trash(db: State<'_, Database>) -> Result<Vec<Conversation>, AppError> {
+    db.list_trash().map_err(AppError::from)
+}
+
+#[tauri::command]
+pub fn restore_conversation(db: State<'_, Database>, id: String) -> Result<(), AppError> {
+    if db.restore_conversation(&id)? {
+        Ok(())
+    } else {
+        Err(AppError::NotFound("Conversation is not in the trash".into()))
+    }
 }
 
+/// Permanently delete trashed conversations older than `older_than_days`
+/// (all of them by default).
 #[tauri::command]
-pub fn delete_conversation(db: State<'_, Database>, id: String) -> Result<(), String> {
-    db.delete_conversation(&id).map_err(|e| e.to_string())
+pub fn purge_trash(db: State<'_, Database>, older_than_days: Option<u32>) -> Result<usize, AppError> {
+    db.purge_trash(older_than_days.unwrap_or(0)).map_err(AppError::from)
 }
 
 #[tauri::command]
@@ -86,73 +244,918 @@ pub fn rename_conversation(
     db: State<'_, Database>,
     id: String,
     title: String,
-) -> Result<(), String> {
-    db.update_conversation_title(&id, &title)
-        .map_err(|e| e.to_string())
+) -> Result<(), AppError> {
+    db.update_conversation_title(&id, &title).map_err(AppError::from)
+}
+
+/// Branch a conversation into a new one, copying its messages up to and
+/// including `up_to_message_id` (or all of them). The original is untouched.
+#[tauri::command]
+pub fn fork_conversation(
+    db: State<'_, Database>,
+    conversation_id: String,
+    up_to_message_id: Option<String>,
+) -> Result<Conversation, AppError> {
+    if db.get_conversation(&conversation_id)?.is_none() {
+        return Err(AppError::NotFound("Conversation not found".into()));
+    }
+    db.fork_conversation(&conversation_id, up_to_message_id.as_deref())?
+        .ok_or(AppError::NotFound("Message not found in this conversation".into()))
 }
 
 #[tauri::command]
 pub fn get_messages(
     db: State<'_, Database>,
     conversation_id: String,
-) -> Result<Vec<Message>, String> {
-    db.get_messages(&conversation_id)
-        .map_err(|e| e.to_string())
+) -> Result<Vec<Message>, AppError> {
+    db.get_messages(&conversation_id).map_err(AppError::from)
 }
 
+/// Send a user message and stream the reply. A client-generated
+/// `message_id` makes the call safe to retry: the user turn is saved once and
+/// a retry only generates the reply. A `system_template` is rendered and
+/// becomes the conversation's system prompt from this turn on.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn send_message(
     app: tauri::AppHandle,
     db: State<'_, Database>,
     conversation_id: String,
     content: String,
+    model: Option<String>,
+    attachments: Option<Vec<String>>,
+    message_id: Option<String>,
+    system_template: Option<TemplateRef>,
+    context_files: Option<Vec<String>>,
+) -> Result<Message, AppError> {
+    let requested = model.filter(|m| !m.is_empty());
+    let model = resolve_model(&db, &conversation_id, requested.clone())?;
+    let system_prompt = system_template
+        .map(|template| render_template_ref(&db, &template))
+        .transpose()?;
+
+    // 1. Validate image attachments before anything is saved
+    let attachments = attachments.unwrap_or_default();
+    if !attachments.is_empty() {
+        let (provider, model_id) = resolve_provider(&model, &db)?;
+        if !provider.supports_vision(&model_id) {
+            return Err(AppError::InvalidInput(format!(
+                "Model {} does not support image inputs",
+                model
+            )));
+        }
+        for path in &attachments {
+            Attachment::from_path(Path::new(path))?;
+        }
+    }
+
+    // Context files are read now so a bad one rejects the send, but only go
+    // out with this request; they aren't stored with the message
+    let file_context = match context_files.filter(|paths| !paths.is_empty()) {
+        Some(paths) => {
+            // Last segment, so "openrouter/openai/gpt-4o" counts as gpt-4o
+            let model_id = model.rsplit_once('/').map_or(model.as_str(), |(_, id)| id);
+            let budget = file_context_budget(&db, &conversation_id, model_id, &content)?;
+            Some(file_context(&paths, model_id, budget)?)
+        }
+        None => None,
+    };
+
+    if let Some(prompt) = &system_prompt {
+        db.update_conversation_system_prompt(&conversation_id, Some(prompt))?;
+    }
+    if requested.is_some() {
+        remember_model(&db, &conversation_id, &model)?;
+    }
+
+    // 2. Save user message, storing attachment paths rather than image data
+    save_user_message(&db, &conversation_id, &content, &attachments, message_id.as_deref())?;
+
+    generate_reply(&app, &db, &conversation_id, &model, None, file_context.as_deref()).await
+}
+
+/// Tokens left free for the reply when fitting context files into the
+/// context window.
+const REPLY_RESERVE_TOKENS: usize = 1_024;
+/// Allowance for the closing tag and truncation note of each context file.
+const FILE_FOOTER_TOKENS: usize = 16;
+
+/// Tokens the context files of a send may take: whatever the window has left
+/// after the system prompt, the history, the new message and room to reply.
+fn file_context_budget(
+    db: &Database,
+    conversation_id: &str,
+    model_id: &str,
+    content: &str,
+) -> Result<usize, AppError> {
+    let system_prompt = db
+        .get_conversation(conversation_id)?
+        .and_then(|c| c.system_prompt)
+        .unwrap_or_default();
+    let messages = db.get_messages(conversation_id)?;
+    let prompt_tokens = tokens::count_chat_tokens(
+        model_id,
+        [("system", system_prompt.as_str())]
+            .into_iter()
+            .chain(messages.iter().map(|m| (m.role.as_str(), m.content.as_str())))
+            .chain([("user", content)]),
+    );
+    Ok(tokens::context_limit(model_id).saturating_sub(prompt_tokens + REPLY_RESERVE_TOKENS))
+}
+
+/// The text of `paths` as one context block of at most `budget` tokens.
+/// Files past the budget are cut short and marked as truncated. Fails if any
+/// file can't be parsed, e.g. an unsupported type.
+fn file_context(paths: &[String], model_id: &str, budget: usize) -> Result<String, AppError> {
+    let files = paths
+        .iter()
+        .map(|path| {
+            let path = Path::new(path);
+            let name = path
+                .file_name()
+                .map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+            doc_processor::parse_file(path)
+                .map(|parsed| (name.clone(), parsed.content))
+                .map_err(|e| AppError::InvalidInput(format!("Can't attach {}: {}", name, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut block = String::from("The user attached these files as context for this message.\n");
+    let mut remaining = budget.saturating_sub(tokens::count_tokens(model_id, &block));
+    for (name, content) in files {
+        let header = format!("\n<file name=\"{}\">\n", name);
+        remaining = remaining.saturating_sub(tokens::count_tokens(model_id, &header) + FILE_FOOTER_TOKENS);
+        let kept = truncate_to_tokens(model_id, content.trim(), remaining);
+        remaining -= tokens::count_tokens(model_id, kept);
+        block.push_str(&header);
+        block.push_str(kept);
+        if kept.len() < content.trim().len() {
+            block.push_str("\n[truncated to fit the context window]");
+        }
+        block.push_str("\n</file>\n");
+    }
+    Ok(block)
+}
+
+/// The longest prefix of `text` that is at most `max_tokens` tokens.
+fn truncate_to_tokens<'a>(model_id: &str, text: &'a str, max_tokens: usize) -> &'a str {
+    let mut end = text.len();
+    loop {
+        let count = tokens::count_tokens(model_id, &text[..end]);
+        if count <= max_tokens {
+            return &text[..end];
+        }
+        // Shrink in proportion to the overshoot, always by at least a byte
+        end = (end * max_tokens / count).min(end - 1);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+    }
+}
+
+/// Prepend `context` to the last user turn of an outgoing request.
+fn with_file_context(messages: &mut [ChatMessage], context: &str) {
+    if let Some(message) = messages.iter_mut().rev().find(|m| m.role == "user") {
+        message.content = format!("{}\n{}", context, message.content);
+    }
+}
+
+/// Save the user turn of `send_message`, at most once per `message_id`. A
+/// retry drops any reply already saved for it, since it is regenerated.
+fn save_user_message(
+    db: &Database,
+    conversation_id: &str,
+    content: &str,
+    attachments: &[String],
+    message_id: Option<&str>,
+) -> Result<Message, AppError> {
+    let Some(id) = message_id else {
+        return Ok(db.add_message_with_attachments(conversation_id, "user", content, attachments)?);
+    };
+    let message = db
+        .add_message_once(id, conversation_id, "user", content, attachments)?
+        .ok_or_else(|| {
+            AppError::InvalidInput(format!("Message id {} belongs to another conversation", id))
+        })?;
+    db.delete_messages_after(&message.id)?;
+    Ok(message)
+}
+
+/// Delete a single message. With `cascade`, deleting a user message also
+/// removes the assistant reply that follows it. The conversation's
+/// `updated_at` is refreshed either way.
+#[tauri::command]
+pub fn delete_message(
+    db: State<'_, Database>,
+    message_id: String,
+    cascade: bool,
+) -> Result<usize, AppError> {
+    db.delete_message(&message_id, cascade)?
+        .ok_or(AppError::NotFound("Message not found".into()))
+}
+
+/// Edit a user message and drop everything after it in the conversation.
+/// When `regenerate` is set, a fresh assistant reply is streamed and returned.
+#[tauri::command]
+pub async fn edit_message(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    message_id: String,
+    new_content: String,
+    regenerate: Option<bool>,
+    model: Option<String>,
+) -> Result<Option<Message>, AppError> {
+    let message = db
+        .get_message(&message_id)?
+        .ok_or(AppError::NotFound("Message not found".into()))?;
+    if message.role != "user" {
+        return Err(AppError::InvalidInput("Only user messages can be edited".into()));
+    }
+
+    db.edit_message_and_truncate(&message_id, &new_content)?;
+
+    if !regenerate.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let requested = model.filter(|m| !m.is_empty());
+    let model = resolve_model(&db, &message.conversation_id, requested.clone())?;
+    if requested.is_some() {
+        remember_model(&db, &message.conversation_id, &model)?;
+    }
+    generate_reply(&app, &db, &message.conversation_id, &model, None, None)
+        .await
+        .map(Some)
+}
+
+/// Pick the model for a turn: the one passed by the caller, else the
+/// conversation's stored model, else the `default_model` setting. Nothing is
+/// saved; see `remember_model`.
+fn resolve_model(
+    db: &Database,
+    conversation_id: &str,
+    requested: Option<String>,
+) -> Result<String, AppError> {
+    let conversation = db
+        .get_conversation(conversation_id)?
+        .ok_or(AppError::NotFound("Conversation not found".into()))?;
+    match requested.filter(|m| !m.is_empty()) {
+        Some(model) => Ok(model),
+        None => match conversation.model.filter(|m| !m.is_empty()) {
+            Some(model) => Ok(model),
+            None => default_model(db)?.ok_or(AppError::InvalidInput(
+                "No model specified and no default_model configured".into(),
+            )),
+        },
+    }
+}
+
+/// Make an explicitly requested `model` the conversation's model. Called once
+/// a turn is accepted, so a rejected send doesn't switch models.
+fn remember_model(db: &Database, conversation_id: &str, model: &str) -> Result<(), AppError> {
+    let conversation = db
+        .get_conversation(conversation_id)?
+        .ok_or(AppError::NotFound("Conversation not found".into()))?;
+    if conversation.model.as_deref() != Some(model) {
+        db.update_conversation_model(conversation_id, model)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenEstimate {
+    pub prompt_tokens: usize,
+    pub context_limit: usize,
+    /// Negative once the prompt no longer fits.
+    pub remaining: i64,
+}
+
+/// Estimate the prompt size of the conversation plus an unsent `draft`, so the
+/// UI can warn before a request would overflow the model's context window.
+/// Image attachments are not counted.
+#[tauri::command]
+pub fn estimate_tokens(
+    db: State<'_, Database>,
+    conversation_id: String,
     model: String,
-) -> Result<Message, String> {
-    // 1. Save user message
-    db.add_message(&conversation_id, "user", &content)
-        .map_err(|e| e.to_string())?;
+    draft: Option<String>,
+) -> Result<TokenEstimate, AppError> {
+    // Last segment, so "openrouter/openai/gpt-4o" counts as gpt-4o
+    let model_id = model.rsplit_once('/').map_or(model.as_str(), |(_, id)| id);
+    let messages = db.get_messages(&conversation_id)?;
+    let draft = draft.unwrap_or_default();
+    let prompt_tokens = tokens::count_chat_tokens(
+        model_id,
+        messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .chain((!draft.is_empty()).then_some(("user", draft.as_str()))),
+    );
+    let context_limit = tokens::context_limit(model_id);
+    Ok(TokenEstimate {
+        prompt_tokens,
+        context_limit,
+        remaining: context_limit as i64 - prompt_tokens as i64,
+    })
+}
 
-    // 2. Load full conversation history for context
-    let messages = db
-        .get_messages(&conversation_id)
-        .map_err(|e| e.to_string())?;
-    let chat_messages: Vec<ChatMessage> = messages
+/// Encode stored attachment paths, skipping files that have since gone missing.
+fn load_attachments(paths: &[String]) -> Vec<Attachment> {
+    paths
         .iter()
-        .map(|m| ChatMessage {
-            role: m.role.clone(),
-            content: m.content.clone(),
+        .filter_map(|p| match Attachment::from_path(Path::new(p)) {
+            Ok(a) => Some(a),
+            Err(e) => {
+                eprintln!("Skipping attachment: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Continue an assistant reply that stopped early, typically at
+/// `max_tokens`. The model is re-prompted with the history up to the reply
+/// and the continuation is appended to it, streamed under the same message id.
+#[tauri::command]
+pub async fn continue_message(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    message_id: String,
+) -> Result<Message, AppError> {
+    let message = db
+        .get_message(&message_id)?
+        .ok_or(AppError::NotFound("Message not found".into()))?;
+    if message.role != "assistant" {
+        return Err(AppError::InvalidInput(
+            "Only assistant messages can be continued".into(),
+        ));
+    }
+    let model = match message.model.clone().filter(|m| !m.is_empty()) {
+        Some(model) => model,
+        None => resolve_model(&db, &message.conversation_id, None)?,
+    };
+    generate_reply(&app, &db, &message.conversation_id, &model, Some(&message), None).await
+}
+
+/// Sent after a cut-off reply to ask for the rest of it. Not saved.
+const CONTINUE_PROMPT: &str = "Your previous reply was cut off. Continue exactly where it stopped, \
+without repeating anything and without a preamble.";
+
+/// History sent to the model. When continuing `reply`, the turns after it are
+/// dropped and the continuation prompt is appended.
+fn history_for_reply(messages: Vec<Message>, continuing: Option<&Message>) -> Vec<Message> {
+    let Some(reply) = continuing else {
+        return messages;
+    };
+    let mut history: Vec<Message> = messages
+        .into_iter()
+        .scan(false, |past_reply, m| {
+            if *past_reply {
+                return None;
+            }
+            *past_reply = m.id == reply.id;
+            Some(m)
         })
         .collect();
+    history.push(Message {
+        id: String::new(),
+        conversation_id: reply.conversation_id.clone(),
+        role: "user".into(),
+        content: CONTINUE_PROMPT.into(),
+        created_at: String::new(),
+        attachments: Vec::new(),
+        model: None,
+        truncated: false,
+        tool_call_id: None,
+        tool_calls: Vec::new(),
+    });
+    history
+}
+
+/// The system prompt followed by the conversation history. Images from
+/// earlier turns are only re-sent to models that accept them.
+fn reply_messages(
+    db: &Database,
+    conversation_id: &str,
+    vision: bool,
+    continuing: Option<&Message>,
+) -> Result<Vec<ChatMessage>, AppError> {
+    let system_prompt = db
+        .get_conversation(conversation_id)?
+        .and_then(|c| c.system_prompt);
+    let messages = history_for_reply(db.get_messages(conversation_id)?, continuing);
+    Ok(system_prompt
+        .map(|prompt| ChatMessage {
+            role: "system".into(),
+            content: prompt,
+            attachments: Vec::new(),
+            tool_call_id: None,
+            tool_calls: Vec::new(),
+        })
+        .into_iter()
+        .chain(messages.iter().map(|m| ChatMessage {
+            role: m.role.clone(),
+            content: m.content.clone(),
+            attachments: if vision {
+                load_attachments(&m.attachments)
+            } else {
+                Vec::new()
+            },
+            tool_call_id: m.tool_call_id.clone(),
+            tool_calls: m.tool_calls.clone(),
+        }))
+        .collect())
+}
 
-    // 3. Resolve provider
+/// The request the next reply in a conversation would send, with
+/// credentials redacted, for debugging prompts and parameters. Nothing is
+/// sent and the conversation is left untouched. `model` defaults as for
+/// `send_message`. With `rag_query`, the sources `rag_query` would retrieve
+/// are added to the system prompt.
+#[tauri::command]
+pub async fn inspect_chat_request(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    conversation_id: String,
+    model: Option<String>,
+    rag_query: Option<String>,
+    top_k: Option<usize>,
+) -> Result<InspectedRequest, AppError> {
+    let model = resolve_model(&db, &conversation_id, model)?;
     let (provider, model_id) = resolve_provider(&model, &db)?;
+    let vision = provider.supports_vision(&model_id);
+    let mut messages = reply_messages(&db, &conversation_id, vision, None)?;
+    if let Some(query) = rag_query.filter(|q| !q.trim().is_empty()) {
+        let context = rag_context(&app, &db, query, top_k).await?;
+        with_system_context(&mut messages, context);
+    }
+    let request = ChatRequest {
+        messages,
+        model: model_id,
+        stream: true,
+        params: generation_params(&db),
+    };
+    provider.inspect_chat(&request).map_err(AppError::from)
+}
+
+/// Append `context` to the leading system message, adding one if there is
+/// none. Providers like Claude only take a single system prompt.
+pub(crate) fn with_system_context(messages: &mut Vec<ChatMessage>, context: String) {
+    match messages.first_mut() {
+        Some(first) if first.role == "system" => {
+            first.content = format!("{}\n\n{}", first.content, context);
+        }
+        _ => messages.insert(
+            0,
+            ChatMessage {
+                role: "system".into(),
+                content: context,
+                attachments: Vec::new(),
+                tool_call_id: None,
+                tool_calls: Vec::new(),
+            },
+        ),
+    }
+}
 
-    // 4. Stream response, emitting events to frontend
-    let conv_id = conversation_id.clone();
+/// Stream an assistant reply to the current conversation history and save it.
+/// With `continuing`, the reply extends that message instead of adding one.
+async fn generate_reply(
+    app: &tauri::AppHandle,
+    db: &Database,
+    conversation_id: &str,
+    model: &str,
+    continuing: Option<&Message>,
+    file_context: Option<&str>,
+) -> Result<Message, AppError> {
+    // 1. Resolve provider
+    let (provider, model_id) = resolve_provider(model, db)?;
+
+    // 2. Load full conversation history for context
+    let vision = provider.supports_vision(&model_id);
+    let mut chat_messages = reply_messages(db, conversation_id, vision, continuing)?;
+    if let Some(context) = file_context {
+        with_file_context(&mut chat_messages, context);
+    }
+
+    // 3. Stream response, emitting events to frontend. The reply id is fixed
+    // up front so every delta can be matched to its message bubble.
+    let conv_id = conversation_id.to_string();
+    let message_id = match continuing {
+        Some(reply) => reply.id.clone(),
+        None => uuid::Uuid::new_v4().to_string(),
+    };
+    let prefix = continuing.map_or("", |reply| reply.content.as_str());
     let request = ChatRequest {
         messages: chat_messages,
         model: model_id,
         stream: true,
+        params: generation_params(db),
     };
 
-    let full_content = provider
-        .chat_stream(&request, |chunk: StreamChunk| {
-            let _ = app.emit(
-                "chat-stream",
-                ChatStreamEvent {
-                    conversation_id: conv_id.clone(),
-                    delta: chunk.delta,
-                    done: chunk.done,
-                },
-            );
-        })
-        .await
-        .map_err(|e| e.to_string())?;
+    // Usage and the truncation flag arrive on the provider's final chunk and
+    // go out with our own
+    let usage: Mutex<Option<TokenUsage>> = Mutex::new(None);
+    let truncated = AtomicBool::new(false);
+    let emit = |delta: String, done: bool, error: bool| {
+        let _ = app.emit(
+            "chat-stream",
+            ChatStreamEvent {
+                conversation_id: conv_id.clone(),
+                message_id: message_id.clone(),
+                model: model.to_string(),
+                delta,
+                done,
+                error,
+                truncated: done && !error && truncated.load(Ordering::SeqCst),
+                usage: if done { usage.lock().unwrap().take() } else { None },
+            },
+        );
+    };
 
-    // 5. Save assistant message
+    // With incremental saving, the reply row is created empty up front (after
+    // the history was loaded, so it isn't sent as context)
+    let saver = match stream_save_interval(db) {
+        Some(interval) => {
+            if continuing.is_none() {
+                db.add_message_with_id(&message_id, conversation_id, "assistant", "", &[], Some(model))?;
+            }
+            Some(StreamSaver::new(db, &message_id, prefix, interval))
+        }
+        None => None,
+    };
+
+    // Deltas are batched into one event per flush window. The provider's own
+    // `done` is held back until the reply is checked and saved.
+    let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+    let (result, last) = futures::future::join(
+        provider.chat_stream_to(&request, tx),
+        coalesce_deltas(rx, STREAM_FLUSH_INTERVAL, |delta| {
+            if let Some(saver) = &saver {
+                saver.push(&delta);
+            }
+            emit(delta, false, false);
+        }),
+    )
+    .await;
+    if let Some(last) = last {
+        *usage.lock().unwrap() = last.usage;
+        truncated.store(last.truncated, Ordering::SeqCst);
+    }
+    let continuation = match result {
+        Ok(content) if !content.trim().is_empty() => content,
+        result => {
+            // Leave a continued reply as it was before the attempt
+            match continuing {
+                Some(reply) if saver.is_some() => {
+                    db.update_message_content(&message_id, &reply.content)?;
+                }
+                None if saver.is_some() => {
+                    db.delete_message(&message_id, false)?;
+                }
+                _ => {}
+            }
+            emit(String::new(), true, true);
+            return Err(match result {
+                Err(e) => e.into(),
+                Ok(_) => AppError::EmptyResponse(
+                    "The model returned an empty response; nothing was saved".into(),
+                ),
+            });
+        }
+    };
+    let full_content = format!("{}{}", prefix, continuation);
+
+    // 4. Save (or finalize) the assistant message
+    if saver.is_some() || continuing.is_some() {
+        db.update_message_content(&message_id, &full_content)?;
+    } else {
+        db.add_message_with_id(
+            &message_id,
+            conversation_id,
+            "assistant",
+            &full_content,
+            &[],
+            Some(model),
+        )?;
+    }
+    db.set_message_truncated(&message_id, truncated.load(Ordering::SeqCst))?;
     let assistant_msg = db
-        .add_message(&conversation_id, "assistant", &full_content)
-        .map_err(|e| e.to_string())?;
+        .get_message(&message_id)?
+        .ok_or(AppError::NotFound("Message not found".into()))?;
+    emit(String::new(), true, false);
 
     Ok(assistant_msg)
 }
+
+/// Deltas arriving within this long of each other go out as one event, so
+/// fast models don't flood the frontend with one event per token.
+const STREAM_FLUSH_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Pass streamed text to `flush` in batches: the first delta after a flush
+/// opens a window of `interval`, and everything received within it is flushed
+/// together. Runs until the sender hangs up and returns the provider's final
+/// (`done`) chunk, if it sent one.
+async fn coalesce_deltas(
+    mut rx: mpsc::Receiver<StreamChunk>,
+    interval: Duration,
+    mut flush: impl FnMut(String),
+) -> Option<StreamChunk> {
+    let mut pending = String::new();
+    let mut flush_at = tokio::time::Instant::now();
+    let mut last = None;
+    loop {
+        let next = if pending.is_empty() {
+            Ok(rx.recv().await)
+        } else {
+            tokio::time::timeout_at(flush_at, rx.recv()).await
+        };
+        match next {
+            Ok(Some(chunk)) if chunk.done => {
+                if !pending.is_empty() {
+                    flush(std::mem::take(&mut pending));
+                }
+                last = Some(chunk);
+            }
+            Ok(Some(chunk)) => {
+                if pending.is_empty() {
+                    flush_at = tokio::time::Instant::now() + interval;
+                }
+                pending.push_str(&chunk.delta);
+            }
+            Ok(None) => {
+                if !pending.is_empty() {
+                    flush(pending);
+                }
+                return last;
+            }
+            // Window elapsed with text waiting
+            Err(_) => flush(std::mem::take(&mut pending)),
+        }
+    }
+}
+
+/// Writes a streaming reply into its message row at most once per
+/// `interval`, so a crash mid-stream leaves the text received so far.
+struct StreamSaver<'a> {
+    db: &'a Database,
+    message_id: &'a str,
+    interval: Duration,
+    /// Text received so far and when it was last written.
+    state: Mutex<(String, Instant)>,
+}
+
+impl<'a> StreamSaver<'a> {
+    /// `prefix` is content the message already has, as when continuing a reply.
+    fn new(db: &'a Database, message_id: &'a str, prefix: &str, interval: Duration) -> Self {
+        Self {
+            db,
+            message_id,
+            interval,
+            state: Mutex::new((prefix.to_string(), Instant::now())),
+        }
+    }
+
+    fn push(&self, delta: &str) {
+        let mut state = self.state.lock().unwrap();
+        let (content, saved_at) = &mut *state;
+        content.push_str(delta);
+        if saved_at.elapsed() < self.interval {
+            return;
+        }
+        // Best effort: the final content is written when the stream ends
+        if let Err(e) = self.db.update_message_content(self.message_id, content) {
+            eprintln!("Failed to save partial reply: {}", e);
+        }
+        *saved_at = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_files_reach_request_but_not_history() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        let dir = std::env::temp_dir().join(format!("ai-box-context-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let notes = dir.join("notes.md");
+        std::fs::write(&notes, "# Launch\nThe launch code is PURPLE-HIPPO.").unwrap();
+        let long = dir.join("long.txt");
+        std::fs::write(&long, "lorem ipsum ".repeat(2_000)).unwrap();
+        let binary = dir.join("photo.xyz");
+        std::fs::write(&binary, [0u8, 1, 2]).unwrap();
+        let paths = |files: &[&std::path::Path]| -> Vec<String> {
+            files.iter().map(|p| p.to_string_lossy().into_owned()).collect()
+        };
+
+        let rejected = file_context(&paths(&[&notes, &binary]), "gpt-4o", 10_000);
+        let context = file_context(&paths(&[&notes]), "gpt-4o", 10_000).unwrap();
+        let cut = file_context(&paths(&[&notes, &long]), "gpt-4o", 200).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        match rejected {
+            Err(AppError::InvalidInput(message)) => {
+                assert!(message.contains("photo.xyz") && message.contains("Unsupported"), "{}", message)
+            }
+            other => panic!("expected an invalid input error, got {:?}", other),
+        }
+        assert!(cut.contains("PURPLE-HIPPO"));
+        assert!(cut.contains("[truncated to fit the context window]"));
+        assert!(tokens::count_tokens("gpt-4o", &cut) <= 200);
+
+        save_user_message(&db, &conv.id, "What is the launch code?", &[], None).unwrap();
+        let mut messages = reply_messages(&db, &conv.id, false, None).unwrap();
+        with_file_context(&mut messages, &context);
+        let request = ChatRequest {
+            messages,
+            model: "gpt-4o".into(),
+            stream: true,
+            params: Default::default(),
+        };
+        let inspected = Provider::openai("sk-test".into()).inspect_chat(&request).unwrap();
+        let sent = inspected.body["messages"][0]["content"].as_str().unwrap();
+        assert!(sent.contains("<file name=\"notes.md\">"), "{}", sent);
+        assert!(sent.contains("PURPLE-HIPPO"));
+        assert!(sent.ends_with("What is the launch code?"));
+        assert_eq!(db.get_messages(&conv.id).unwrap()[0].content, "What is the launch code?");
+    }
+
+    #[test]
+    fn test_retried_send_saves_user_message_once() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+
+        let first = save_user_message(&db, &conv.id, "Hello", &[], Some("m1")).unwrap();
+        // A reply the client never received is replaced by the retry's
+        db.add_message(&conv.id, "assistant", "Hi!").unwrap();
+        let retry = save_user_message(&db, &conv.id, "Hello", &[], Some("m1")).unwrap();
+        assert_eq!(first.id, "m1");
+        assert_eq!(retry.id, "m1");
+        assert_eq!(db.get_messages(&conv.id).unwrap().len(), 1);
+
+        // Without an id every call is a new turn
+        save_user_message(&db, &conv.id, "Again", &[], None).unwrap();
+        save_user_message(&db, &conv.id, "Again", &[], None).unwrap();
+        assert_eq!(db.get_messages(&conv.id).unwrap().len(), 3);
+
+        let other = db.create_conversation("Other", None).unwrap();
+        assert!(matches!(
+            save_user_message(&db, &other.id, "Hello", &[], Some("m1")),
+            Err(AppError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_stream_saver_throttles_writes() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        let msg = db.add_message(&conv.id, "assistant", "").unwrap();
+        let content = || db.get_message(&msg.id).unwrap().unwrap().content;
+
+        let slow = StreamSaver::new(&db, &msg.id, "", Duration::from_secs(3600));
+        slow.push("Hel");
+        slow.push("lo");
+        assert_eq!(content(), "");
+
+        let eager = StreamSaver::new(&db, &msg.id, "", Duration::ZERO);
+        eager.push("Hel");
+        assert_eq!(content(), "Hel");
+        eager.push("lo");
+        assert_eq!(content(), "Hello");
+    }
+
+    fn chunk(delta: &str, done: bool) -> StreamChunk {
+        StreamChunk {
+            delta: delta.into(),
+            done,
+            usage: None,
+            truncated: done,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_batches_deltas_within_window() {
+        let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        for delta in ["Hel", "lo", ", ", "world"] {
+            tx.send(chunk(delta, false)).await.unwrap();
+        }
+        tx.send(chunk("", true)).await.unwrap();
+        drop(tx);
+
+        let mut flushed = Vec::new();
+        let last = coalesce_deltas(rx, Duration::from_secs(3600), |d| flushed.push(d)).await;
+        assert_eq!(flushed, vec!["Hello, world"]);
+        assert!(last.is_some_and(|c| c.done && c.truncated));
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_flushes_when_window_elapses() {
+        let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        let producer = async move {
+            tx.send(chunk("first", false)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tx.send(chunk("second", false)).await.unwrap();
+        };
+        let mut flushed = Vec::new();
+        let (_, last) = futures::future::join(
+            producer,
+            coalesce_deltas(rx, Duration::from_millis(5), |d| flushed.push(d)),
+        )
+        .await;
+        assert_eq!(flushed, vec!["first", "second"]);
+        assert!(last.is_none());
+    }
+
+    #[test]
+    fn test_continuing_a_reply_drops_later_turns() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        db.add_message(&conv.id, "user", "Write an essay").unwrap();
+        let reply = db.add_message(&conv.id, "assistant", "Essays are").unwrap();
+        db.add_message(&conv.id, "user", "Thanks").unwrap();
+        let messages = db.get_messages(&conv.id).unwrap();
+
+        assert_eq!(history_for_reply(messages.clone(), None).len(), 3);
+        let history = history_for_reply(messages, Some(&reply));
+        let roles: Vec<&str> = history.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+        assert_eq!(history[1].content, "Essays are");
+        assert_eq!(history[2].content, CONTINUE_PROMPT);
+    }
+
+    #[test]
+    fn test_new_conversation_defaults_to_configured_model() {
+        let db = Database::open_in_memory().unwrap();
+        let bare = new_conversation(&db, "No default yet", None).unwrap();
+        assert_eq!(bare.model, None);
+
+        db.set_setting("default_model", "claude/claude-sonnet-4-20250514").unwrap();
+        let conv = new_conversation(&db, "Test", None).unwrap();
+        assert_eq!(conv.model.as_deref(), Some("claude/claude-sonnet-4-20250514"));
+        let stored = db.get_conversation(&conv.id).unwrap().unwrap();
+        assert_eq!(stored.model, conv.model);
+
+        let empty = new_conversation(&db, "Empty", Some(String::new())).unwrap();
+        assert_eq!(empty.model, conv.model);
+        let explicit = new_conversation(&db, "Explicit", Some("openai/gpt-4o".into())).unwrap();
+        assert_eq!(explicit.model.as_deref(), Some("openai/gpt-4o"));
+    }
+
+    #[test]
+    fn test_default_system_prompt_seeds_only_new_conversations() {
+        let db = Database::open_in_memory().unwrap();
+        let existing = new_conversation(&db, "Before", None).unwrap();
+        assert_eq!(existing.system_prompt, None);
+
+        db.set_setting("default_system_prompt", "You are a terse pirate.").unwrap();
+        let conv = new_conversation(&db, "After", None).unwrap();
+        assert_eq!(conv.system_prompt.as_deref(), Some("You are a terse pirate."));
+        let stored = db.get_conversation(&conv.id).unwrap().unwrap();
+        assert_eq!(stored.system_prompt, conv.system_prompt);
+
+        // Changing the default rewrites neither conversation
+        db.set_setting("default_system_prompt", "You are a poet.").unwrap();
+        assert_eq!(db.get_conversation(&existing.id).unwrap().unwrap().system_prompt, None);
+        assert_eq!(
+            db.get_conversation(&conv.id).unwrap().unwrap().system_prompt.as_deref(),
+            Some("You are a terse pirate.")
+        );
+
+        db.set_setting("default_system_prompt", "  ").unwrap();
+        assert_eq!(new_conversation(&db, "Blank", None).unwrap().system_prompt, None);
+    }
+
+    #[test]
+    fn test_resolve_model_fallback_chain() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+
+        // Nothing stored and no default configured
+        assert!(matches!(
+            resolve_model(&db, &conv.id, None),
+            Err(AppError::InvalidInput(_))
+        ));
+
+        db.set_setting("default_model", "ollama/llama3").unwrap();
+        assert_eq!(resolve_model(&db, &conv.id, None).unwrap(), "ollama/llama3");
+
+        // An explicit choice wins, but only sticks once remembered
+        assert_eq!(
+            resolve_model(&db, &conv.id, Some("openai/gpt-4o".into())).unwrap(),
+            "openai/gpt-4o"
+        );
+        assert_eq!(db.get_conversation(&conv.id).unwrap().unwrap().model, None);
+        remember_model(&db, &conv.id, "openai/gpt-4o").unwrap();
+        let stored = db.get_conversation(&conv.id).unwrap().unwrap();
+        assert_eq!(stored.model.as_deref(), Some("openai/gpt-4o"));
+        assert_eq!(resolve_model(&db, &conv.id, None).unwrap(), "openai/gpt-4o");
+
+        assert!(matches!(
+            resolve_model(&db, "missing", None),
+            Err(AppError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,44 @@
+use crate::commands::settings::apply_llm_settings;
+use crate::db::{Database, DatabaseStats};
+use crate::error::AppError;
+use serde::Serialize;
+use std::path::Path;
+use tauri::State;
+
+/// Save a consistent copy of the whole database (chats, knowledge base and
+/// settings) to `dest_path`, safe to run while the app is in use.
+#[tauri::command]
+pub fn backup_database(db: State<'_, Database>, dest_path: String) -> Result<(), AppError> {
+    db.backup_to(Path::new(&dest_path)).map_err(AppError::from)
+}
+
+/// Replace the current database with a backup made by `backup_database`.
+#[tauri::command]
+pub fn restore_database(db: State<'_, Database>, src_path: String) -> Result<(), AppError> {
+    db.restore_from(Path::new(&src_path))?;
+    apply_llm_settings(&db);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn database_stats(db: State<'_, Database>) -> Result<DatabaseStats, AppError> {
+    db.stats().map_err(AppError::from)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompactResult {
+    pub before_bytes: i64,
+    pub after_bytes: i64,
+    pub freed_bytes: i64,
+}
+
+/// Reclaim space left by deleted conversations and documents.
+#[tauri::command]
+pub fn compact_database(db: State<'_, Database>) -> Result<CompactResult, AppError> {
+    let (before_bytes, after_bytes) = db.compact()?;
+    Ok(CompactResult {
+        before_bytes,
+        after_bytes,
+        freed_bytes: before_bytes - after_bytes,
+    })
+}
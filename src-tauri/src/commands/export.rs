@@ -0,0 +1,361 @@
+use crate::db::models::{Conversation, Message};
+use crate::db::Database;
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use std::io::{Seek, Write};
+use tauri::State;
+
+/// Version of the JSON export layout, bumped on incompatible changes.
+const EXPORT_VERSION: u32 = 1;
+
+/// Version of the full-archive layout written by `export_all`. A future
+/// `import_all` checks this before reading the rest of the file.
+pub const ARCHIVE_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationExport {
+    pub version: u32,
+    pub conversation: Conversation,
+    pub messages: Vec<Message>,
+}
+
+/// Export a conversation as `"markdown"` or `"json"`. The rendered document is
+/// returned, and also written to `dest_path` when one is given (the frontend
+/// picks it with the dialog plugin).
+#[tauri::command]
+pub fn export_conversation(
+    db: State<'_, Database>,
+    conversation_id: String,
+    format: String,
+    dest_path: Option<String>,
+) -> Result<String, AppError> {
+    let conversation = db
+        .get_conversation(&conversation_id)?
+        .ok_or(AppError::NotFound("Conversation not found".into()))?;
+    let messages = db.get_messages(&conversation_id)?;
+
+    let output = match format.as_str() {
+        "markdown" | "md" => render_markdown(&conversation, &messages),
+        "json" => serde_json::to_string_pretty(&ConversationExport {
+            version: EXPORT_VERSION,
+            conversation,
+            messages,
+        })?,
+        _ => {
+            return Err(AppError::InvalidInput(format!(
+                "Unsupported export format: {}",
+                format
+            )))
+        }
+    };
+
+    if let Some(path) = dest_path {
+        std::fs::write(&path, &output)?;
+    }
+    Ok(output)
+}
+
+/// Recreate a conversation from a JSON export. Ids are regenerated so the
+/// import never collides with existing rows.
+#[tauri::command]
+pub fn import_conversation(db: State<'_, Database>, json: String) -> Result<Conversation, AppError> {
+    let export: ConversationExport = serde_json::from_str(&json)
+        .map_err(|e| AppError::InvalidInput(format!("Invalid export file: {}", e)))?;
+    if export.version > EXPORT_VERSION {
+        return Err(AppError::InvalidInput(format!(
+            "Export version {} is newer than supported version {}",
+            export.version, EXPORT_VERSION
+        )));
+    }
+    db.import_conversation(&export.conversation, &export.messages).map_err(AppError::from)
+}
+
+/// Entry in a full JSON archive; borrows so each conversation can be written
+/// and dropped before the next one is loaded.
+#[derive(Serialize)]
+struct ArchivedConversation<'a> {
+    conversation: &'a Conversation,
+    messages: &'a [Message],
+}
+
+/// Back up every conversation to `dest_path` (picked by the frontend with the
+/// dialog plugin). `"json"` writes a single archive document; `"markdown"`
+/// writes a zip with one transcript per conversation. Conversations are
+/// loaded and written one at a time so large histories never sit in memory
+/// all at once. Conversations in the trash are left out. Returns the number
+/// of exported conversations.
+#[tauri::command]
+pub fn export_all(
+    db: State<'_, Database>,
+    format: String,
+    dest_path: String,
+) -> Result<usize, AppError> {
+    // Checked first so a bad format doesn't leave an empty file behind
+    if !matches!(format.as_str(), "json" | "markdown" | "md") {
+        return Err(AppError::InvalidInput(format!(
+            "Unsupported export format: {}",
+            format
+        )));
+    }
+    let file = std::fs::File::create(&dest_path)?;
+    if format == "json" {
+        write_json_archive(&db, std::io::BufWriter::new(file))
+    } else {
+        write_markdown_zip(&db, file)
+    }
+}
+
+fn write_json_archive(db: &Database, mut out: impl Write) -> Result<usize, AppError> {
+    let conversations = db.list_conversations()?;
+    write!(
+        out,
+        "{{\"schema_version\":{},\"app_version\":\"{}\",\"conversations\":[",
+        ARCHIVE_SCHEMA_VERSION,
+        env!("CARGO_PKG_VERSION")
+    )?;
+
+    for (i, conversation) in conversations.iter().enumerate() {
+        let messages = db.get_messages(&conversation.id)?;
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        serde_json::to_writer(
+            &mut out,
+            &ArchivedConversation {
+                conversation,
+                messages: &messages,
+            },
+        )?;
+    }
+
+    out.write_all(b"]}")?;
+    out.flush()?;
+    Ok(conversations.len())
+}
+
+fn write_markdown_zip(db: &Database, out: impl Write + Seek) -> Result<usize, AppError> {
+    let conversations = db.list_conversations()?;
+    let mut zip = zip::ZipWriter::new(out);
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+
+    for conversation in &conversations {
+        let messages = db.get_messages(&conversation.id)?;
+        zip.start_file(archive_file_name(conversation), options)?;
+        zip.write_all(render_markdown(conversation, &messages).as_bytes())?;
+    }
+
+    zip.finish()?;
+    Ok(conversations.len())
+}
+
+/// Build a filesystem-safe, unique entry name like `rust-help-1a2b3c4d.md`.
+fn archive_file_name(conversation: &Conversation) -> String {
+    let slug: String = conversation
+        .title
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let slug: Vec<&str> = slug.split('-').filter(|s| !s.is_empty()).collect();
+    let slug: String = slug.join("-").chars().take(48).collect();
+    let short_id: String = conversation.id.chars().take(8).collect();
+    if slug.is_empty() {
+        format!("{}.md", short_id)
+    } else {
+        format!("{}-{}.md", slug, short_id)
+    }
+}
+
+fn role_label(role: &str) -> &str {
+    match role {
+        "user" => "User",
+        "assistant" => "Assistant",
+        "system" => "System",
+        "tool" => "Tool",
+        other => other,
+    }
+}
+
+/// Render a role-labeled Markdown transcript. Message bodies are kept verbatim
+/// so code blocks survive; a fence left open by a truncated reply is closed so
+/// it doesn't swallow the following turns.
+pub fn render_markdown(conversation: &Conversation, messages: &[Message]) -> String {
+    let mut out = format!("# {}\n\n", conversation.title);
+    if let Some(model) = &conversation.model {
+        out.push_str(&format!("- Model: `{}`\n", model));
+    }
+    out.push_str(&format!("- Created: {}\n\n", conversation.created_at));
+
+    for msg in messages {
+        out.push_str(&format!(
+            "## {} ({})\n\n",
+            role_label(&msg.role),
+            msg.created_at
+        ));
+        let body = msg.content.trim_end();
+        out.push_str(body);
+        out.push('\n');
+        if let Some(fence) = open_fence(body) {
+            out.push_str(&fence);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Return the fence marker needed to close a code block left open in `text`.
+fn open_fence(text: &str) -> Option<String> {
+    let mut open: Option<String> = None;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let marker_char = match trimmed.chars().next() {
+            Some(c @ ('`' | '~')) => c,
+            _ => continue,
+        };
+        let marker_len = trimmed.chars().take_while(|&c| c == marker_char).count();
+        if marker_len < 3 {
+            continue;
+        }
+        let marker = marker_char.to_string().repeat(marker_len);
+        match &open {
+            None => open = Some(marker),
+            // A closing fence uses the same character, is at least as long,
+            // and carries no info string.
+            Some(current)
+                if current.starts_with(marker_char)
+                    && marker_len >= current.len()
+                    && trimmed[marker.len()..].trim().is_empty() =>
+            {
+                open = None
+            }
+            Some(_) => {}
+        }
+    }
+    open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            id: "m".into(),
+            conversation_id: "c".into(),
+            role: role.into(),
+            content: content.into(),
+            created_at: "2025-01-01 00:00:00".into(),
+            attachments: Vec::new(),
+            model: None,
+            truncated: false,
+            tool_call_id: None,
+            tool_calls: Vec::new(),
+        }
+    }
+
+    fn conversation() -> Conversation {
+        Conversation {
+            id: "c".into(),
+            title: "Rust help".into(),
+            model: Some("openai/gpt-4o".into()),
+            created_at: "2025-01-01 00:00:00".into(),
+            updated_at: "2025-01-01 00:00:00".into(),
+            deleted_at: None,
+            system_prompt: None,
+            tags: None,
+        }
+    }
+
+    #[test]
+    fn test_markdown_preserves_code_blocks() {
+        let reply = "Try this:\n\n```rust\nfn main() {}\n```\n\nDone.";
+        let md = render_markdown(
+            &conversation(),
+            &[message("user", "How?"), message("assistant", reply)],
+        );
+        assert!(md.contains("## User"));
+        assert!(md.contains("## Assistant"));
+        assert!(md.contains(reply));
+        assert_eq!(md.matches("```").count(), 2);
+    }
+
+    #[test]
+    fn test_markdown_closes_unterminated_fence() {
+        let md = render_markdown(
+            &conversation(),
+            &[
+                message("assistant", "````md\n```inner\n"),
+                message("user", "next"),
+            ],
+        );
+        let next = md.find("## User").unwrap();
+        assert!(md[..next].trim_end().ends_with("````"));
+    }
+
+    #[test]
+    fn test_json_import_uses_fresh_ids() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Original", Some("ollama/llama3")).unwrap();
+        db.add_message(&conv.id, "user", "hi").unwrap();
+        db.add_message(&conv.id, "assistant", "hello").unwrap();
+
+        let export = ConversationExport {
+            version: EXPORT_VERSION,
+            conversation: db.get_conversation(&conv.id).unwrap().unwrap(),
+            messages: db.get_messages(&conv.id).unwrap(),
+        };
+        let json = serde_json::to_string(&export).unwrap();
+        let parsed: ConversationExport = serde_json::from_str(&json).unwrap();
+
+        let imported = db
+            .import_conversation(&parsed.conversation, &parsed.messages)
+            .unwrap();
+        assert_ne!(imported.id, conv.id);
+        assert_eq!(imported.model.as_deref(), Some("ollama/llama3"));
+
+        let messages = db.get_messages(&imported.id).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| export.messages.iter().all(|o| o.id != m.id)));
+        assert_eq!(messages[1].content, "hello");
+    }
+
+    #[test]
+    fn test_json_archive_contains_all_conversations() {
+        let db = Database::open_in_memory().unwrap();
+        let a = db.create_conversation("First", None).unwrap();
+        db.add_message(&a.id, "user", "one").unwrap();
+        let b = db.create_conversation("Second", None).unwrap();
+        db.add_message(&b.id, "user", "two").unwrap();
+        db.add_message(&b.id, "assistant", "three").unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(write_json_archive(&db, &mut buf).unwrap(), 2);
+
+        let archive: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(archive["schema_version"], ARCHIVE_SCHEMA_VERSION);
+        let entries = archive["conversations"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        let total: usize = entries
+            .iter()
+            .map(|e| e["messages"].as_array().unwrap().len())
+            .sum();
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn test_markdown_zip_has_one_file_per_conversation() {
+        let db = Database::open_in_memory().unwrap();
+        db.create_conversation("Trip plans: Japan", None).unwrap();
+        db.create_conversation("Trip plans: Japan", None).unwrap();
+
+        let mut buf = std::io::Cursor::new(Vec::new());
+        assert_eq!(write_markdown_zip(&db, &mut buf).unwrap(), 2);
+
+        let mut zip = zip::ZipArchive::new(buf).unwrap();
+        assert_eq!(zip.len(), 2);
+        let name = zip.by_index(0).unwrap().name().to_string();
+        assert!(name.starts_with("trip-plans-japan-"));
+        assert!(name.ends_with(".md"));
+    }
+}
@@ -1,51 +1,265 @@
-use crate::db::models::Document;
+use crate::cancel::{CancelGuard, CancelRegistry};
+use crate::commands::chat::{resolve_provider, ChatStreamEvent};
+use crate::commands::settings::{base_url_setting, generation_params, openai_extra_headers};
+use crate::db::models::{
+    Chunk, Document, Job, JOB_CANCELLED, JOB_FAILED, JOB_INDEXING, JOB_PARTIAL, JOB_READY,
+};
 use crate::db::Database;
 use crate::doc_processor;
 use crate::embedding::{
-    self, bytes_to_embedding, embedding_to_bytes, generate_embeddings, search_similar,
+    self, bytes_to_embedding, embedding_to_bytes, generate_embeddings, is_near_duplicate,
+    search_normalized_diverse, EmbeddingBackend,
 };
+use crate::error::AppError;
 use crate::llm::openai::OpenAiConfig;
+use crate::llm::{ChatMessage, ChatRequest, StreamChunk};
+use crate::tokens;
 use rusqlite::params;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::path::Path;
-use tauri::State;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tauri::{Emitter, Manager, State};
+use tokio::sync::Notify;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChunkInfo {
     pub id: String,
     pub content: String,
     pub chunk_index: i32,
+    /// 1-based page range the chunk was cut from (PDFs only).
+    pub page_start: Option<u32>,
+    pub page_end: Option<u32>,
+    /// Cosine similarity from the vector search.
     pub score: Option<f32>,
+    /// Cross-encoder relevance, set when the search was reranked.
+    pub rerank_score: Option<f32>,
+    /// Position of the chunk's score between the weakest (0) and strongest
+    /// (1) result of this search, for display.
+    #[serde(default)]
+    pub relevance: Option<f32>,
+}
+
+const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// Chunks of one document more similar than this are dropped on upload.
+const DEFAULT_DEDUP_UPLOAD_THRESHOLD: f32 = 0.98;
+/// Search results more similar than this to a higher-ranked one are hidden.
+const DEFAULT_DEDUP_SEARCH_THRESHOLD: f32 = 0.95;
+
+/// Read a dedup threshold setting, falling back to `default` when unset or
+/// unparsable.
+fn dedup_threshold(db: &Database, key: &str, default: f32) -> f32 {
+    db.get_setting(key)
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Backend shared by document upload and search, picked by the
+/// `embedding_provider` setting ("openai" by default, or "local").
+///
+/// For "openai", `embedding_base_url` can point at a separate server (e.g. a
+/// local embedding service) and falls back to the OpenAI chat endpoint.
+/// Returns `None` when neither an OpenAI key nor a dedicated embedding
+/// endpoint is configured.
+fn embedding_backend(app: &tauri::AppHandle, db: &Database) -> Option<EmbeddingBackend> {
+    let provider = db.get_setting("embedding_provider").ok().flatten();
+    if provider.as_deref() == Some("local") {
+        let cache_dir = app.path().app_data_dir().ok()?.join("models");
+        return Some(EmbeddingBackend::Local { cache_dir });
+    }
+
+    let api_key = db.get_setting("openai_api_key").ok().flatten();
+    let embedding_base_url = base_url_setting(db, "embedding_base_url");
+    if api_key.is_none() && embedding_base_url.is_none() {
+        return None;
+    }
+    let base_url = embedding_base_url
+        .or_else(|| base_url_setting(db, "openai_base_url"))
+        .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
+    let model = db
+        .get_setting("embedding_model")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL.to_string());
+    let dimensions = db
+        .get_setting("embedding_dimensions")
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse().ok());
+    Some(EmbeddingBackend::OpenAi {
+        config: OpenAiConfig {
+            api_key: api_key.unwrap_or_default(),
+            base_url,
+            extra_headers: openai_extra_headers(db),
+        },
+        model,
+        dimensions,
+    })
+}
+
+/// Vectors from different models aren't comparable, so refuse to mix them.
+fn ensure_embedding_model(stored: &[String], configured: &str) -> Result<(), AppError> {
+    let others: Vec<&str> = stored
+        .iter()
+        .map(String::as_str)
+        .filter(|m| *m != configured)
+        .collect();
+    if others.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::InvalidInput(format!(
+            "Existing chunks were embedded with {}, but the configured embedding model is {}. \
+             Switch embedding_model back or re-upload your documents.",
+            others.join(", "),
+            configured
+        )))
+    }
+}
+
+/// Search only compares vectors as long as the query's, so chunks of any
+/// other length would quietly never match. Fail loudly instead, e.g. after
+/// `embedding_dimensions` changed or the endpoint started serving another
+/// model.
+fn ensure_embedding_dimensions(query_dim: usize, stored_dims: &[usize]) -> Result<(), AppError> {
+    let mut dims: Vec<usize> = stored_dims
+        .iter()
+        .copied()
+        .filter(|&dim| dim != query_dim)
+        .collect();
+    if dims.is_empty() {
+        return Ok(());
+    }
+    dims.sort_unstable();
+    dims.dedup();
+    let dims: Vec<String> = dims.iter().map(usize::to_string).collect();
+    Err(AppError::InvalidInput(format!(
+        "Stored embeddings are {}-dim, query is {}-dim; re-embed the knowledge base",
+        dims.join("/"),
+        query_dim
+    )))
 }
 
 #[tauri::command]
-pub fn list_documents(db: State<'_, Database>) -> Result<Vec<Document>, String> {
-    let conn = db.conn.lock().unwrap();
-    let mut stmt = conn
-        .prepare("SELECT id, filename, file_type, file_path, file_size, created_at FROM documents ORDER BY created_at DESC")
-        .map_err(|e| e.to_string())?;
-    let rows = stmt
-        .query_map([], |row| {
-            Ok(Document {
-                id: row.get(0)?,
-                filename: row.get(1)?,
-                file_type: row.get(2)?,
-                file_path: row.get(3)?,
-                file_size: row.get(4)?,
-                created_at: row.get(5)?,
-            })
-        })
-        .map_err(|e| e.to_string())?;
-    rows.collect::<Result<Vec<_>, _>>()
-        .map_err(|e| e.to_string())
+pub fn list_documents(db: State<'_, Database>) -> Result<Vec<Document>, AppError> {
+    db.list_documents().map_err(AppError::from)
+}
+
+/// Progress of an upload or re-embed, emitted on "embedding-progress". The
+/// first event carries the document id needed for `cancel_upload`.
+#[derive(Clone, Serialize)]
+struct EmbeddingProgress {
+    document_id: String,
+    embedded: usize,
+    total: usize,
 }
 
+/// Chunks sent to the embedding endpoint per request.
+const EMBED_BATCH_SIZE: usize = 20;
+
+/// Characters per chunk and shared between neighbours unless the upload
+/// asks for something else.
+const DEFAULT_CHUNK_SIZE: usize = 512;
+const DEFAULT_CHUNK_OVERLAP: usize = 64;
+const MIN_CHUNK_SIZE: usize = 32;
+const MAX_CHUNK_SIZE: usize = 8_192;
+
+/// Requested chunking with defaults filled in, rejecting sizes that would
+/// produce useless chunks (or never advance, for overlap >= size).
+fn chunking_params(
+    chunk_size: Option<usize>,
+    overlap: Option<usize>,
+) -> Result<(usize, usize), AppError> {
+    let size = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let overlap = overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP.min(size / 2));
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size) {
+        return Err(AppError::InvalidInput(format!(
+            "chunk_size must be between {} and {}, got {}",
+            MIN_CHUNK_SIZE, MAX_CHUNK_SIZE, size
+        )));
+    }
+    if overlap >= size {
+        return Err(AppError::InvalidInput(format!(
+            "overlap ({}) must be smaller than chunk_size ({})",
+            overlap, size
+        )));
+    }
+    Ok((size, overlap))
+}
+
+/// Parse and store a file, then queue its chunks for the embedding worker
+/// and return without waiting: the document comes back with
+/// `index_status: "indexing"` and progress arrives as "embedding-progress"
+/// events. `chunk_size` and `overlap` (in characters) are stored on the
+/// document so `update_document` splits it the same way. Text that is
+/// already indexed, under any filename, is rejected or returned as the
+/// existing document depending on `duplicate_uploads`.
 #[tauri::command]
 pub async fn upload_document(
+    app: tauri::AppHandle,
     db: State<'_, Database>,
+    queue: State<'_, EmbeddingQueue>,
     file_path: String,
-) -> Result<Document, String> {
-    let path = Path::new(&file_path);
+    chunk_size: Option<usize>,
+    overlap: Option<usize>,
+) -> Result<Document, AppError> {
+    let (chunk_size, overlap) = chunking_params(chunk_size, overlap)?;
+    let embedding = embedding_backend(&app, &db);
+    let model = embedding.as_ref().map(|b| b.model_id());
+    let doc_id = match store_upload(&db, &file_path, chunk_size, overlap, model)? {
+        StoredUpload::Existing(doc) => return Ok(doc),
+        StoredUpload::New(id) => id,
+    };
+    if embedding.is_some() {
+        queue.wake();
+    }
+
+    // Return the created document
+    db.get_document(&doc_id)?
+        .ok_or(AppError::NotFound("Document not found".into()))
+}
+
+enum StoredUpload {
+    /// Id of the inserted document.
+    New(String),
+    /// The same text was already indexed and `duplicate_uploads` is "reuse".
+    Existing(Document),
+}
+
+/// Hex SHA-256 of a document's parsed text. Hashing the text rather than
+/// the file matches renamed copies and files that differ only in encoding.
+fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// What to do with an upload whose text matches `existing`: an error naming
+/// the indexed copy, unless `duplicate_uploads` is set to "reuse".
+fn duplicate_upload(db: &Database, existing: Document) -> Result<StoredUpload, AppError> {
+    if db.get_setting("duplicate_uploads")?.as_deref() == Some("reuse") {
+        return Ok(StoredUpload::Existing(existing));
+    }
+    Err(AppError::InvalidInput(format!(
+        "This content is already indexed as {}",
+        existing.filename
+    )))
+}
+
+/// Parse, chunk and insert an upload with unembedded chunks, queued for
+/// embedding when there is an `embedding_model`, checking for an identical
+/// document first. `embedding_model` is the model the chunks
+/// will be embedded with, checked against what's already stored before
+/// anything is written.
+fn store_upload(
+    db: &Database,
+    file_path: &str,
+    chunk_size: usize,
+    overlap: usize,
+    embedding_model: Option<&str>,
+) -> Result<StoredUpload, AppError> {
+    let path = Path::new(file_path);
     let filename = path
         .file_name()
         .and_then(|n| n.to_str())
@@ -57,177 +271,1585 @@ pub async fn upload_document(
 
     // Parse file content
     let parsed = doc_processor::parse_file(path)?;
+    let hash = content_hash(&parsed.content);
+    if let Some(existing) = db.find_document_by_hash(&hash, None)? {
+        return duplicate_upload(db, existing);
+    }
 
     // Chunk the text
-    let chunks = doc_processor::chunk_text(&parsed.content, 512, 64);
+    let chunks = doc_processor::chunk_document(&parsed, chunk_size, overlap);
     if chunks.is_empty() {
-        return Err("Document is empty or could not be parsed".into());
+        return Err(AppError::InvalidInput("Document is empty or could not be parsed".into()));
     }
 
-    // Save document and chunks to DB (sync block — no await inside)
-    let doc_id = uuid::Uuid::new_v4().to_string();
-    let (api_key, base_url, chunk_rows) = {
-        let conn = db.conn.lock().unwrap();
-        conn.execute(
-            "INSERT INTO documents (id, filename, file_type, file_path, file_size) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![doc_id, filename, parsed.file_type, file_path, file_size],
-        )
-        .map_err(|e| e.to_string())?;
+    // Check the embedding model before writing anything, so a mismatch with
+    // existing chunks doesn't leave a half-indexed document
+    if let Some(model) = embedding_model {
+        let stored = db.embedding_models_in_use()?;
+        ensure_embedding_model(&stored, model)?;
+    }
 
-        let mut saved_chunks = Vec::new();
-        for (i, chunk_text) in chunks.iter().enumerate() {
-            let chunk_id = uuid::Uuid::new_v4().to_string();
-            conn.execute(
-                "INSERT INTO chunks (id, document_id, content, chunk_index) VALUES (?1, ?2, ?3, ?4)",
-                params![chunk_id, doc_id, chunk_text, i as i32],
-            )
-            .map_err(|e| e.to_string())?;
-            saved_chunks.push((chunk_id, chunk_text.clone()));
+    let doc = Document {
+        id: uuid::Uuid::new_v4().to_string(),
+        filename,
+        file_type: parsed.file_type,
+        file_path: file_path.to_string(),
+        file_size,
+        created_at: String::new(),
+        chunk_size: Some(chunk_size as u32),
+        chunk_overlap: Some(overlap as u32),
+        index_status: None,
+        embedded_chunks: 0,
+        chunk_count: 0,
+        content_hash: Some(hash),
+    };
+    // Without an embedding endpoint the chunks just stay pending
+    db.add_document(&doc, &chunks, embedding_model.is_some())?;
+    Ok(StoredUpload::New(doc.id))
+}
+
+/// What uploading a file with the given chunking would produce.
+#[derive(Debug, Serialize)]
+pub struct DocumentEstimate {
+    pub chunk_count: usize,
+    /// Tokens sent to the embedding endpoint, overlap included.
+    pub total_tokens: usize,
+    pub min_chunk_tokens: usize,
+    pub max_chunk_tokens: usize,
+    pub avg_chunk_tokens: f64,
+}
+
+/// Parse and chunk a file the way `upload_document` would and report the
+/// chunk count and token totals, without storing or embedding anything, so
+/// chunk settings and embedding cost can be judged up front. Tokens are
+/// counted for the configured embedding model.
+#[tauri::command]
+pub fn estimate_document(
+    db: State<'_, Database>,
+    file_path: String,
+    chunk_size: Option<usize>,
+    overlap: Option<usize>,
+) -> Result<DocumentEstimate, AppError> {
+    let (chunk_size, overlap) = chunking_params(chunk_size, overlap)?;
+    let parsed = doc_processor::parse_file(Path::new(&file_path))?;
+    let chunks = doc_processor::chunk_document(&parsed, chunk_size, overlap);
+    let model = db
+        .get_setting("embedding_model")?
+        .unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL.to_string());
+    Ok(estimate_chunks(&chunks, &model))
+}
+
+fn estimate_chunks(chunks: &[doc_processor::TextChunk], model_id: &str) -> DocumentEstimate {
+    let counts: Vec<usize> = chunks
+        .iter()
+        .map(|c| tokens::count_tokens(model_id, &c.content))
+        .collect();
+    let total_tokens = counts.iter().sum();
+    DocumentEstimate {
+        chunk_count: counts.len(),
+        total_tokens,
+        min_chunk_tokens: counts.iter().copied().min().unwrap_or(0),
+        max_chunk_tokens: counts.iter().copied().max().unwrap_or(0),
+        avg_chunk_tokens: if counts.is_empty() {
+            0.0
+        } else {
+            total_tokens as f64 / counts.len() as f64
+        },
+    }
+}
+
+/// Embed `pending` `(chunk id, text)` rows of a document batch by batch,
+/// committing each batch in its own transaction. Cancellation is checked
+/// between batches, so every chunk is either fully written or still has a
+/// NULL embedding for `reembed_document` to pick up. Near-duplicates of `kept`
+/// or of an earlier chunk are deleted. Returns false if cancelled.
+async fn embed_pending(
+    db: &Database,
+    backend: &EmbeddingBackend,
+    pending: &[(String, String)],
+    mut kept: Vec<Vec<f32>>,
+    cancel: &CancelGuard<'_>,
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<bool, AppError> {
+    let threshold =
+        dedup_threshold(db, "dedup_upload_threshold", DEFAULT_DEDUP_UPLOAD_THRESHOLD);
+    let progress = |embedded| on_progress(embedded, pending.len());
+    progress(0);
+
+    let mut embedded = 0;
+    for batch in pending.chunks(EMBED_BATCH_SIZE) {
+        if cancel.is_cancelled() {
+            return Ok(false);
         }
+        let texts: Vec<String> = batch.iter().map(|(_, text)| text.clone()).collect();
+        let embeddings = generate_embeddings(backend, &texts).await?;
 
-        // Read settings while we have the lock
-        let api_key = db.get_setting("openai_api_key").ok().flatten();
-        let base_url = db
-            .get_setting("openai_base_url")
-            .ok()
-            .flatten()
-            .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
+        let mut conn = db.conn()?;
+        let tx = conn.transaction()?;
+        for ((chunk_id, _), emb) in batch.iter().zip(embeddings) {
+            let kept_refs: Vec<&[f32]> = kept.iter().map(Vec::as_slice).collect();
+            if is_near_duplicate(&emb, &kept_refs, threshold) {
+                tx.execute("DELETE FROM chunks WHERE id = ?1", params![chunk_id])?;
+            } else {
+                tx.execute(
+                    "UPDATE chunks SET embedding = ?1, embedding_model = ?2, embedding_dim = ?3
+                     WHERE id = ?4",
+                    params![embedding_to_bytes(&emb), backend.model_id(), emb.len(), chunk_id],
+                )?;
+                kept.push(emb);
+            }
+        }
+        tx.commit()?;
+        drop(conn);
 
-        (api_key, base_url, saved_chunks)
-    }; // lock released here
+        embedded += batch.len();
+        progress(embedded);
+    }
+    Ok(true)
+}
 
-    // Generate embeddings asynchronously (if API key is available)
-    if let Some(api_key) = api_key {
-        let config = OpenAiConfig {
-            api_key,
-            base_url,
-        };
-        let batch_size = 20;
-        for batch in chunk_rows.chunks(batch_size) {
-            let texts: Vec<String> = batch.iter().map(|(_, c)| c.clone()).collect();
-            match generate_embeddings(&config, &texts, "text-embedding-3-small").await {
-                Ok(embeddings) => {
-                    let conn = db.conn.lock().unwrap();
-                    for ((chunk_id, _), emb) in batch.iter().zip(embeddings.iter()) {
-                        let bytes = embedding_to_bytes(emb);
-                        conn.execute(
-                            "UPDATE chunks SET embedding = ?1 WHERE id = ?2",
-                            params![bytes, chunk_id],
-                        )
-                        .ok();
-                    }
-                }
+/// Stop embedding a document that is being uploaded or re-embedded, or
+/// drop it from the queue if the worker hasn't reached it yet. Chunks
+/// embedded so far are kept; the rest stay pending. Returns false if no
+/// embedding job is queued or running for `document_id`.
+#[tauri::command]
+pub fn cancel_upload(
+    db: State<'_, Database>,
+    cancels: State<'_, CancelRegistry>,
+    document_id: String,
+) -> Result<bool, AppError> {
+    Ok(cancels.cancel(&document_id) || db.cancel_queued_job(&document_id)?)
+}
+
+/// Wakes the embedding worker when a job is queued.
+#[derive(Default)]
+pub struct EmbeddingQueue {
+    notify: Notify,
+}
+
+impl EmbeddingQueue {
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Start the task that works through queued embedding jobs one at a time,
+/// beginning with any left over from the last run. Needs `Database`,
+/// `CancelRegistry` and `EmbeddingQueue` to be managed already.
+pub(crate) fn spawn_embedding_worker(app: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let db = app.state::<Database>().inner().clone();
+        loop {
+            match db.next_job() {
+                Ok(Some(job)) => run_job(&app, &db, &job.document_id).await,
+                Ok(None) => app.state::<EmbeddingQueue>().notify.notified().await,
                 Err(e) => {
-                    eprintln!("Embedding generation failed (non-fatal): {}", e);
-                    break;
+                    eprintln!("Failed to read the embedding queue: {}", e);
+                    app.state::<EmbeddingQueue>().notify.notified().await
                 }
             }
         }
+    });
+}
+
+/// Embed a queued document and record how the job ended.
+async fn run_job(app: &tauri::AppHandle, db: &Database, document_id: &str) {
+    let cancels = app.state::<CancelRegistry>();
+    // Taken over by `reembed_document` or `reembed_pending`, which record
+    // the outcome themselves
+    let Some(cancel) = cancels.try_register(document_id) else {
+        return;
+    };
+    let result = match embedding_backend(app, db) {
+        Some(backend) => embed_document(app, db, &backend, document_id, &cancel).await,
+        None => Err(AppError::NotConfigured("No embedding provider configured".into())),
+    };
+    drop(cancel);
+    if let Err(e) = record_outcome(db, document_id, &result) {
+        eprintln!("Failed to update embedding job {}: {}", document_id, e);
+    }
+}
+
+/// Record how embedding a document ended on its job. An error after some
+/// chunks were embedded leaves the document `partial` rather than `failed`.
+fn record_outcome(
+    db: &Database,
+    document_id: &str,
+    result: &Result<bool, AppError>,
+) -> Result<(), AppError> {
+    match result {
+        Ok(true) => db.finish_job(document_id, JOB_READY, None)?,
+        Ok(false) => db.finish_job(document_id, JOB_CANCELLED, None)?,
+        Err(e) => {
+            let (embedded, _) = db.chunk_embedding_counts(document_id)?;
+            let status = if embedded > 0 { JOB_PARTIAL } else { JOB_FAILED };
+            db.finish_job(document_id, status, Some(&e.to_string()))?
+        }
     }
+    Ok(())
+}
 
-    // Return the created document
-    let doc = {
-        let conn = db.conn.lock().unwrap();
-        conn.query_row(
-            "SELECT id, filename, file_type, file_path, file_size, created_at FROM documents WHERE id = ?1",
-            params![doc_id],
-            |row| {
-                Ok(Document {
-                    id: row.get(0)?,
-                    filename: row.get(1)?,
-                    file_type: row.get(2)?,
-                    file_path: row.get(3)?,
-                    file_size: row.get(4)?,
-                    created_at: row.get(5)?,
-                })
-            },
-        )
-        .map_err(|e| e.to_string())?
+/// Every embedding job, most recently updated first.
+#[tauri::command]
+pub fn list_jobs(db: State<'_, Database>) -> Result<Vec<Job>, AppError> {
+    db.list_jobs().map_err(AppError::from)
+}
+
+/// The embedding job of a document, or `None` if it never had one.
+#[tauri::command]
+pub fn job_status(db: State<'_, Database>, document_id: String) -> Result<Option<Job>, AppError> {
+    db.get_job(&document_id).map_err(AppError::from)
+}
+
+/// Embed the chunks of a document that have no embedding yet, e.g. after a
+/// cancelled upload or a failed embedding call. Returns how many chunks are
+/// still pending afterwards (0 once the document is fully indexed).
+#[tauri::command]
+pub async fn reembed_document(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    cancels: State<'_, CancelRegistry>,
+    id: String,
+) -> Result<usize, AppError> {
+    db.get_document(&id)?
+        .ok_or(AppError::NotFound("Document not found".into()))?;
+    let backend = embedding_backend(&app, &db)
+        .ok_or(AppError::NotConfigured("No embedding provider configured".into()))?;
+
+    let Some(cancel) = claim_document(&db, &cancels, &id)? else {
+        return Err(AppError::InvalidInput(
+            "Document is still being indexed in the background".into(),
+        ));
     };
-    Ok(doc)
+    let result = embed_document(&app, &db, &backend, &id, &cancel).await;
+    drop(cancel);
+    record_outcome(&db, &id, &result)?;
+    result?;
+    Ok(db.chunk_embedding_counts(&id)?.1)
+}
+
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct ReembedSummary {
+    /// Documents that had pending chunks and were worked on.
+    pub documents: usize,
+    /// Chunks that have an embedding now but didn't before.
+    pub embedded: usize,
+    /// Chunks still without an embedding, e.g. of documents that failed.
+    pub remaining: usize,
+    /// Documents whose embedding calls failed.
+    pub failed: usize,
 }
 
+/// Set while `reembed_pending` runs, so overlapping runs don't embed the
+/// same chunks twice.
+static REEMBED_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Holds `REEMBED_RUNNING` and clears it when dropped, so a run that errors,
+/// panics or is abandoned mid-await doesn't block the next one.
+struct ReembedGuard;
+
+impl ReembedGuard {
+    /// `None` if another run holds the flag.
+    fn acquire() -> Option<Self> {
+        REEMBED_RUNNING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| ReembedGuard)
+    }
+}
+
+impl Drop for ReembedGuard {
+    fn drop(&mut self) {
+        REEMBED_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Embed every chunk that has no embedding, across all documents, e.g.
+/// after an API outage during uploads. Documents are worked through one at a
+/// time with "embedding-progress" events as for an upload; one that fails is
+/// marked failed and the rest still run. Documents the background worker is
+/// indexing are left to it. Safe to run again until `remaining` is 0.
 #[tauri::command]
-pub fn delete_document(db: State<'_, Database>, id: String) -> Result<(), String> {
-    let conn = db.conn.lock().unwrap();
-    conn.execute("DELETE FROM documents WHERE id = ?1", params![id])
-        .map_err(|e| e.to_string())?;
+pub async fn reembed_pending(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    cancels: State<'_, CancelRegistry>,
+) -> Result<ReembedSummary, AppError> {
+    let backend = embedding_backend(&app, &db)
+        .ok_or(AppError::NotConfigured("No embedding provider configured".into()))?;
+    let Some(_running) = ReembedGuard::acquire() else {
+        return Err(AppError::InvalidInput("Pending chunks are already being embedded".into()));
+    };
+    reembed_all_pending(&db, &backend, &cancels, |id, embedded, total| {
+        report_progress(&app, &db, id, embedded, total)
+    })
+    .await
+}
+
+async fn reembed_all_pending(
+    db: &Database,
+    backend: &EmbeddingBackend,
+    cancels: &CancelRegistry,
+    on_progress: impl Fn(&str, usize, usize) + Sync,
+) -> Result<ReembedSummary, AppError> {
+    let mut summary = ReembedSummary::default();
+    for id in db.documents_with_pending_chunks()? {
+        let Some(cancel) = claim_document(db, cancels, &id)? else {
+            summary.remaining += db.chunk_embedding_counts(&id)?.1;
+            continue;
+        };
+        let (embedded_before, _) = db.chunk_embedding_counts(&id)?;
+        let result = embed_document_with(db, backend, &id, &cancel, |embedded, total| {
+            on_progress(&id, embedded, total)
+        })
+        .await;
+        drop(cancel);
+        if let Err(e) = &result {
+            eprintln!("Failed to embed pending chunks of {}: {}", id, e);
+            summary.failed += 1;
+        }
+        record_outcome(db, &id, &result)?;
+        let (embedded_after, remaining) = db.chunk_embedding_counts(&id)?;
+        summary.documents += 1;
+        summary.embedded += embedded_after.saturating_sub(embedded_before);
+        summary.remaining += remaining;
+    }
+    Ok(summary)
+}
+
+/// Take embedding a document over from the worker. Returns `None` while
+/// the worker is running its job; a job that is only queued is cancelled so
+/// the worker doesn't pick it up as well.
+fn claim_document<'a>(
+    db: &Database,
+    cancels: &'a CancelRegistry,
+    document_id: &str,
+) -> Result<Option<CancelGuard<'a>>, AppError> {
+    let Some(cancel) = cancels.try_register(document_id) else {
+        return Ok(None);
+    };
+    db.cancel_queued_job(document_id)?;
+    Ok(Some(cancel))
+}
+
+/// Record embedding progress on the document's job and emit it as
+/// "embedding-progress".
+fn report_progress(
+    app: &tauri::AppHandle,
+    db: &Database,
+    document_id: &str,
+    embedded: usize,
+    total: usize,
+) {
+    let _ = db.set_job_progress(document_id, embedded, total);
+    let _ = app.emit(
+        "embedding-progress",
+        EmbeddingProgress {
+            document_id: document_id.to_string(),
+            embedded,
+            total,
+        },
+    );
+}
+
+/// Embed whatever chunks of a document are still pending, deduplicating
+/// against the ones already embedded. Returns false if cancelled.
+async fn embed_document(
+    app: &tauri::AppHandle,
+    db: &Database,
+    backend: &EmbeddingBackend,
+    id: &str,
+    cancel: &CancelGuard<'_>,
+) -> Result<bool, AppError> {
+    embed_document_with(db, backend, id, cancel, |embedded, total| {
+        report_progress(app, db, id, embedded, total)
+    })
+    .await
+}
+
+/// `embed_document`, reporting `(embedded, total)` to `on_progress`.
+async fn embed_document_with(
+    db: &Database,
+    backend: &EmbeddingBackend,
+    id: &str,
+    cancel: &CancelGuard<'_>,
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<bool, AppError> {
+    let stored = db.embedding_models_in_use()?;
+    ensure_embedding_model(&stored, backend.model_id())?;
+
+    let (pending, kept) = {
+        let conn = db.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, content FROM chunks WHERE document_id = ?1 AND embedding IS NULL ORDER BY chunk_index",
+        )?;
+        let pending = stmt
+            .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        let mut stmt = conn.prepare(
+            "SELECT embedding FROM chunks WHERE document_id = ?1 AND embedding IS NOT NULL",
+        )?;
+        let kept = stmt
+            .query_map(params![id], |row| row.get::<_, Vec<u8>>(0))?
+            .map(|bytes| bytes.map(|b| bytes_to_embedding(&b)))
+            .collect::<Result<Vec<_>, _>>()?;
+        (pending, kept)
+    }; // connection returned to the pool
+
+    embed_pending(db, backend, &pending, kept, cancel, on_progress).await
+}
+
+/// Embed a document's chunks in batches. Near-duplicates of an earlier chunk
+/// of the same document come back as `None` and should be dropped.
+async fn embed_chunks(
+    db: &Database,
+    backend: &EmbeddingBackend,
+    texts: &[String],
+) -> Result<Vec<Option<Vec<f32>>>, AppError> {
+    let threshold =
+        dedup_threshold(db, "dedup_upload_threshold", DEFAULT_DEDUP_UPLOAD_THRESHOLD);
+    let mut kept: Vec<Vec<f32>> = Vec::new();
+    let mut result = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(EMBED_BATCH_SIZE) {
+        for emb in generate_embeddings(backend, batch).await? {
+            let kept_refs: Vec<&[f32]> = kept.iter().map(Vec::as_slice).collect();
+            if is_near_duplicate(&emb, &kept_refs, threshold) {
+                result.push(None);
+            } else {
+                kept.push(emb.clone());
+                result.push(Some(emb));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Re-parse, re-chunk and re-embed a document from `file_path`, keeping its
+/// id and `created_at`. Everything is embedded before the old chunks are
+/// touched, so a failure leaves the previous index intact. Refused while
+/// the document's embedding job is queued or running. New text that matches
+/// another document is handled as on upload, per `duplicate_uploads`.
+#[tauri::command]
+pub async fn update_document(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    id: String,
+    file_path: String,
+) -> Result<Document, AppError> {
+    let existing = db
+        .get_document(&id)?
+        .ok_or(AppError::NotFound("Document not found".into()))?;
+    let embedding = embedding_backend(&app, &db);
+    reindex_document(&db, embedding.as_ref(), existing, &file_path).await
+}
+
+async fn reindex_document(
+    db: &Database,
+    embedding: Option<&EmbeddingBackend>,
+    existing: Document,
+    file_path: &str,
+) -> Result<Document, AppError> {
+    if existing.index_status.as_deref() == Some(JOB_INDEXING) {
+        return Err(AppError::InvalidInput(format!(
+            "{} is still being indexed; cancel it or wait for it to finish before updating",
+            existing.filename
+        )));
+    }
+
+    let (chunk_size, overlap) = chunking_params(
+        existing.chunk_size.map(|n| n as usize),
+        existing.chunk_overlap.map(|n| n as usize),
+    )?;
+    let path = Path::new(file_path);
+    let parsed = doc_processor::parse_file(path)?;
+    let hash = content_hash(&parsed.content);
+    if let Some(other) = db.find_document_by_hash(&hash, Some(&existing.id))? {
+        if let StoredUpload::Existing(doc) = duplicate_upload(db, other)? {
+            return Ok(doc);
+        }
+    }
+    let chunks = doc_processor::chunk_document(&parsed, chunk_size, overlap);
+    if chunks.is_empty() {
+        return Err(AppError::InvalidInput("Document is empty or could not be parsed".into()));
+    }
+
+    let rows: Vec<(doc_processor::TextChunk, Option<Vec<f32>>)> = match embedding {
+        Some(backend) => {
+            let stored = db.embedding_models_in_use()?;
+            ensure_embedding_model(&stored, backend.model_id())?;
+            let texts: Vec<String> = chunks.iter().map(|c| c.content.clone()).collect();
+            let embeddings = embed_chunks(db, backend, &texts).await?;
+            if let Some(dim) = embeddings.iter().flatten().next().map(Vec::len) {
+                ensure_embedding_dimensions(dim, &db.embedding_dims_in_use(backend.model_id())?)?;
+            }
+            chunks
+                .into_iter()
+                .zip(embeddings)
+                .filter(|(_, emb)| emb.is_some())
+                .collect()
+        }
+        None => chunks.into_iter().map(|c| (c, None)).collect(),
+    };
+
+    let doc = Document {
+        filename: path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string(),
+        file_type: parsed.file_type,
+        file_path: file_path.to_string(),
+        file_size: std::fs::metadata(path).map(|m| m.len() as i64).ok(),
+        content_hash: Some(hash),
+        ..existing
+    };
+    let model = embedding.map(|b| b.model_id());
+    if !db.replace_document(&doc, &rows, model)? {
+        return Err(AppError::NotFound("Document not found".into()));
+    }
+    // Re-read for the new chunk counts and job status
+    db.get_document(&doc.id)?
+        .ok_or(AppError::NotFound("Document not found".into()))
+}
+
+#[tauri::command]
+pub fn delete_document(db: State<'_, Database>, id: String) -> Result<(), AppError> {
+    let conn = db.conn()?;
+    conn.execute("DELETE FROM documents WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClearedKnowledgeBase {
+    pub documents: usize,
+    pub chunks: usize,
+    /// Source files removed from disk, with `delete_files`.
+    pub files_deleted: usize,
+}
+
+/// Delete every document and chunk, leaving chats alone. With
+/// `delete_files`, source files kept in the app's data directory are removed
+/// too; files elsewhere are the user's originals and are never touched.
+#[tauri::command]
+pub fn clear_knowledge_base(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    delete_files: Option<bool>,
+) -> Result<ClearedKnowledgeBase, AppError> {
+    let (paths, chunks) = db.clear_knowledge_base()?;
+    let mut files_deleted = 0;
+    if delete_files.unwrap_or(false) {
+        let data_dir = app.path().app_data_dir()?;
+        for path in paths.iter().map(Path::new).filter(|p| p.starts_with(&data_dir)) {
+            match std::fs::remove_file(path) {
+                Ok(()) => files_deleted += 1,
+                Err(e) => eprintln!("Failed to delete {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(ClearedKnowledgeBase {
+        documents: paths.len(),
+        chunks,
+        files_deleted,
+    })
+}
+
+/// Chunks returned per `list_chunks` page when no limit is given.
+const DEFAULT_CHUNK_PAGE_SIZE: usize = 100;
+
+/// Inspect what a document was chunked into, a page at a time.
+#[tauri::command]
+pub fn list_chunks(
+    db: State<'_, Database>,
+    document_id: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Vec<Chunk>, AppError> {
+    let limit = limit.unwrap_or(DEFAULT_CHUNK_PAGE_SIZE);
+    Ok(db.list_chunks(&document_id, offset.unwrap_or(0), limit)?)
+}
+
+/// Prune a single chunk from the index.
+#[tauri::command]
+pub fn delete_chunk(db: State<'_, Database>, chunk_id: String) -> Result<(), AppError> {
+    if !db.delete_chunk(&chunk_id)? {
+        return Err(AppError::NotFound("Chunk not found".into()));
+    }
     Ok(())
 }
 
-/// Search knowledge base for chunks relevant to a query
+/// Rescale stored chunk vectors to unit length, which search relies on.
+/// Upgrading runs this once; it is exposed for repairing vectors written by
+/// other tools. Returns the number of chunks changed.
+#[tauri::command]
+pub fn renormalize_embeddings(db: State<'_, Database>) -> Result<usize, AppError> {
+    Ok(db.renormalize_embeddings()?)
+}
+
+/// Search knowledge base for chunks relevant to a query. Chunks with a
+/// cosine score below `min_score` are dropped instead of padding `top_k`.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn search_knowledge_base(
+    app: tauri::AppHandle,
     db: State<'_, Database>,
     query: String,
     top_k: Option<usize>,
-) -> Result<Vec<ChunkInfo>, String> {
-    let top_k = top_k.unwrap_or(5);
+    rerank: Option<bool>,
+    rerank_top_n: Option<usize>,
+    document_ids: Option<Vec<String>>,
+    min_score: Option<f32>,
+) -> Result<Vec<ChunkInfo>, AppError> {
+    let params = SearchParams {
+        top_k: top_k.unwrap_or(5),
+        rerank: rerank.unwrap_or(false),
+        rerank_top_n,
+        document_ids,
+        min_score,
+    };
+    search_chunks(&app, &db, query, params).await
+}
+
+struct SearchParams {
+    top_k: usize,
+    rerank: bool,
+    rerank_top_n: Option<usize>,
+    document_ids: Option<Vec<String>>,
+    min_score: Option<f32>,
+}
+
+impl SearchParams {
+    /// Plain vector search for the `top_k` best chunks.
+    fn top(top_k: usize) -> Self {
+        Self {
+            top_k,
+            rerank: false,
+            rerank_top_n: None,
+            document_ids: None,
+            min_score: None,
+        }
+    }
+}
+
+async fn search_chunks(
+    app: &tauri::AppHandle,
+    db: &Database,
+    query: String,
+    params: SearchParams,
+) -> Result<Vec<ChunkInfo>, AppError> {
+    let SearchParams {
+        top_k,
+        rerank,
+        rerank_top_n,
+        document_ids,
+        min_score,
+    } = params;
+    // Over-retrieve when reranking, then cut back down to top_k
+    let candidates = if rerank {
+        rerank_top_n.unwrap_or(20).max(top_k)
+    } else {
+        top_k
+    };
 
     // Read settings and chunk data synchronously (before any await)
-    let (config, chunk_data) = {
-        let api_key = db
-            .get_setting("openai_api_key")
-            .ok()
-            .flatten()
-            .ok_or("OpenAI API key required for knowledge base search")?;
-        let base_url = db
-            .get_setting("openai_base_url")
-            .ok()
-            .flatten()
-            .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
-        let config = OpenAiConfig { api_key, base_url };
-
-        let conn = db.conn.lock().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT id, content, chunk_index, embedding FROM chunks WHERE embedding IS NOT NULL")
-            .map_err(|e| e.to_string())?;
-        let data: Vec<(String, String, i32, Vec<f32>)> = stmt
-            .query_map([], |row| {
-                let bytes: Vec<u8> = row.get(3)?;
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    bytes_to_embedding(&bytes),
-                ))
-            })
-            .map_err(|e| e.to_string())?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| e.to_string())?;
+    let backend = embedding_backend(app, db).ok_or(AppError::NotConfigured(
+        "An embedding backend (OpenAI API key, embedding_base_url, or embedding_provider = \"local\") is required for knowledge base search".into(),
+    ))?;
+    let model = backend.model_id().to_string();
+    let stored = db.embedding_models_in_use()?;
+    ensure_embedding_model(&stored, &model)?;
 
-        (config, data)
-    }; // lock released
 
     // Generate query embedding (async)
     let query_embeddings =
-        generate_embeddings(&config, &[query], "text-embedding-3-small").await?;
+        generate_embeddings(&backend, std::slice::from_ref(&query)).await?;
     let query_emb = query_embeddings
         .first()
         .ok_or("Failed to generate query embedding")?;
+    ensure_embedding_dimensions(query_emb.len(), &db.embedding_dims_in_use(&model)?)?;
+
+    let chunk_data = {
+        let conn = db.conn()?;
+        load_candidate_chunks(&conn, &model, query_emb.len(), document_ids.as_deref())?
+    }; // connection returned to the pool
 
     // Build (id, embedding) pairs for search
     let emb_pairs: Vec<(String, Vec<f32>)> = chunk_data
         .iter()
-        .map(|(id, _, _, emb)| (id.clone(), emb.clone()))
+        .map(|c| (c.id.clone(), c.embedding.clone()))
         .collect();
 
-    let results = search_similar(query_emb, &emb_pairs, top_k);
+    let threshold =
+        dedup_threshold(db, "dedup_search_threshold", DEFAULT_DEDUP_SEARCH_THRESHOLD);
+    let mut results = search_normalized_diverse(query_emb, &emb_pairs, candidates, threshold);
+    drop_below_min_score(&mut results, min_score);
 
     // Map back to ChunkInfo
     let chunks: Vec<ChunkInfo> = results
         .iter()
         .filter_map(|(id, score)| {
-            chunk_data.iter().find(|(cid, _, _, _)| cid == id).map(
-                |(_, content, idx, _)| ChunkInfo {
-                    id: id.clone(),
-                    content: content.clone(),
-                    chunk_index: *idx,
-                    score: Some(*score),
-                },
-            )
+            chunk_data.iter().find(|c| &c.id == id).map(|c| ChunkInfo {
+                id: id.clone(),
+                content: c.content.clone(),
+                chunk_index: c.chunk_index,
+                page_start: c.page_start,
+                page_end: c.page_end,
+                score: Some(*score),
+                rerank_score: None,
+                relevance: None,
+            })
         })
         .collect();
 
+    let mut chunks = if !rerank || chunks.is_empty() {
+        chunks
+    } else {
+        let cache_dir = app
+            .path()
+            .app_data_dir()?
+            .join("models");
+        let documents = chunks.iter().map(|c| c.content.clone()).collect();
+        let scores = embedding::rerank(cache_dir, query, documents).await?;
+        apply_rerank(chunks, &scores, top_k)
+    };
+    set_relevance(&mut chunks);
     Ok(chunks)
 }
+
+/// Drop `(id, score)` results scoring under `min_score`.
+fn drop_below_min_score(results: &mut Vec<(String, f32)>, min_score: Option<f32>) {
+    if let Some(min) = min_score {
+        results.retain(|(_, score)| *score >= min);
+    }
+}
+
+/// Min-max normalise the score that ranked the results (the rerank score if
+/// present, else cosine) into `relevance`. A lone result is fully relevant.
+fn set_relevance(chunks: &mut [ChunkInfo]) {
+    let rank_score = |c: &ChunkInfo| c.rerank_score.or(c.score).unwrap_or(0.0);
+    let (min, max) = chunks.iter().map(rank_score).fold(
+        (f32::INFINITY, f32::NEG_INFINITY),
+        |(lo, hi), s| (lo.min(s), hi.max(s)),
+    );
+    for chunk in chunks.iter_mut() {
+        let relevance = if max > min {
+            (rank_score(chunk) - min) / (max - min)
+        } else {
+            1.0
+        };
+        chunk.relevance = Some(relevance);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RagAnswer {
+    pub answer: String,
+    /// Retrieved chunks the answer cites, in citation order.
+    pub sources: Vec<ChunkInfo>,
+}
+
+/// Retrieve context for `query` and have `model` answer from it, citing
+/// sources as `[n]`. The answer streams on "chat-stream" with an empty
+/// conversation id.
+#[tauri::command]
+pub async fn rag_query(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    query: String,
+    model: String,
+    top_k: Option<usize>,
+) -> Result<RagAnswer, AppError> {
+    let (provider, model_id) = resolve_provider(&model, &db)?;
+    let chunks = rag_chunks(&app, &db, query.clone(), top_k).await?;
+
+    let request = ChatRequest {
+        messages: vec![
+            ChatMessage {
+                role: "system".into(),
+                content: build_rag_prompt(&chunks),
+                attachments: Vec::new(),
+                tool_call_id: None,
+                tool_calls: Vec::new(),
+            },
+            ChatMessage {
+                role: "user".into(),
+                content: query,
+                attachments: Vec::new(),
+                tool_call_id: None,
+                tool_calls: Vec::new(),
+            },
+        ],
+        model: model_id,
+        stream: true,
+        params: generation_params(&db),
+    };
+    // As with chat replies, the provider's own `done` is held back until the
+    // answer is checked
+    let message_id = uuid::Uuid::new_v4().to_string();
+    let last: Mutex<Option<StreamChunk>> = Mutex::new(None);
+    let emit = |delta: String, done: bool, error: bool| {
+        let last = if done { last.lock().unwrap().take() } else { None };
+        let _ = app.emit(
+            "chat-stream",
+            ChatStreamEvent {
+                conversation_id: String::new(),
+                message_id: message_id.clone(),
+                model: model.clone(),
+                delta,
+                done,
+                error,
+                truncated: !error && last.as_ref().is_some_and(|chunk| chunk.truncated),
+                usage: last.and_then(|chunk| chunk.usage),
+            },
+        );
+    };
+    let result = provider
+        .chat_stream(&request, |chunk: StreamChunk| {
+            if chunk.done {
+                *last.lock().unwrap() = Some(chunk);
+            } else {
+                emit(chunk.delta, false, false);
+            }
+        })
+        .await;
+    let answer = match result {
+        Ok(answer) if !answer.trim().is_empty() => answer,
+        result => {
+            emit(String::new(), true, true);
+            return Err(match result {
+                Err(e) => e.into(),
+                Ok(_) => AppError::EmptyResponse("The model returned an empty answer".into()),
+            });
+        }
+    };
+    emit(String::new(), true, false);
+
+    let sources = cited_chunks(&answer, chunks);
+    Ok(RagAnswer { answer, sources })
+}
+
+/// The chunks `rag_query` answers from; none matching is an error.
+async fn rag_chunks(
+    app: &tauri::AppHandle,
+    db: &Database,
+    query: String,
+    top_k: Option<usize>,
+) -> Result<Vec<ChunkInfo>, AppError> {
+    let chunks = search_chunks(app, db, query, SearchParams::top(top_k.unwrap_or(5))).await?;
+    if chunks.is_empty() {
+        return Err(AppError::NotFound("No indexed documents matched the query".into()));
+    }
+    Ok(chunks)
+}
+
+/// The system prompt `rag_query` would send for `query`.
+pub(crate) async fn rag_context(
+    app: &tauri::AppHandle,
+    db: &Database,
+    query: String,
+    top_k: Option<usize>,
+) -> Result<String, AppError> {
+    Ok(build_rag_prompt(&rag_chunks(app, db, query, top_k).await?))
+}
+
+#[derive(Debug, Serialize)]
+pub struct RagPreview {
+    /// System prompt `rag_query` would send, sources included.
+    pub context: String,
+    /// Estimated tokens of `context`.
+    pub total_tokens: usize,
+    pub chunks: Vec<PreviewChunk>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreviewChunk {
+    /// Source label as it appears in the context, e.g. "[2] (page 4)".
+    pub label: String,
+    /// Estimated tokens of the chunk's text.
+    pub tokens: usize,
+    #[serde(flatten)]
+    pub chunk: ChunkInfo,
+}
+
+/// Show the context `rag_query` would inject for `query` without calling a
+/// model, to help tune `top_k` and chunk size. Tokens are counted for
+/// `model` when given.
+#[tauri::command]
+pub async fn preview_rag_context(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    query: String,
+    top_k: Option<usize>,
+    model: Option<String>,
+) -> Result<RagPreview, AppError> {
+    let chunks = search_chunks(&app, &db, query, SearchParams::top(top_k.unwrap_or(5))).await?;
+    // Last segment, so "openrouter/openai/gpt-4o" counts as gpt-4o
+    let model = model.unwrap_or_default();
+    let model_id = model.rsplit_once('/').map_or(model.as_str(), |(_, id)| id);
+    Ok(rag_preview(chunks, model_id))
+}
+
+fn rag_preview(chunks: Vec<ChunkInfo>, model_id: &str) -> RagPreview {
+    let context = build_rag_prompt(&chunks);
+    RagPreview {
+        total_tokens: tokens::count_tokens(model_id, &context),
+        context,
+        chunks: chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| PreviewChunk {
+                label: source_label(i, &chunk),
+                tokens: tokens::count_tokens(model_id, &chunk.content),
+                chunk,
+            })
+            .collect(),
+    }
+}
+
+/// System prompt listing the retrieved chunks as numbered sources.
+fn build_rag_prompt(chunks: &[ChunkInfo]) -> String {
+    let mut prompt = String::from(
+        "Answer the user's question using only the sources below. \
+         Cite every claim with the number of its source in square brackets, e.g. [1]. \
+         If the sources don't contain the answer, say so.\n",
+    );
+    for (i, chunk) in chunks.iter().enumerate() {
+        prompt.push_str(&format!("\n{}\n{}\n", source_label(i, chunk), chunk.content));
+    }
+    prompt
+}
+
+/// "[n]" or "[n] (page 12)" for the chunk at index `i` of the sources.
+fn source_label(i: usize, chunk: &ChunkInfo) -> String {
+    match page_label(chunk) {
+        Some(label) => format!("[{}] ({})", i + 1, label),
+        None => format!("[{}]", i + 1),
+    }
+}
+
+/// "page 12" or "pages 3-4", for chunks with a known page range.
+fn page_label(chunk: &ChunkInfo) -> Option<String> {
+    match (chunk.page_start, chunk.page_end) {
+        (Some(start), Some(end)) if end > start => Some(format!("pages {}-{}", start, end)),
+        (Some(start), _) => Some(format!("page {}", start)),
+        _ => None,
+    }
+}
+
+/// The chunks referenced as `[n]` in `answer`, in order of first citation.
+fn cited_chunks(answer: &str, chunks: Vec<ChunkInfo>) -> Vec<ChunkInfo> {
+    let mut order: Vec<usize> = Vec::new();
+    for part in answer.split('[').skip(1) {
+        let Some((num, _)) = part.split_once(']') else {
+            continue;
+        };
+        if let Ok(n) = num.trim().parse::<usize>() {
+            if (1..=chunks.len()).contains(&n) && !order.contains(&(n - 1)) {
+                order.push(n - 1);
+            }
+        }
+    }
+    let mut slots: Vec<Option<ChunkInfo>> = chunks.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| slots[i].take()).collect()
+}
+
+/// A searchable chunk and its vector.
+struct CandidateChunk {
+    id: String,
+    content: String,
+    chunk_index: i32,
+    page_start: Option<u32>,
+    page_end: Option<u32>,
+    embedding: Vec<f32>,
+}
+
+/// Embedded chunks searchable with `model` whose vectors have `dim`
+/// entries, optionally restricted to the given documents.
+fn load_candidate_chunks(
+    conn: &rusqlite::Connection,
+    model: &str,
+    dim: usize,
+    document_ids: Option<&[String]>,
+) -> rusqlite::Result<Vec<CandidateChunk>> {
+    use rusqlite::types::Value;
+
+    let mut sql = String::from(
+        "SELECT id, content, chunk_index, embedding, page_start, page_end FROM chunks
+         WHERE embedding IS NOT NULL AND embedding_model = ?1 AND embedding_dim = ?2",
+    );
+    let mut args: Vec<Value> = vec![Value::Text(model.to_string()), Value::Integer(dim as i64)];
+    if let Some(ids) = document_ids {
+        let placeholders: Vec<String> = (0..ids.len()).map(|i| format!("?{}", i + 3)).collect();
+        sql.push_str(&format!(" AND document_id IN ({})", placeholders.join(", ")));
+        args.extend(ids.iter().cloned().map(Value::Text));
+    }
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(args), |row| {
+        let bytes: Vec<u8> = row.get(3)?;
+        Ok(CandidateChunk {
+            id: row.get(0)?,
+            content: row.get(1)?,
+            chunk_index: row.get(2)?,
+            page_start: row.get(4)?,
+            page_end: row.get(5)?,
+            embedding: bytes_to_embedding(&bytes),
+        })
+    })?;
+    rows.collect()
+}
+
+/// Attach rerank scores, reorder by them (cosine score breaks ties), and
+/// keep the best `top_k`.
+fn apply_rerank(mut chunks: Vec<ChunkInfo>, scores: &[f32], top_k: usize) -> Vec<ChunkInfo> {
+    for (chunk, score) in chunks.iter_mut().zip(scores) {
+        chunk.rerank_score = Some(*score);
+    }
+    chunks.sort_by(|a, b| {
+        b.rerank_score
+            .partial_cmp(&a.rerank_score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal))
+    });
+    chunks.truncate(top_k);
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{serve, Reply};
+
+    fn chunk(id: &str, score: f32) -> ChunkInfo {
+        ChunkInfo {
+            id: id.into(),
+            content: String::new(),
+            chunk_index: 0,
+            page_start: None,
+            page_end: None,
+            score: Some(score),
+            rerank_score: None,
+            relevance: None,
+        }
+    }
+
+    #[test]
+    fn test_custom_chunking_flows_into_chunks() {
+        assert_eq!(chunking_params(None, None).unwrap(), (512, 64));
+        assert_eq!(chunking_params(Some(64), None).unwrap(), (64, 32));
+        assert!(chunking_params(Some(100), Some(100)).is_err());
+        assert!(chunking_params(Some(8), None).is_err());
+        assert!(chunking_params(Some(100_000), None).is_err());
+
+        let doc = doc_processor::ParsedDocument {
+            content: "x".repeat(1000),
+            file_type: "txt".into(),
+            pages: None,
+            chapters: None,
+        };
+        let (size, overlap) = chunking_params(Some(200), Some(50)).unwrap();
+        let chunks = doc_processor::chunk_document(&doc, size, overlap);
+        // Windows start every 150 characters: 0, 150, ..., 900
+        assert_eq!(chunks.len(), 7);
+        assert!(chunks.iter().all(|c| c.content.len() <= 200));
+        assert_eq!(chunks[0].content.len(), 200);
+    }
+
+    #[test]
+    fn test_apply_rerank_reorders_and_keeps_cosine() {
+        let chunks = vec![chunk("a", 0.9), chunk("b", 0.8), chunk("c", 0.7)];
+        let reranked = apply_rerank(chunks, &[0.1, 2.5, 1.0], 2);
+        let ids: Vec<&str> = reranked.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["b", "c"]);
+        assert_eq!(reranked[0].score, Some(0.8));
+        assert_eq!(reranked[0].rerank_score, Some(2.5));
+    }
+
+    #[test]
+    fn test_min_score_drops_low_similarity_results() {
+        let mut results = vec![
+            ("a".to_string(), 0.82),
+            ("b".to_string(), 0.41),
+            ("c".to_string(), 0.12),
+        ];
+        drop_below_min_score(&mut results, None);
+        assert_eq!(results.len(), 3);
+        drop_below_min_score(&mut results, Some(0.4));
+        let ids: Vec<&str> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_relevance_spans_result_set() {
+        let mut chunks = vec![chunk("a", 0.9), chunk("b", 0.7), chunk("c", 0.5)];
+        set_relevance(&mut chunks);
+        let relevance: Vec<f32> = chunks.iter().map(|c| c.relevance.unwrap()).collect();
+        assert!((relevance[0] - 1.0).abs() < 1e-6);
+        assert!((relevance[1] - 0.5).abs() < 1e-6);
+        assert!(relevance[2].abs() < 1e-6);
+
+        let mut single = vec![chunk("a", 0.3)];
+        set_relevance(&mut single);
+        assert_eq!(single[0].relevance, Some(1.0));
+    }
+
+    #[test]
+    fn test_cited_chunks_in_citation_order() {
+        let chunks = vec![chunk("a", 0.9), chunk("b", 0.8), chunk("c", 0.7)];
+        let answer = "Rust is fast [3] and safe [1][3]. See also [7] and [x].";
+        let ids: Vec<String> = cited_chunks(answer, chunks)
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, vec!["c", "a"]);
+    }
+
+    #[test]
+    fn test_rag_prompt_labels_pages() {
+        let mut one = chunk("a", 0.9);
+        one.content = "Alpha".into();
+        one.page_start = Some(12);
+        one.page_end = Some(12);
+        let mut span = chunk("b", 0.8);
+        span.content = "Beta".into();
+        span.page_start = Some(3);
+        span.page_end = Some(4);
+        let mut plain = chunk("c", 0.7);
+        plain.content = "Gamma".into();
+
+        let prompt = build_rag_prompt(&[one, span, plain]);
+        assert!(prompt.contains("[1] (page 12)\nAlpha"));
+        assert!(prompt.contains("[2] (pages 3-4)\nBeta"));
+        assert!(prompt.contains("[3]\nGamma"));
+    }
+
+    #[test]
+    fn test_rag_preview_matches_prompt_and_counts_tokens() {
+        let mut first = chunk("a", 0.9);
+        first.content = "The reactor runs at 300 kelvin.".into();
+        first.page_start = Some(4);
+        let mut second = chunk("b", 0.8);
+        second.content = "Coolant is replaced yearly.".into();
+
+        let prompt = build_rag_prompt(&[first.clone(), second.clone()]);
+        let preview = rag_preview(vec![first, second], "gpt-4o");
+        assert_eq!(preview.context, prompt);
+        assert_eq!(preview.total_tokens, tokens::count_tokens("gpt-4o", &prompt));
+        let labels: Vec<&str> = preview.chunks.iter().map(|c| c.label.as_str()).collect();
+        assert_eq!(labels, ["[1] (page 4)", "[2]"]);
+        let chunk_tokens: usize = preview.chunks.iter().map(|c| c.tokens).sum();
+        assert!(chunk_tokens > 0 && chunk_tokens < preview.total_tokens);
+    }
+
+    #[test]
+    fn test_document_filter_excludes_other_documents() {
+        let db = Database::open_in_memory().unwrap();
+        let conn = db.conn().unwrap();
+        for doc in ["a", "b", "c"] {
+            insert_document(&conn, doc, "txt");
+            conn.execute(
+                "INSERT INTO chunks (id, document_id, content, chunk_index, embedding, embedding_model, embedding_dim)
+                 VALUES (?1, ?1, ?1, 0, ?2, 'm', 2)",
+                params![doc, embedding_to_bytes(&[1.0, 0.0])],
+            )
+            .unwrap();
+        }
+
+        let all = load_candidate_chunks(&conn, "m", 2, None).unwrap();
+        assert_eq!(all.len(), 3);
+        assert!(load_candidate_chunks(&conn, "m", 512, None).unwrap().is_empty());
+
+        let ids = vec!["a".to_string(), "c".to_string()];
+        let mut scoped: Vec<String> = load_candidate_chunks(&conn, "m", 2, Some(&ids))
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        scoped.sort();
+        assert_eq!(scoped, vec!["a", "c"]);
+
+        assert!(load_candidate_chunks(&conn, "m", 2, Some(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ensure_embedding_model_accepts_matching() {
+        assert!(ensure_embedding_model(&[], "text-embedding-3-small").is_ok());
+        let stored = vec!["nomic-embed-text".to_string()];
+        assert!(ensure_embedding_model(&stored, "nomic-embed-text").is_ok());
+    }
+
+    #[test]
+    fn test_ensure_embedding_model_rejects_divergent() {
+        let stored = vec!["text-embedding-3-small".to_string()];
+        let err = ensure_embedding_model(&stored, "nomic-embed-text")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("text-embedding-3-small"));
+        assert!(err.contains("nomic-embed-text"));
+    }
+
+    #[test]
+    fn test_identical_content_is_indexed_once() {
+        let db = Database::open_in_memory().unwrap();
+        let dir = std::env::temp_dir().join(format!("ai-box-dedup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = "Quarterly numbers. ".repeat(100);
+        let original = dir.join("report.md");
+        let copy = dir.join("report (1).md");
+        std::fs::write(&original, &text).unwrap();
+        std::fs::write(&copy, &text).unwrap();
+        let upload = |path: &Path| store_upload(&db, path.to_str().unwrap(), 256, 32, None);
+
+        let StoredUpload::New(id) = upload(&original).unwrap() else {
+            panic!("first upload should be stored");
+        };
+        let chunks = db.get_document(&id).unwrap().unwrap().chunk_count;
+        assert!(chunks > 0);
+        let err = upload(&copy).err().unwrap().to_string();
+        assert!(err.contains("already indexed as report.md"), "{}", err);
+
+        db.set_setting("duplicate_uploads", "reuse").unwrap();
+        match upload(&copy).unwrap() {
+            StoredUpload::Existing(doc) => assert_eq!(doc.id, id),
+            StoredUpload::New(_) => panic!("duplicate was stored again"),
+        }
+        let docs = db.list_documents().unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].chunk_count, chunks);
+        assert_eq!(docs[0].content_hash.as_deref(), Some(content_hash(&text).as_str()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_store_does_not_block_retry() {
+        let db = Database::open_in_memory().unwrap();
+        let path = std::env::temp_dir().join(format!("ai-box-retry-{}.md", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "Retry me. ".repeat(100)).unwrap();
+        let upload = || store_upload(&db, path.to_str().unwrap(), 256, 32, Some("test-embed"));
+
+        // Fail on the third chunk, after the document row is written
+        db.conn()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER fail_chunk BEFORE INSERT ON chunks WHEN NEW.chunk_index = 2
+                 BEGIN SELECT RAISE(ABORT, 'disk full'); END",
+            )
+            .unwrap();
+        assert!(upload().is_err());
+        assert!(db.list_documents().unwrap().is_empty());
+        assert!(db.list_jobs().unwrap().is_empty());
+
+        db.conn().unwrap().execute_batch("DROP TRIGGER fail_chunk").unwrap();
+        let StoredUpload::New(id) = upload().unwrap() else {
+            panic!("retry was taken for a duplicate");
+        };
+        let doc = db.get_document(&id).unwrap().unwrap();
+        assert_eq!(doc.index_status.as_deref(), Some(JOB_INDEXING));
+        assert!(doc.chunk_count > 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_estimate_reports_chunk_token_spread() {
+        let chunk = |content: &str| doc_processor::TextChunk {
+            content: content.into(),
+            page_start: None,
+            page_end: None,
+        };
+        let model = DEFAULT_EMBEDDING_MODEL;
+        let chunks = [chunk(&"word ".repeat(40)), chunk("word word"), chunk(&"word ".repeat(9))];
+        let counts: Vec<usize> = chunks
+            .iter()
+            .map(|c| tokens::count_tokens(model, &c.content))
+            .collect();
+
+        let estimate = estimate_chunks(&chunks, model);
+        assert_eq!(estimate.chunk_count, 3);
+        assert_eq!(estimate.total_tokens, counts.iter().sum::<usize>());
+        assert_eq!(estimate.min_chunk_tokens, counts[1]);
+        assert_eq!(estimate.max_chunk_tokens, counts[0]);
+        assert!((estimate.avg_chunk_tokens - estimate.total_tokens as f64 / 3.0).abs() < 1e-9);
+
+        let empty = estimate_chunks(&[], model);
+        assert_eq!((empty.chunk_count, empty.total_tokens, empty.max_chunk_tokens), (0, 0, 0));
+        assert_eq!(empty.avg_chunk_tokens, 0.0);
+    }
+
+    #[test]
+    fn test_reembed_guard_is_released_on_unwind() {
+        let first = ReembedGuard::acquire().unwrap();
+        assert!(ReembedGuard::acquire().is_none());
+        drop(first);
+
+        let panicked = std::panic::catch_unwind(|| {
+            let _running = ReembedGuard::acquire().unwrap();
+            panic!("embedding blew up");
+        });
+        assert!(panicked.is_err());
+        assert!(ReembedGuard::acquire().is_some());
+    }
+
+    #[test]
+    fn test_dimension_mismatch_is_reported() {
+        assert!(ensure_embedding_dimensions(1536, &[1536]).is_ok());
+        assert!(ensure_embedding_dimensions(768, &[]).is_ok());
+
+        let err = ensure_embedding_dimensions(768, &[1536, 768])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("1536-dim, query is 768-dim"), "{}", err);
+        assert!(err.contains("re-embed"));
+    }
+
+    /// Answer `requests` embedding calls on a local port with a distinct
+    /// one-hot vector per input, failing every call after the first `ok`
+    /// with a 500, and return the base URL.
+    fn serve_embeddings(requests: usize, ok: usize) -> String {
+        let mut served = 0;
+        let mut next = 0;
+        serve(requests, move |body| {
+            served += 1;
+            if served > ok {
+                return Reply {
+                    status: "500 Internal Server Error",
+                    content_type: "application/json",
+                    body: r#"{"error": {"message": "upstream down"}}"#.into(),
+                };
+            }
+            let inputs = serde_json::from_str::<serde_json::Value>(body).unwrap()["input"]
+                .as_array()
+                .unwrap()
+                .len();
+            let data: Vec<serde_json::Value> = (0..inputs)
+                .map(|_| {
+                    let mut embedding = vec![0.0f32; 64];
+                    embedding[next % 64] = 2.0;
+                    next += 1;
+                    serde_json::json!({ "embedding": embedding })
+                })
+                .collect();
+            Reply::ok("application/json", serde_json::json!({ "data": data }).to_string())
+        })
+    }
+
+    /// An OpenAI-compatible embedding backend at `base_url`.
+    fn test_backend(base_url: String) -> EmbeddingBackend {
+        EmbeddingBackend::OpenAi {
+            config: OpenAiConfig {
+                api_key: String::new(),
+                base_url,
+                extra_headers: Vec::new(),
+            },
+            model: "test-embed".into(),
+            dimensions: None,
+        }
+    }
+
+    /// Insert a bare document row named and stored as `<id>.<file_type>`.
+    fn insert_document(conn: &rusqlite::Connection, id: &str, file_type: &str) {
+        conn.execute(
+            "INSERT INTO documents (id, filename, file_type, file_path) VALUES (?1, ?2, ?3, ?2)",
+            params![id, format!("{}.{}", id, file_type), file_type],
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reembed_pending_fills_null_embeddings() {
+        let db = Database::open_in_memory().unwrap();
+        {
+            let conn = db.conn().unwrap();
+            for doc in ["a", "b"] {
+                insert_document(&conn, doc, "txt");
+                for i in 0..3 {
+                    conn.execute(
+                        "INSERT INTO chunks (id, document_id, content, chunk_index) VALUES (?1, ?2, ?1, ?3)",
+                        params![format!("{}{}", doc, i), doc, i],
+                    )
+                    .unwrap();
+                }
+            }
+            // Already embedded chunks are left alone
+            let mut done = vec![0.0f32; 64];
+            done[63] = 1.0;
+            conn.execute(
+                "UPDATE chunks SET embedding = ?1, embedding_model = 'test-embed', embedding_dim = 64 WHERE id = 'b2'",
+                params![embedding_to_bytes(&done)],
+            )
+            .unwrap();
+        }
+        let backend = test_backend(serve_embeddings(2, 2));
+
+        let progress = std::sync::Mutex::new(Vec::new());
+        let cancels = CancelRegistry::default();
+        let summary = reembed_all_pending(&db, &backend, &cancels, |id, embedded, total| {
+            progress.lock().unwrap().push((id.to_string(), embedded, total))
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            summary,
+            ReembedSummary {
+                documents: 2,
+                embedded: 5,
+                remaining: 0,
+                failed: 0,
+            }
+        );
+        assert_eq!(db.chunk_embedding_counts("a").unwrap(), (3, 0));
+        assert_eq!(db.chunk_embedding_counts("b").unwrap(), (3, 0));
+        assert!(progress.lock().unwrap().contains(&("b".to_string(), 2, 2)));
+
+        // Nothing left to do on a second run
+        let again = reembed_all_pending(&db, &backend, &cancels, |_, _, _| {}).await.unwrap();
+        assert_eq!(again, ReembedSummary::default());
+    }
+
+    #[tokio::test]
+    async fn test_reembed_takes_over_queued_jobs_but_not_running_ones() {
+        let db = Database::open_in_memory().unwrap();
+        {
+            let conn = db.conn().unwrap();
+            for doc in ["queued", "running"] {
+                insert_document(&conn, doc, "txt");
+                conn.execute(
+                    "INSERT INTO chunks (id, document_id, content, chunk_index) VALUES (?1, ?1, ?1, 0)",
+                    params![doc],
+                )
+                .unwrap();
+            }
+        }
+        db.enqueue_job("queued", 1).unwrap();
+        db.enqueue_job("running", 1).unwrap();
+        let backend = test_backend(serve_embeddings(1, 1));
+
+        let cancels = CancelRegistry::default();
+        let worker = cancels.register("running");
+        let summary = reembed_all_pending(&db, &backend, &cancels, |_, _, _| {}).await.unwrap();
+        drop(worker);
+        assert_eq!(summary.documents, 1);
+        assert_eq!(summary.remaining, 1);
+        // The worker won't run the queued job a second time
+        assert_eq!(db.get_job("queued").unwrap().unwrap().status, JOB_READY);
+        assert_eq!(db.next_job().unwrap().unwrap().document_id, "running");
+    }
+
+    #[tokio::test]
+    async fn test_embedding_error_mid_document_reports_partial_index() {
+        let db = Database::open_in_memory().unwrap();
+        let total = EMBED_BATCH_SIZE + 5;
+        {
+            let conn = db.conn().unwrap();
+            insert_document(&conn, "d", "txt");
+            for i in 0..total {
+                conn.execute(
+                    "INSERT INTO chunks (id, document_id, content, chunk_index) VALUES (?1, 'd', ?1, ?2)",
+                    params![format!("c{}", i), i],
+                )
+                .unwrap();
+            }
+        }
+        db.enqueue_job("d", total).unwrap();
+        // The first batch is embedded, the second call fails
+        let backend = test_backend(serve_embeddings(2, 1));
+        let cancels = CancelRegistry::default();
+        let cancel = cancels.register("d");
+        let result = embed_document_with(&db, &backend, "d", &cancel, |_, _| {}).await;
+        assert!(result.is_err());
+        record_outcome(&db, "d", &result).unwrap();
+
+        let doc = db.get_document("d").unwrap().unwrap();
+        assert_eq!(doc.index_status.as_deref(), Some(JOB_PARTIAL));
+        assert_eq!((doc.embedded_chunks, doc.chunk_count), (EMBED_BATCH_SIZE, total));
+        let job = db.get_job("d").unwrap().unwrap();
+        assert!(job.error.unwrap().contains("upstream down"));
+    }
+
+    #[tokio::test]
+    async fn test_update_clears_failed_job() {
+        let db = Database::open_in_memory().unwrap();
+        let path = std::env::temp_dir().join(format!("ai-box-update-{}.md", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "Updated notes. ".repeat(60)).unwrap();
+        {
+            let conn = db.conn().unwrap();
+            insert_document(&conn, "d", "md");
+        }
+        db.enqueue_job("d", 3).unwrap();
+        let indexing = db.get_document("d").unwrap().unwrap();
+        let err = reindex_document(&db, None, indexing, path.to_str().unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("still being indexed"), "{}", err);
+
+        db.finish_job("d", JOB_FAILED, Some("API error: 500")).unwrap();
+        let backend = test_backend(serve_embeddings(1, 1));
+        let failed = db.get_document("d").unwrap().unwrap();
+        let doc = reindex_document(&db, Some(&backend), failed, path.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(doc.index_status.as_deref(), Some(JOB_READY));
+        assert!(doc.chunk_count > 0);
+        assert_eq!(doc.embedded_chunks, doc.chunk_count);
+        assert_eq!(db.get_job("d").unwrap().unwrap().error, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_update_uses_validated_chunking() {
+        let db = Database::open_in_memory().unwrap();
+        let path = std::env::temp_dir().join(format!("ai-box-rechunk-{}.md", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "Short chunks. ".repeat(20)).unwrap();
+        {
+            // Small chunks with the overlap left to the default
+            let conn = db.conn().unwrap();
+            insert_document(&conn, "d", "md");
+            conn.execute("UPDATE documents SET chunk_size = 40 WHERE id = 'd'", []).unwrap();
+        }
+        let existing = db.get_document("d").unwrap().unwrap();
+        let doc = reindex_document(&db, None, existing, path.to_str().unwrap()).await.unwrap();
+        assert!(doc.chunk_count > 1);
+        assert_eq!(doc.index_status, None);
+
+        db.conn()
+            .unwrap()
+            .execute("UPDATE documents SET chunk_size = 40, chunk_overlap = 40 WHERE id = 'd'", [])
+            .unwrap();
+        let invalid = db.get_document("d").unwrap().unwrap();
+        let err = reindex_document(&db, None, invalid, path.to_str().unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_update_checks_other_documents_for_duplicates() {
+        let db = Database::open_in_memory().unwrap();
+        let dir = std::env::temp_dir().join(format!("ai-box-update-dup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = "Shared notes. ".repeat(60);
+        let original = dir.join("notes.md");
+        std::fs::write(&original, &text).unwrap();
+        let original = original.to_str().unwrap();
+        let StoredUpload::New(id) = store_upload(&db, original, 256, 32, None).unwrap() else {
+            panic!("first upload should be stored");
+        };
+        {
+            let conn = db.conn().unwrap();
+            insert_document(&conn, "d", "md");
+        }
+
+        // A document can be updated from its own text, not another's
+        let own = db.get_document(&id).unwrap().unwrap();
+        reindex_document(&db, None, own, original).await.unwrap();
+        let other = db.get_document("d").unwrap().unwrap();
+        let err = reindex_document(&db, None, other, original).await.unwrap_err();
+        assert!(err.to_string().contains("already indexed as notes.md"), "{}", err);
+
+        db.set_setting("duplicate_uploads", "reuse").unwrap();
+        let other = db.get_document("d").unwrap().unwrap();
+        let doc = reindex_document(&db, None, other, original).await.unwrap();
+        assert_eq!(doc.id, id);
+        let untouched = db.get_document("d").unwrap().unwrap();
+        assert_eq!((untouched.chunk_count, untouched.content_hash), (0, None));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,7 @@
 pub mod chat;
+pub mod database;
+pub mod export;
 pub mod knowledge;
+pub mod server;
 pub mod settings;
+pub mod templates;
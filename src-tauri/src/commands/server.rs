@@ -0,0 +1,97 @@
+use crate::db::Database;
+use crate::error::AppError;
+use crate::server::{LocalServer, ServerContext, DEFAULT_HOST, DEFAULT_PORT};
+use serde::Serialize;
+use tauri::{Manager, State};
+
+#[derive(Debug, Serialize)]
+pub struct LocalServerStatus {
+    pub running: bool,
+    /// E.g. "http://127.0.0.1:8711/v1", while running.
+    pub base_url: Option<String>,
+}
+
+fn status(server: &LocalServer) -> LocalServerStatus {
+    let address = server.address();
+    LocalServerStatus {
+        running: address.is_some(),
+        base_url: address.map(|addr| format!("http://{}/v1", addr)),
+    }
+}
+
+/// Start (or restart, picking up new host and port settings) the local
+/// OpenAI-compatible server and remember to start it on launch. Generates
+/// `local_server_api_key` on first use.
+#[tauri::command]
+pub fn start_local_server(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    server: State<'_, LocalServer>,
+) -> Result<LocalServerStatus, AppError> {
+    let api_key = match db.get_setting("local_server_api_key")?.filter(|k| !k.is_empty()) {
+        Some(key) => key,
+        None => {
+            let key = format!("sk-aibox-{}", uuid::Uuid::new_v4().simple());
+            db.set_setting("local_server_api_key", &key)?;
+            key
+        }
+    };
+    start(&app, &db, &server, api_key)?;
+    db.set_setting("local_server_enabled", "true")?;
+    Ok(status(&server))
+}
+
+#[tauri::command]
+pub fn stop_local_server(
+    db: State<'_, Database>,
+    server: State<'_, LocalServer>,
+) -> Result<LocalServerStatus, AppError> {
+    server.stop();
+    db.set_setting("local_server_enabled", "false")?;
+    Ok(status(&server))
+}
+
+#[tauri::command]
+pub fn local_server_status(server: State<'_, LocalServer>) -> LocalServerStatus {
+    status(&server)
+}
+
+/// Start the server at launch if it was left on. Needs `Database` and
+/// `LocalServer` to be managed already.
+pub(crate) fn start_if_enabled(app: &tauri::AppHandle) {
+    let db = app.state::<Database>();
+    if db.get_setting("local_server_enabled").ok().flatten().as_deref() != Some("true") {
+        return;
+    }
+    let api_key = db.get_setting("local_server_api_key").ok().flatten();
+    let Some(api_key) = api_key.filter(|k| !k.is_empty()) else {
+        return;
+    };
+    if let Err(e) = start(app, &db, &app.state::<LocalServer>(), api_key) {
+        eprintln!("Failed to start the local API server: {}", e);
+    }
+}
+
+fn start(
+    app: &tauri::AppHandle,
+    db: &Database,
+    server: &LocalServer,
+    api_key: String,
+) -> Result<(), AppError> {
+    let host = db
+        .get_setting("local_server_host")?
+        .unwrap_or_else(|| DEFAULT_HOST.to_string());
+    let port = db
+        .get_setting("local_server_port")?
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+    let ctx = ServerContext {
+        app: app.clone(),
+        db: db.clone(),
+        api_key,
+    };
+    server
+        .start((&host, port), ctx)
+        .map_err(|e| AppError::Other(format!("Couldn't listen on {}:{}: {}", host, port, e)))?;
+    Ok(())
+}
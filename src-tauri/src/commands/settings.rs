@@ -1,8 +1,17 @@
+use crate::commands::chat::resolve_provider;
 use crate::db::Database;
-use crate::llm::ModelInfo;
+use crate::debug_log;
+use crate::error::AppError;
+use crate::llm::copilot::LoginFlows;
+use crate::llm::{
+    self, GenerationParams, LlmError, ModelInfo, DEFAULT_FIRST_TOKEN_TIMEOUT_SECS,
+    DEFAULT_MAX_CONCURRENT_REQUESTS, REQUEST_LIMITER,
+};
+use crate::secrets;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tauri::State;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use tauri::{Emitter, Manager, State};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AppSettings {
@@ -10,10 +19,33 @@ pub struct AppSettings {
     pub openai_base_url: Option<String>,
     pub claude_api_key: Option<String>,
     pub claude_base_url: Option<String>,
+    pub openrouter_api_key: Option<String>,
     pub ollama_host: Option<String>,
     pub copilot_oauth_token: Option<String>,
     pub default_model: Option<String>,
+    pub default_system_prompt: Option<String>,
     pub theme: Option<String>,
+    pub embedding_model: Option<String>,
+    pub embedding_base_url: Option<String>,
+    pub embedding_provider: Option<String>,
+    pub embedding_dimensions: Option<String>,
+    pub dedup_upload_threshold: Option<String>,
+    pub dedup_search_threshold: Option<String>,
+    pub duplicate_uploads: Option<String>,
+    pub temperature: Option<String>,
+    pub max_tokens: Option<String>,
+    pub top_p: Option<String>,
+    pub stop_sequences: Option<String>,
+    pub max_concurrent_requests: Option<String>,
+    pub first_token_timeout_secs: Option<String>,
+    pub openai_extra_headers: Option<String>,
+    pub stream_save_interval_ms: Option<String>,
+    pub claude_prompt_caching: Option<String>,
+    pub debug_logging: Option<String>,
+    pub local_server_enabled: Option<String>,
+    pub local_server_host: Option<String>,
+    pub local_server_port: Option<String>,
+    pub local_server_api_key: Option<String>,
 }
 
 const SETTING_KEYS: &[&str] = &[
@@ -21,169 +53,1071 @@ const SETTING_KEYS: &[&str] = &[
     "openai_base_url",
     "claude_api_key",
     "claude_base_url",
+    "openrouter_api_key",
     "ollama_host",
     "copilot_oauth_token",
     "default_model",
+    "default_system_prompt",
     "theme",
+    "embedding_model",
+    "embedding_base_url",
+    "embedding_provider",
+    "embedding_dimensions",
+    "dedup_upload_threshold",
+    "dedup_search_threshold",
+    "duplicate_uploads",
+    "temperature",
+    "max_tokens",
+    "top_p",
+    "stop_sequences",
+    "max_concurrent_requests",
+    "first_token_timeout_secs",
+    "openai_extra_headers",
+    "stream_save_interval_ms",
+    "claude_prompt_caching",
+    "debug_logging",
+    "local_server_enabled",
+    "local_server_host",
+    "local_server_port",
+    "local_server_api_key",
 ];
 
+/// Mask API keys for display as `abcd...wxyz`. Values too short to mask
+/// meaningfully are returned unchanged.
+fn mask_setting(key: &str, value: String) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if key.ends_with("_api_key") && chars.len() > 8 {
+        let head: String = chars[..4].iter().collect();
+        let tail: String = chars[chars.len() - 4..].iter().collect();
+        format!("{}...{}", head, tail)
+    } else {
+        value
+    }
+}
+
 #[tauri::command]
-pub fn get_settings(db: State<'_, Database>) -> Result<HashMap<String, String>, String> {
+pub fn get_settings(db: State<'_, Database>) -> Result<HashMap<String, String>, AppError> {
     let mut map = HashMap::new();
     for key in SETTING_KEYS {
-        if let Some(value) = db.get_setting(key).map_err(|e| e.to_string())? {
-            // Mask API keys for display
-            if key.ends_with("_api_key") && value.len() > 8 {
-                let masked = format!("{}...{}", &value[..4], &value[value.len() - 4..]);
-                map.insert(key.to_string(), masked);
-            } else {
-                map.insert(key.to_string(), value);
-            }
+        if let Some(value) = db.get_setting(key)? {
+            map.insert(key.to_string(), mask_setting(key, value));
         }
     }
     Ok(map)
 }
 
+/// Secrets the settings screen edits, and so may read back unmasked.
+const EDITABLE_SECRETS: &[&str] = &[
+    "openai_api_key",
+    "claude_api_key",
+    "openrouter_api_key",
+    "local_server_api_key",
+];
+
+/// Label of the window that hosts the settings screen.
+const SETTINGS_WINDOW: &str = "main";
+
+/// Return the unmasked value of a secret so the settings screen can pre-fill
+/// it for editing. Only the secrets it edits can be read, and only from its
+/// window; everything else should keep using the masked `get_settings`.
 #[tauri::command]
-pub fn set_setting(db: State<'_, Database>, key: String, value: String) -> Result<(), String> {
-    if !SETTING_KEYS.contains(&key.as_str()) {
-        return Err(format!("Unknown setting key: {}", key));
+pub fn get_setting_raw(
+    window: tauri::Window,
+    db: State<'_, Database>,
+    key: String,
+) -> Result<Option<String>, AppError> {
+    if window.label() != SETTINGS_WINDOW {
+        return Err(AppError::InvalidInput(
+            "Secrets can only be read from the settings screen".into(),
+        ));
     }
-    db.set_setting(&key, &value).map_err(|e| e.to_string())
+    if !EDITABLE_SECRETS.contains(&key.as_str()) {
+        return Err(AppError::InvalidInput(format!("Setting {} can't be read unmasked", key)));
+    }
+    db.get_setting(&key).map_err(AppError::from)
 }
 
+/// Save a setting. Base URLs are stored normalized; the result is a warning
+/// to show when the URL looks wrong but might still work.
 #[tauri::command]
-pub fn delete_setting(db: State<'_, Database>, key: String) -> Result<(), String> {
-    let conn = db.conn.lock().unwrap();
-    conn.execute("DELETE FROM settings WHERE key = ?1", rusqlite::params![key])
-        .map_err(|e| e.to_string())?;
+pub fn set_setting(
+    db: State<'_, Database>,
+    key: String,
+    value: String,
+) -> Result<Option<String>, AppError> {
+    validate_setting(&key, &value)?;
+    let mut warning = None;
+    let value = match normalized_base_url(&key, &value)? {
+        Some(url) => {
+            warning = base_url_warning(&key, &url);
+            url
+        }
+        None => value,
+    };
+    db.set_setting(&key, &value)?;
+    forget_models_for(&key);
+    apply_llm_settings(&db);
+    Ok(warning)
+}
+
+/// Reject unknown keys and values the setting can't hold.
+fn validate_setting(key: &str, value: &str) -> Result<(), AppError> {
+    if !SETTING_KEYS.contains(&key) {
+        return Err(AppError::InvalidInput(format!("Unknown setting key: {}", key)));
+    }
+    if key == "embedding_provider" && !matches!(value, "openai" | "local") {
+        return Err(AppError::InvalidInput(format!(
+            "embedding_provider must be \"openai\" or \"local\", got {}",
+            value
+        )));
+    }
+    if key == "embedding_dimensions" && !value.parse::<u32>().is_ok_and(|n| n > 0) {
+        return Err(AppError::InvalidInput(format!(
+            "embedding_dimensions must be a positive integer, got {}",
+            value
+        )));
+    }
+    if key.starts_with("dedup_") && !value.parse::<f32>().is_ok_and(|t| t > 0.0 && t <= 1.0) {
+        return Err(AppError::InvalidInput(format!(
+            "{} must be a number in (0, 1], got {}",
+            key, value
+        )));
+    }
+    if key == "duplicate_uploads" && !matches!(value, "reject" | "reuse") {
+        return Err(AppError::InvalidInput(format!(
+            "duplicate_uploads must be \"reject\" or \"reuse\", got {}",
+            value
+        )));
+    }
+    validate_generation_setting(key, value)?;
+    if key == "max_concurrent_requests" && !value.parse::<usize>().is_ok_and(|n| n > 0) {
+        return Err(AppError::InvalidInput(format!(
+            "max_concurrent_requests must be a positive integer, got {}",
+            value
+        )));
+    }
+    if key == "first_token_timeout_secs" && value.parse::<u64>().is_err() {
+        return Err(AppError::InvalidInput(format!(
+            "first_token_timeout_secs must be a whole number of seconds (0 disables), got {}",
+            value
+        )));
+    }
+    if key == "stream_save_interval_ms" && value.parse::<u64>().is_err() {
+        return Err(AppError::InvalidInput(format!(
+            "stream_save_interval_ms must be a whole number of milliseconds (0 disables), got {}",
+            value
+        )));
+    }
+    if key == "local_server_port" && !value.parse::<u16>().is_ok_and(|p| p > 0) {
+        return Err(AppError::InvalidInput(format!(
+            "local_server_port must be a port number between 1 and 65535, got {}",
+            value
+        )));
+    }
+    if key == "local_server_host" && value != "localhost" && value.parse::<std::net::IpAddr>().is_err() {
+        return Err(AppError::InvalidInput(format!(
+            "local_server_host must be an IP address such as 127.0.0.1, got {}",
+            value
+        )));
+    }
+    // Empty clears the key, and the server makes a new one when it starts
+    if key == "local_server_api_key"
+        && !value.is_empty()
+        && value.chars().count() < crate::server::MIN_API_KEY_LEN
+    {
+        return Err(AppError::InvalidInput(format!(
+            "local_server_api_key must be at least {} characters",
+            crate::server::MIN_API_KEY_LEN
+        )));
+    }
+    if matches!(key, "claude_prompt_caching" | "debug_logging" | "local_server_enabled")
+        && !matches!(value, "true" | "false")
+    {
+        return Err(AppError::InvalidInput(format!(
+            "{} must be \"true\" or \"false\", got {}",
+            key, value
+        )));
+    }
+    if key == "openai_extra_headers" {
+        crate::llm::openai::parse_extra_headers(value).map_err(AppError::InvalidInput)?;
+    }
+    normalized_base_url(key, value)?;
     Ok(())
 }
 
-#[tauri::command]
-pub fn get_available_models(db: State<'_, Database>) -> Result<Vec<ModelInfo>, String> {
-    let mut models = Vec::new();
+/// Settings holding a provider base URL, normalized on save and on use.
+const BASE_URL_KEYS: &[&str] = &["openai_base_url", "claude_base_url", "ollama_host", "embedding_base_url"];
+
+/// `value` normalized when `key` is a base URL setting.
+fn normalized_base_url(key: &str, value: &str) -> Result<Option<String>, AppError> {
+    if !BASE_URL_KEYS.contains(&key) {
+        return Ok(None);
+    }
+    normalize_base_url(value)
+        .map(Some)
+        .map_err(|e| AppError::InvalidInput(format!("{} {}", key, e)))
+}
 
-    // OpenAI models
-    if db
-        .get_setting("openai_api_key")
+/// `raw` without surrounding whitespace or trailing slashes. A URL without
+/// a scheme gets `http://` for a loopback host and `https://` otherwise;
+/// schemes other than http and https are rejected.
+pub(crate) fn normalize_base_url(raw: &str) -> Result<String, String> {
+    let trimmed = raw.trim();
+    let url = match trimmed.split_once("://") {
+        Some((scheme, rest)) => {
+            let scheme = scheme.to_ascii_lowercase();
+            if scheme != "http" && scheme != "https" {
+                return Err(format!("must be an http:// or https:// URL, got {}", raw));
+            }
+            format!("{}://{}", scheme, rest.trim_end_matches('/'))
+        }
+        None => {
+            let trimmed = trimmed.trim_end_matches('/');
+            let host = trimmed.split('/').next().unwrap_or_default();
+            let host = host.rsplit_once(':').map_or(host, |(host, _port)| host);
+            let loopback = matches!(host, "localhost" | "[::1]" | "0.0.0.0") || host.starts_with("127.");
+            format!("{}://{}", if loopback { "http" } else { "https" }, trimmed)
+        }
+    };
+    match reqwest::Url::parse(&url) {
+        Ok(parsed) if parsed.host_str().is_some_and(|h| !h.is_empty()) => Ok(url),
+        _ => Err(format!("is not a valid URL: {}", raw)),
+    }
+}
+
+/// A warning when `url` already ends in a path the provider appends to
+/// every request, e.g. "/chat/completions", which would then be doubled.
+fn base_url_warning(key: &str, url: &str) -> Option<String> {
+    let appended: &[&str] = match key {
+        "openai_base_url" | "embedding_base_url" => {
+            &["/chat/completions", "/completions", "/embeddings", "/models"]
+        }
+        "claude_base_url" => &["/v1/messages", "/v1/models", "/v1"],
+        "ollama_host" => &["/v1", "/api/chat", "/api/generate", "/api/tags", "/api"],
+        _ => return None,
+    };
+    let path = url.to_ascii_lowercase();
+    appended.iter().find(|suffix| path.ends_with(*suffix)).map(|suffix| {
+        format!(
+            "{} ends in {}, which is added to every request. Remove it unless your server expects it twice.",
+            key, suffix
+        )
+    })
+}
+
+/// A base URL setting, normalized in case it was saved before validation
+/// existed. Values that don't normalize are used as stored.
+pub(crate) fn base_url_setting(db: &Database, key: &str) -> Option<String> {
+    db.get_setting(key)
         .ok()
         .flatten()
-        .is_some()
-    {
-        models.extend([
-            ModelInfo {
-                id: "openai/gpt-4o".into(),
-                name: "GPT-4o".into(),
-                provider: "OpenAI".into(),
-            },
-            ModelInfo {
-                id: "openai/gpt-4o-mini".into(),
-                name: "GPT-4o Mini".into(),
-                provider: "OpenAI".into(),
-            },
-            ModelInfo {
-                id: "openai/gpt-4.1".into(),
-                name: "GPT-4.1".into(),
-                provider: "OpenAI".into(),
-            },
-        ]);
+        .filter(|url| !url.trim().is_empty())
+        .map(|url| normalize_base_url(&url).unwrap_or(url))
+}
+
+/// Version of the settings export file; bump on incompatible changes.
+const SETTINGS_EXPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SettingsExport {
+    version: u32,
+    settings: BTreeMap<String, String>,
+}
+
+/// Every stored setting except API keys and OAuth tokens, which stay on the
+/// machine they were entered on.
+fn export_payload(db: &Database) -> Result<SettingsExport, AppError> {
+    let mut settings = BTreeMap::new();
+    for key in SETTING_KEYS.iter().filter(|k| !secrets::is_secret_key(k)) {
+        if let Some(value) = db.get_setting(key)? {
+            settings.insert(key.to_string(), value);
+        }
     }
+    Ok(SettingsExport {
+        version: SETTINGS_EXPORT_VERSION,
+        settings,
+    })
+}
+
+/// Serialize the non-secret settings as JSON for `import_settings` on
+/// another machine.
+#[tauri::command]
+pub fn export_settings(db: State<'_, Database>) -> Result<String, AppError> {
+    let export = export_payload(&db)?;
+    Ok(serde_json::to_string_pretty(&export)?)
+}
+
+/// Apply a file written by `export_settings`. Every entry is validated
+/// before anything is stored, so a bad file changes nothing. Returns the
+/// number of settings applied.
+#[tauri::command]
+pub fn import_settings(db: State<'_, Database>, json: String) -> Result<usize, AppError> {
+    import_payload(&db, &json)
+}
 
-    // Claude models
-    if db
-        .get_setting("claude_api_key")
+fn import_payload(db: &Database, json: &str) -> Result<usize, AppError> {
+    let export: SettingsExport = serde_json::from_str(json)
+        .map_err(|e| AppError::InvalidInput(format!("Invalid settings file: {}", e)))?;
+    if export.version > SETTINGS_EXPORT_VERSION {
+        return Err(AppError::InvalidInput(format!(
+            "Settings file version {} is newer than supported version {}",
+            export.version, SETTINGS_EXPORT_VERSION
+        )));
+    }
+    for (key, value) in &export.settings {
+        if secrets::is_secret_key(key) {
+            return Err(AppError::InvalidInput(format!(
+                "{} is machine-local and can't be imported",
+                key
+            )));
+        }
+        validate_setting(key, value)?;
+    }
+    for (key, value) in &export.settings {
+        let value = normalized_base_url(key, value)?.unwrap_or_else(|| value.clone());
+        db.set_setting(key, &value)?;
+        forget_models_for(key);
+    }
+    apply_llm_settings(db);
+    Ok(export.settings.len())
+}
+
+/// Push the settings that govern every outbound LLM request (concurrency cap,
+/// first-token timeout, debug logging) into the modules that send them.
+pub(crate) fn apply_llm_settings(db: &Database) {
+    let get = |key: &str| db.get_setting(key).ok().flatten();
+    let limit = get("max_concurrent_requests")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS);
+    REQUEST_LIMITER.set_limit(limit);
+    let timeout = get("first_token_timeout_secs")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_FIRST_TOKEN_TIMEOUT_SECS);
+    llm::set_first_token_timeout(timeout);
+    debug_log::set_enabled(get("debug_logging").as_deref() == Some("true"));
+}
+
+/// Where request logs are written while `debug_logging` is on, so users can
+/// attach them to bug reports.
+#[tauri::command]
+pub fn get_log_path() -> Result<String, AppError> {
+    debug_log::log_path()
+        .map(|path| path.to_string_lossy().into_owned())
+        .ok_or(AppError::Other("Logging is not initialized".into()))
+}
+
+#[tauri::command]
+pub fn clear_logs() -> Result<(), AppError> {
+    debug_log::clear().map_err(|e| AppError::Other(format!("Failed to clear logs: {}", e)))
+}
+
+fn validate_generation_setting(key: &str, value: &str) -> Result<(), AppError> {
+    let valid = match key {
+        "temperature" => value.parse::<f32>().is_ok_and(|t| (0.0..=2.0).contains(&t)),
+        "top_p" => value.parse::<f32>().is_ok_and(|p| p > 0.0 && p <= 1.0),
+        "max_tokens" => value.parse::<u32>().is_ok_and(|n| n > 0),
+        "stop_sequences" => serde_json::from_str::<Vec<String>>(value).is_ok(),
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::InvalidInput(format!("Invalid value for {}: {}", key, value)))
+    }
+}
+
+/// Generation parameters from settings; unset or unparsable values leave
+/// the provider default.
+pub(crate) fn generation_params(db: &Database) -> GenerationParams {
+    let get = |key: &str| db.get_setting(key).ok().flatten();
+    GenerationParams {
+        temperature: get("temperature").and_then(|v| v.parse().ok()),
+        max_tokens: get("max_tokens").and_then(|v| v.parse().ok()),
+        top_p: get("top_p").and_then(|v| v.parse().ok()),
+        stop: get("stop_sequences")
+            .and_then(|v| serde_json::from_str(&v).ok())
+            .unwrap_or_default(),
+    }
+}
+
+/// Extra headers for OpenAI-compatible requests. Validated on save, so a
+/// stored value only fails to parse if the database was edited by hand.
+pub(crate) fn openai_extra_headers(db: &Database) -> Vec<(String, String)> {
+    db.get_setting("openai_extra_headers")
         .ok()
         .flatten()
-        .is_some()
-    {
-        models.extend([
-            ModelInfo {
-                id: "claude/claude-sonnet-4-20250514".into(),
-                name: "Claude Sonnet 4".into(),
-                provider: "Anthropic".into(),
-            },
-            ModelInfo {
-                id: "claude/claude-haiku-3-5-20241022".into(),
-                name: "Claude Haiku 3.5".into(),
-                provider: "Anthropic".into(),
-            },
-        ]);
+        .and_then(|v| crate::llm::openai::parse_extra_headers(&v).ok())
+        .unwrap_or_default()
+}
+
+/// How often a streaming reply is written to the database while it arrives.
+/// `None` (unset or 0) saves it only once the stream finishes.
+pub(crate) fn stream_save_interval(db: &Database) -> Option<Duration> {
+    db.get_setting("stream_save_interval_ms")
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis)
+}
+
+#[tauri::command]
+pub fn delete_setting(db: State<'_, Database>, key: String) -> Result<(), AppError> {
+    db.delete_setting(&key)?;
+    forget_models_for(&key);
+    apply_llm_settings(&db);
+    Ok(())
+}
+
+/// Drop the in-memory model list of the provider `key` configures, so a new
+/// API key or base URL isn't answered with the old server's models.
+fn forget_models_for(key: &str) {
+    let prefix = match key {
+        "openai_api_key" | "openai_base_url" | "openai_extra_headers" => "openai",
+        "openrouter_api_key" => "openrouter",
+        "ollama_host" => "ollama",
+        _ => return,
+    };
+    llm::openai::forget_cached_models_for(prefix);
+}
+
+/// Model lists are refreshed in the background once the cache is this old.
+const MODEL_CACHE_MAX_AGE_SECS: i64 = 10 * 60;
+
+/// Model-id prefixes of the providers that are currently configured.
+fn configured_prefixes(db: &Database) -> Vec<&'static str> {
+    let mut prefixes = Vec::new();
+    if db.get_setting("openai_api_key").ok().flatten().is_some() {
+        prefixes.push("openai");
+    }
+    if db.get_setting("claude_api_key").ok().flatten().is_some() {
+        prefixes.push("claude");
+    }
+    if db.get_setting("openrouter_api_key").ok().flatten().is_some() {
+        prefixes.push("openrouter");
     }
+    if db.get_setting("copilot_oauth_token").ok().flatten().is_some() {
+        prefixes.push("copilot");
+    }
+    // Ollama is always available — local
+    prefixes.push("ollama");
+    prefixes
+}
+
+fn cached_models_for_configured(db: &Database) -> Result<Vec<ModelInfo>, AppError> {
+    let prefixes = configured_prefixes(db);
+    let models = db.get_cached_models()?;
+    Ok(models
+        .into_iter()
+        .filter(|m| {
+            m.id
+                .split_once('/')
+                .is_some_and(|(prefix, _)| prefixes.contains(&prefix))
+        })
+        .collect())
+}
 
-    // Ollama models (always available — local)
-    models.extend([
-        ModelInfo {
-            id: "ollama/llama3".into(),
-            name: "Llama 3".into(),
-            provider: "Ollama".into(),
+/// Query every configured provider concurrently and upsert the results into
+/// `model_cache`. A provider whose fetch fails keeps its previous entries
+/// (or gets the built-in defaults if it was never cached).
+async fn refresh_model_cache(db: &Database) -> Result<(), AppError> {
+    let openai_config = db.get_setting("openai_api_key").ok().flatten().map(|api_key| {
+        crate::llm::openai::OpenAiConfig {
+            api_key,
+            base_url: base_url_setting(db, "openai_base_url")
+                .unwrap_or_else(|| "https://api.openai.com/v1".to_string()),
+            extra_headers: openai_extra_headers(db),
+        }
+    });
+    let openrouter = db
+        .get_setting("openrouter_api_key")
+        .ok()
+        .flatten()
+        .map(crate::llm::openrouter_config);
+    let copilot_token = db.get_setting("copilot_oauth_token").ok().flatten();
+    let ollama_host = ollama_host(db);
+    let ollama_config = crate::llm::openai::OpenAiConfig {
+        api_key: String::new(),
+        base_url: format!("{}/v1", ollama_host),
+        extra_headers: Vec::new(),
+    };
+
+    let (openai, openrouter, copilot, ollama) = futures::join!(
+        async {
+            match &openai_config {
+                Some(config) => Some(crate::llm::openai::fetch_openai_models(config).await),
+                None => None,
+            }
         },
-        ModelInfo {
-            id: "ollama/qwen2.5".into(),
-            name: "Qwen 2.5".into(),
-            provider: "Ollama".into(),
+        async {
+            match &openrouter {
+                Some(config) => Some(
+                    crate::llm::openai::fetch_models(config, "openrouter", "OpenRouter").await,
+                ),
+                None => None,
+            }
         },
-    ]);
+        async {
+            match &copilot_token {
+                Some(token) => Some(crate::llm::copilot::fetch_models(token).await),
+                None => None,
+            }
+        },
+        crate::llm::openai::fetch_models(&ollama_config, "ollama", "Ollama"),
+    );
+
+    let ollama = match ollama {
+        Ok(mut models) => {
+            llm::ollama::describe_models(&ollama_host, &mut models).await;
+            Ok(models)
+        }
+        Err(e) => Err(e),
+    };
+    let mut results = vec![("ollama", ollama, default_ollama_models())];
+    if let Some(result) = openai {
+        results.push(("openai", result, default_openai_models()));
+    }
+    if let Some(result) = openrouter {
+        results.push(("openrouter", result, Vec::new()));
+    }
+    if let Some(result) = copilot {
+        results.push(("copilot", result, Vec::new()));
+    }
+    if db.get_setting("claude_api_key").ok().flatten().is_some() {
+        results.push(("claude", Ok(default_claude_models()), Vec::new()));
+    }
 
-    Ok(models)
+    for (prefix, result, defaults) in results {
+        match result {
+            Ok(models) if !models.is_empty() => db.replace_cached_models(prefix, &models)?,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to refresh {} models, keeping cached list: {}", prefix, e);
+                if !defaults.is_empty() && !db.has_cached_models(prefix).unwrap_or(true) {
+                    db.replace_cached_models(prefix, &defaults)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fast model list read from `model_cache`. An empty cache is filled inline;
+/// a stale one is refreshed in the background for the next call.
+#[tauri::command]
+pub async fn get_available_models(db: State<'_, Database>) -> Result<Vec<ModelInfo>, AppError> {
+    available_models(&db).await
+}
+
+pub(crate) async fn available_models(db: &Database) -> Result<Vec<ModelInfo>, AppError> {
+    match db.model_cache_age_secs()? {
+        None => refresh_model_cache(db).await?,
+        Some(age) if age > MODEL_CACHE_MAX_AGE_SECS => {
+            let db = db.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = refresh_model_cache(&db).await {
+                    eprintln!("Background model refresh failed: {}", e);
+                }
+            });
+        }
+        Some(_) => {}
+    }
+    cached_models_for_configured(db)
+}
+
+/// Return the cached model list without touching the network.
+#[tauri::command]
+pub fn get_cached_models(db: State<'_, Database>) -> Result<Vec<ModelInfo>, AppError> {
+    cached_models_for_configured(&db)
+}
+
+/// Re-fetch models from every configured provider and return the updated list.
+#[tauri::command]
+pub async fn refresh_models(db: State<'_, Database>) -> Result<Vec<ModelInfo>, AppError> {
+    refresh_model_cache(&db).await?;
+    cached_models_for_configured(&db)
+}
+
+/// Each provider check gives up after this long, so one hanging endpoint
+/// doesn't hold up the whole report.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+pub struct ProviderHealth {
+    /// Model-id prefix of the provider, e.g. "openai".
+    pub provider: String,
+    pub reachable: bool,
+    /// `None` when the provider takes no credentials (Ollama) or the check
+    /// couldn't tell.
+    pub credentials_valid: Option<bool>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Ping every configured provider concurrently and report connectivity,
+/// round-trip latency and whether the stored credentials are accepted. A
+/// provider that can't be set up is reported unhealthy; the rest are still
+/// checked.
+#[tauri::command]
+pub async fn provider_health(db: State<'_, Database>) -> Result<Vec<ProviderHealth>, AppError> {
+    let checks = configured_prefixes(&db).into_iter().map(|prefix| {
+        // Only the provider half of the model string matters for a ping
+        let provider = resolve_provider(&format!("{}/", prefix), &db);
+        async move {
+            let provider = match provider {
+                Ok((provider, _)) => provider,
+                Err(e) => return setup_failed_report(prefix, &e),
+            };
+            let started = Instant::now();
+            let outcome = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, provider.ping())
+                .await
+                .ok();
+            health_report(prefix, outcome, started.elapsed())
+        }
+    });
+    Ok(futures::future::join_all(checks).await)
+}
+
+/// Report for a provider whose configuration couldn't be read or used.
+fn setup_failed_report(provider: &str, error: &AppError) -> ProviderHealth {
+    ProviderHealth {
+        provider: provider.to_string(),
+        reachable: false,
+        credentials_valid: None,
+        latency_ms: 0,
+        error: Some(error.to_string()),
+    }
+}
+
+/// Interpret a ping; `outcome` is `None` when it timed out.
+fn health_report(
+    provider: &str,
+    outcome: Option<Result<(), LlmError>>,
+    latency: Duration,
+) -> ProviderHealth {
+    let has_credentials = provider != "ollama";
+    let (reachable, credentials_valid, error) = match outcome {
+        Some(Ok(())) => (true, has_credentials.then_some(true), None),
+        Some(Err(LlmError::Api { status: 401 | 403, message })) => {
+            (true, Some(false), Some(format!("Credentials rejected: {}", message)))
+        }
+        // Any other response still proves the endpoint is up
+        Some(Err(e @ (LlmError::Api { .. } | LlmError::Parse(_)))) => {
+            (true, None, Some(e.to_string()))
+        }
+        Some(Err(e)) => (false, None, Some(e.to_string())),
+        None => (
+            false,
+            None,
+            Some(format!("No response within {}s", HEALTH_CHECK_TIMEOUT.as_secs())),
+        ),
+    };
+    ProviderHealth {
+        provider: provider.to_string(),
+        reachable,
+        credentials_valid,
+        latency_ms: latency.as_millis() as u64,
+        error,
+    }
+}
+
+fn default_openai_models() -> Vec<ModelInfo> {
+    vec![
+        ModelInfo::new("openai/gpt-4o", "GPT-4o", "OpenAI"),
+        ModelInfo::new("openai/gpt-4o-mini", "GPT-4o Mini", "OpenAI"),
+        ModelInfo::new("openai/gpt-4.1", "GPT-4.1", "OpenAI"),
+    ]
+}
+
+fn default_claude_models() -> Vec<ModelInfo> {
+    vec![
+        ModelInfo::new("claude/claude-sonnet-4-20250514", "Claude Sonnet 4", "Anthropic"),
+        ModelInfo::new("claude/claude-haiku-3-5-20241022", "Claude Haiku 3.5", "Anthropic"),
+    ]
+}
+
+fn default_ollama_models() -> Vec<ModelInfo> {
+    vec![
+        ModelInfo::new("ollama/llama3", "Llama 3", "Ollama"),
+        ModelInfo::new("ollama/qwen2.5", "Qwen 2.5", "Ollama"),
+    ]
 }
 
 /// Fetch available models from the Copilot API.
 #[tauri::command]
 pub async fn fetch_copilot_models(
     db: State<'_, Database>,
-) -> Result<Vec<ModelInfo>, String> {
+) -> Result<Vec<ModelInfo>, AppError> {
     let oauth_token = db
         .get_setting("copilot_oauth_token")
         .ok()
         .flatten()
-        .ok_or("GitHub Copilot not logged in")?;
+        .ok_or(AppError::NotConfigured("GitHub Copilot not logged in".into()))?;
 
     crate::llm::copilot::fetch_models(&oauth_token)
         .await
-        .map_err(|e| e.to_string())
+        .map_err(AppError::from)
 }
 
 /// Start GitHub Device OAuth flow — returns device_code, user_code, verification_uri.
+/// Supersedes any flow still pending, so its token will no longer be saved.
 #[tauri::command]
-pub async fn copilot_start_login() -> Result<crate::llm::copilot::DeviceCodeResponse, String> {
-    crate::llm::copilot::start_device_flow()
-        .await
-        .map_err(|e| e.to_string())
+pub async fn copilot_start_login(
+    flows: State<'_, LoginFlows>,
+) -> Result<crate::llm::copilot::DeviceCodeResponse, AppError> {
+    let login = crate::llm::copilot::start_device_flow().await?;
+    flows.begin(&login.device_code);
+    Ok(login)
+}
+
+/// Abandon the pending device flow for `device_code`, e.g. when the login
+/// dialog is closed. A waiting `copilot_await_login` fails with `cancelled`
+/// at its next poll. Returns false if the flow had already finished.
+#[tauri::command]
+pub fn copilot_cancel_login(flows: State<'_, LoginFlows>, device_code: String) -> bool {
+    flows.finish(&device_code)
 }
 
 /// Poll GitHub for OAuth token completion. Returns the token string or null if still pending.
 #[tauri::command]
 pub async fn copilot_poll_login(
     db: State<'_, Database>,
+    flows: State<'_, LoginFlows>,
     device_code: String,
-) -> Result<Option<String>, String> {
+) -> Result<Option<String>, AppError> {
+    if !flows.is_pending(&device_code) {
+        return Err(login_cancelled());
+    }
     let result = crate::llm::copilot::poll_device_flow(&device_code)
-        .await
-        .map_err(|e| e.to_string())?;
+        .await?;
 
     if let Some(ref token) = result {
-        db.set_setting("copilot_oauth_token", token)
-            .map_err(|e| e.to_string())?;
+        save_copilot_login(&db, &flows, &device_code, token).await?;
     }
 
     Ok(result)
 }
 
+/// Wait for the user to finish the device flow and persist the token. Polls at
+/// the server-provided interval, emits `copilot-login-progress` events so the
+/// UI can keep showing the user code, and fails once the code expires. This is
+/// the recommended login path; `copilot_poll_login` remains for manual polling.
+#[tauri::command]
+pub async fn copilot_await_login(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    device_code: String,
+    interval: Option<u64>,
+    expires_in: Option<u64>,
+) -> Result<String, AppError> {
+    let flows = app.state::<LoginFlows>();
+    let token = crate::llm::copilot::await_device_flow(
+        &device_code,
+        interval.unwrap_or(5),
+        expires_in.unwrap_or(900),
+        |progress| {
+            let _ = app.emit("copilot-login-progress", progress);
+        },
+        || !flows.is_pending(&device_code),
+    )
+    .await?;
+
+    save_copilot_login(&db, &flows, &device_code, &token).await?;
+    Ok(token)
+}
+
+/// Save the token a device flow produced, unless the flow was cancelled or
+/// superseded while the token was on its way.
+async fn save_copilot_login(
+    db: &Database,
+    flows: &LoginFlows,
+    device_code: &str,
+    token: &str,
+) -> Result<(), AppError> {
+    if !flows.finish(device_code) {
+        return Err(login_cancelled());
+    }
+    db.set_setting("copilot_oauth_token", token)?;
+    reset_copilot_token(db).await
+}
+
+fn login_cancelled() -> AppError {
+    AppError::Cancelled("This Copilot login was cancelled or replaced by a newer one".into())
+}
+
 /// Check if Copilot is logged in (has stored oauth token).
 #[tauri::command]
-pub fn copilot_is_logged_in(db: State<'_, Database>) -> Result<bool, String> {
+pub fn copilot_is_logged_in(db: State<'_, Database>) -> Result<bool, AppError> {
     Ok(db.get_setting("copilot_oauth_token").ok().flatten().is_some())
 }
 
 /// Logout from Copilot (remove stored oauth token).
 #[tauri::command]
-pub fn copilot_logout(db: State<'_, Database>) -> Result<(), String> {
-    let conn = db.conn.lock().unwrap();
-    conn.execute(
-        "DELETE FROM settings WHERE key = ?1",
-        rusqlite::params!["copilot_oauth_token"],
-    )
-    .map_err(|e| e.to_string())?;
+pub async fn copilot_logout(db: State<'_, Database>) -> Result<(), AppError> {
+    db.delete_setting("copilot_oauth_token")?;
+    reset_copilot_token(&db).await
+}
+
+/// The configured Ollama host, without the `/v1` suffix.
+pub(crate) fn ollama_host(db: &Database) -> String {
+    base_url_setting(db, "ollama_host").unwrap_or_else(|| llm::ollama::DEFAULT_HOST.to_string())
+}
+
+/// An Ollama error, with a host that isn't listening explained as such.
+fn ollama_error(host: &str, e: LlmError) -> AppError {
+    match e {
+        LlmError::Http(e) if e.is_connect() => AppError::Network(format!(
+            "Ollama isn't reachable at {}. Make sure it is running or check the ollama_host setting.",
+            host
+        )),
+        other => AppError::from(other),
+    }
+}
+
+/// Whether an Ollama model (e.g. "llama3" or "ollama/llama3:8b") is installed.
+#[tauri::command]
+pub async fn ollama_model_installed(db: State<'_, Database>, name: String) -> Result<bool, AppError> {
+    let host = ollama_host(&db);
+    let installed = llm::ollama::installed_models(&host)
+        .await
+        .map_err(|e| ollama_error(&host, e))?;
+    Ok(llm::ollama::is_installed(&installed, ollama_model_name(&name)?))
+}
+
+#[derive(Clone, Serialize)]
+struct OllamaPullEvent {
+    name: String,
+    #[serde(flatten)]
+    progress: llm::ollama::PullProgress,
+}
+
+/// Download an Ollama model, emitting `ollama-pull-progress` events with the
+/// status and `completed`/`total` bytes of the layer being fetched. Resolves
+/// once the model is installed.
+#[tauri::command]
+pub async fn pull_ollama_model(
+    app: tauri::AppHandle,
+    db: State<'_, Database>,
+    name: String,
+) -> Result<(), AppError> {
+    let host = ollama_host(&db);
+    let model = ollama_model_name(&name)?.to_string();
+    llm::ollama::pull(&host, &model, |progress| {
+        let _ = app.emit(
+            "ollama-pull-progress",
+            OllamaPullEvent {
+                name: model.clone(),
+                progress,
+            },
+        );
+    })
+    .await
+    .map_err(|e| ollama_error(&host, e))?;
+    llm::openai::forget_cached_models(&format!("{}/v1", host));
+    Ok(())
+}
+
+/// The model name Ollama knows, with any `ollama/` prefix dropped.
+fn ollama_model_name(name: &str) -> Result<&str, AppError> {
+    let name = name.trim();
+    let name = name.strip_prefix("ollama/").unwrap_or(name);
+    if name.is_empty() {
+        return Err(AppError::InvalidInput("Model name cannot be empty".into()));
+    }
+    Ok(name)
+}
+
+/// Settings rows that keep the exchanged Copilot API token across restarts.
+const COPILOT_TOKEN_KEY: &str = "copilot_api_token";
+const COPILOT_TOKEN_EXPIRY_KEY: &str = "copilot_api_token_expires_at";
+
+/// Seed the Copilot token cache from the previous run and persist every
+/// token exchanged from now on, so a restart doesn't cost an exchange.
+pub(crate) fn restore_copilot_token(db: &Database) {
+    let get = |key: &str| db.get_setting(key).ok().flatten();
+    let expires_at = get(COPILOT_TOKEN_EXPIRY_KEY).and_then(|v| v.parse().ok());
+    if let (Some(token), Some(expires_at)) = (get(COPILOT_TOKEN_KEY), expires_at) {
+        crate::llm::copilot::restore_token(token, expires_at);
+    }
+    let db = db.clone();
+    crate::llm::copilot::set_token_store(move |token, expires_at| {
+        let saved = db
+            .set_setting(COPILOT_TOKEN_KEY, token)
+            .and_then(|()| db.set_setting(COPILOT_TOKEN_EXPIRY_KEY, &expires_at.to_string()));
+        // Best effort: without it the next run just exchanges again
+        if let Err(e) = saved {
+            eprintln!("Failed to persist Copilot token: {}", e);
+        }
+    });
+}
+
+/// Drop the exchanged token, which belongs to the previous OAuth token.
+async fn reset_copilot_token(db: &Database) -> Result<(), AppError> {
+    crate::llm::copilot::forget_token().await;
+    db.delete_setting(COPILOT_TOKEN_KEY)?;
+    db.delete_setting(COPILOT_TOKEN_EXPIRY_KEY)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_server_key_has_a_minimum_length() {
+        assert!(validate_setting("local_server_api_key", "short").is_err());
+        assert!(validate_setting("local_server_api_key", "sk-0123456789abcdef").is_ok());
+        assert!(validate_setting("local_server_api_key", "").is_ok());
+    }
+
+    #[test]
+    fn test_validate_generation_setting() {
+        assert!(validate_generation_setting("temperature", "0.7").is_ok());
+        assert!(validate_generation_setting("temperature", "3").is_err());
+        assert!(validate_generation_setting("max_tokens", "0").is_err());
+        assert!(validate_generation_setting("stop_sequences", r#"["END"]"#).is_ok());
+        assert!(validate_generation_setting("stop_sequences", "END").is_err());
+    }
+
+    #[test]
+    fn test_normalize_base_url() {
+        assert_eq!(normalize_base_url("https://llm.example.com/v1/").unwrap(), "https://llm.example.com/v1");
+        assert_eq!(normalize_base_url(" HTTPS://llm.example.com// ").unwrap(), "https://llm.example.com");
+        assert_eq!(normalize_base_url("localhost:11434").unwrap(), "http://localhost:11434");
+        assert_eq!(normalize_base_url("127.0.0.1:8080/v1/").unwrap(), "http://127.0.0.1:8080/v1");
+        assert_eq!(normalize_base_url("api.example.com/v1").unwrap(), "https://api.example.com/v1");
+        assert!(normalize_base_url("ftp://files.example.com").is_err());
+        assert!(normalize_base_url("https://").is_err());
+        assert!(normalize_base_url("").is_err());
+        assert!(validate_setting("openai_base_url", "file:///etc/passwd").is_err());
+        assert!(validate_setting("ollama_host", "localhost:11434").is_ok());
+    }
+
+    #[test]
+    fn test_base_url_warns_about_endpoint_paths() {
+        let warning = base_url_warning("openai_base_url", "https://llm.example.com/v1/chat/completions");
+        assert!(warning.unwrap().contains("/chat/completions"));
+        assert!(base_url_warning("openai_base_url", "https://llm.example.com/v1").is_none());
+        assert!(base_url_warning("claude_base_url", "https://api.anthropic.com/v1").is_some());
+        assert!(base_url_warning("claude_base_url", "https://api.anthropic.com").is_none());
+        assert!(base_url_warning("ollama_host", "http://localhost:11434/v1").is_some());
+        assert!(base_url_warning("ollama_host", "http://localhost:11434").is_none());
+    }
+
+    #[test]
+    fn test_stored_base_urls_are_normalized_on_use() {
+        let db = Database::open_in_memory().unwrap();
+        db.set_setting("openai_api_key", "sk-test").unwrap();
+        db.set_setting("openai_base_url", "https://llm.example.com/v1/").unwrap();
+        db.set_setting("ollama_host", "localhost:11434/").unwrap();
+        let Ok((llm::Provider::OpenAi(config), _)) = resolve_provider("openai/gpt-4o", &db) else {
+            panic!("expected an OpenAI provider")
+        };
+        assert_eq!(config.base_url, "https://llm.example.com/v1");
+        assert_eq!(ollama_host(&db), "http://localhost:11434");
+    }
+
+    #[tokio::test]
+    async fn test_superseded_or_cancelled_login_is_not_saved() {
+        let db = Database::open_in_memory().unwrap();
+        let flows = LoginFlows::default();
+        flows.begin("first");
+        flows.begin("second");
+        assert!(!flows.is_pending("first"));
+
+        // The first dialog's token arrives after the second flow started
+        let stale = save_copilot_login(&db, &flows, "first", "gho_stale").await;
+        assert!(matches!(stale, Err(AppError::Cancelled(_))));
+        assert_eq!(db.get_setting("copilot_oauth_token").unwrap(), None);
+
+        save_copilot_login(&db, &flows, "second", "gho_current").await.unwrap();
+        assert_eq!(db.get_setting("copilot_oauth_token").unwrap().as_deref(), Some("gho_current"));
+        assert!(!flows.is_pending("second"));
+
+        flows.begin("third");
+        assert!(flows.finish("third"));
+        assert!(!flows.finish("third"));
+        let cancelled = save_copilot_login(&db, &flows, "third", "gho_late").await;
+        assert!(matches!(cancelled, Err(AppError::Cancelled(_))));
+        assert_eq!(db.get_setting("copilot_oauth_token").unwrap().as_deref(), Some("gho_current"));
+    }
+
+    #[test]
+    fn test_settings_export_excludes_secrets() {
+        let db = Database::open_in_memory().unwrap();
+        db.set_setting("openai_api_key", "sk-secret-value").unwrap();
+        db.set_setting("copilot_oauth_token", "gho_token").unwrap();
+        db.set_setting("openai_base_url", "https://llm.example.com/v1").unwrap();
+        db.set_setting("theme", "dark").unwrap();
+
+        let json = serde_json::to_string(&export_payload(&db).unwrap()).unwrap();
+        assert!(!json.contains("sk-secret-value"));
+        assert!(!json.contains("gho_token"));
+        assert!(!json.contains("_api_key"));
+
+        let other = Database::open_in_memory().unwrap();
+        assert_eq!(import_payload(&other, &json).unwrap(), 2);
+        assert_eq!(other.get_setting("theme").unwrap().as_deref(), Some("dark"));
+        assert_eq!(other.get_setting("openai_api_key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_settings_import_rejects_unknown_and_secret_keys() {
+        let db = Database::open_in_memory().unwrap();
+        let unknown = r#"{"version":1,"settings":{"theme":"dark","colour":"red"}}"#;
+        assert!(import_payload(&db, unknown).is_err());
+        // Nothing is stored when any entry is rejected
+        assert_eq!(db.get_setting("theme").unwrap(), None);
+        let secret = r#"{"version":1,"settings":{"claude_api_key":"sk-ant"}}"#;
+        assert!(import_payload(&db, secret).is_err());
+    }
+
+    #[test]
+    fn test_health_report_classification() {
+        let latency = Duration::from_millis(42);
+
+        let ok = health_report("openai", Some(Ok(())), latency);
+        assert!(ok.reachable);
+        assert_eq!(ok.credentials_valid, Some(true));
+        assert_eq!(ok.latency_ms, 42);
+        assert_eq!(health_report("ollama", Some(Ok(())), latency).credentials_valid, None);
+
+        let rejected = LlmError::Api { status: 401, message: "bad key".into() };
+        let auth = health_report("claude", Some(Err(rejected)), latency);
+        assert!(auth.reachable);
+        assert_eq!(auth.credentials_valid, Some(false));
+
+        let server = LlmError::Api { status: 500, message: "oops".into() };
+        let down = health_report("claude", Some(Err(server)), latency);
+        assert!(down.reachable);
+        assert_eq!(down.credentials_valid, None);
+
+        let hung = health_report("copilot", None, latency);
+        assert!(!hung.reachable);
+        assert!(hung.error.unwrap().contains("5s"));
+
+        let error = AppError::NotConfigured("GitHub Copilot not logged in".into());
+        let broken = setup_failed_report("copilot", &error);
+        assert!(!broken.reachable);
+        assert_eq!(broken.credentials_valid, None);
+        assert!(broken.error.unwrap().contains("not logged in"));
+    }
+
+    #[test]
+    fn test_mask_long_api_key() {
+        let masked = mask_setting("openai_api_key", "sk-abcdefghijklmnop".into());
+        assert_eq!(masked, "sk-a...mnop");
+    }
+
+    #[test]
+    fn test_mask_skips_short_values() {
+        assert_eq!(mask_setting("openai_api_key", "short".into()), "short");
+        assert_eq!(mask_setting("openai_api_key", "12345678".into()), "12345678");
+    }
+
+    #[test]
+    fn test_mask_only_applies_to_api_keys() {
+        let url = "https://api.openai.com/v1".to_string();
+        assert_eq!(mask_setting("openai_base_url", url.clone()), url);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::db::models::PromptTemplate;
+use crate::db::Database;
+use crate::error::AppError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tauri::State;
+
+/// A template to render, with values for its `{{variable}}` placeholders.
+#[derive(Debug, Deserialize)]
+pub struct TemplateRef {
+    pub id: String,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+#[tauri::command]
+pub fn list_prompt_templates(db: State<'_, Database>) -> Result<Vec<PromptTemplate>, AppError> {
+    db.list_prompt_templates().map_err(AppError::from)
+}
+
+#[tauri::command]
+pub fn create_prompt_template(
+    db: State<'_, Database>,
+    name: String,
+    content: String,
+) -> Result<PromptTemplate, AppError> {
+    let name = validate_template(&name, &content)?;
+    db.create_prompt_template(name, &content).map_err(AppError::from)
+}
+
+#[tauri::command]
+pub fn update_prompt_template(
+    db: State<'_, Database>,
+    id: String,
+    name: String,
+    content: String,
+) -> Result<(), AppError> {
+    let name = validate_template(&name, &content)?;
+    if db.update_prompt_template(&id, name, &content)? {
+        Ok(())
+    } else {
+        Err(AppError::NotFound("Prompt template not found".into()))
+    }
+}
+
+#[tauri::command]
+pub fn delete_prompt_template(db: State<'_, Database>, id: String) -> Result<(), AppError> {
+    if db.delete_prompt_template(&id)? {
+        Ok(())
+    } else {
+        Err(AppError::NotFound("Prompt template not found".into()))
+    }
+}
+
+/// Set the system prompt of a conversation, either as literal `content` or
+/// rendered from a `template`. Passing neither (or empty content) clears it.
+/// Returns the prompt that was stored.
+#[tauri::command]
+pub fn set_system_prompt(
+    db: State<'_, Database>,
+    conversation_id: String,
+    content: Option<String>,
+    template: Option<TemplateRef>,
+) -> Result<Option<String>, AppError> {
+    let prompt = match (content, template) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::InvalidInput(
+                "Pass either content or a template, not both".into(),
+            ))
+        }
+        (_, Some(template)) => Some(render_template_ref(&db, &template)?),
+        (content, None) => content.filter(|c| !c.trim().is_empty()),
+    };
+    if !db.update_conversation_system_prompt(&conversation_id, prompt.as_deref())? {
+        return Err(AppError::NotFound("Conversation not found".into()));
+    }
+    Ok(prompt)
+}
+
+/// Trimmed name, once the content is known to be a well-formed template.
+fn validate_template<'a>(name: &'a str, content: &str) -> Result<&'a str, AppError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AppError::InvalidInput("Template name cannot be empty".into()));
+    }
+    placeholders(content)?;
+    Ok(name)
+}
+
+/// Load a stored template and fill in its variables.
+pub(crate) fn render_template_ref(db: &Database, template: &TemplateRef) -> Result<String, AppError> {
+    let stored = db
+        .get_prompt_template(&template.id)?
+        .ok_or(AppError::NotFound("Prompt template not found".into()))?;
+    render_template(&stored.content, &template.variables)
+}
+
+/// Replace every `{{name}}` in `content` with its value from `variables`.
+/// Placeholders without a value are an error rather than being left in the
+/// prompt; surplus variables are ignored.
+pub(crate) fn render_template(
+    content: &str,
+    variables: &HashMap<String, String>,
+) -> Result<String, AppError> {
+    let parts = placeholders(content)?;
+    let mut missing: Vec<&str> = parts
+        .iter()
+        .filter_map(|part| match part {
+            Part::Variable(name) if !variables.contains_key(*name) => Some(*name),
+            _ => None,
+        })
+        .collect();
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        missing.dedup();
+        return Err(AppError::InvalidInput(format!(
+            "Unresolved template variables: {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(parts
+        .iter()
+        .map(|part| match part {
+            Part::Text(text) => *text,
+            Part::Variable(name) => variables[*name].as_str(),
+        })
+        .collect())
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+/// Split a template into literal text and variable names. An unclosed
+/// `{{` or an empty name is a syntax error.
+fn placeholders(content: &str) -> Result<Vec<Part<'_>>, AppError> {
+    let mut parts = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("{{") {
+        parts.push(Part::Text(&rest[..start]));
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or_else(|| {
+            AppError::InvalidInput("Template has an unclosed {{ placeholder".into())
+        })?;
+        let name = after[..end].trim();
+        if name.is_empty() {
+            return Err(AppError::InvalidInput("Template has an empty {{}} placeholder".into()));
+        }
+        parts.push(Part::Variable(name));
+        rest = &after[end + 2..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_render_substitutes_variables() {
+        let rendered = render_template(
+            "You review {{ language }} code for {{team}}. Be strict about {{language}}.",
+            &vars(&[("language", "Rust"), ("team", "infra"), ("unused", "x")]),
+        )
+        .unwrap();
+        assert_eq!(rendered, "You review Rust code for infra. Be strict about Rust.");
+        assert_eq!(render_template("No placeholders", &HashMap::new()).unwrap(), "No placeholders");
+    }
+
+    #[test]
+    fn test_render_rejects_unresolved_and_malformed_placeholders() {
+        match render_template("{{b}} and {{a}} and {{b}}", &HashMap::new()) {
+            Err(AppError::InvalidInput(msg)) => assert!(msg.ends_with("a, b"), "{}", msg),
+            other => panic!("expected InvalidInput, got {:?}", other),
+        }
+        assert!(render_template("Hello {{name", &vars(&[("name", "x")])).is_err());
+        assert!(render_template("Hello {{ }}", &HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use crate::embedding::{bytes_to_embedding, embedding_to_bytes, is_normalized, normalize};
+use rusqlite::{params, Connection, Result};
+
+/// Ordered schema steps. Step `n` (1-based) brings a database from
+/// `user_version` n-1 to n; append new steps, never reorder or edit old ones.
+///
+/// Databases created before versioning report version 0 but may already
+/// have some of these changes applied, so every step must be idempotent.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    create_base_tables,
+    create_model_cache,
+    add_message_attachments,
+    add_chunk_embedding_model,
+    create_tags,
+    add_conversation_deleted_at,
+    add_chunk_pages,
+    add_message_model,
+    add_document_chunking,
+    add_message_truncated,
+    create_prompt_templates,
+    relax_message_roles,
+    add_chunk_embedding_dim,
+    create_jobs,
+    normalize_chunk_embeddings,
+    add_model_capabilities,
+    add_document_content_hash,
+    add_message_tool_calls,
+];
+
+/// Schema version of a fully migrated database.
+pub const LATEST_VERSION: usize = MIGRATIONS.len();
+
+pub fn user_version(conn: &Connection) -> Result<usize> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Apply every step past the database's `user_version`, each in its own
+/// transaction together with the version bump.
+pub fn run(conn: &mut Connection) -> Result<()> {
+    let current = user_version(conn)?;
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(current) {
+        let tx = conn.transaction()?;
+        step(&tx)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {};", i + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    conn.query_row(
+        &format!("SELECT EXISTS(SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1)", table),
+        params![column],
+        |row| row.get(0),
+    )
+}
+
+/// `ALTER TABLE ... ADD COLUMN` for databases created before the column existed.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    if !column_exists(conn, table, column)? {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, decl))?;
+    }
+    Ok(())
+}
+
+fn create_base_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS conversations (
+            id TEXT PRIMARY KEY,
+            title TEXT NOT NULL,
+            model TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );
+
+        CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            conversation_id TEXT NOT NULL,
+            role TEXT NOT NULL CHECK (role IN ('user', 'assistant', 'system')),
+            content TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS documents (
+            id TEXT PRIMARY KEY,
+            filename TEXT NOT NULL,
+            file_type TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            file_size INTEGER,
+            created_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );
+
+        CREATE TABLE IF NOT EXISTS chunks (
+            id TEXT PRIMARY KEY,
+            document_id TEXT NOT NULL,
+            content TEXT NOT NULL,
+            chunk_index INTEGER NOT NULL,
+            embedding BLOB,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (document_id) REFERENCES documents(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        ",
+    )
+}
+
+fn create_model_cache(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS model_cache (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            provider TEXT NOT NULL,
+            fetched_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );
+        ",
+    )
+}
+
+/// JSON array of image file paths attached to the message.
+fn add_message_attachments(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "messages", "attachments", "TEXT")
+}
+
+/// Embedding model that produced each chunk's vector. Older rows were always
+/// embedded with the previous hardcoded default.
+fn add_chunk_embedding_model(conn: &Connection) -> Result<()> {
+    if column_exists(conn, "chunks", "embedding_model")? {
+        return Ok(());
+    }
+    add_column_if_missing(conn, "chunks", "embedding_model", "TEXT")?;
+    conn.execute(
+        "UPDATE chunks SET embedding_model = 'text-embedding-3-small' WHERE embedding IS NOT NULL",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_tags(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS tags (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE IF NOT EXISTS conversation_tags (
+            conversation_id TEXT NOT NULL,
+            tag_id INTEGER NOT NULL,
+            PRIMARY KEY (conversation_id, tag_id),
+            FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+            FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+        );
+        ",
+    )
+}
+
+/// Set when a conversation is moved to the trash.
+fn add_conversation_deleted_at(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "conversations", "deleted_at", "TEXT")
+}
+
+/// 1-based page range a chunk was cut from, for formats with pages.
+fn add_chunk_pages(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "chunks", "page_start", "INTEGER")?;
+    add_column_if_missing(conn, "chunks", "page_end", "INTEGER")
+}
+
+/// Model that produced each assistant reply.
+fn add_message_model(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "messages", "model", "TEXT")
+}
+
+/// Chunk size and overlap a document was split with, reused on update.
+fn add_document_chunking(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "documents", "chunk_size", "INTEGER")?;
+    add_column_if_missing(conn, "documents", "chunk_overlap", "INTEGER")
+}
+
+/// Whether an assistant reply stopped at the token limit.
+fn add_message_truncated(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "messages", "truncated", "INTEGER NOT NULL DEFAULT 0")
+}
+
+/// Reusable system prompts, and the system prompt of each conversation.
+fn create_prompt_templates(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS prompt_templates (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            content TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );
+        ",
+    )?;
+    add_column_if_missing(conn, "conversations", "system_prompt", "TEXT")
+}
+
+/// Drop the CHECK on `messages.role` so tool results (and later roles) can
+/// be stored; the allowed roles are validated in code instead. SQLite can't
+/// alter a constraint, so the table is rebuilt, keeping rowids since they
+/// order messages. Also records which tool call a `tool` message answers.
+fn relax_message_roles(conn: &Connection) -> Result<()> {
+    let sql: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'messages'",
+        [],
+        |row| row.get(0),
+    )?;
+    if sql.contains("CHECK") {
+        conn.execute_batch(
+            "
+            CREATE TABLE messages_new (
+                id TEXT PRIMARY KEY,
+                conversation_id TEXT NOT NULL,
+                role TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                attachments TEXT,
+                model TEXT,
+                truncated INTEGER NOT NULL DEFAULT 0,
+                FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+            );
+            INSERT INTO messages_new
+                (rowid, id, conversation_id, role, content, created_at, attachments, model, truncated)
+                SELECT rowid, id, conversation_id, role, content, created_at, attachments, model, truncated
+                FROM messages;
+            DROP TABLE messages;
+            ALTER TABLE messages_new RENAME TO messages;
+            ",
+        )?;
+    }
+    add_column_if_missing(conn, "messages", "tool_call_id", "TEXT")
+}
+
+/// Length of each chunk's vector, so search only compares vectors of the
+/// same dimension. Existing vectors are 4-byte floats.
+fn add_chunk_embedding_dim(conn: &Connection) -> Result<()> {
+    if column_exists(conn, "chunks", "embedding_dim")? {
+        return Ok(());
+    }
+    add_column_if_missing(conn, "chunks", "embedding_dim", "INTEGER")?;
+    conn.execute(
+        "UPDATE chunks SET embedding_dim = length(embedding) / 4 WHERE embedding IS NOT NULL",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Background embedding of each uploaded document, one row per document.
+fn create_jobs(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS jobs (
+            document_id TEXT PRIMARY KEY,
+            status TEXT NOT NULL,
+            embedded INTEGER NOT NULL DEFAULT 0,
+            total INTEGER NOT NULL DEFAULT 0,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            error TEXT,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+            FOREIGN KEY (document_id) REFERENCES documents(id) ON DELETE CASCADE
+        );
+        ",
+    )
+}
+
+/// Search ranks stored vectors by dot product, which needs them at unit
+/// length; older vectors were stored as the backend returned them.
+fn normalize_chunk_embeddings(conn: &Connection) -> Result<()> {
+    renormalize_embeddings(conn).map(|_| ())
+}
+
+/// Rescale every stored chunk vector that isn't unit length. Returns the
+/// number of chunks rewritten.
+pub(super) fn renormalize_embeddings(conn: &Connection) -> Result<usize> {
+    let stored: Vec<(String, Vec<f32>)> = {
+        let mut stmt = conn.prepare("SELECT id, embedding FROM chunks WHERE embedding IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| {
+            let bytes: Vec<u8> = row.get(1)?;
+            Ok((row.get(0)?, bytes_to_embedding(&bytes)))
+        })?;
+        rows.collect::<Result<_>>()?
+    };
+    let mut rewritten = 0;
+    for (id, mut embedding) in stored {
+        // The zero vector has no direction to keep
+        if is_normalized(&embedding) || embedding.iter().all(|x| *x == 0.0) {
+            continue;
+        }
+        normalize(&mut embedding);
+        conn.execute(
+            "UPDATE chunks SET embedding = ?1 WHERE id = ?2",
+            params![embedding_to_bytes(&embedding), id],
+        )?;
+        rewritten += 1;
+    }
+    Ok(rewritten)
+}
+
+/// What each cached model supports. Rows cached earlier stay NULL until the
+/// next refresh.
+fn add_model_capabilities(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "model_cache", "supports_streaming", "INTEGER")?;
+    add_column_if_missing(conn, "model_cache", "supports_vision", "INTEGER")?;
+    add_column_if_missing(conn, "model_cache", "supports_tools", "INTEGER")?;
+    add_column_if_missing(conn, "model_cache", "context_length", "INTEGER")
+}
+
+/// SHA-256 of each document's parsed text, for spotting re-uploads. Left
+/// NULL for documents indexed before it was recorded.
+fn add_document_content_hash(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "documents", "content_hash", "TEXT")?;
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_documents_content_hash ON documents(content_hash)",
+    )
+}
+
+/// The tool calls an assistant reply made, as a JSON array, so they can be
+/// replayed ahead of the `tool` messages that answer them.
+fn add_message_tool_calls(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "messages", "tool_calls", "TEXT")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrades_unversioned_database() {
+        // Schema and data as written by releases before migrations existed
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_base_tables(&conn).unwrap();
+        conn.execute_batch(
+            "
+            INSERT INTO conversations (id, title) VALUES ('c1', 'Old chat');
+            INSERT INTO messages (id, conversation_id, role, content) VALUES ('m1', 'c1', 'user', 'hi');
+            INSERT INTO documents (id, filename, file_type, file_path) VALUES ('d1', 'a.md', 'md', '/a.md');
+            INSERT INTO chunks (id, document_id, content, chunk_index, embedding) VALUES ('k1', 'd1', 'x', 0, X'00000040');
+            INSERT INTO chunks (id, document_id, content, chunk_index) VALUES ('k2', 'd1', 'y', 1);
+            ",
+        )
+        .unwrap();
+        assert_eq!(user_version(&conn).unwrap(), 0);
+
+        run(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn).unwrap(), LATEST_VERSION);
+        assert!(column_exists(&conn, "messages", "attachments").unwrap());
+        assert!(column_exists(&conn, "model_cache", "fetched_at").unwrap());
+        let content: String = conn
+            .query_row("SELECT content FROM messages WHERE id = 'm1'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, "hi");
+        conn.execute(
+            "INSERT INTO messages (id, conversation_id, role, content, tool_call_id)
+             VALUES ('m2', 'c1', 'tool', '42', 'call_1')",
+            [],
+        )
+        .unwrap();
+        let models: Vec<Option<String>> = conn
+            .prepare("SELECT embedding_model FROM chunks ORDER BY chunk_index")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(models, vec![Some("text-embedding-3-small".to_string()), None]);
+        let dims: Vec<Option<i64>> = conn
+            .prepare("SELECT embedding_dim FROM chunks ORDER BY chunk_index")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(dims, vec![Some(1), None]);
+        let embedding: Vec<u8> = conn
+            .query_row("SELECT embedding FROM chunks WHERE id = 'k1'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(bytes_to_embedding(&embedding), vec![1.0]);
+    }
+
+    #[test]
+    fn test_run_is_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO settings (key, value) VALUES ('theme', 'dark')",
+            [],
+        )
+        .unwrap();
+        run(&mut conn).unwrap();
+        assert_eq!(user_version(&conn).unwrap(), LATEST_VERSION);
+        let theme: String = conn
+            .query_row("SELECT value FROM settings WHERE key = 'theme'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(theme, "dark");
+    }
+}
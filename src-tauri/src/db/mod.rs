@@ -1,127 +1,472 @@
+mod migrations;
 pub mod models;
 
-use models::{Conversation, Message};
+use crate::doc_processor::TextChunk;
+use crate::embedding::embedding_to_bytes;
+use crate::llm::{ModelCapabilities, ModelInfo, ToolCall};
+use crate::secrets;
+use models::{
+    Chunk, Conversation, Document, Job, Message, PromptTemplate, JOB_CANCELLED, JOB_INDEXING,
+    JOB_READY, MESSAGE_ROLES,
+};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Connection, Result};
-use std::sync::Mutex;
 
+pub type DbConnection = PooledConnection<SqliteConnectionManager>;
+
+/// Clones share the same connection pool.
+#[derive(Clone)]
 pub struct Database {
-    pub conn: Mutex<Connection>,
+    pool: Pool<SqliteConnectionManager>,
+    /// Route secret settings through the OS keychain (see `crate::secrets`).
+    use_keychain: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct DatabaseStats {
+    pub total_bytes: i64,
+    /// Space held by deleted rows, reclaimable with `compact`.
+    pub free_bytes: i64,
+    pub table_rows: std::collections::BTreeMap<String, i64>,
+    pub embedding_bytes: i64,
+}
+
+/// Columns read by `conversation_from_row`, in order.
+const CONVERSATION_COLUMNS: &str =
+    "id, title, model, created_at, updated_at, deleted_at, system_prompt";
+
+fn conversation_from_row(row: &rusqlite::Row) -> Result<Conversation> {
+    Ok(Conversation {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        model: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+        deleted_at: row.get(5)?,
+        system_prompt: row.get(6)?,
+        tags: None,
+    })
+}
+
+/// Columns read by `template_from_row`, in order.
+const TEMPLATE_COLUMNS: &str = "id, name, content, created_at, updated_at";
+
+fn template_from_row(row: &rusqlite::Row) -> Result<PromptTemplate> {
+    Ok(PromptTemplate {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        content: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+    })
+}
+
+/// Columns read by `message_from_row`, in order.
+const MESSAGE_COLUMNS: &str =
+    "id, conversation_id, role, content, created_at, attachments, model, truncated, tool_call_id, tool_calls";
+
+fn message_from_row(row: &rusqlite::Row) -> Result<Message> {
+    let attachments: Option<String> = row.get(5)?;
+    let tool_calls: Option<String> = row.get(9)?;
+    Ok(Message {
+        id: row.get(0)?,
+        conversation_id: row.get(1)?,
+        role: row.get(2)?,
+        content: row.get(3)?,
+        created_at: row.get(4)?,
+        attachments: attachments
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
+        model: row.get(6)?,
+        truncated: row.get(7)?,
+        tool_call_id: row.get(8)?,
+        tool_calls: tool_calls
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
+    })
+}
+
+/// The schema accepts any role, so unknown ones are rejected here.
+fn check_role(role: &str) -> Result<()> {
+    if MESSAGE_ROLES.contains(&role) {
+        Ok(())
+    } else {
+        Err(rusqlite::Error::ToSqlConversionFailure(
+            format!("Unknown message role: {}", role).into(),
+        ))
+    }
+}
+
+const DOCUMENT_COLUMNS: &str =
+    "id, filename, file_type, file_path, file_size, created_at, chunk_size, chunk_overlap,
+     (SELECT status FROM jobs WHERE jobs.document_id = documents.id),
+     (SELECT COUNT(embedding) FROM chunks WHERE chunks.document_id = documents.id),
+     (SELECT COUNT(*) FROM chunks WHERE chunks.document_id = documents.id),
+     content_hash";
+
+fn document_from_row(row: &rusqlite::Row) -> Result<Document> {
+    Ok(Document {
+        id: row.get(0)?,
+        filename: row.get(1)?,
+        file_type: row.get(2)?,
+        file_path: row.get(3)?,
+        file_size: row.get(4)?,
+        created_at: row.get(5)?,
+        chunk_size: row.get(6)?,
+        chunk_overlap: row.get(7)?,
+        index_status: row.get(8)?,
+        embedded_chunks: row.get(9)?,
+        chunk_count: row.get(10)?,
+        content_hash: row.get(11)?,
+    })
+}
+
+/// Columns read by `job_from_row`, in order.
+const JOB_COLUMNS: &str =
+    "document_id, status, embedded, total, attempts, error, created_at, updated_at";
+
+fn job_from_row(row: &rusqlite::Row) -> Result<Job> {
+    Ok(Job {
+        document_id: row.get(0)?,
+        status: row.get(1)?,
+        embedded: row.get(2)?,
+        total: row.get(3)?,
+        attempts: row.get(4)?,
+        error: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+fn enqueue_job_on(conn: &Connection, document_id: &str, total: usize) -> Result<()> {
+    conn.execute(
+        "INSERT INTO jobs (document_id, status, total) VALUES (?1, ?2, ?3)
+         ON CONFLICT (document_id) DO UPDATE SET
+             status = excluded.status, embedded = 0, total = excluded.total,
+             error = NULL, updated_at = datetime('now')",
+        params![document_id, JOB_INDEXING, total],
+    )?;
+    Ok(())
+}
+
+/// Delete the messages of a conversation that come after the one at
+/// `(created_at, rowid)`, in the same order as `get_messages`, and refresh
+/// its `updated_at`. Returns the number of deleted messages.
+fn delete_messages_after(
+    conn: &Connection,
+    conversation_id: &str,
+    created_at: &str,
+    rowid: i64,
+) -> Result<usize> {
+    let deleted = conn.execute(
+        "DELETE FROM messages
+         WHERE conversation_id = ?1
+           AND (created_at > ?2 OR (created_at = ?2 AND rowid > ?3))",
+        params![conversation_id, created_at, rowid],
+    )?;
+    conn.execute(
+        "UPDATE conversations SET updated_at = datetime('now') WHERE id = ?1",
+        params![conversation_id],
+    )?;
+    Ok(deleted)
+}
+
+fn attachments_to_json(attachments: &[String]) -> Option<String> {
+    if attachments.is_empty() {
+        None
+    } else {
+        serde_json::to_string(attachments).ok()
+    }
+}
+
+fn tool_calls_to_json(tool_calls: &[ToolCall]) -> Option<String> {
+    if tool_calls.is_empty() {
+        None
+    } else {
+        serde_json::to_string(tool_calls).ok()
+    }
+}
+
+/// Pool checkout failures (all connections busy past the timeout) surface as
+/// `SQLITE_BUSY` so callers keep dealing in `rusqlite::Error`.
+fn pool_error(e: r2d2::Error) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+        Some(e.to_string()),
+    )
+}
+
+/// Per-connection settings; `journal_mode=WAL` is persisted by `migrate`.
+fn init_connection(conn: &mut Connection) -> Result<()> {
+    conn.execute_batch("PRAGMA foreign_keys=ON; PRAGMA busy_timeout=5000;")
 }
 
 impl Database {
     pub fn new(app_dir: &std::path::Path) -> Result<Self> {
         std::fs::create_dir_all(app_dir).ok();
         let db_path = app_dir.join("ai-box.db");
-        let conn = Connection::open(db_path)?;
+        let manager = SqliteConnectionManager::file(db_path).with_init(init_connection);
+        let pool = Pool::new(manager).map_err(pool_error)?;
         let db = Self {
-            conn: Mutex::new(conn),
+            pool,
+            use_keychain: true,
         };
         db.migrate()?;
+        db.migrate_secrets_to_keychain()?;
         Ok(db)
     }
 
-    fn migrate(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute_batch(
-            "
-            PRAGMA journal_mode=WAL;
-            PRAGMA foreign_keys=ON;
-
-            CREATE TABLE IF NOT EXISTS conversations (
-                id TEXT PRIMARY KEY,
-                title TEXT NOT NULL,
-                model TEXT,
-                created_at TEXT NOT NULL DEFAULT (datetime('now')),
-                updated_at TEXT NOT NULL DEFAULT (datetime('now'))
-            );
-
-            CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                conversation_id TEXT NOT NULL,
-                role TEXT NOT NULL CHECK (role IN ('user', 'assistant', 'system')),
-                content TEXT NOT NULL,
-                created_at TEXT NOT NULL DEFAULT (datetime('now')),
-                FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
-            );
-
-            CREATE TABLE IF NOT EXISTS documents (
-                id TEXT PRIMARY KEY,
-                filename TEXT NOT NULL,
-                file_type TEXT NOT NULL,
-                file_path TEXT NOT NULL,
-                file_size INTEGER,
-                created_at TEXT NOT NULL DEFAULT (datetime('now'))
-            );
+    /// Every `:memory:` connection is its own database, so tests share a
+    /// single pooled connection.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        let manager = SqliteConnectionManager::memory().with_init(init_connection);
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_timeout(std::time::Duration::from_secs(1))
+            .build(manager)
+            .map_err(pool_error)?;
+        let db = Self {
+            pool,
+            use_keychain: false,
+        };
+        db.migrate()?;
+        Ok(db)
+    }
 
-            CREATE TABLE IF NOT EXISTS chunks (
-                id TEXT PRIMARY KEY,
-                document_id TEXT NOT NULL,
-                content TEXT NOT NULL,
-                chunk_index INTEGER NOT NULL,
-                embedding BLOB,
-                created_at TEXT NOT NULL DEFAULT (datetime('now')),
-                FOREIGN KEY (document_id) REFERENCES documents(id) ON DELETE CASCADE
-            );
+    /// Check out a pooled connection. Don't hold it across an `.await`.
+    pub fn conn(&self) -> Result<DbConnection> {
+        self.pool.get().map_err(pool_error)
+    }
 
-            CREATE TABLE IF NOT EXISTS settings (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL
-            );
-            ",
-        )?;
-        Ok(())
+    fn migrate(&self) -> Result<()> {
+        let mut conn = self.conn()?;
+        // Can't be changed inside a transaction, so it stays out of the steps
+        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+        migrations::run(&mut conn)
     }
 
     // ── Conversations ──
 
     pub fn create_conversation(&self, title: &str, model: Option<&str>) -> Result<Conversation> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn()?;
         let id = uuid::Uuid::new_v4().to_string();
         conn.execute(
             "INSERT INTO conversations (id, title, model) VALUES (?1, ?2, ?3)",
             params![id, title, model],
         )?;
         let conv = conn.query_row(
-            "SELECT id, title, model, created_at, updated_at FROM conversations WHERE id = ?1",
-            params![id],
-            |row| {
-                Ok(Conversation {
-                    id: row.get(0)?,
-                    title: row.get(1)?,
-                    model: row.get(2)?,
-                    created_at: row.get(3)?,
-                    updated_at: row.get(4)?,
-                })
-            },
+            &format!("SELECT {} FROM conversations WHERE id = ?1", CONVERSATION_COLUMNS),
+            params![id],
+            conversation_from_row,
         )?;
         Ok(conv)
     }
 
     pub fn list_conversations(&self) -> Result<Vec<Conversation>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, title, model, created_at, updated_at FROM conversations ORDER BY updated_at DESC",
-        )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(Conversation {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                model: row.get(2)?,
-                created_at: row.get(3)?,
-                updated_at: row.get(4)?,
-            })
-        })?;
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM conversations WHERE deleted_at IS NULL ORDER BY updated_at DESC",
+            CONVERSATION_COLUMNS
+        ))?;
+        let rows = stmt.query_map([], conversation_from_row)?;
         rows.collect()
     }
 
+    /// Soft-deleted conversations, most recently deleted first.
+    pub fn list_trash(&self) -> Result<Vec<Conversation>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM conversations WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+            CONVERSATION_COLUMNS
+        ))?;
+        let rows = stmt.query_map([], conversation_from_row)?;
+        rows.collect()
+    }
+
+    pub fn get_conversation(&self, id: &str) -> Result<Option<Conversation>> {
+        let conn = self.conn()?;
+        let result = conn.query_row(
+            &format!("SELECT {} FROM conversations WHERE id = ?1", CONVERSATION_COLUMNS),
+            params![id],
+            conversation_from_row,
+        );
+        match result {
+            Ok(conv) => Ok(Some(conv)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Recreate a conversation and its messages under fresh ids, keeping the
+    /// original title, model and timestamps. Runs in a single transaction.
+    pub fn import_conversation(
+        &self,
+        conversation: &Conversation,
+        messages: &[Message],
+    ) -> Result<Conversation> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let id = uuid::Uuid::new_v4().to_string();
+        tx.execute(
+            "INSERT INTO conversations (id, title, model, created_at, updated_at, system_prompt)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id,
+                conversation.title,
+                conversation.model,
+                conversation.created_at,
+                conversation.updated_at,
+                conversation.system_prompt
+            ],
+        )?;
+        for msg in messages {
+            check_role(&msg.role)?;
+            tx.execute(
+                "INSERT INTO messages (id, conversation_id, role, content, attachments, created_at, model, tool_call_id, tool_calls)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    uuid::Uuid::new_v4().to_string(),
+                    id,
+                    msg.role,
+                    msg.content,
+                    attachments_to_json(&msg.attachments),
+                    msg.created_at,
+                    msg.model,
+                    msg.tool_call_id,
+                    tool_calls_to_json(&msg.tool_calls)
+                ],
+            )?;
+        }
+        tx.commit()?;
+        drop(conn);
+
+        self.get_conversation(&id)?
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)
+    }
+
+    /// Copy a conversation into a new one titled "Copy of …", with its
+    /// messages up to and including `up_to` (all of them if `None`) under
+    /// fresh ids. Returns `None` if the conversation doesn't exist or `up_to`
+    /// isn't one of its messages.
+    pub fn fork_conversation(&self, id: &str, up_to: Option<&str>) -> Result<Option<Conversation>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let source = match tx.query_row(
+            &format!("SELECT {} FROM conversations WHERE id = ?1", CONVERSATION_COLUMNS),
+            params![id],
+            conversation_from_row,
+        ) {
+            Ok(conv) => conv,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut messages: Vec<Message> = {
+            let mut stmt = tx.prepare(&format!(
+                "SELECT {} FROM messages WHERE conversation_id = ?1 ORDER BY created_at ASC, rowid ASC",
+                MESSAGE_COLUMNS
+            ))?;
+            let rows = stmt.query_map(params![id], message_from_row)?;
+            rows.collect::<Result<_>>()?
+        };
+        if let Some(up_to) = up_to {
+            match messages.iter().position(|m| m.id == up_to) {
+                Some(last) => messages.truncate(last + 1),
+                None => return Ok(None),
+            }
+        }
+
+        let fork_id = uuid::Uuid::new_v4().to_string();
+        tx.execute(
+            "INSERT INTO conversations (id, title, model, system_prompt) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                fork_id,
+                format!("Copy of {}", source.title),
+                source.model,
+                source.system_prompt
+            ],
+        )?;
+        for msg in &messages {
+            tx.execute(
+                "INSERT INTO messages (id, conversation_id, role, content, attachments, created_at, model, truncated, tool_call_id, tool_calls)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    uuid::Uuid::new_v4().to_string(),
+                    fork_id,
+                    msg.role,
+                    msg.content,
+                    attachments_to_json(&msg.attachments),
+                    msg.created_at,
+                    msg.model,
+                    msg.truncated,
+                    msg.tool_call_id,
+                    tool_calls_to_json(&msg.tool_calls)
+                ],
+            )?;
+        }
+        let fork = tx.query_row(
+            &format!("SELECT {} FROM conversations WHERE id = ?1", CONVERSATION_COLUMNS),
+            params![fork_id],
+            conversation_from_row,
+        )?;
+        tx.commit()?;
+        Ok(Some(fork))
+    }
+
+    /// Permanently delete a conversation along with its messages and tags.
     pub fn delete_conversation(&self, id: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn()?;
         conn.execute("DELETE FROM conversations WHERE id = ?1", params![id])?;
         Ok(())
     }
 
+    /// Delete every conversation, trashed ones included, with their messages
+    /// and tags. Returns the number of conversations and messages removed.
+    pub fn delete_all_conversations(&self) -> Result<(usize, usize)> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let messages: usize = tx.query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
+        let conversations = tx.execute("DELETE FROM conversations", [])?;
+        // Tags only exist while attached to a conversation
+        tx.execute("DELETE FROM tags", [])?;
+        tx.commit()?;
+        Ok((conversations, messages))
+    }
+
+    /// Move a conversation to the trash.
+    pub fn trash_conversation(&self, id: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE conversations SET deleted_at = datetime('now') WHERE id = ?1 AND deleted_at IS NULL",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    /// Take a conversation back out of the trash. Returns false if it wasn't there.
+    pub fn restore_conversation(&self, id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let restored = conn.execute(
+            "UPDATE conversations SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL",
+            params![id],
+        )?;
+        Ok(restored > 0)
+    }
+
+    /// Hard-delete conversations that have been in the trash for at least
+    /// `older_than_days` days. Returns how many were removed.
+    pub fn purge_trash(&self, older_than_days: u32) -> Result<usize> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM conversations
+             WHERE deleted_at IS NOT NULL AND deleted_at <= datetime('now', ?1)",
+            params![format!("-{} days", older_than_days)],
+        )
+    }
+
     pub fn update_conversation_title(&self, id: &str, title: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn()?;
         conn.execute(
             "UPDATE conversations SET title = ?1, updated_at = datetime('now') WHERE id = ?2",
             params![title, id],
@@ -129,14 +474,197 @@ impl Database {
         Ok(())
     }
 
+    pub fn update_conversation_model(&self, id: &str, model: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE conversations SET model = ?1 WHERE id = ?2",
+            params![model, id],
+        )?;
+        Ok(())
+    }
+
+    /// Set or clear (`None`) the conversation's system prompt. Returns false
+    /// if there is no such conversation.
+    pub fn update_conversation_system_prompt(&self, id: &str, prompt: Option<&str>) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE conversations SET system_prompt = ?1 WHERE id = ?2",
+            params![prompt, id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // ── Prompt templates ──
+
+    pub fn create_prompt_template(&self, name: &str, content: &str) -> Result<PromptTemplate> {
+        let conn = self.conn()?;
+        let id = uuid::Uuid::new_v4().to_string();
+        conn.execute(
+            "INSERT INTO prompt_templates (id, name, content) VALUES (?1, ?2, ?3)",
+            params![id, name, content],
+        )?;
+        conn.query_row(
+            &format!("SELECT {} FROM prompt_templates WHERE id = ?1", TEMPLATE_COLUMNS),
+            params![id],
+            template_from_row,
+        )
+    }
+
+    pub fn get_prompt_template(&self, id: &str) -> Result<Option<PromptTemplate>> {
+        let conn = self.conn()?;
+        let result = conn.query_row(
+            &format!("SELECT {} FROM prompt_templates WHERE id = ?1", TEMPLATE_COLUMNS),
+            params![id],
+            template_from_row,
+        );
+        match result {
+            Ok(template) => Ok(Some(template)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn list_prompt_templates(&self) -> Result<Vec<PromptTemplate>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM prompt_templates ORDER BY name COLLATE NOCASE",
+            TEMPLATE_COLUMNS
+        ))?;
+        let rows = stmt.query_map([], template_from_row)?;
+        rows.collect()
+    }
+
+    /// Returns false if there is no template with this id.
+    pub fn update_prompt_template(&self, id: &str, name: &str, content: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE prompt_templates SET name = ?1, content = ?2, updated_at = datetime('now')
+             WHERE id = ?3",
+            params![name, content, id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Returns false if there is no template with this id.
+    pub fn delete_prompt_template(&self, id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let deleted = conn.execute("DELETE FROM prompt_templates WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    // ── Tags ──
+
+    /// Attach `tag` to a conversation, creating the tag on first use.
+    pub fn add_tag(&self, conversation_id: &str, tag: &str) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", params![tag])?;
+        tx.execute(
+            "INSERT OR IGNORE INTO conversation_tags (conversation_id, tag_id)
+             SELECT ?1, id FROM tags WHERE name = ?2",
+            params![conversation_id, tag],
+        )?;
+        tx.commit()
+    }
+
+    /// Detach `tag` from a conversation; the tag itself goes once unused.
+    pub fn remove_tag(&self, conversation_id: &str, tag: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM conversation_tags
+             WHERE conversation_id = ?1 AND tag_id = (SELECT id FROM tags WHERE name = ?2)",
+            params![conversation_id, tag],
+        )?;
+        conn.execute(
+            "DELETE FROM tags WHERE name = ?1
+             AND NOT EXISTS (SELECT 1 FROM conversation_tags WHERE tag_id = tags.id)",
+            params![tag],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_tags(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT name FROM tags ORDER BY name")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Conversations carrying `tag`, most recently updated first.
+    pub fn list_conversations_by_tag(&self, tag: &str) -> Result<Vec<Conversation>> {
+        let conn = self.conn()?;
+        let columns: Vec<String> = CONVERSATION_COLUMNS
+            .split(", ")
+            .map(|c| format!("c.{}", c))
+            .collect();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM conversations c
+             JOIN conversation_tags ct ON ct.conversation_id = c.id
+             JOIN tags t ON t.id = ct.tag_id
+             WHERE t.name = ?1 AND c.deleted_at IS NULL
+             ORDER BY c.updated_at DESC",
+            columns.join(", ")
+        ))?;
+        let rows = stmt.query_map(params![tag], conversation_from_row)?;
+        rows.collect()
+    }
+
+    /// Fill in `tags` on each conversation with one query over the links.
+    pub fn attach_tags(&self, conversations: &mut [Conversation]) -> Result<()> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT ct.conversation_id, t.name FROM conversation_tags ct
+             JOIN tags t ON t.id = ct.tag_id
+             ORDER BY t.name",
+        )?;
+        let mut by_conversation: std::collections::HashMap<String, Vec<String>> =
+            std::collections::HashMap::new();
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?;
+        for row in rows {
+            let (conversation_id, name) = row?;
+            by_conversation.entry(conversation_id).or_default().push(name);
+        }
+        for conv in conversations {
+            conv.tags = Some(by_conversation.remove(&conv.id).unwrap_or_default());
+        }
+        Ok(())
+    }
+
     // ── Messages ──
 
     pub fn add_message(&self, conversation_id: &str, role: &str, content: &str) -> Result<Message> {
-        let conn = self.conn.lock().unwrap();
+        self.add_message_with_attachments(conversation_id, role, content, &[])
+    }
+
+    /// Insert a message together with the file paths of its image attachments.
+    pub fn add_message_with_attachments(
+        &self,
+        conversation_id: &str,
+        role: &str,
+        content: &str,
+        attachments: &[String],
+    ) -> Result<Message> {
         let id = uuid::Uuid::new_v4().to_string();
+        self.add_message_with_id(&id, conversation_id, role, content, attachments, None)
+    }
+
+    /// Insert a message under an id chosen by the caller, e.g. one already
+    /// announced to the frontend while the reply was streaming. `model` is
+    /// the full model string that produced an assistant reply.
+    pub fn add_message_with_id(
+        &self,
+        id: &str,
+        conversation_id: &str,
+        role: &str,
+        content: &str,
+        attachments: &[String],
+        model: Option<&str>,
+    ) -> Result<Message> {
+        check_role(role)?;
+        let conn = self.conn()?;
         conn.execute(
-            "INSERT INTO messages (id, conversation_id, role, content) VALUES (?1, ?2, ?3, ?4)",
-            params![id, conversation_id, role, content],
+            "INSERT INTO messages (id, conversation_id, role, content, attachments, model) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![id, conversation_id, role, content, attachments_to_json(attachments), model],
         )?;
         // Touch conversation updated_at
         conn.execute(
@@ -144,42 +672,767 @@ impl Database {
             params![conversation_id],
         )?;
         let msg = conn.query_row(
-            "SELECT id, conversation_id, role, content, created_at FROM messages WHERE id = ?1",
-            params![id],
-            |row| {
-                Ok(Message {
-                    id: row.get(0)?,
-                    conversation_id: row.get(1)?,
-                    role: row.get(2)?,
-                    content: row.get(3)?,
-                    created_at: row.get(4)?,
-                })
-            },
+            &format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS),
+            params![id],
+            message_from_row,
         )?;
         Ok(msg)
     }
 
+    /// Record the tool calls an assistant reply made, so the `tool` messages
+    /// answering them can be replayed after them.
+    pub fn set_tool_calls(&self, message_id: &str, tool_calls: &[ToolCall]) -> Result<()> {
+        let changed = self.conn()?.execute(
+            "UPDATE messages SET tool_calls = ?1 WHERE id = ?2 AND role = 'assistant'",
+            params![tool_calls_to_json(tool_calls), message_id],
+        )?;
+        if changed == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok(())
+    }
+
+    /// Store the result of the tool call `tool_call_id` as a `tool` message,
+    /// so it can be replayed to the provider with the rest of the history.
+    pub fn add_tool_result(
+        &self,
+        conversation_id: &str,
+        tool_call_id: &str,
+        content: &str,
+    ) -> Result<Message> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO messages (id, conversation_id, role, content, tool_call_id) VALUES (?1, ?2, 'tool', ?3, ?4)",
+            params![id, conversation_id, content, tool_call_id],
+        )?;
+        conn.execute(
+            "UPDATE conversations SET updated_at = datetime('now') WHERE id = ?1",
+            params![conversation_id],
+        )?;
+        conn.query_row(
+            &format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS),
+            params![id],
+            message_from_row,
+        )
+    }
+
+    /// Insert a message under a caller-chosen id unless one with that id is
+    /// already stored, in which case the stored message is returned as is.
+    /// Returns `None` if the id is taken by a message in another conversation.
+    pub fn add_message_once(
+        &self,
+        id: &str,
+        conversation_id: &str,
+        role: &str,
+        content: &str,
+        attachments: &[String],
+    ) -> Result<Option<Message>> {
+        check_role(role)?;
+        let conn = self.conn()?;
+        let inserted = conn.execute(
+            "INSERT INTO messages (id, conversation_id, role, content, attachments) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (id) DO NOTHING",
+            params![id, conversation_id, role, content, attachments_to_json(attachments)],
+        )?;
+        if inserted > 0 {
+            conn.execute(
+                "UPDATE conversations SET updated_at = datetime('now') WHERE id = ?1",
+                params![conversation_id],
+            )?;
+        }
+        let msg = conn.query_row(
+            &format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS),
+            params![id],
+            message_from_row,
+        )?;
+        Ok((msg.conversation_id == conversation_id).then_some(msg))
+    }
+
+    pub fn get_message(&self, id: &str) -> Result<Option<Message>> {
+        let conn = self.conn()?;
+        let result = conn.query_row(
+            &format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS),
+            params![id],
+            message_from_row,
+        );
+        match result {
+            Ok(msg) => Ok(Some(msg)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // `created_at` only has second resolution, so messages inserted in quick
+    // succession share a timestamp. `rowid` breaks ties in insertion order.
     pub fn get_messages(&self, conversation_id: &str) -> Result<Vec<Message>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages WHERE conversation_id = ?1 ORDER BY created_at ASC, rowid ASC",
+            MESSAGE_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![conversation_id], message_from_row)?;
+        rows.collect()
+    }
+
+    pub fn set_message_truncated(&self, id: &str, truncated: bool) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE messages SET truncated = ?1 WHERE id = ?2",
+            params![truncated, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_message_content(&self, id: &str, content: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE messages SET content = ?1 WHERE id = ?2",
+            params![content, id],
+        )?;
+        conn.execute(
+            "UPDATE conversations SET updated_at = datetime('now')
+             WHERE id = (SELECT conversation_id FROM messages WHERE id = ?1)",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    /// Replace a message's content and delete every message after it in the
+    /// conversation, in a single transaction, using the same
+    /// `(created_at, rowid)` ordering as `get_messages`. Returns the number of
+    /// deleted messages.
+    pub fn edit_message_and_truncate(&self, id: &str, content: &str) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let (conversation_id, created_at, rowid): (String, String, i64) = tx.query_row(
+            "SELECT conversation_id, created_at, rowid FROM messages WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        tx.execute(
+            "UPDATE messages SET content = ?1 WHERE id = ?2",
+            params![content, id],
+        )?;
+        let deleted = delete_messages_after(&tx, &conversation_id, &created_at, rowid)?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Delete every message after `id` in its conversation, e.g. a reply
+    /// about to be regenerated. Returns the number of deleted messages, or
+    /// `None` if the message doesn't exist.
+    pub fn delete_messages_after(&self, id: &str) -> Result<Option<usize>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let found = tx.query_row(
+            "SELECT conversation_id, created_at, rowid FROM messages WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        );
+        let (conversation_id, created_at, rowid): (String, String, i64) = match found {
+            Ok(found) => found,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let deleted = delete_messages_after(&tx, &conversation_id, &created_at, rowid)?;
+        tx.commit()?;
+        Ok(Some(deleted))
+    }
+
+    /// Delete a single message and refresh the conversation's `updated_at`.
+    /// With `cascade`, deleting a user message also removes the assistant
+    /// reply immediately following it. Returns the number of deleted
+    /// messages, or `None` if the message doesn't exist.
+    pub fn delete_message(&self, id: &str, cascade: bool) -> Result<Option<usize>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let found = tx.query_row(
+            "SELECT conversation_id, role, created_at, rowid FROM messages WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        );
+        let (conversation_id, role, created_at, rowid): (String, String, String, i64) = match found {
+            Ok(found) => found,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut ids = vec![id.to_string()];
+        if cascade && role == "user" {
+            let next = tx.query_row(
+                "SELECT id, role FROM messages
+                 WHERE conversation_id = ?1
+                   AND (created_at > ?2 OR (created_at = ?2 AND rowid > ?3))
+                 ORDER BY created_at ASC, rowid ASC LIMIT 1",
+                params![conversation_id, created_at, rowid],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            );
+            match next {
+                Ok((next_id, next_role)) if next_role == "assistant" => ids.push(next_id),
+                Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut deleted = 0;
+        for msg_id in &ids {
+            deleted += tx.execute("DELETE FROM messages WHERE id = ?1", params![msg_id])?;
+        }
+        tx.execute(
+            "UPDATE conversations SET updated_at = datetime('now') WHERE id = ?1",
+            params![conversation_id],
+        )?;
+        tx.commit()?;
+        Ok(Some(deleted))
+    }
+
+    // ── Knowledge base ──
+
+    /// Distinct embedding models used by the chunks that have a vector.
+    pub fn embedding_models_in_use(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, conversation_id, role, content, created_at FROM messages WHERE conversation_id = ?1 ORDER BY created_at ASC",
+            "SELECT DISTINCT embedding_model FROM chunks
+             WHERE embedding IS NOT NULL AND embedding_model IS NOT NULL
+             ORDER BY embedding_model",
         )?;
-        let rows = stmt.query_map(params![conversation_id], |row| {
-            Ok(Message {
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// `(embedded, pending)` chunk counts of a document.
+    pub fn chunk_embedding_counts(&self, document_id: &str) -> Result<(usize, usize)> {
+        let conn = self.conn()?;
+        conn.query_row(
+            "SELECT COUNT(embedding), COUNT(*) - COUNT(embedding) FROM chunks WHERE document_id = ?1",
+            params![document_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    /// Ids of documents with chunks that have no embedding, oldest first.
+    pub fn documents_with_pending_chunks(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT d.id FROM documents d
+             WHERE EXISTS (SELECT 1 FROM chunks c WHERE c.document_id = d.id AND c.embedding IS NULL)
+             ORDER BY d.created_at, d.rowid",
+        )?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Distinct vector lengths of the chunks embedded with `model`.
+    pub fn embedding_dims_in_use(&self, model: &str) -> Result<Vec<usize>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT embedding_dim FROM chunks
+             WHERE embedding IS NOT NULL AND embedding_model = ?1 AND embedding_dim IS NOT NULL
+             ORDER BY embedding_dim",
+        )?;
+        let rows = stmt.query_map(params![model], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Rescale stored chunk vectors to unit length, for chunks written before
+    /// embeddings were normalized. Returns the number of chunks changed.
+    pub fn renormalize_embeddings(&self) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let rewritten = migrations::renormalize_embeddings(&tx)?;
+        tx.commit()?;
+        Ok(rewritten)
+    }
+
+    /// Delete every document, and through the cascade every chunk, in one
+    /// transaction. Returns the removed documents' file paths and the number
+    /// of chunks that went with them.
+    pub fn clear_knowledge_base(&self) -> Result<(Vec<String>, usize)> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let chunks: usize = tx.query_row("SELECT COUNT(*) FROM chunks", [], |row| row.get(0))?;
+        let paths = {
+            let mut stmt = tx.prepare("SELECT file_path FROM documents")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<Result<Vec<String>>>()?
+        };
+        tx.execute("DELETE FROM documents", [])?;
+        tx.commit()?;
+        Ok((paths, chunks))
+    }
+
+    pub fn list_documents(&self) -> Result<Vec<Document>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM documents ORDER BY created_at DESC",
+            DOCUMENT_COLUMNS
+        ))?;
+        let rows = stmt.query_map([], document_from_row)?;
+        rows.collect()
+    }
+
+    pub fn get_document(&self, id: &str) -> Result<Option<Document>> {
+        let conn = self.conn()?;
+        let result = conn.query_row(
+            &format!("SELECT {} FROM documents WHERE id = ?1", DOCUMENT_COLUMNS),
+            params![id],
+            document_from_row,
+        );
+        match result {
+            Ok(doc) => Ok(Some(doc)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The oldest document other than `except_id` whose parsed text hashes
+    /// to `hash`.
+    pub fn find_document_by_hash(
+        &self,
+        hash: &str,
+        except_id: Option<&str>,
+    ) -> Result<Option<Document>> {
+        let conn = self.conn()?;
+        let result = conn.query_row(
+            &format!(
+                "SELECT {} FROM documents WHERE content_hash = ?1 AND (?2 IS NULL OR id != ?2)
+                 ORDER BY created_at ASC LIMIT 1",
+                DOCUMENT_COLUMNS
+            ),
+            params![hash, except_id],
+            document_from_row,
+        );
+        match result {
+            Ok(doc) => Ok(Some(doc)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // ── Embedding jobs ──
+
+    /// Queue a document for the embedding worker. A finished, failed or
+    /// cancelled job for the same document is reset and queued again.
+    pub fn enqueue_job(&self, document_id: &str, total: usize) -> Result<()> {
+        let conn = self.conn()?;
+        enqueue_job_on(&conn, document_id, total)
+    }
+
+    /// Oldest queued job, counting it as a new attempt. Jobs interrupted by
+    /// a restart are still queued and get picked up again.
+    pub fn next_job(&self) -> Result<Option<Job>> {
+        let conn = self.conn()?;
+        let id: Option<String> = match conn.query_row(
+            "SELECT document_id FROM jobs WHERE status = ?1 ORDER BY updated_at, rowid LIMIT 1",
+            params![JOB_INDEXING],
+            |row| row.get(0),
+        ) {
+            Ok(id) => Some(id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e),
+        };
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        conn.execute(
+            "UPDATE jobs SET attempts = attempts + 1, updated_at = datetime('now') WHERE document_id = ?1",
+            params![id],
+        )?;
+        drop(conn);
+        self.get_job(&id)
+    }
+
+    pub fn set_job_progress(&self, document_id: &str, embedded: usize, total: usize) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE jobs SET embedded = ?2, total = ?3, updated_at = datetime('now') WHERE document_id = ?1",
+            params![document_id, embedded, total],
+        )?;
+        Ok(())
+    }
+
+    /// Record how a job ended. A no-op for documents without a job.
+    pub fn finish_job(&self, document_id: &str, status: &str, error: Option<&str>) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE jobs SET status = ?2, error = ?3, updated_at = datetime('now') WHERE document_id = ?1",
+            params![document_id, status, error],
+        )?;
+        Ok(())
+    }
+
+    /// Cancel a job that is queued but not yet running. Returns false if
+    /// there was no such job.
+    pub fn cancel_queued_job(&self, document_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE jobs SET status = ?2, updated_at = datetime('now') WHERE document_id = ?1 AND status = ?3",
+            params![document_id, JOB_CANCELLED, JOB_INDEXING],
+        )?;
+        Ok(updated > 0)
+    }
+
+    pub fn get_job(&self, document_id: &str) -> Result<Option<Job>> {
+        let conn = self.conn()?;
+        let result = conn.query_row(
+            &format!("SELECT {} FROM jobs WHERE document_id = ?1", JOB_COLUMNS),
+            params![document_id],
+            job_from_row,
+        );
+        match result {
+            Ok(job) => Ok(Some(job)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every job, most recently updated first.
+    pub fn list_jobs(&self) -> Result<Vec<Job>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM jobs ORDER BY updated_at DESC, rowid DESC",
+            JOB_COLUMNS
+        ))?;
+        let rows = stmt.query_map([], job_from_row)?;
+        rows.collect()
+    }
+
+    /// A page of a document's chunks in order, without their vectors.
+    pub fn list_chunks(&self, document_id: &str, offset: usize, limit: usize) -> Result<Vec<Chunk>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, document_id, content, chunk_index, page_start, page_end,
+                    embedding IS NOT NULL, created_at
+             FROM chunks WHERE document_id = ?1
+             ORDER BY chunk_index ASC LIMIT ?2 OFFSET ?3",
+        )?;
+        let rows = stmt.query_map(params![document_id, limit as i64, offset as i64], |row| {
+            Ok(Chunk {
                 id: row.get(0)?,
-                conversation_id: row.get(1)?,
-                role: row.get(2)?,
-                content: row.get(3)?,
-                created_at: row.get(4)?,
+                document_id: row.get(1)?,
+                content: row.get(2)?,
+                chunk_index: row.get(3)?,
+                page_start: row.get(4)?,
+                page_end: row.get(5)?,
+                has_embedding: row.get(6)?,
+                created_at: row.get(7)?,
             })
         })?;
         rows.collect()
     }
 
+    /// Returns false if no chunk has that id.
+    pub fn delete_chunk(&self, id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(conn.execute("DELETE FROM chunks WHERE id = ?1", params![id])? > 0)
+    }
+
+    /// Insert a new document and its unembedded chunks in a single
+    /// transaction, queueing it for the embedding worker with `queue_job`, so
+    /// a failure leaves nothing behind to block a retry.
+    pub fn add_document(&self, doc: &Document, chunks: &[TextChunk], queue_job: bool) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO documents (id, filename, file_type, file_path, file_size, chunk_size, chunk_overlap, content_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                doc.id,
+                doc.filename,
+                doc.file_type,
+                doc.file_path,
+                doc.file_size,
+                doc.chunk_size,
+                doc.chunk_overlap,
+                doc.content_hash
+            ],
+        )?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO chunks (id, document_id, content, chunk_index, page_start, page_end)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    uuid::Uuid::new_v4().to_string(),
+                    doc.id,
+                    chunk.content,
+                    i as i32,
+                    chunk.page_start,
+                    chunk.page_end
+                ],
+            )?;
+        }
+        if queue_job {
+            enqueue_job_on(&tx, &doc.id, chunks.len())?;
+        }
+        tx.commit()
+    }
+
+    /// Swap a document's file metadata and chunks for freshly parsed ones in
+    /// a single transaction, keeping its id and `created_at`. Chunks without
+    /// an embedding are stored unembedded. With an `embedding_model` the
+    /// document's job is marked ready; without one it is dropped, as for an
+    /// upload with no embedding endpoint. Returns false if the document
+    /// doesn't exist.
+    pub fn replace_document(
+        &self,
+        doc: &Document,
+        chunks: &[(TextChunk, Option<Vec<f32>>)],
+        embedding_model: Option<&str>,
+    ) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let updated = tx.execute(
+            "UPDATE documents SET filename = ?1, file_type = ?2, file_path = ?3, file_size = ?4, content_hash = ?5
+             WHERE id = ?6",
+            params![doc.filename, doc.file_type, doc.file_path, doc.file_size, doc.content_hash, doc.id],
+        )?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        tx.execute("DELETE FROM chunks WHERE document_id = ?1", params![doc.id])?;
+        for (i, (chunk, embedding)) in chunks.iter().enumerate() {
+            let bytes = embedding.as_deref().map(embedding_to_bytes);
+            let model = embedding.as_ref().and(embedding_model);
+            tx.execute(
+                "INSERT INTO chunks (id, document_id, content, chunk_index, embedding, embedding_model, embedding_dim, page_start, page_end)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    uuid::Uuid::new_v4().to_string(),
+                    doc.id,
+                    chunk.content,
+                    i as i32,
+                    bytes,
+                    model,
+                    embedding.as_ref().map(Vec::len),
+                    chunk.page_start,
+                    chunk.page_end
+                ],
+            )?;
+        }
+        if embedding_model.is_some() {
+            let embedded = chunks.iter().filter(|(_, emb)| emb.is_some()).count();
+            tx.execute(
+                "UPDATE jobs SET status = ?2, embedded = ?3, total = ?4, error = NULL,
+                     updated_at = datetime('now')
+                 WHERE document_id = ?1",
+                params![doc.id, JOB_READY, embedded, chunks.len()],
+            )?;
+        } else {
+            tx.execute("DELETE FROM jobs WHERE document_id = ?1", params![doc.id])?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    // ── Model cache ──
+
+    pub fn get_cached_models(&self) -> Result<Vec<ModelInfo>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, name, provider, supports_streaming, supports_vision, supports_tools, context_length
+             FROM model_cache ORDER BY id ASC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let id: String = row.get(0)?;
+            let mut model = ModelInfo::new(id, row.get::<_, String>(1)?, row.get::<_, String>(2)?);
+            // Rows cached before capabilities were stored keep the built-in guesses
+            if let (Some(streaming), Some(vision), Some(tools)) = (row.get(3)?, row.get(4)?, row.get(5)?) {
+                model.capabilities = ModelCapabilities {
+                    supports_streaming: streaming,
+                    supports_vision: vision,
+                    supports_tools: tools,
+                };
+            }
+            if let Some(context_length) = row.get(6)? {
+                model.context_length = context_length;
+            }
+            Ok(model)
+        })?;
+        rows.collect()
+    }
+
+    /// Replace every cached model whose id starts with `{prefix}/` with
+    /// `models`, leaving other providers' entries untouched.
+    pub fn replace_cached_models(&self, prefix: &str, models: &[ModelInfo]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM model_cache WHERE id LIKE ?1 || '/%'",
+            params![prefix],
+        )?;
+        for model in models {
+            tx.execute(
+                "INSERT OR REPLACE INTO model_cache
+                     (id, name, provider, supports_streaming, supports_vision, supports_tools, context_length, fetched_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, datetime('now'))",
+                params![
+                    model.id,
+                    model.name,
+                    model.provider,
+                    model.capabilities.supports_streaming,
+                    model.capabilities.supports_vision,
+                    model.capabilities.supports_tools,
+                    model.context_length
+                ],
+            )?;
+        }
+        tx.commit()
+    }
+
+    pub fn has_cached_models(&self, prefix: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM model_cache WHERE id LIKE ?1 || '/%')",
+            params![prefix],
+            |row| row.get(0),
+        )
+    }
+
+    /// Seconds since the oldest cache entry was fetched, or `None` when empty.
+    pub fn model_cache_age_secs(&self) -> Result<Option<i64>> {
+        let conn = self.conn()?;
+        conn.query_row(
+            "SELECT CAST(strftime('%s', 'now') - strftime('%s', MIN(fetched_at)) AS INTEGER) FROM model_cache",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    // ── Maintenance ──
+
+    /// Write a consistent copy of the database to `dest` with SQLite's
+    /// online backup API. The WAL is checkpointed first so the main file is
+    /// current as well.
+    pub fn backup_to(&self, dest: &std::path::Path) -> Result<()> {
+        let conn = self.conn()?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        conn.backup(rusqlite::DatabaseName::Main, dest, None)
+    }
+
+    /// Replace the live database with the backup at `src`. The backup is
+    /// checked for integrity and a known schema first, then copied page by
+    /// page over the open database (so pooled connections stay valid) and
+    /// migrated up to the current schema.
+    pub fn restore_from(&self, src: &std::path::Path) -> Result<()> {
+        let backup = Connection::open_with_flags(src, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let integrity: String = backup.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        let has_conversations: bool = backup.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'conversations')",
+            [],
+            |row| row.get(0),
+        )?;
+        let version = migrations::user_version(&backup)?;
+        if integrity != "ok" || !has_conversations || version > migrations::LATEST_VERSION {
+            return Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_NOTADB),
+                Some(format!(
+                    "Not a usable ai-box backup (integrity: {}, schema version {})",
+                    integrity, version
+                )),
+            ));
+        }
+
+        let mut conn = self.conn()?;
+        rusqlite::backup::Backup::new(&backup, &mut conn)?.run_to_completion(
+            256,
+            std::time::Duration::ZERO,
+            None,
+        )?;
+        migrations::run(&mut conn)
+    }
+
+    /// Size of the main database file in bytes, from its page count.
+    fn size_bytes(conn: &Connection) -> Result<i64> {
+        conn.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn stats(&self) -> Result<DatabaseStats> {
+        let conn = self.conn()?;
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_>>()?;
+        let mut table_rows = std::collections::BTreeMap::new();
+        for table in tables {
+            let count: i64 =
+                conn.query_row(&format!("SELECT COUNT(*) FROM \"{}\"", table), [], |row| row.get(0))?;
+            table_rows.insert(table, count);
+        }
+        Ok(DatabaseStats {
+            total_bytes: Self::size_bytes(&conn)?,
+            free_bytes: conn.query_row(
+                "SELECT freelist_count * page_size FROM pragma_freelist_count(), pragma_page_size()",
+                [],
+                |row| row.get(0),
+            )?,
+            table_rows,
+            embedding_bytes: conn.query_row(
+                "SELECT COALESCE(SUM(LENGTH(embedding)), 0) FROM chunks",
+                [],
+                |row| row.get(0),
+            )?,
+        })
+    }
+
+    /// Checkpoint the WAL and `VACUUM`, returning the file size before and
+    /// after.
+    pub fn compact(&self) -> Result<(i64, i64)> {
+        let conn = self.conn()?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        let before = Self::size_bytes(&conn)?;
+        conn.execute_batch("VACUUM;")?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok((before, Self::size_bytes(&conn)?))
+    }
+
     // ── Settings ──
 
+    /// Read a setting. Secret keys whose row holds the keychain reference are
+    /// resolved from the OS keychain; an unavailable keychain reads as unset.
     pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
-        let conn = self.conn.lock().unwrap();
+        let value = self.get_setting_row(key)?;
+        match value {
+            Some(v) if v == secrets::KEYCHAIN_REF => match secrets::load(key) {
+                Ok(secret) => Ok(secret),
+                Err(e) => {
+                    eprintln!("Keychain read for {} failed: {}", key, e);
+                    Ok(None)
+                }
+            },
+            other => Ok(other),
+        }
+    }
+
+    /// Write a setting. Secret keys go to the OS keychain when possible and
+    /// fall back to the plaintext row if no keychain is available.
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        if self.use_keychain && secrets::is_secret_key(key) {
+            match secrets::store(key, value) {
+                Ok(()) => return self.set_setting_row(key, secrets::KEYCHAIN_REF),
+                Err(e) => eprintln!("Keychain unavailable, storing {} in database: {}", key, e),
+            }
+        }
+        self.set_setting_row(key, value)
+    }
+
+    pub fn delete_setting(&self, key: &str) -> Result<()> {
+        if self.use_keychain && secrets::is_secret_key(key) {
+            if let Err(e) = secrets::delete(key) {
+                eprintln!("Keychain delete for {} failed: {}", key, e);
+            }
+        }
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM settings WHERE key = ?1", params![key])?;
+        Ok(())
+    }
+
+    fn get_setting_row(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.conn()?;
         let result = conn.query_row(
             "SELECT value FROM settings WHERE key = ?1",
             params![key],
@@ -192,12 +1445,602 @@ impl Database {
         }
     }
 
-    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+    fn set_setting_row(&self, key: &str, value: &str) -> Result<()> {
+        let conn = self.conn()?;
         conn.execute(
             "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
             params![key, value],
         )?;
         Ok(())
     }
+
+    /// Move plaintext secrets left by older versions into the keychain.
+    /// Rows stay plaintext if the keychain rejects them, so nothing is lost.
+    fn migrate_secrets_to_keychain(&self) -> Result<()> {
+        let plaintext: Vec<(String, String)> = {
+            let conn = self.conn()?;
+            let mut stmt = conn.prepare("SELECT key, value FROM settings WHERE value != ?1")?;
+            let rows = stmt.query_map(params![secrets::KEYCHAIN_REF], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+            rows.collect::<Result<Vec<(String, String)>>>()?
+                .into_iter()
+                .filter(|(key, _)| secrets::is_secret_key(key))
+                .collect()
+        };
+        for (key, value) in plaintext {
+            self.set_setting(&key, &value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_and_compact_reclaim_embedding_space() {
+        let db = Database::open_in_memory().unwrap();
+        {
+            let conn = db.conn().unwrap();
+            conn.execute(
+                "INSERT INTO documents (id, filename, file_type, file_path) VALUES ('d', 'a.txt', 'txt', '/a.txt')",
+                [],
+            )
+            .unwrap();
+            let embedding = embedding_to_bytes(&vec![0.5; 1536]);
+            for i in 0..50 {
+                conn.execute(
+                    "INSERT INTO chunks (id, document_id, content, chunk_index, embedding) VALUES (?1, 'd', 'x', ?2, ?3)",
+                    params![format!("c{}", i), i, embedding],
+                )
+                .unwrap();
+            }
+        }
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.table_rows["chunks"], 50);
+        assert_eq!(stats.embedding_bytes, 50 * 1536 * 4);
+
+        db.conn().unwrap().execute("DELETE FROM documents", []).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.table_rows["chunks"], 0);
+        assert!(stats.free_bytes > 0);
+
+        let (before, after) = db.compact().unwrap();
+        assert!(after < before);
+        assert_eq!(db.stats().unwrap().free_bytes, 0);
+    }
+
+    fn temp_db_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ai-box-test-{}.db", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_backup_and_restore_roundtrip() {
+        let db = Database::open_in_memory().unwrap();
+        let kept = db.create_conversation("Before backup", None).unwrap();
+        db.add_message(&kept.id, "user", "hello").unwrap();
+        let path = temp_db_path();
+        db.backup_to(&path).unwrap();
+
+        db.create_conversation("After backup", None).unwrap();
+        db.delete_conversation(&kept.id).unwrap();
+
+        db.restore_from(&path).unwrap();
+        let titles: Vec<String> = db
+            .list_conversations()
+            .unwrap()
+            .into_iter()
+            .map(|c| c.title)
+            .collect();
+        assert_eq!(titles, vec!["Before backup"]);
+        assert_eq!(db.get_messages(&kept.id).unwrap()[0].content, "hello");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_restore_rejects_foreign_database() {
+        let path = temp_db_path();
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE unrelated (x INTEGER);")
+            .unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        db.create_conversation("Still here", None).unwrap();
+        assert!(db.restore_from(&path).is_err());
+        assert_eq!(db.list_conversations().unwrap().len(), 1);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_trash_and_restore_conversation() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Oops", None).unwrap();
+        db.add_message(&conv.id, "user", "keep me").unwrap();
+
+        db.trash_conversation(&conv.id).unwrap();
+        assert!(db.list_conversations().unwrap().is_empty());
+        let trash = db.list_trash().unwrap();
+        assert_eq!(trash.len(), 1);
+        assert!(trash[0].deleted_at.is_some());
+
+        assert!(db.restore_conversation(&conv.id).unwrap());
+        assert!(!db.restore_conversation(&conv.id).unwrap());
+        let listed = db.list_conversations().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].deleted_at, None);
+        assert_eq!(db.get_messages(&conv.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_purge_trash_respects_age() {
+        let db = Database::open_in_memory().unwrap();
+        let old = db.create_conversation("Old", None).unwrap();
+        let recent = db.create_conversation("Recent", None).unwrap();
+        let active = db.create_conversation("Active", None).unwrap();
+        db.add_message(&old.id, "user", "bye").unwrap();
+        db.trash_conversation(&old.id).unwrap();
+        db.trash_conversation(&recent.id).unwrap();
+        db.conn()
+            .unwrap()
+            .execute(
+                "UPDATE conversations SET deleted_at = datetime('now', '-40 days') WHERE id = ?1",
+                params![old.id],
+            )
+            .unwrap();
+
+        assert_eq!(db.purge_trash(30).unwrap(), 1);
+        assert!(db.get_conversation(&old.id).unwrap().is_none());
+        assert!(db.get_messages(&old.id).unwrap().is_empty());
+        assert_eq!(db.list_trash().unwrap().len(), 1);
+
+        assert_eq!(db.purge_trash(0).unwrap(), 1);
+        assert!(db.list_trash().unwrap().is_empty());
+        assert!(db.get_conversation(&active.id).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_tag_assignment_and_filtered_listing() {
+        let db = Database::open_in_memory().unwrap();
+        let work = db.create_conversation("Work", None).unwrap();
+        let home = db.create_conversation("Home", None).unwrap();
+        db.add_tag(&work.id, "rust").unwrap();
+        db.add_tag(&work.id, "rust").unwrap();
+        db.add_tag(&work.id, "job").unwrap();
+        db.add_tag(&home.id, "rust").unwrap();
+
+        let rust: Vec<String> = db
+            .list_conversations_by_tag("rust")
+            .unwrap()
+            .into_iter()
+            .map(|c| c.title)
+            .collect();
+        assert_eq!(rust.len(), 2);
+        let job = db.list_conversations_by_tag("job").unwrap();
+        assert_eq!(job.len(), 1);
+        assert_eq!(job[0].id, work.id);
+
+        let mut all = db.list_conversations().unwrap();
+        db.attach_tags(&mut all).unwrap();
+        let work_tags = all.iter().find(|c| c.id == work.id).unwrap().tags.clone();
+        assert_eq!(work_tags, Some(vec!["job".to_string(), "rust".to_string()]));
+
+        db.remove_tag(&work.id, "job").unwrap();
+        assert!(db.list_conversations_by_tag("job").unwrap().is_empty());
+        assert_eq!(db.list_tags().unwrap(), vec!["rust"]);
+    }
+
+    #[test]
+    fn test_deleting_conversation_drops_tag_links() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Doomed", None).unwrap();
+        db.add_tag(&conv.id, "tmp").unwrap();
+        db.delete_conversation(&conv.id).unwrap();
+
+        assert!(db.list_conversations_by_tag("tmp").unwrap().is_empty());
+        let links: i64 = db
+            .conn()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM conversation_tags", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(links, 0);
+    }
+
+    #[test]
+    fn test_replace_document_keeps_id_and_created_at() {
+        let db = Database::open_in_memory().unwrap();
+        {
+            let conn = db.conn().unwrap();
+            conn.execute(
+                "INSERT INTO documents (id, filename, file_type, file_path, created_at)
+                 VALUES ('doc', 'old.md', 'md', '/old.md', '2024-01-01 00:00:00')",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO chunks (id, document_id, content, chunk_index) VALUES ('c1', 'doc', 'stale', 0)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let mut doc = db.get_document("doc").unwrap().unwrap();
+        doc.filename = "new.md".into();
+        let page = |content: &str, n| TextChunk {
+            content: content.into(),
+            page_start: Some(n),
+            page_end: Some(n),
+        };
+        let chunks = vec![
+            (page("fresh one", 1), Some(vec![1.0, 0.0])),
+            (page("fresh two", 2), None),
+        ];
+        assert!(db.replace_document(&doc, &chunks, Some("test-model")).unwrap());
+
+        let stored = db.get_document("doc").unwrap().unwrap();
+        assert_eq!(stored.filename, "new.md");
+        assert_eq!(stored.created_at, "2024-01-01 00:00:00");
+
+        let conn = db.conn().unwrap();
+        let rows: Vec<(String, Option<String>, Option<u32>)> = conn
+            .prepare("SELECT content, embedding_model, page_start FROM chunks WHERE document_id = 'doc' ORDER BY chunk_index")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("fresh one".to_string(), Some("test-model".to_string()), Some(1)),
+                ("fresh two".to_string(), None, Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_chunks_pages_and_delete() {
+        let db = Database::open_in_memory().unwrap();
+        {
+            let conn = db.conn().unwrap();
+            conn.execute(
+                "INSERT INTO documents (id, filename, file_type, file_path) VALUES ('doc', 'a.md', 'md', '/a.md')",
+                [],
+            )
+            .unwrap();
+            for i in 0..5 {
+                let embedding = (i % 2 == 0).then(|| embedding_to_bytes(&[1.0]));
+                conn.execute(
+                    "INSERT INTO chunks (id, document_id, content, chunk_index, embedding) VALUES (?1, 'doc', ?1, ?2, ?3)",
+                    params![format!("c{}", i), i, embedding],
+                )
+                .unwrap();
+            }
+        }
+
+        let page = db.list_chunks("doc", 1, 2).unwrap();
+        let ids: Vec<&str> = page.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["c1", "c2"]);
+        assert!(!page[0].has_embedding);
+        assert!(page[1].has_embedding);
+        assert_eq!(db.list_chunks("doc", 4, 10).unwrap().len(), 1);
+
+        assert!(db.delete_chunk("c1").unwrap());
+        assert!(!db.delete_chunk("c1").unwrap());
+        assert_eq!(db.list_chunks("doc", 0, 10).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_replace_missing_document() {
+        let db = Database::open_in_memory().unwrap();
+        let doc = Document {
+            id: "missing".into(),
+            filename: "a.md".into(),
+            file_type: "md".into(),
+            file_path: "/a.md".into(),
+            file_size: None,
+            created_at: String::new(),
+            chunk_size: None,
+            chunk_overlap: None,
+            index_status: None,
+            embedded_chunks: 0,
+            chunk_count: 0,
+            content_hash: None,
+        };
+        assert!(!db.replace_document(&doc, &[], None).unwrap());
+    }
+
+    #[test]
+    fn test_edit_middle_message_truncates_tail() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        let first = db.add_message(&conv.id, "user", "first").unwrap();
+        db.add_message(&conv.id, "assistant", "reply one").unwrap();
+        let middle = db.add_message(&conv.id, "user", "secnod").unwrap();
+        db.add_message(&conv.id, "assistant", "reply two").unwrap();
+        db.add_message(&conv.id, "user", "third").unwrap();
+
+        let deleted = db.edit_message_and_truncate(&middle.id, "second").unwrap();
+        assert_eq!(deleted, 2);
+
+        let messages = db.get_messages(&conv.id).unwrap();
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["first", "reply one", "second"]);
+        assert_eq!(messages[0].id, first.id);
+        assert_eq!(messages[2].id, middle.id);
+
+        assert_eq!(db.delete_messages_after(&first.id).unwrap(), Some(2));
+        assert_eq!(db.get_messages(&conv.id).unwrap().len(), 1);
+        assert_eq!(db.delete_messages_after("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_tool_message_round_trips() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        db.add_message(&conv.id, "user", "What's 6 x 7?").unwrap();
+        let call = db.add_message(&conv.id, "assistant", "Calling the calculator").unwrap();
+        let calls = vec![ToolCall {
+            id: "call_1".into(),
+            name: "multiply".into(),
+            arguments: r#"{"a":6,"b":7}"#.into(),
+        }];
+        db.set_tool_calls(&call.id, &calls).unwrap();
+        let result = db.add_tool_result(&conv.id, "call_1", "42").unwrap();
+        assert!(db.set_tool_calls(&result.id, &calls).is_err());
+        assert_eq!(result.role, "tool");
+
+        let messages = db.get_messages(&conv.id).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2].role, "tool");
+        assert_eq!(messages[2].content, "42");
+        assert_eq!(messages[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(messages[0].tool_call_id, None);
+        assert_eq!(messages[1].tool_calls, calls);
+        assert!(messages[0].tool_calls.is_empty());
+
+        let copy = db.import_conversation(&conv, &messages).unwrap();
+        let copied = db.get_messages(&copy.id).unwrap();
+        assert_eq!(copied[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(copied[1].tool_calls, calls);
+
+        assert!(db.add_message(&conv.id, "narrator", "hi").is_err());
+        assert_eq!(db.get_messages(&conv.id).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_fork_is_independent_of_original() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Trip", Some("openai/gpt-4o")).unwrap();
+        db.update_conversation_system_prompt(&conv.id, Some("Be brief")).unwrap();
+        let question = db.add_message(&conv.id, "user", "Where to?").unwrap();
+        let answer = db.add_message(&conv.id, "assistant", "Lisbon").unwrap();
+        db.add_message(&conv.id, "user", "And after?").unwrap();
+
+        let fork = db.fork_conversation(&conv.id, Some(&answer.id)).unwrap().unwrap();
+        assert_ne!(fork.id, conv.id);
+        assert_eq!(fork.title, "Copy of Trip");
+        assert_eq!(fork.model.as_deref(), Some("openai/gpt-4o"));
+        assert_eq!(fork.system_prompt.as_deref(), Some("Be brief"));
+        let forked = db.get_messages(&fork.id).unwrap();
+        let contents: Vec<&str> = forked.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Where to?", "Lisbon"]);
+        assert!(forked.iter().all(|m| m.id != question.id && m.id != answer.id));
+
+        // Edits on either side stay on that side
+        db.update_message_content(&forked[1].id, "Porto").unwrap();
+        db.add_message(&fork.id, "user", "Why Porto?").unwrap();
+        db.update_message_content(&question.id, "Where now?").unwrap();
+        let original: Vec<String> = db
+            .get_messages(&conv.id)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(original, ["Where now?", "Lisbon", "And after?"]);
+        let forked: Vec<String> = db
+            .get_messages(&fork.id)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(forked, ["Where to?", "Porto", "Why Porto?"]);
+
+        let whole = db.fork_conversation(&conv.id, None).unwrap().unwrap();
+        assert_eq!(db.get_messages(&whole.id).unwrap().len(), 3);
+        assert!(db.fork_conversation(&conv.id, Some("missing")).unwrap().is_none());
+        assert!(db.fork_conversation("missing", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reply_model_recorded_per_message() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        db.add_message(&conv.id, "user", "hi").unwrap();
+        db.add_message_with_id("r1", &conv.id, "assistant", "one", &[], Some("openai/gpt-4o"))
+            .unwrap();
+        db.add_message_with_id("r2", &conv.id, "assistant", "two", &[], Some("ollama/llama3"))
+            .unwrap();
+
+        let models: Vec<Option<String>> = db
+            .get_messages(&conv.id)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.model)
+            .collect();
+        assert_eq!(
+            models,
+            vec![None, Some("openai/gpt-4o".into()), Some("ollama/llama3".into())]
+        );
+
+        let messages = db.get_messages(&conv.id).unwrap();
+        let copy = db.import_conversation(&conv, &messages).unwrap();
+        let copied = db.get_messages(&copy.id).unwrap();
+        assert_eq!(copied[2].model.as_deref(), Some("ollama/llama3"));
+    }
+
+    #[test]
+    fn test_delete_message_without_cascade() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        let question = db.add_message(&conv.id, "user", "question").unwrap();
+        db.add_message(&conv.id, "assistant", "answer").unwrap();
+
+        let deleted = db.delete_message(&question.id, false).unwrap();
+        assert_eq!(deleted, Some(1));
+        assert_eq!(db.delete_message(&question.id, false).unwrap(), None);
+
+        let messages = db.get_messages(&conv.id).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "answer");
+    }
+
+    #[test]
+    fn test_delete_message_cascades_to_reply() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Test", None).unwrap();
+        db.add_message(&conv.id, "user", "keep me").unwrap();
+        db.add_message(&conv.id, "assistant", "kept reply").unwrap();
+        let bad = db.add_message(&conv.id, "user", "bad turn").unwrap();
+        db.add_message(&conv.id, "assistant", "bad reply").unwrap();
+        db.add_message(&conv.id, "user", "follow-up").unwrap();
+
+        let deleted = db.delete_message(&bad.id, true).unwrap();
+        assert_eq!(deleted, Some(2));
+
+        let messages = db.get_messages(&conv.id).unwrap();
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["keep me", "kept reply", "follow-up"]);
+    }
+
+    fn model(id: &str) -> ModelInfo {
+        ModelInfo::new(id, id, "Test")
+    }
+
+    #[test]
+    fn test_replace_cached_models_is_scoped_to_provider() {
+        let db = Database::open_in_memory().unwrap();
+        db.replace_cached_models("openai", &[model("openai/gpt-4o"), model("openai/old")])
+            .unwrap();
+        db.replace_cached_models("copilot", &[model("copilot/gpt-4o")])
+            .unwrap();
+
+        // A later refresh of one provider drops its stale models only
+        db.replace_cached_models("openai", &[model("openai/gpt-4o")])
+            .unwrap();
+
+        let ids: Vec<String> = db.get_cached_models().unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec!["copilot/gpt-4o", "openai/gpt-4o"]);
+        assert!(db.has_cached_models("copilot").unwrap());
+        assert!(!db.has_cached_models("claude").unwrap());
+        assert!(db.model_cache_age_secs().unwrap().is_some());
+
+        // Reported capabilities survive the cache
+        let mut custom = model("ollama/custom");
+        custom.capabilities.supports_tools = true;
+        custom.context_length = 4096;
+        db.replace_cached_models("ollama", &[custom]).unwrap();
+        let cached = db.get_cached_models().unwrap();
+        let custom = cached.iter().find(|m| m.id == "ollama/custom").unwrap();
+        assert!(custom.capabilities.supports_tools);
+        assert_eq!(custom.context_length, 4096);
+    }
+
+    #[test]
+    fn test_prompt_template_crud() {
+        let db = Database::open_in_memory().unwrap();
+        let reviewer = db.create_prompt_template("Reviewer", "Review {{language}} code.").unwrap();
+        db.create_prompt_template("astronomer", "You know the stars.").unwrap();
+
+        let names: Vec<String> = db
+            .list_prompt_templates()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(names, vec!["astronomer", "Reviewer"]);
+
+        assert!(db.update_prompt_template(&reviewer.id, "Reviewer", "Review {{language}}.").unwrap());
+        let updated = db.get_prompt_template(&reviewer.id).unwrap().unwrap();
+        assert_eq!(updated.content, "Review {{language}}.");
+
+        assert!(db.delete_prompt_template(&reviewer.id).unwrap());
+        assert!(!db.delete_prompt_template(&reviewer.id).unwrap());
+        assert!(!db.update_prompt_template(&reviewer.id, "x", "y").unwrap());
+        assert!(db.get_prompt_template(&reviewer.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_clear_knowledge_base_keeps_chats() {
+        let db = Database::open_in_memory().unwrap();
+        let conv = db.create_conversation("Chat", None).unwrap();
+        db.add_message(&conv.id, "user", "Hello").unwrap();
+        {
+            let conn = db.conn().unwrap();
+            conn.execute(
+                "INSERT INTO documents (id, filename, file_type, file_path) VALUES ('d1', 'a.txt', 'txt', '/tmp/a.txt')",
+                [],
+            )
+            .unwrap();
+            for i in 0..3 {
+                conn.execute(
+                    "INSERT INTO chunks (id, document_id, content, chunk_index) VALUES (?1, 'd1', 'text', ?2)",
+                    params![format!("c{}", i), i],
+                )
+                .unwrap();
+            }
+        }
+
+        let (paths, chunks) = db.clear_knowledge_base().unwrap();
+        assert_eq!(paths, vec!["/tmp/a.txt"]);
+        assert_eq!(chunks, 3);
+        assert!(db.list_documents().unwrap().is_empty());
+        assert_eq!(db.get_messages(&conv.id).unwrap().len(), 1);
+
+        db.add_tag(&conv.id, "work").unwrap();
+        assert_eq!(db.delete_all_conversations().unwrap(), (1, 1));
+        assert!(db.list_conversations().unwrap().is_empty());
+        assert!(db.list_tags().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failed_job_can_be_requeued() {
+        let db = Database::open_in_memory().unwrap();
+        for doc in ["d1", "d2"] {
+            db.conn()
+                .unwrap()
+                .execute(
+                    "INSERT INTO documents (id, filename, file_type, file_path) VALUES (?1, ?1, 'txt', ?1)",
+                    params![doc],
+                )
+                .unwrap();
+            db.enqueue_job(doc, 10).unwrap();
+        }
+        assert_eq!(
+            db.get_document("d1").unwrap().unwrap().index_status.as_deref(),
+            Some(JOB_INDEXING)
+        );
+
+        // Oldest first; each pick counts as an attempt
+        let job = db.next_job().unwrap().unwrap();
+        assert_eq!((job.document_id.as_str(), job.attempts), ("d1", 1));
+        db.set_job_progress("d1", 4, 10).unwrap();
+        db.finish_job("d1", models::JOB_FAILED, Some("API error: 500")).unwrap();
+        assert_eq!(db.next_job().unwrap().unwrap().document_id, "d2");
+        db.finish_job("d2", models::JOB_READY, None).unwrap();
+        assert!(db.next_job().unwrap().is_none());
+
+        db.enqueue_job("d1", 6).unwrap();
+        let job = db.next_job().unwrap().unwrap();
+        assert_eq!(job.document_id, "d1");
+        assert_eq!((job.embedded, job.total, job.attempts), (0, 6, 2));
+        assert_eq!(job.error, None);
+        assert_eq!(db.list_jobs().unwrap().len(), 2);
+
+        assert!(db.cancel_queued_job("d1").unwrap());
+        assert!(!db.cancel_queued_job("d2").unwrap());
+        assert_eq!(db.get_job("d1").unwrap().unwrap().status, JOB_CANCELLED);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::llm::ToolCall;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -7,8 +8,32 @@ pub struct Conversation {
     pub model: Option<String>,
     pub created_at: String,
     pub updated_at: String,
+    /// When the conversation was moved to the trash.
+    #[serde(default)]
+    pub deleted_at: Option<String>,
+    /// Sent ahead of the history with every request.
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Only filled in when the caller asked for tags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
 }
 
+/// A reusable system prompt. `content` may contain `{{variable}}`
+/// placeholders that are filled in when it is used.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PromptTemplate {
+    pub id: String,
+    pub name: String,
+    pub content: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// Roles a stored message may have. `tool` messages carry the result of a
+/// tool call and name the call in `tool_call_id`.
+pub const MESSAGE_ROLES: &[&str] = &["user", "assistant", "system", "tool"];
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message {
     pub id: String,
@@ -16,6 +41,22 @@ pub struct Message {
     pub role: String,
     pub content: String,
     pub created_at: String,
+    /// File paths of images attached to the message.
+    #[serde(default)]
+    pub attachments: Vec<String>,
+    /// Model that produced an assistant reply, e.g. "openai/gpt-4o". Unset
+    /// for user messages and replies saved before this was recorded.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// The reply stopped at the token limit and can be continued.
+    #[serde(default)]
+    pub truncated: bool,
+    /// For `tool` messages, the id of the tool call this is the result of.
+    #[serde(default)]
+    pub tool_call_id: Option<String>,
+    /// For assistant replies, the tool calls they made.
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -26,6 +67,52 @@ pub struct Document {
     pub file_path: String,
     pub file_size: Option<i64>,
     pub created_at: String,
+    /// Chunking the document was indexed with; unset for documents
+    /// uploaded before it was configurable (they used the defaults).
+    #[serde(default)]
+    pub chunk_size: Option<u32>,
+    #[serde(default)]
+    pub chunk_overlap: Option<u32>,
+    /// Status of the document's embedding job (see [`Job`]); unset for
+    /// documents indexed before jobs existed or without an embedding backend.
+    #[serde(default)]
+    pub index_status: Option<String>,
+    /// Chunks with an embedding, out of `chunk_count`. Fewer means the
+    /// document only partly shows up in search.
+    #[serde(default)]
+    pub embedded_chunks: usize,
+    #[serde(default)]
+    pub chunk_count: usize,
+    /// SHA-256 of the parsed text; unset for documents indexed before it
+    /// was recorded.
+    #[serde(default)]
+    pub content_hash: Option<String>,
+}
+
+/// The job is waiting for, or being processed by, the embedding worker.
+pub const JOB_INDEXING: &str = "indexing";
+/// Every chunk has been embedded.
+pub const JOB_READY: &str = "ready";
+/// Embedding stopped on an error; enqueueing the document again retries it.
+pub const JOB_FAILED: &str = "failed";
+/// Embedding stopped on an error after some chunks were embedded, so the
+/// document is searchable but under-indexed. Retried like a failed job.
+pub const JOB_PARTIAL: &str = "partial";
+pub const JOB_CANCELLED: &str = "cancelled";
+
+/// Background embedding of one document's pending chunks.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Job {
+    pub document_id: String,
+    pub status: String,
+    /// Chunks embedded so far out of `total` pending when the run started.
+    pub embedded: usize,
+    pub total: usize,
+    /// Runs started, including the current one.
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,5 +121,9 @@ pub struct Chunk {
     pub document_id: String,
     pub content: String,
     pub chunk_index: i32,
+    pub page_start: Option<u32>,
+    pub page_end: Option<u32>,
+    /// Whether the chunk has a vector and so can be found by search.
+    pub has_embedding: bool,
     pub created_at: String,
 }
@@ -0,0 +1,337 @@
+//! Opt-in trace of outbound provider traffic, for attaching to bug reports.
+//! When the `debug_logging` setting is on, every request sent through
+//! [`send`] is appended to `logs/debug.log` in the app data dir with its
+//! headers and body, followed by the response status. Credentials are
+//! redacted before anything reaches the file.
+
+use reqwest::header::HeaderMap;
+use reqwest::{Request, RequestBuilder, Response};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const LOG_FILE: &str = "debug.log";
+/// The previous log, kept when the current one is rotated.
+const ROTATED_LOG_FILE: &str = "debug.log.1";
+/// The log is rotated once it grows past this size.
+const MAX_LOG_BYTES: u64 = 5 * 1024 * 1024;
+/// Request bodies are cut to this many characters, so base64 images don't
+/// fill the log.
+const MAX_BODY_CHARS: usize = 8_000;
+/// Characters kept from an error response body.
+const MAX_SNIPPET_CHARS: usize = 2_000;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Prefixes of provider keys and tokens (OpenAI, Anthropic, OpenRouter,
+/// GitHub, Copilot), redacted wherever they appear in text.
+const SECRET_PREFIXES: &[&str] = &["sk-", "ghu_", "gho_", "ghp_", "github_pat_", "tid="];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOG_DIR: OnceLock<PathBuf> = OnceLock::new();
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Log into `<app_dir>/logs`. Called once at startup.
+pub fn init(app_dir: &Path) {
+    let _ = LOG_DIR.set(app_dir.join("logs"));
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Path of the current log file, whether or not it exists yet.
+pub fn log_path() -> Option<PathBuf> {
+    LOG_DIR.get().map(|dir| dir.join(LOG_FILE))
+}
+
+/// Delete the current and rotated logs.
+pub fn clear() -> std::io::Result<()> {
+    let Some(dir) = LOG_DIR.get() else {
+        return Ok(());
+    };
+    let _guard = WRITE_LOCK.lock().unwrap();
+    for name in [LOG_FILE, ROTATED_LOG_FILE] {
+        match fs::remove_file(dir.join(name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Send `req`, logging it and the response status when logging is on.
+/// Use in place of `RequestBuilder::send` for every provider request.
+pub async fn send(req: RequestBuilder) -> reqwest::Result<Response> {
+    if !enabled() {
+        return req.send().await;
+    }
+    let (client, request) = req.build_split();
+    let request = request?;
+    let body = request
+        .body()
+        .and_then(|b| b.as_bytes())
+        .map(|bytes| truncate(&redact_body(&String::from_utf8_lossy(bytes)), MAX_BODY_CHARS))
+        .unwrap_or_default();
+    write(&format!(
+        "--> {} {}\n{}{}",
+        request.method(),
+        redact_text(request.url().as_str()),
+        redact_headers(request.headers()),
+        body
+    ));
+
+    let started = Instant::now();
+    let result = client.execute(request).await;
+    let elapsed = started.elapsed().as_millis();
+    match &result {
+        Ok(resp) => write(&format!(
+            "<-- {} {} ({} ms)",
+            resp.status(),
+            redact_text(resp.url().as_str()),
+            elapsed
+        )),
+        Err(e) => write(&format!("<-- failed after {} ms: {}", elapsed, redact_text(&e.to_string()))),
+    }
+    result
+}
+
+/// Body of a non-success response, logged (redacted) when logging is on.
+pub async fn error_text(resp: Response) -> String {
+    let status = resp.status();
+    let text = resp.text().await.unwrap_or_default();
+    if enabled() {
+        write(&format!(
+            "<-- {} body: {}",
+            status,
+            truncate(&redact_body(&text), MAX_SNIPPET_CHARS)
+        ));
+    }
+    text
+}
+
+/// A request as it would be sent, credentials redacted.
+#[derive(Debug, Clone, Serialize)]
+pub struct InspectedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderEntry>,
+    /// The JSON body, or a string if the body isn't JSON.
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HeaderEntry {
+    pub name: String,
+    pub value: String,
+}
+
+/// Describe `request` without sending it.
+pub fn inspect(request: &Request) -> InspectedRequest {
+    let body = request
+        .body()
+        .and_then(|b| b.as_bytes())
+        .map(|bytes| {
+            let text = redact_body(&String::from_utf8_lossy(bytes));
+            serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text))
+        })
+        .unwrap_or(serde_json::Value::Null);
+    InspectedRequest {
+        method: request.method().to_string(),
+        url: redact_text(request.url().as_str()),
+        headers: redacted_header_pairs(request.headers())
+            .into_iter()
+            .map(|(name, value)| HeaderEntry { name, value })
+            .collect(),
+        body,
+    }
+}
+
+fn write(entry: &str) {
+    let Some(dir) = LOG_DIR.get() else {
+        return;
+    };
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let path = dir.join(LOG_FILE);
+    if fs::metadata(&path).is_ok_and(|m| m.len() > MAX_LOG_BYTES) {
+        let _ = fs::rename(&path, dir.join(ROTATED_LOG_FILE));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    // Logging must never break the request it describes
+    let _ = fs::create_dir_all(dir).and_then(|_| {
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "[{:.3}] {}", now, entry)
+    });
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}… ({} bytes total)", &text[..end], text.len()),
+        None => text.to_string(),
+    }
+}
+
+/// Header or field names whose values are credentials.
+fn is_sensitive_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    matches!(
+        name.as_str(),
+        "authorization" | "proxy-authorization" | "cookie" | "token" | "device_code"
+    ) || name.ends_with("_token")
+        || ["api_key", "api-key", "apikey", "secret", "password"]
+            .iter()
+            .any(|s| name.contains(s))
+}
+
+/// `(name, value)` for every header, credentials replaced.
+fn redacted_header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_sensitive_name(name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                redact_text(&String::from_utf8_lossy(value.as_bytes()))
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// One `name: value` line per header, credentials replaced.
+pub(crate) fn redact_headers(headers: &HeaderMap) -> String {
+    redacted_header_pairs(headers)
+        .into_iter()
+        .map(|(name, value)| format!("{}: {}\n", name, value))
+        .collect()
+}
+
+/// A JSON or form-encoded body with credential fields replaced and key-like
+/// tokens redacted everywhere else.
+pub(crate) fn redact_body(body: &str) -> String {
+    if let Ok(mut json) = serde_json::from_str::<serde_json::Value>(body) {
+        redact_json(&mut json);
+        return json.to_string();
+    }
+    let is_form = !body.is_empty()
+        && body.contains('=')
+        && !body.contains(char::is_whitespace);
+    if is_form {
+        let pairs: Vec<String> = body
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if is_sensitive_name(name) => format!("{}={}", name, REDACTED),
+                _ => pair.to_string(),
+            })
+            .collect();
+        return redact_text(&pairs.join("&"));
+    }
+    redact_text(body)
+}
+
+fn redact_json(value: &mut serde_json::Value) {
+    use serde_json::Value;
+    match value {
+        Value::Object(map) => {
+            for (name, field) in map.iter_mut() {
+                if is_sensitive_name(name) && field.is_string() {
+                    *field = Value::String(REDACTED.into());
+                } else {
+                    redact_json(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        Value::String(text) => *text = redact_text(text),
+        _ => {}
+    }
+}
+
+/// Replace anything that looks like a key or bearer token in free text,
+/// e.g. a key echoed back in an error message.
+fn redact_text(text: &str) -> String {
+    let is_delimiter = |c: char| c.is_whitespace() || "\"'`,<>()[]{}".contains(c);
+    let mut out = String::with_capacity(text.len());
+    let mut after_bearer = false;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest.find(is_delimiter).unwrap_or(rest.len());
+        let (word, tail) = rest.split_at(end);
+        let prefix = SECRET_PREFIXES
+            .iter()
+            .find(|p| word.starts_with(*p) && word.len() >= p.len() + 8);
+        if let Some(prefix) = prefix {
+            out.push_str(prefix);
+            out.push_str(REDACTED);
+        } else if after_bearer && !word.is_empty() {
+            out.push_str(REDACTED);
+        } else {
+            out.push_str(word);
+        }
+        if !word.is_empty() {
+            after_bearer = word.eq_ignore_ascii_case("bearer");
+        }
+
+        // Copy the delimiter run verbatim
+        let delimiters = tail.find(|c: char| !is_delimiter(c)).unwrap_or(tail.len());
+        out.push_str(&tail[..delimiters]);
+        rest = &tail[delimiters..];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_redacts_keys_in_bodies() {
+        let body = r#"{
+            "model": "gpt-4o",
+            "max_tokens": 512,
+            "api_key": "plain-secret-value",
+            "messages": [{"role": "user", "content": "my key is sk-proj-abcdef1234567890, keep it safe"}]
+        }"#;
+        let redacted = redact_body(body);
+        assert!(!redacted.contains("plain-secret-value"), "{}", redacted);
+        assert!(!redacted.contains("abcdef1234567890"), "{}", redacted);
+        assert!(redacted.contains("my key is sk-[REDACTED], keep it safe"), "{}", redacted);
+        assert!(redacted.contains(r#""max_tokens":512"#));
+        assert!(redacted.contains("gpt-4o"));
+
+        let form = redact_body("client_id=Iv1.b507a08c87ecfe98&device_code=3584d83530557fdd1f46af8289938c8ef79f9dc5");
+        assert_eq!(form, "client_id=Iv1.b507a08c87ecfe98&device_code=[REDACTED]");
+
+        let error = redact_body("Incorrect API key provided: sk-ant-api03-XXXXXXXXXXXX.");
+        assert_eq!(error, "Incorrect API key provided: sk-[REDACTED]");
+    }
+
+    #[test]
+    fn test_redacts_auth_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer abc.def.ghi"));
+        headers.insert("x-api-key", HeaderValue::from_static("sk-ant-0123456789"));
+        headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
+        let redacted = redact_headers(&headers);
+        assert!(redacted.contains("authorization: [REDACTED]\n"));
+        assert!(redacted.contains("x-api-key: [REDACTED]\n"));
+        assert!(redacted.contains("anthropic-version: 2023-06-01\n"));
+
+        assert_eq!(
+            redact_text("got Bearer xyz, gho_short and ghu_0123456789abcdef"),
+            "got Bearer [REDACTED], gho_short and ghu_[REDACTED]"
+        );
+    }
+}
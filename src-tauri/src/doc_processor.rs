@@ -5,6 +5,19 @@ use std::path::Path;
 pub struct ParsedDocument {
     pub content: String,
     pub file_type: String,
+    /// Text of each page, for formats that have pages (PDF).
+    pub pages: Option<Vec<String>>,
+    /// Text of each chapter in reading order (EPUB). Each chapter is chunked
+    /// on its own, so no chunk straddles a chapter break.
+    pub chapters: Option<Vec<String>>,
+}
+
+/// A chunk of document text and the 1-based pages it came from, when known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextChunk {
+    pub content: String,
+    pub page_start: Option<u32>,
+    pub page_end: Option<u32>,
 }
 
 /// Parse a document file into plain text
@@ -17,32 +30,188 @@ pub fn parse_file(path: &Path) -> Result<ParsedDocument, String> {
 
     match ext.as_str() {
         "txt" => {
-            let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+            let content = read_text(path)?;
             Ok(ParsedDocument {
                 content,
                 file_type: "txt".into(),
+                pages: None,
+                chapters: None,
             })
         }
         "md" | "markdown" => {
-            let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+            let content = read_text(path)?;
             Ok(ParsedDocument {
                 content,
                 file_type: "md".into(),
+                pages: None,
+                chapters: None,
             })
         }
         "pdf" => {
             let bytes = fs::read(path).map_err(|e| e.to_string())?;
-            let content = pdf_extract::extract_text_from_mem(&bytes)
+            let pages = pdf_extract::extract_text_from_mem_by_pages(&bytes)
                 .map_err(|e| format!("PDF parse error: {}", e))?;
             Ok(ParsedDocument {
-                content,
+                content: pages.join("\n"),
                 file_type: "pdf".into(),
+                pages: Some(pages),
+                chapters: None,
+            })
+        }
+        "epub" => {
+            let chapters = read_epub_chapters(path)?;
+            Ok(ParsedDocument {
+                content: chapters.join("\n\n"),
+                file_type: "epub".into(),
+                pages: None,
+                chapters: Some(chapters),
             })
         }
         _ => Err(format!("Unsupported file type: .{}", ext)),
     }
 }
 
+/// Readable text of every chapter in spine order. Chapters are decoded and
+/// stripped one at a time, so only one chapter's markup is held in memory.
+fn read_epub_chapters(path: &Path) -> Result<Vec<String>, String> {
+    let mut book = epub::doc::EpubDoc::new(path).map_err(|e| format!("EPUB parse error: {}", e))?;
+    let mut chapters = Vec::new();
+    loop {
+        if let Some((html, _mime)) = book.get_current_str() {
+            let text = html_to_text(&html);
+            if !text.is_empty() {
+                chapters.push(text);
+            }
+        }
+        if !book.go_next() {
+            break;
+        }
+    }
+    Ok(chapters)
+}
+
+/// Plain text of an (X)HTML document: tags dropped, block elements on their
+/// own lines, `<head>`/`<script>`/`<style>` skipped, common entities decoded.
+fn html_to_text(html: &str) -> String {
+    const BLOCKS: &[&str] = &[
+        "p", "div", "br", "li", "tr", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "pre",
+        "section", "article", "hr", "dt", "dd",
+    ];
+    let mut text = String::new();
+    let mut rest = html;
+    let mut skip_until: Option<String> = None;
+    while let Some(open) = rest.find('<') {
+        if skip_until.is_none() {
+            text.push_str(&decode_entities(&rest[..open]));
+        }
+        let Some(close) = rest[open..].find('>') else {
+            break;
+        };
+        let tag = &rest[open + 1..open + close];
+        rest = &rest[open + close + 1..];
+
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        if let Some(end) = &skip_until {
+            if tag.starts_with('/') && name == *end {
+                skip_until = None;
+            }
+            continue;
+        }
+        match name.as_str() {
+            "head" | "script" | "style" if !tag.starts_with('/') && !tag.ends_with('/') => {
+                skip_until = Some(name);
+            }
+            name if BLOCKS.contains(&name) => text.push('\n'),
+            _ => {}
+        }
+    }
+    if skip_until.is_none() {
+        text.push_str(&decode_entities(rest));
+    }
+
+    // Collapse runs of whitespace within lines and drop blank lines
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .map(|hex| u32::from_str_radix(hex, 16).ok())
+                    .unwrap_or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Read a text file in whatever encoding it was saved in. A BOM wins, then
+/// valid UTF-8; anything else (Windows-1252, GBK, Shift_JIS exports...) is
+/// guessed from the bytes.
+fn read_text(path: &Path) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    decode_text(&bytes).ok_or_else(|| {
+        format!(
+            "Could not detect the text encoding of {}; save it as UTF-8 and try again",
+            path.display()
+        )
+    })
+}
+
+fn decode_text(bytes: &[u8]) -> Option<String> {
+    if let Some((encoding, bom_len)) = encoding_rs::Encoding::for_bom(bytes) {
+        let (text, had_errors) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+        return (!had_errors).then(|| text.into_owned());
+    }
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return Some(text.to_string());
+    }
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, true);
+    let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+    (!had_errors).then(|| text.into_owned())
+}
+
 /// Split text into overlapping chunks for embedding
 pub fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
     let text = text.trim();
@@ -73,6 +242,74 @@ pub fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String>
     chunks
 }
 
+/// Chunk a parsed document, attributing chunks to pages when it has them.
+pub fn chunk_document(doc: &ParsedDocument, chunk_size: usize, overlap: usize) -> Vec<TextChunk> {
+    match (&doc.pages, &doc.chapters) {
+        (Some(pages), _) => chunk_pages(pages, chunk_size, overlap),
+        (None, Some(chapters)) => chapters
+            .iter()
+            .flat_map(|chapter| chunk_text(chapter, chunk_size, overlap))
+            .map(|content| TextChunk {
+                content,
+                page_start: None,
+                page_end: None,
+            })
+            .collect(),
+        (None, None) => chunk_text(&doc.content, chunk_size, overlap)
+            .into_iter()
+            .map(|content| TextChunk {
+                content,
+                page_start: None,
+                page_end: None,
+            })
+            .collect(),
+    }
+}
+
+/// Chunk page by page so no chunk straddles a page break, except that runs
+/// of short pages are packed into one chunk covering their range.
+fn chunk_pages(pages: &[String], chunk_size: usize, overlap: usize) -> Vec<TextChunk> {
+    let mut chunks = Vec::new();
+    let mut pending: Option<TextChunk> = None;
+
+    for (i, page) in pages.iter().enumerate() {
+        let number = i as u32 + 1;
+        let text = page.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let len = text.chars().count();
+
+        if let Some(run) = pending.as_mut() {
+            if run.content.chars().count() + 1 + len <= chunk_size {
+                run.content.push('\n');
+                run.content.push_str(text);
+                run.page_end = Some(number);
+                continue;
+            }
+        }
+        chunks.extend(pending.take());
+
+        if len <= chunk_size {
+            pending = Some(TextChunk {
+                content: text.to_string(),
+                page_start: Some(number),
+                page_end: Some(number),
+            });
+        } else {
+            chunks.extend(chunk_text(text, chunk_size, overlap).into_iter().map(|content| {
+                TextChunk {
+                    content,
+                    page_start: Some(number),
+                    page_end: Some(number),
+                }
+            }));
+        }
+    }
+    chunks.extend(pending);
+    chunks
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -91,4 +328,200 @@ mod tests {
         // Check overlap exists
         assert_eq!(chunks[0].len(), 40);
     }
+
+    #[test]
+    fn test_latin1_text_survives_to_chunks() {
+        let text = "Le café de la gare sert une crème brûlée à déguster près de la fenêtre.";
+        let latin1: Vec<u8> = text.chars().map(|c| c as u8).collect();
+        assert!(std::str::from_utf8(&latin1).is_err());
+
+        let path = std::env::temp_dir().join(format!("ai-box-latin1-{}.txt", std::process::id()));
+        fs::write(&path, &latin1).unwrap();
+        let parsed = parse_file(&path);
+        fs::remove_file(&path).ok();
+
+        let parsed = parsed.unwrap();
+        assert_eq!(parsed.file_type, "txt");
+        assert_eq!(chunk_text(&parsed.content, 512, 64), vec![text]);
+    }
+
+    #[test]
+    fn test_decode_honours_bom() {
+        let mut utf16 = vec![0xFF, 0xFE];
+        utf16.extend("héllo".encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(decode_text(&utf16).unwrap(), "héllo");
+    }
+
+    /// A minimal PDF with one line of Courier text per page.
+    fn pdf_with_pages(pages: &[&str]) -> Vec<u8> {
+        let n = pages.len();
+        let kids: Vec<String> = (0..n).map(|i| format!("{} 0 R", 4 + 2 * i)).collect();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), n),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>".to_string(),
+        ];
+        for (i, text) in pages.iter().enumerate() {
+            let stream = format!("BT /F1 12 Tf 72 720 Td ({}) Tj ET", text);
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] \
+                 /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+                5 + 2 * i
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}\nendstream",
+                stream.len(),
+                stream
+            ));
+        }
+
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).into_bytes());
+        }
+        let xref = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).into_bytes());
+        for offset in offsets {
+            pdf.extend(format!("{:010} 00000 n \n", offset).into_bytes());
+        }
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref
+            )
+            .into_bytes(),
+        );
+        pdf
+    }
+
+    #[test]
+    fn test_pdf_chunks_carry_page_range() {
+        let long = "Page three is long enough that it has to be split";
+        let path = std::env::temp_dir().join(format!("ai-box-pages-{}.pdf", std::process::id()));
+        fs::write(&path, pdf_with_pages(&["Intro", "Contents", long])).unwrap();
+        let parsed = parse_file(&path);
+        fs::remove_file(&path).ok();
+
+        let parsed = parsed.unwrap();
+        assert_eq!(parsed.pages.as_ref().map(Vec::len), Some(3));
+        assert!(!parsed.content.contains("IntroContents"));
+
+        let chunks = chunk_document(&parsed, 30, 5);
+        // The two short pages share a chunk; the long one is split on its own
+        assert_eq!(chunks[0].content, "Intro\nContents");
+        assert_eq!((chunks[0].page_start, chunks[0].page_end), (Some(1), Some(2)));
+        assert!(chunks.len() > 2);
+        for chunk in &chunks[1..] {
+            assert_eq!((chunk.page_start, chunk.page_end), (Some(3), Some(3)));
+            assert!(long.contains(&chunk.content));
+        }
+    }
+
+    /// A minimal EPUB 2 book with one XHTML file per chapter body.
+    fn epub_with_chapters(chapters: &[&str]) -> Vec<u8> {
+        use std::io::Write;
+        use zip::write::SimpleFileOptions;
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let stored = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("mimetype", stored).unwrap();
+        zip.write_all(b"application/epub+zip").unwrap();
+        zip.start_file("META-INF/container.xml", stored).unwrap();
+        zip.write_all(
+            br#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#,
+        )
+        .unwrap();
+
+        let manifest: String = (0..chapters.len())
+            .map(|i| {
+                format!(
+                    r#"<item id="ch{i}" href="ch{i}.xhtml" media-type="application/xhtml+xml"/>"#
+                )
+            })
+            .collect();
+        let spine: String = (0..chapters.len())
+            .map(|i| format!(r#"<itemref idref="ch{i}"/>"#))
+            .collect();
+        zip.start_file("OEBPS/content.opf", stored).unwrap();
+        zip.write_all(
+            format!(
+                r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>Fixture</dc:title><dc:identifier id="id">fixture</dc:identifier>
+  </metadata>
+  <manifest>{manifest}</manifest>
+  <spine>{spine}</spine>
+</package>"#
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        for (i, body) in chapters.iter().enumerate() {
+            zip.start_file(format!("OEBPS/ch{i}.xhtml"), stored).unwrap();
+            zip.write_all(
+                format!(
+                    r#"<?xml version="1.0"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Chapter {i}</title>
+<style>p {{ margin: 0 }}</style></head><body>{body}</body></html>"#
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_epub_chunks_split_on_chapters() {
+        let path = std::env::temp_dir().join(format!("ai-box-book-{}.epub", std::process::id()));
+        fs::write(
+            &path,
+            epub_with_chapters(&[
+                "<h1>One</h1><p>Tom &amp; Jerry <em>ran</em>.</p>",
+                "<script>ignored()</script>",
+                "<h1>Two</h1><p>The end&#33;</p>",
+            ]),
+        )
+        .unwrap();
+        let parsed = parse_file(&path);
+        fs::remove_file(&path).ok();
+
+        let parsed = parsed.unwrap();
+        assert_eq!(parsed.file_type, "epub");
+        // The chapter with nothing readable is dropped
+        assert_eq!(
+            parsed.chapters,
+            Some(vec!["One\nTom & Jerry ran.".to_string(), "Two\nThe end!".to_string()])
+        );
+        assert_eq!(parsed.content, "One\nTom & Jerry ran.\n\nTwo\nThe end!");
+
+        // Both chapters would fit one chunk, but stay apart
+        let chunks = chunk_document(&parsed, 512, 64);
+        let contents: Vec<_> = chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(contents, vec!["One\nTom & Jerry ran.", "Two\nThe end!"]);
+        assert_eq!(chunks[0].page_start, None);
+    }
+
+    #[test]
+    fn test_text_chunks_have_no_pages() {
+        let doc = ParsedDocument {
+            content: "Plain notes".into(),
+            file_type: "txt".into(),
+            pages: None,
+            chapters: None,
+        };
+        let chunks = chunk_document(&doc, 512, 64);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].page_start, None);
+    }
 }
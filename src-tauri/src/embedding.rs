@@ -1,11 +1,48 @@
+use crate::debug_log;
 use crate::llm::openai::OpenAiConfig;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/// Identifier recorded on chunks embedded by the local backend.
+pub const LOCAL_EMBEDDING_MODEL: &str = "local/all-MiniLM-L6-v2";
+
+/// all-MiniLM-L6-v2 produces 384-dim vectors, unlike OpenAI's 1536/3072-dim
+/// ones. Chunks record which model embedded them so the two never get mixed.
+pub const LOCAL_EMBEDDING_DIM: usize = 384;
+
+/// Where embeddings come from, chosen by the `embedding_provider` setting.
+#[derive(Debug, Clone)]
+pub enum EmbeddingBackend {
+    /// OpenAI-compatible `/embeddings` endpoint. `dimensions` shortens the
+    /// vectors of models that support it (text-embedding-3-*).
+    OpenAi {
+        config: OpenAiConfig,
+        model: String,
+        dimensions: Option<u32>,
+    },
+    /// In-process ONNX model; weights are downloaded to `cache_dir` once and
+    /// everything runs offline afterwards.
+    Local { cache_dir: PathBuf },
+}
+
+impl EmbeddingBackend {
+    /// Identifier stored in `chunks.embedding_model`.
+    pub fn model_id(&self) -> &str {
+        match self {
+            EmbeddingBackend::OpenAi { model, .. } => model,
+            EmbeddingBackend::Local { .. } => LOCAL_EMBEDDING_MODEL,
+        }
+    }
+}
 
 #[derive(Serialize)]
 struct EmbeddingRequest {
     model: String,
     input: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<u32>,
 }
 
 #[derive(Deserialize)]
@@ -18,17 +55,109 @@ struct EmbeddingData {
     embedding: Vec<f32>,
 }
 
-/// Generate embeddings for a list of texts using the OpenAI-compatible API
+/// Generate embeddings with whichever backend is configured. Vectors come
+/// back at unit length, so they can be compared with [`dot_similarity`].
 pub async fn generate_embeddings(
+    backend: &EmbeddingBackend,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>, String> {
+    let mut embeddings = match backend {
+        EmbeddingBackend::OpenAi {
+            config,
+            model,
+            dimensions,
+        } => {
+            // Keyed by endpoint rather than provider: the embedding server
+            // is often a local one with nothing to do with OpenAI chat
+            let _permit = crate::llm::REQUEST_LIMITER.acquire(&config.base_url).await;
+            generate_openai_embeddings(config, texts, model, *dimensions).await
+        }
+        EmbeddingBackend::Local { cache_dir } => {
+            let cache_dir = cache_dir.clone();
+            let texts = texts.to_vec();
+            tokio::task::spawn_blocking(move || generate_local_embeddings(cache_dir, texts))
+                .await
+                .map_err(|e| e.to_string())?
+        }
+    }?;
+    embeddings.iter_mut().for_each(|e| normalize(e));
+    Ok(embeddings)
+}
+
+static LOCAL_MODEL: OnceLock<Mutex<fastembed::TextEmbedding>> = OnceLock::new();
+
+fn generate_local_embeddings(cache_dir: PathBuf, texts: Vec<String>) -> Result<Vec<Vec<f32>>, String> {
+    let model = match LOCAL_MODEL.get() {
+        Some(model) => model,
+        None => {
+            let options = fastembed::InitOptions::new(fastembed::EmbeddingModel::AllMiniLML6V2)
+                .with_cache_dir(cache_dir)
+                .with_show_download_progress(false);
+            let loaded = fastembed::TextEmbedding::try_new(options)
+                .map_err(|e| format!("Failed to load local embedding model: {}", e))?;
+            LOCAL_MODEL.get_or_init(|| Mutex::new(loaded))
+        }
+    };
+    model
+        .lock()
+        .unwrap()
+        .embed(texts, None)
+        .map_err(|e| format!("Local embedding failed: {}", e))
+}
+
+static RERANK_MODEL: OnceLock<Mutex<fastembed::TextRerank>> = OnceLock::new();
+
+/// Score each document's relevance to `query` with a local cross-encoder
+/// (BGE reranker). Scores are returned in the same order as `documents`;
+/// higher is more relevant.
+pub async fn rerank(
+    cache_dir: PathBuf,
+    query: String,
+    documents: Vec<String>,
+) -> Result<Vec<f32>, String> {
+    tokio::task::spawn_blocking(move || {
+        let model = match RERANK_MODEL.get() {
+            Some(model) => model,
+            None => {
+                let options =
+                    fastembed::RerankInitOptions::new(fastembed::RerankerModel::BGERerankerBase)
+                        .with_cache_dir(cache_dir)
+                        .with_show_download_progress(false);
+                let loaded = fastembed::TextRerank::try_new(options)
+                    .map_err(|e| format!("Failed to load reranker model: {}", e))?;
+                RERANK_MODEL.get_or_init(|| Mutex::new(loaded))
+            }
+        };
+        let docs: Vec<&str> = documents.iter().map(String::as_str).collect();
+        let results = model
+            .lock()
+            .unwrap()
+            .rerank(query.as_str(), docs, false, None)
+            .map_err(|e| format!("Rerank failed: {}", e))?;
+
+        let mut scores = vec![f32::MIN; documents.len()];
+        for r in results {
+            scores[r.index] = r.score;
+        }
+        Ok(scores)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Generate embeddings for a list of texts using the OpenAI-compatible API
+pub async fn generate_openai_embeddings(
     config: &OpenAiConfig,
     texts: &[String],
     model: &str,
+    dimensions: Option<u32>,
 ) -> Result<Vec<Vec<f32>>, String> {
     let client = Client::new();
 
     let body = EmbeddingRequest {
         model: model.to_string(),
         input: texts.to_vec(),
+        dimensions,
     };
 
     let mut req = client
@@ -36,14 +165,12 @@ pub async fn generate_embeddings(
         .header("Content-Type", "application/json")
         .json(&body);
 
-    if !config.api_key.is_empty() {
-        req = req.header("Authorization", format!("Bearer {}", config.api_key));
-    }
+    req = config.apply_headers(req);
 
-    let resp = req.send().await.map_err(|e| e.to_string())?;
+    let resp = debug_log::send(req).await.map_err(|e| e.to_string())?;
 
     if !resp.status().is_success() {
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(format!("Embedding API error: {}", text));
     }
 
@@ -65,6 +192,30 @@ pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     dot / (norm_a * norm_b)
 }
 
+/// Similarity of two unit-length vectors: their dot product, which equals
+/// the cosine without recomputing norms. Use [`cosine_similarity`] for
+/// vectors that may not be normalized.
+pub fn dot_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// Scale `v` to unit length in place. The zero vector is left as is.
+pub fn normalize(v: &mut [f32]) {
+    let norm: f32 = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Whether `v` has unit length, within float error.
+pub fn is_normalized(v: &[f32]) -> bool {
+    let norm: f32 = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    (norm - 1.0).abs() < 1e-4
+}
+
 /// Search for the most relevant chunks given a query embedding
 pub fn search_similar(
     query_embedding: &[f32],
@@ -81,6 +232,73 @@ pub fn search_similar(
     scored
 }
 
+/// Whether `candidate` is a near-duplicate of any vector in `kept`, i.e. its
+/// cosine similarity to one of them exceeds `threshold`. A threshold of 1 or
+/// more disables the check.
+pub fn is_near_duplicate(candidate: &[f32], kept: &[&[f32]], threshold: f32) -> bool {
+    threshold < 1.0
+        && kept
+            .iter()
+            .any(|k| cosine_similarity(candidate, k) > threshold)
+}
+
+/// Like `search_similar`, but walks the ranking greedily and skips any
+/// chunk that is a near-duplicate of a higher-ranked result (MMR-style), so
+/// repeated boilerplate doesn't crowd out other matches.
+pub fn search_similar_diverse(
+    query_embedding: &[f32],
+    chunk_embeddings: &[(String, Vec<f32>)],
+    top_k: usize,
+    threshold: f32,
+) -> Vec<(String, f32)> {
+    rank_diverse(query_embedding, chunk_embeddings, top_k, threshold, cosine_similarity)
+}
+
+/// `search_similar_diverse` for a query and chunks that are all unit length,
+/// as stored chunks and [`generate_embeddings`] output are. Ranks by dot
+/// product, skipping the per-comparison norms.
+pub fn search_normalized_diverse(
+    query_embedding: &[f32],
+    chunk_embeddings: &[(String, Vec<f32>)],
+    top_k: usize,
+    threshold: f32,
+) -> Vec<(String, f32)> {
+    rank_diverse(query_embedding, chunk_embeddings, top_k, threshold, dot_similarity)
+}
+
+fn rank_diverse(
+    query_embedding: &[f32],
+    chunk_embeddings: &[(String, Vec<f32>)],
+    top_k: usize,
+    threshold: f32,
+    score: fn(&[f32], &[f32]) -> f32,
+) -> Vec<(String, f32)> {
+    let mut scored: Vec<(usize, f32)> = chunk_embeddings
+        .iter()
+        .enumerate()
+        .map(|(i, (_, emb))| (i, score(query_embedding, emb)))
+        .collect();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut selected: Vec<(usize, f32)> = Vec::new();
+    for (i, score) in scored {
+        if selected.len() >= top_k {
+            break;
+        }
+        let kept: Vec<&[f32]> = selected
+            .iter()
+            .map(|(j, _)| chunk_embeddings[*j].1.as_slice())
+            .collect();
+        if !is_near_duplicate(&chunk_embeddings[i].1, &kept, threshold) {
+            selected.push((i, score));
+        }
+    }
+    selected
+        .into_iter()
+        .map(|(i, score)| (chunk_embeddings[i].0.clone(), score))
+        .collect()
+}
+
 /// Serialize embedding to bytes for SQLite BLOB storage
 pub fn embedding_to_bytes(embedding: &[f32]) -> Vec<u8> {
     embedding
@@ -101,6 +319,98 @@ pub fn bytes_to_embedding(bytes: &[u8]) -> Vec<f32> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_is_near_duplicate() {
+        let a = [1.0, 0.0, 0.0];
+        let nearly_a = [0.999, 0.01, 0.0];
+        let b = [0.0, 1.0, 0.0];
+        assert!(is_near_duplicate(&nearly_a, &[&a], 0.98));
+        assert!(!is_near_duplicate(&b, &[&a], 0.98));
+        // A threshold of 1 turns dedup off, even for identical vectors
+        assert!(!is_near_duplicate(&a, &[&a], 1.0));
+    }
+
+    #[test]
+    fn test_search_similar_diverse_suppresses_duplicates() {
+        let chunks = vec![
+            ("a".to_string(), vec![1.0, 0.0]),
+            ("a_copy".to_string(), vec![0.999, 0.001]),
+            ("b".to_string(), vec![0.7, 0.7]),
+        ];
+        let query = [1.0, 0.0];
+        let plain: Vec<String> = search_similar(&query, &chunks, 2)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(plain, vec!["a", "a_copy"]);
+
+        let diverse: Vec<String> = search_similar_diverse(&query, &chunks, 2, 0.95)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(diverse, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_dot_similarity_matches_cosine_for_normalized_vectors() {
+        let vectors = [
+            vec![0.3, -1.2, 4.0, 0.05],
+            vec![2.0, 2.0, -0.5, 1.0],
+            vec![-0.01, 0.7, 0.0, 9.5],
+        ];
+        for a in &vectors {
+            for b in &vectors {
+                let (mut na, mut nb) = (a.clone(), b.clone());
+                normalize(&mut na);
+                normalize(&mut nb);
+                assert!(is_normalized(&na));
+                assert!((dot_similarity(&na, &nb) - cosine_similarity(a, b)).abs() < 1e-6);
+            }
+        }
+        let mut zero = vec![0.0; 3];
+        normalize(&mut zero);
+        assert_eq!(zero, vec![0.0; 3]);
+    }
+
+    /// Timing comparison behind ranking stored chunks by dot product. Run with
+    /// `cargo test --release bench_dot_vs_cosine_search -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_dot_vs_cosine_search() {
+        const CHUNKS: usize = 10_000;
+        const DIMS: usize = 1536;
+        const RUNS: u32 = 10;
+        // Fixed-seed LCG, so every run ranks the same vectors
+        let mut seed: u32 = 42;
+        let mut vector = || {
+            let mut v: Vec<f32> = (0..DIMS)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+                })
+                .collect();
+            normalize(&mut v);
+            v
+        };
+        let query = vector();
+        let chunks: Vec<(String, Vec<f32>)> =
+            (0..CHUNKS).map(|i| (i.to_string(), vector())).collect();
+
+        type Search = fn(&[f32], &[(String, Vec<f32>)], usize, f32) -> Vec<(String, f32)>;
+        let time = |search: Search| {
+            let start = std::time::Instant::now();
+            let mut ids = Vec::new();
+            for _ in 0..RUNS {
+                ids = search(&query, &chunks, 10, 1.0).into_iter().map(|(id, _)| id).collect();
+            }
+            (start.elapsed() / RUNS, ids)
+        };
+        let (cosine, cosine_ids) = time(search_similar_diverse);
+        let (dot, dot_ids) = time(search_normalized_diverse);
+        println!("{} x {} dims: cosine {:?}, dot {:?} per search", CHUNKS, DIMS, cosine, dot);
+        assert_eq!(cosine_ids, dot_ids);
+    }
+
     #[test]
     fn test_cosine_similarity_identical() {
         let a = vec![1.0, 2.0, 3.0];
@@ -114,6 +424,37 @@ mod tests {
         assert!(cosine_similarity(&a, &b).abs() < 1e-6);
     }
 
+    #[test]
+    fn test_backend_model_ids_differ() {
+        let local = EmbeddingBackend::Local {
+            cache_dir: PathBuf::from("models"),
+        };
+        let openai = EmbeddingBackend::OpenAi {
+            config: OpenAiConfig {
+                api_key: String::new(),
+                base_url: "https://api.openai.com/v1".into(),
+                extra_headers: Vec::new(),
+            },
+            model: "text-embedding-3-small".into(),
+            dimensions: None,
+        };
+        assert_eq!(local.model_id(), LOCAL_EMBEDDING_MODEL);
+        assert_eq!(openai.model_id(), "text-embedding-3-small");
+    }
+
+    #[test]
+    fn test_dimensions_sent_only_when_configured() {
+        let request = |dimensions| EmbeddingRequest {
+            model: "text-embedding-3-small".into(),
+            input: vec!["hello".into()],
+            dimensions,
+        };
+        let json = serde_json::to_value(request(Some(512))).unwrap();
+        assert_eq!(json["dimensions"], 512);
+        let json = serde_json::to_value(request(None)).unwrap();
+        assert!(json.get("dimensions").is_none());
+    }
+
     #[test]
     fn test_embedding_roundtrip() {
         let emb = vec![0.1, 0.2, -0.3, 0.4];
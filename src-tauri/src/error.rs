@@ -0,0 +1,167 @@
+use crate::llm::LlmError;
+use serde::Serialize;
+
+/// Error returned by every Tauri command. Serialized as
+/// `{ "code": "...", "message": "..." }` so the frontend can branch on
+/// `code` instead of parsing messages.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    /// The provider rejected the credentials (HTTP 401/403).
+    #[error("{0}")]
+    Auth(String),
+    #[error("{0}")]
+    RateLimited(String),
+    #[error("{0}")]
+    Timeout(String),
+    /// The request never got a response (DNS, connection refused, TLS...).
+    #[error("{0}")]
+    Network(String),
+    /// A required setting (API key, login) is missing.
+    #[error("{0}")]
+    NotConfigured(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    /// The model answered with no text; nothing was saved.
+    #[error("{0}")]
+    EmptyResponse(String),
+    /// The user called the operation off; nothing was saved.
+    #[error("{0}")]
+    Cancelled(String),
+    /// Any other non-success response from a provider.
+    #[error("API error: {status} - {message}")]
+    Api { status: u16, message: String },
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Auth(_) => "auth",
+            AppError::RateLimited(_) => "rate_limited",
+            AppError::Timeout(_) => "timeout",
+            AppError::Network(_) => "network",
+            AppError::NotConfigured(_) => "not_configured",
+            AppError::NotFound(_) => "not_found",
+            AppError::InvalidInput(_) => "invalid_input",
+            AppError::EmptyResponse(_) => "empty_response",
+            AppError::Cancelled(_) => "cancelled",
+            AppError::Api { .. } => "api",
+            AppError::Database(_) => "database",
+            AppError::Other(_) => "other",
+        }
+    }
+}
+
+impl Serialize for AppError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        #[derive(Serialize)]
+        struct Repr<'a> {
+            code: &'a str,
+            message: String,
+        }
+        Repr {
+            code: self.code(),
+            message: self.to_string(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl From<LlmError> for AppError {
+    fn from(e: LlmError) -> Self {
+        match e {
+            LlmError::Api { status: 401 | 403, message } => AppError::Auth(message),
+            LlmError::Api { status: 429, message } => AppError::RateLimited(message),
+            LlmError::Api { status, message } => AppError::Api { status, message },
+            LlmError::Timeout(message) => AppError::Timeout(message),
+            e @ LlmError::FirstTokenTimeout => AppError::Timeout(e.to_string()),
+            LlmError::EmptyResponse(message) => {
+                AppError::EmptyResponse(format!("The model returned an empty response: {}", message))
+            }
+            LlmError::Cancelled(message) => AppError::Cancelled(message),
+            LlmError::Http(e) if e.is_timeout() => AppError::Timeout(e.to_string()),
+            LlmError::Http(e) if e.is_connect() || e.is_request() => {
+                AppError::Network(e.to_string())
+            }
+            other => AppError::Other(other.to_string()),
+        }
+    }
+}
+
+impl From<String> for AppError {
+    fn from(message: String) -> Self {
+        AppError::Other(message)
+    }
+}
+
+impl From<&str> for AppError {
+    fn from(message: &str) -> Self {
+        AppError::Other(message.to_string())
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Other(e.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for AppError {
+    fn from(e: zip::result::ZipError) -> Self {
+        AppError::Other(e.to_string())
+    }
+}
+
+impl From<tauri::Error> for AppError {
+    fn from(e: tauri::Error) -> Self {
+        AppError::Other(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Other(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_llm_status_codes_map_to_error_codes() {
+        let api = |status| LlmError::Api {
+            status,
+            message: "nope".into(),
+        };
+        assert_eq!(AppError::from(api(401)).code(), "auth");
+        assert_eq!(AppError::from(api(403)).code(), "auth");
+        assert_eq!(AppError::from(api(429)).code(), "rate_limited");
+        assert_eq!(AppError::from(api(500)).code(), "api");
+        assert_eq!(
+            AppError::from(LlmError::Timeout("device flow".into())).code(),
+            "timeout"
+        );
+    }
+
+    #[test]
+    fn test_serializes_code_and_message() {
+        let json = serde_json::to_value(AppError::NotConfigured("OpenAI API key not configured".into()))
+            .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "not_configured",
+                "message": "OpenAI API key not configured",
+            })
+        );
+    }
+}
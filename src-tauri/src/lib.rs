@@ -1,8 +1,16 @@
+mod cancel;
 mod commands;
 mod db;
+mod debug_log;
 mod doc_processor;
 mod embedding;
+mod error;
 mod llm;
+mod secrets;
+mod server;
+#[cfg(test)]
+mod test_util;
+mod tokens;
 
 use db::Database;
 use tauri::Manager;
@@ -14,34 +22,100 @@ pub fn run() {
         .plugin(tauri_plugin_dialog::init())
         .setup(|app| {
             let app_dir = app.path().app_data_dir()?;
+            debug_log::init(&app_dir);
             let database =
                 Database::new(&app_dir).expect("Failed to initialize database");
+            commands::settings::apply_llm_settings(&database);
+            commands::settings::restore_copilot_token(&database);
             app.manage(database);
+            app.manage(cancel::CancelRegistry::default());
+            app.manage(llm::copilot::LoginFlows::default());
+            app.manage(commands::knowledge::EmbeddingQueue::default());
+            commands::knowledge::spawn_embedding_worker(app.handle().clone());
+            app.manage(server::LocalServer::default());
+            commands::server::start_if_enabled(app.handle());
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             // Chat
             commands::chat::create_conversation,
             commands::chat::list_conversations,
+            commands::chat::add_tag,
+            commands::chat::remove_tag,
+            commands::chat::list_tags,
+            commands::chat::list_conversations_by_tag,
             commands::chat::delete_conversation,
+            commands::chat::delete_all_conversations,
+            commands::chat::list_trash,
+            commands::chat::restore_conversation,
+            commands::chat::purge_trash,
             commands::chat::rename_conversation,
+            commands::chat::fork_conversation,
             commands::chat::get_messages,
             commands::chat::send_message,
+            commands::chat::continue_message,
+            commands::chat::edit_message,
+            commands::chat::delete_message,
+            commands::chat::estimate_tokens,
+            commands::chat::inspect_chat_request,
+            // Export
+            commands::export::export_conversation,
+            commands::export::import_conversation,
+            commands::export::export_all,
+            commands::database::backup_database,
+            commands::database::restore_database,
+            commands::database::database_stats,
+            commands::database::compact_database,
             // Settings
             commands::settings::get_settings,
+            commands::settings::get_setting_raw,
             commands::settings::set_setting,
             commands::settings::delete_setting,
+            commands::settings::get_log_path,
+            commands::settings::clear_logs,
+            commands::settings::export_settings,
+            commands::settings::import_settings,
             commands::settings::get_available_models,
+            commands::settings::get_cached_models,
+            commands::settings::refresh_models,
+            commands::settings::provider_health,
             commands::settings::fetch_copilot_models,
             commands::settings::copilot_start_login,
+            commands::settings::copilot_cancel_login,
             commands::settings::copilot_poll_login,
+            commands::settings::copilot_await_login,
             commands::settings::copilot_is_logged_in,
             commands::settings::copilot_logout,
+            commands::settings::ollama_model_installed,
+            commands::settings::pull_ollama_model,
+            // Local API server
+            commands::server::start_local_server,
+            commands::server::stop_local_server,
+            commands::server::local_server_status,
+            // Prompt templates
+            commands::templates::list_prompt_templates,
+            commands::templates::create_prompt_template,
+            commands::templates::update_prompt_template,
+            commands::templates::delete_prompt_template,
+            commands::templates::set_system_prompt,
             // Knowledge base
             commands::knowledge::list_documents,
             commands::knowledge::upload_document,
+            commands::knowledge::estimate_document,
+            commands::knowledge::cancel_upload,
+            commands::knowledge::list_jobs,
+            commands::knowledge::job_status,
+            commands::knowledge::reembed_document,
+            commands::knowledge::reembed_pending,
+            commands::knowledge::update_document,
             commands::knowledge::delete_document,
+            commands::knowledge::clear_knowledge_base,
+            commands::knowledge::list_chunks,
+            commands::knowledge::delete_chunk,
+            commands::knowledge::renormalize_embeddings,
             commands::knowledge::search_knowledge_base,
+            commands::knowledge::rag_query,
+            commands::knowledge::preview_rag_context,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -1,14 +1,27 @@
-use super::{ChatRequest, ChatResponse, LlmError, StreamChunk};
-use futures::StreamExt;
+use super::{
+    first_token_deadline, is_length_finish, next_chunk, ChatMessage, ChatRequest, ChatResponse,
+    replayable_messages, LineBuffer, LlmError, StreamChunk, TokenUsage,
+};
+use crate::debug_log;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::Sender;
 
 #[derive(Debug, Clone)]
 pub struct ClaudeConfig {
     pub api_key: String,
     pub base_url: String,
+    /// Mark the system prompt and large messages as cacheable.
+    pub prompt_caching: bool,
 }
 
+/// Claude caches at most this many prompt prefixes per request.
+const MAX_CACHE_BREAKPOINTS: usize = 4;
+/// Prefixes under ~1024 tokens aren't cached, so smaller messages aren't
+/// worth one of the breakpoints.
+const MIN_CACHEABLE_CHARS: usize = 4_000;
+
 #[derive(Serialize)]
 struct ClaudeRequest {
     model: String,
@@ -16,83 +29,371 @@ struct ClaudeRequest {
     messages: Vec<ClaudeMessage>,
     stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    system: Option<String>,
+    system: Option<ClaudeMessageContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 struct ClaudeMessage {
     role: String,
-    content: String,
+    content: ClaudeMessageContent,
+}
+
+/// Plain text, or a content-block array when the message carries images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ClaudeMessageContent {
+    Text(String),
+    Blocks(Vec<ClaudeContentBlock>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClaudeContentBlock {
+    Text {
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    Image { source: ClaudeImageSource },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+}
+
+/// Marks the end of a cacheable prompt prefix.
+#[derive(Serialize)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+const EPHEMERAL: CacheControl = CacheControl { kind: "ephemeral" };
+
+#[derive(Serialize)]
+struct ClaudeImageSource {
+    #[serde(rename = "type")]
+    source_type: &'static str,
+    media_type: String,
+    data: String,
 }
 
 #[derive(Deserialize)]
 struct ClaudeResponse {
     content: Vec<ClaudeContent>,
+    #[serde(default)]
+    usage: Option<TokenUsage>,
+    #[serde(default)]
+    stop_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct ClaudeContent {
-    text: String,
+    #[serde(default)]
+    text: Option<String>,
 }
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 enum ClaudeStreamEvent {
     #[serde(rename = "content_block_delta")]
-    ContentBlockDelta { delta: ClaudeDelta },
+    ContentBlockDelta {
+        #[serde(default)]
+        index: usize,
+        delta: ClaudeDelta,
+    },
+    #[serde(rename = "message_start")]
+    MessageStart { message: ClaudeMessageStart },
+    #[serde(rename = "message_delta")]
+    MessageDelta {
+        delta: ClaudeMessageDelta,
+        #[serde(default)]
+        usage: Option<TokenUsage>,
+    },
     #[serde(rename = "message_stop")]
     MessageStop {},
+    #[serde(rename = "error")]
+    Error { error: ClaudeStreamError },
     #[serde(other)]
     Other,
 }
 
+/// Body of a mid-stream `error` event, e.g. `overloaded_error`.
+#[derive(Deserialize)]
+struct ClaudeStreamError {
+    #[serde(rename = "type")]
+    error_type: String,
+    message: String,
+}
+
+impl From<ClaudeStreamError> for LlmError {
+    /// Uses the HTTP status Claude would have returned for the same error
+    /// before the stream started.
+    fn from(e: ClaudeStreamError) -> Self {
+        let status = match e.error_type.as_str() {
+            "invalid_request_error" => 400,
+            "authentication_error" => 401,
+            "permission_error" => 403,
+            "not_found_error" => 404,
+            "request_too_large" => 413,
+            "rate_limit_error" => 429,
+            "overloaded_error" => 529,
+            _ => 500,
+        };
+        LlmError::Api {
+            status,
+            message: format!("{}: {}", e.error_type, e.message),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct ClaudeDelta {
     text: Option<String>,
 }
 
-fn build_request(request: &ChatRequest) -> ClaudeRequest {
-    let system_msg = request
-        .messages
+#[derive(Deserialize)]
+struct ClaudeMessageStart {
+    #[serde(default)]
+    usage: TokenUsage,
+}
+
+/// Fold the running totals of a `message_delta` into the counts from
+/// `message_start`. Output is cumulative; the rest only appear when set.
+fn merge_usage(usage: &mut TokenUsage, delta: TokenUsage) {
+    usage.output_tokens = delta.output_tokens;
+    if delta.input_tokens > 0 {
+        usage.input_tokens = delta.input_tokens;
+    }
+    if delta.cache_creation_input_tokens > 0 {
+        usage.cache_creation_input_tokens = delta.cache_creation_input_tokens;
+    }
+    if delta.cache_read_input_tokens > 0 {
+        usage.cache_read_input_tokens = delta.cache_read_input_tokens;
+    }
+}
+
+#[derive(Deserialize)]
+struct ClaudeMessageDelta {
+    stop_reason: Option<String>,
+}
+
+/// Text of a streamed reply, kept per content block so deltas from
+/// interleaved blocks come back out in block order.
+#[derive(Default)]
+struct StreamedBlocks {
+    blocks: BTreeMap<usize, String>,
+}
+
+impl StreamedBlocks {
+    fn push(&mut self, index: usize, text: &str) {
+        self.blocks.entry(index).or_default().push_str(text);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn content(&self) -> String {
+        self.blocks.values().map(String::as_str).collect()
+    }
+}
+
+/// Output-token ceiling of a Claude model, matched on its id. Unknown ids get
+/// the smallest ceiling so the request is never rejected for asking too much.
+fn max_output_tokens(model: &str) -> u32 {
+    let id = model.to_lowercase();
+    if let Some((_, rest)) = id.split_once("opus-4") {
+        // "opus-4-1-2025..." is 4.1; "opus-4-2025..." is 4.0. From 4.5 the
+        // ceiling doubled.
+        let minor = rest
+            .trim_start_matches('-')
+            .split('-')
+            .next()
+            .filter(|v| v.len() <= 2)
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0);
+        if minor >= 5 {
+            64_000
+        } else {
+            32_000
+        }
+    } else if id.contains("sonnet-4") || id.contains("haiku-4") || id.contains("3-7-sonnet") {
+        64_000
+    } else if id.contains("3-5-sonnet") || id.contains("3-5-haiku") {
+        8_192
+    } else {
+        4_096
+    }
+}
+
+/// `max_tokens` is mandatory for Claude: the configured value capped to the
+/// model's ceiling, or a default that leaves room for long answers.
+fn resolve_max_tokens(model: &str, requested: Option<u32>) -> u32 {
+    let ceiling = max_output_tokens(model);
+    requested.unwrap_or(8_192).min(ceiling)
+}
+
+/// Text content, switching to blocks when the message carries images or
+/// ends a cached prefix. Tool results become a `tool_result` block and tool
+/// calls `tool_use` blocks after the reply's text.
+fn message_content(m: &ChatMessage, cache: bool) -> ClaudeMessageContent {
+    if let Some(tool_use_id) = m.tool_call_id.as_ref().filter(|_| m.role == "tool") {
+        return ClaudeMessageContent::Blocks(vec![ClaudeContentBlock::ToolResult {
+            tool_use_id: tool_use_id.clone(),
+            content: m.content.clone(),
+            cache_control: cache.then_some(EPHEMERAL),
+        }]);
+    }
+    if !m.tool_calls.is_empty() {
+        // Claude rejects empty text blocks, and a call-only turn has no text
+        let text = (!m.content.is_empty()).then(|| ClaudeContentBlock::Text {
+            text: m.content.clone(),
+            cache_control: None,
+        });
+        let last = m.tool_calls.len() - 1;
+        let calls = m.tool_calls.iter().enumerate().map(|(i, call)| ClaudeContentBlock::ToolUse {
+            id: call.id.clone(),
+            name: call.name.clone(),
+            input: serde_json::from_str(&call.arguments)
+                .unwrap_or_else(|_| serde_json::json!({})),
+            cache_control: (cache && i == last).then_some(EPHEMERAL),
+        });
+        return ClaudeMessageContent::Blocks(text.into_iter().chain(calls).collect());
+    }
+    if m.attachments.is_empty() && !cache {
+        return ClaudeMessageContent::Text(m.content.clone());
+    }
+    let mut blocks: Vec<ClaudeContentBlock> = m
+        .attachments
         .iter()
-        .find(|m| m.role == "system")
-        .map(|m| m.content.clone());
+        .map(|a| ClaudeContentBlock::Image {
+            source: ClaudeImageSource {
+                source_type: "base64",
+                media_type: a.media_type.clone(),
+                data: a.data.clone(),
+            },
+        })
+        .collect();
+    blocks.push(ClaudeContentBlock::Text {
+        text: m.content.clone(),
+        cache_control: cache.then_some(EPHEMERAL),
+    });
+    ClaudeMessageContent::Blocks(blocks)
+}
 
-    let messages: Vec<ClaudeMessage> = request
-        .messages
+fn build_request(request: &ChatRequest, prompt_caching: bool) -> ClaudeRequest {
+    let history = replayable_messages(&request.messages);
+    let system = history.iter().find(|m| m.role == "system");
+    let turns: Vec<&ChatMessage> = history
         .iter()
         .filter(|m| m.role != "system")
-        .map(|m| ClaudeMessage {
-            role: m.role.clone(),
-            content: m.content.clone(),
+        .collect();
+
+    // The system prompt gets a breakpoint, then the latest large messages:
+    // each breakpoint caches everything before it, so later ones save more
+    let mut cached = vec![false; turns.len()];
+    if prompt_caching {
+        let budget = MAX_CACHE_BREAKPOINTS - usize::from(system.is_some());
+        let large = turns
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.content.len() >= MIN_CACHEABLE_CHARS)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        for &i in large.iter().rev().take(budget) {
+            cached[i] = true;
+        }
+    }
+
+    let system_msg = system.map(|m| {
+        if prompt_caching {
+            message_content(m, true)
+        } else {
+            ClaudeMessageContent::Text(m.content.clone())
+        }
+    });
+    let messages: Vec<ClaudeMessage> = turns
+        .iter()
+        .zip(cached)
+        .map(|(m, cache)| ClaudeMessage {
+            // Claude has no tool role: results are sent back by the user
+            role: if m.role == "tool" { "user".into() } else { m.role.clone() },
+            content: message_content(m, cache),
         })
         .collect();
 
     ClaudeRequest {
         model: request.model.clone(),
-        max_tokens: 4096,
+        max_tokens: resolve_max_tokens(&request.model, request.params.max_tokens),
         messages,
         stream: request.stream,
         system: system_msg,
+        temperature: request.params.temperature,
+        top_p: request.params.top_p,
+        stop_sequences: request.params.stop.clone(),
     }
 }
 
-pub async fn chat(config: &ClaudeConfig, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
-    let client = Client::new();
-    let body = build_request(request);
+/// Concatenated text blocks, telling "no content blocks" apart from blocks
+/// that carry no text.
+fn response_text(data: ClaudeResponse) -> Result<String, LlmError> {
+    if data.content.is_empty() {
+        return Err(LlmError::EmptyResponse(
+            "provider returned no content blocks".into(),
+        ));
+    }
+    let text: String = data.content.into_iter().filter_map(|c| c.text).collect();
+    if text.trim().is_empty() {
+        return Err(LlmError::EmptyResponse(
+            "content blocks contain no text".into(),
+        ));
+    }
+    Ok(text)
+}
 
-    let resp = client
+/// `POST /v1/messages` with auth and, when caching, the beta header.
+fn messages_request(config: &ClaudeConfig) -> reqwest::RequestBuilder {
+    let req = Client::new()
         .post(format!("{}/v1/messages", config.base_url))
         .header("Content-Type", "application/json")
         .header("x-api-key", &config.api_key)
-        .header("anthropic-version", "2023-06-01")
-        .json(&body)
-        .send()
-        .await?;
+        .header("anthropic-version", "2023-06-01");
+    if config.prompt_caching {
+        req.header("anthropic-beta", "prompt-caching-2024-07-31")
+    } else {
+        req
+    }
+}
+
+/// The messages request for `request`, streamed if `stream` is set.
+pub(crate) fn chat_request(config: &ClaudeConfig, request: &ChatRequest, stream: bool) -> reqwest::RequestBuilder {
+    let mut body = build_request(request, config.prompt_caching);
+    body.stream = stream;
+    messages_request(config).json(&body)
+}
+
+pub async fn chat(config: &ClaudeConfig, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+    let resp = debug_log::send(chat_request(config, request, false)).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api {
             status,
             message: text,
@@ -100,86 +401,351 @@ pub async fn chat(config: &ClaudeConfig, request: &ChatRequest) -> Result<ChatRe
     }
 
     let data: ClaudeResponse = resp.json().await?;
-    let content = data
-        .content
-        .first()
-        .map(|c| c.text.clone())
-        .unwrap_or_default();
+    let usage = data.usage;
+    let truncated = is_length_finish(data.stop_reason.as_deref());
+    let content = response_text(data)?;
 
     Ok(ChatResponse {
         content,
         model: request.model.clone(),
+        usage,
+        truncated,
     })
 }
 
+/// Check the key with the models list, which costs no tokens.
+pub async fn ping(config: &ClaudeConfig) -> Result<(), LlmError> {
+    let req = Client::new()
+        .get(format!("{}/v1/models", config.base_url))
+        .header("x-api-key", &config.api_key)
+        .header("anthropic-version", "2023-06-01");
+    let resp = debug_log::send(req).await?;
+    super::check_status(resp).await
+}
+
 pub async fn chat_stream(
     config: &ClaudeConfig,
     request: &ChatRequest,
-    on_chunk: impl Fn(StreamChunk) + Send,
+    tx: Sender<StreamChunk>,
 ) -> Result<String, LlmError> {
-    let client = Client::new();
-    let mut body = build_request(request);
-    body.stream = true;
-
-    let resp = client
-        .post(format!("{}/v1/messages", config.base_url))
-        .header("Content-Type", "application/json")
-        .header("x-api-key", &config.api_key)
-        .header("anthropic-version", "2023-06-01")
-        .json(&body)
-        .send()
-        .await?;
+    let resp = debug_log::send(chat_request(config, request, true)).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api {
             status,
             message: text,
         });
     }
 
-    let mut full_content = String::new();
+    let mut blocks = StreamedBlocks::default();
+    let mut usage: Option<TokenUsage> = None;
     let mut stream = resp.bytes_stream();
-    let mut buffer = String::new();
+    let mut lines = LineBuffer::default();
+    let mut ended = false;
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        buffer.push_str(&String::from_utf8_lossy(&chunk));
+    let deadline = first_token_deadline();
 
-        while let Some(pos) = buffer.find('\n') {
-            let line = buffer[..pos].trim().to_string();
-            buffer = buffer[pos + 1..].to_string();
+    while !ended {
+        match next_chunk(&mut stream, deadline.filter(|_| blocks.is_empty())).await? {
+            Some(chunk) => lines.push(&chunk?),
+            None => {
+                lines.finish();
+                ended = true;
+            }
+        }
 
+        while let Some(line) = lines.next_line() {
             if let Some(data) = line.strip_prefix("data: ") {
                 if let Ok(event) = serde_json::from_str::<ClaudeStreamEvent>(data) {
                     match event {
-                        ClaudeStreamEvent::ContentBlockDelta { delta } => {
+                        ClaudeStreamEvent::ContentBlockDelta { index, delta } => {
                             if let Some(text) = delta.text {
-                                full_content.push_str(&text);
-                                on_chunk(StreamChunk {
+                                blocks.push(index, &text);
+                                let _ = tx.send(StreamChunk {
                                     delta: text,
                                     done: false,
-                                });
+                                    usage: None,
+                                    truncated: false,
+                                }).await;
+                            }
+                        }
+                        ClaudeStreamEvent::Error { error } => return Err(error.into()),
+                        ClaudeStreamEvent::MessageStart { message } => usage = Some(message.usage),
+                        // A stop reason (end_turn, stop_sequence, max_tokens)
+                        // means no more text follows
+                        ClaudeStreamEvent::MessageDelta { delta, usage: delta_usage }
+                            if delta.stop_reason.is_some() =>
+                        {
+                            if let Some(delta_usage) = delta_usage {
+                                merge_usage(usage.get_or_insert_with(Default::default), delta_usage);
                             }
+                            let _ = tx.send(StreamChunk {
+                                delta: String::new(),
+                                done: true,
+                                usage,
+                                truncated: is_length_finish(delta.stop_reason.as_deref()),
+                            }).await;
+                            return Ok(blocks.content());
                         }
                         ClaudeStreamEvent::MessageStop {} => {
-                            on_chunk(StreamChunk {
+                            let _ = tx.send(StreamChunk {
                                 delta: String::new(),
                                 done: true,
-                            });
-                            return Ok(full_content);
+                                usage,
+                                truncated: false,
+                            }).await;
+                            return Ok(blocks.content());
                         }
-                        ClaudeStreamEvent::Other => {}
+                        ClaudeStreamEvent::MessageDelta { usage: Some(delta_usage), .. } => {
+                            merge_usage(usage.get_or_insert_with(Default::default), delta_usage);
+                        }
+                        ClaudeStreamEvent::MessageDelta { .. } | ClaudeStreamEvent::Other => {}
                     }
                 }
             }
         }
     }
 
-    on_chunk(StreamChunk {
+    let _ = tx.send(StreamChunk {
         delta: String::new(),
         done: true,
-    });
-    Ok(full_content)
+        usage,
+        truncated: false,
+    }).await;
+    Ok(blocks.content())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{Attachment, GenerationParams, ToolCall};
+
+    #[test]
+    fn test_image_message_uses_base64_source_block() {
+        let request = ChatRequest {
+            messages: vec![ChatMessage {
+                attachments: vec![Attachment {
+                    media_type: "image/jpeg".into(),
+                    data: "AAAA".into(),
+                }],
+                ..ChatMessage::user("Describe")
+            }],
+            model: "claude-sonnet-4-20250514".into(),
+            stream: false,
+            params: Default::default(),
+        };
+        let json = serde_json::to_value(build_request(&request, false)).unwrap();
+        let content = &json["messages"][0]["content"];
+        assert_eq!(content[0]["type"], "image");
+        assert_eq!(content[0]["source"]["type"], "base64");
+        assert_eq!(content[0]["source"]["media_type"], "image/jpeg");
+        assert_eq!(content[1]["type"], "text");
+    }
+
+    #[test]
+    fn test_tool_message_becomes_user_tool_result() {
+        let message = |role: &str, content: &str, tool_call_id: Option<&str>| ChatMessage {
+            role: role.into(),
+            content: content.into(),
+            attachments: Vec::new(),
+            tool_call_id: tool_call_id.map(Into::into),
+            tool_calls: Vec::new(),
+        };
+        let mut call = message("assistant", "Calling the calculator", None);
+        call.tool_calls = vec![ToolCall {
+            id: "toolu_1".into(),
+            name: "multiply".into(),
+            arguments: r#"{"a":6,"b":7}"#.into(),
+        }];
+        let request = ChatRequest {
+            messages: vec![
+                message("user", "What's 6 x 7?", None),
+                call,
+                message("tool", "42", Some("toolu_1")),
+            ],
+            model: "claude-sonnet-4-20250514".into(),
+            stream: false,
+            params: Default::default(),
+        };
+        let json = serde_json::to_value(build_request(&request, false)).unwrap();
+        let call = &json["messages"][1]["content"];
+        assert_eq!(call[0]["text"], "Calling the calculator");
+        assert_eq!(call[1]["type"], "tool_use");
+        assert_eq!(call[1]["id"], "toolu_1");
+        assert_eq!(call[1]["input"], serde_json::json!({"a": 6, "b": 7}));
+        let result = &json["messages"][2];
+        assert_eq!(result["role"], "user");
+        assert_eq!(result["content"][0]["type"], "tool_result");
+        assert_eq!(result["content"][0]["tool_use_id"], "toolu_1");
+        assert_eq!(result["content"][0]["content"], "42");
+    }
+
+    #[test]
+    fn test_stop_sequences_serialized_only_when_set() {
+        let mut request = ChatRequest {
+            messages: vec![ChatMessage::user("Hi")],
+            model: "claude-sonnet-4-20250514".into(),
+            stream: true,
+            params: Default::default(),
+        };
+        let json = serde_json::to_value(build_request(&request, false)).unwrap();
+        assert!(json.get("stop_sequences").is_none());
+        assert_eq!(json["max_tokens"], 8192);
+
+        request.params = GenerationParams {
+            max_tokens: Some(256),
+            stop: vec!["</answer>".into()],
+            ..Default::default()
+        };
+        let json = serde_json::to_value(build_request(&request, false)).unwrap();
+        assert_eq!(json["stop_sequences"], serde_json::json!(["</answer>"]));
+        assert_eq!(json["max_tokens"], 256);
+    }
+
+    #[test]
+    fn test_prompt_caching_marks_system_and_large_messages() {
+        let message = |role: &str, content: String| ChatMessage {
+            role: role.into(),
+            content,
+            attachments: Vec::new(),
+            tool_call_id: None,
+            tool_calls: Vec::new(),
+        };
+        let mut messages = vec![message("system", "Be terse.".into())];
+        for i in 0..5 {
+            messages.push(message("user", format!("doc {} {}", i, "x".repeat(MIN_CACHEABLE_CHARS))));
+        }
+        messages.push(message("user", "Short question".into()));
+        let request = ChatRequest {
+            messages,
+            model: "claude-sonnet-4-20250514".into(),
+            stream: false,
+            params: Default::default(),
+        };
+
+        let plain = serde_json::to_value(build_request(&request, false)).unwrap();
+        assert_eq!(plain["system"], "Be terse.");
+        assert!(!plain.to_string().contains("cache_control"));
+
+        let json = serde_json::to_value(build_request(&request, true)).unwrap();
+        assert_eq!(json["system"][0]["cache_control"]["type"], "ephemeral");
+        let marked: Vec<bool> = json["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"][0].get("cache_control").is_some())
+            .collect();
+        // Three breakpoints left after the system prompt go to the latest
+        // large messages; the short one stays plain text
+        assert_eq!(marked, vec![false, false, true, true, true, false]);
+        assert_eq!(json["messages"][5]["content"], "Short question");
+    }
+
+    #[test]
+    fn test_stream_usage_merges_start_and_delta() {
+        let start: ClaudeStreamEvent = serde_json::from_str(
+            r#"{"type": "message_start", "message": {"id": "m", "usage": {
+                "input_tokens": 12, "output_tokens": 1,
+                "cache_creation_input_tokens": 0, "cache_read_input_tokens": 2048}}}"#,
+        )
+        .unwrap();
+        let ClaudeStreamEvent::MessageStart { message } = start else {
+            panic!("expected message_start");
+        };
+        let mut usage = message.usage;
+
+        let delta: ClaudeStreamEvent = serde_json::from_str(
+            r#"{"type": "message_delta", "delta": {"stop_reason": "end_turn"}, "usage": {"output_tokens": 57}}"#,
+        )
+        .unwrap();
+        let ClaudeStreamEvent::MessageDelta { usage: Some(delta_usage), .. } = delta else {
+            panic!("expected message_delta with usage");
+        };
+        merge_usage(&mut usage, delta_usage);
+        assert_eq!(
+            usage,
+            TokenUsage {
+                input_tokens: 12,
+                output_tokens: 57,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 2048,
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_content_blocks_and_text_are_distinct_errors() {
+        let parse = |json: &str| response_text(serde_json::from_str(json).unwrap());
+        let err = parse(r#"{"content": []}"#).unwrap_err();
+        assert!(err.to_string().contains("no content blocks"));
+        let err = parse(r#"{"content": [{"type": "text", "text": ""}]}"#).unwrap_err();
+        assert!(err.to_string().contains("no text"));
+        assert_eq!(
+            parse(r#"{"content": [{"type": "text", "text": "Hi"}]}"#).unwrap(),
+            "Hi"
+        );
+    }
+
+    #[test]
+    fn test_multiple_text_blocks_are_concatenated() {
+        let data: ClaudeResponse = serde_json::from_str(
+            r#"{"content": [
+                {"type": "text", "text": "First part. "},
+                {"type": "tool_use", "id": "t1", "name": "search", "input": {}},
+                {"type": "text", "text": "Second part."}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(response_text(data).unwrap(), "First part. Second part.");
+    }
+
+    #[test]
+    fn test_streamed_blocks_reassemble_in_index_order() {
+        let mut blocks = StreamedBlocks::default();
+        blocks.push(1, "world");
+        blocks.push(0, "Hello, ");
+        blocks.push(1, "!");
+        assert_eq!(blocks.content(), "Hello, world!");
+    }
+
+    #[test]
+    fn test_error_event_becomes_api_error() {
+        let event: ClaudeStreamEvent = serde_json::from_str(
+            r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#,
+        )
+        .unwrap();
+        let ClaudeStreamEvent::Error { error } = event else {
+            panic!("expected an error event");
+        };
+        match LlmError::from(error) {
+            LlmError::Api { status, message } => {
+                assert_eq!(status, 529);
+                assert_eq!(message, "overloaded_error: Overloaded");
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn test_max_tokens_uses_setting_capped_to_model() {
+        let request = |model: &str, max_tokens| ChatRequest {
+            messages: Vec::new(),
+            model: model.into(),
+            stream: false,
+            params: GenerationParams {
+                max_tokens,
+                ..Default::default()
+            },
+        };
+        let built = |model: &str, max_tokens| build_request(&request(model, max_tokens), false).max_tokens;
+
+        assert_eq!(built("claude-sonnet-4-20250514", Some(20_000)), 20_000);
+        assert_eq!(built("claude-opus-4-20250514", Some(50_000)), 32_000);
+        assert_eq!(built("claude-opus-4-5-20251101", Some(50_000)), 50_000);
+        assert_eq!(built("claude-3-5-haiku-20241022", Some(50_000)), 8_192);
+        assert_eq!(built("claude-3-haiku-20240307", None), 4_096);
+        assert_eq!(built("claude-sonnet-4-20250514", None), 8_192);
+    }
 }
@@ -1,9 +1,15 @@
-use super::{ChatRequest, ChatResponse, LlmError, StreamChunk};
-use futures::StreamExt;
-use reqwest::Client;
+use super::{
+    first_token_deadline, is_json_body, is_length_finish, next_chunk, ChatRequest, ChatResponse,
+    openai::OpenAiToolCall, replayable_messages, LineBuffer, LlmError, StreamChunk,
+};
+use crate::debug_log;
+use reqwest::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
 
 /// Well-known client_id used by Copilot IDE integrations (copilot.vim etc.)
 const GITHUB_CLIENT_ID: &str = "Iv1.b507a08c87ecfe98";
@@ -25,21 +31,26 @@ pub struct DeviceCodeResponse {
     pub user_code: String,
     pub verification_uri: String,
     pub interval: u64,
+    #[serde(default = "default_expires_in")]
+    pub expires_in: u64,
+}
+
+fn default_expires_in() -> u64 {
+    900
 }
 
 /// Step 1: Request a device code from GitHub.
 pub async fn start_device_flow() -> Result<DeviceCodeResponse, LlmError> {
     let client = Client::new();
-    let resp = client
+    let req = client
         .post("https://github.com/login/device/code")
         .header("Accept", "application/json")
-        .form(&[("client_id", GITHUB_CLIENT_ID), ("scope", "copilot")])
-        .send()
-        .await?;
+        .form(&[("client_id", GITHUB_CLIENT_ID), ("scope", "copilot")]);
+    let resp = debug_log::send(req).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api { status, message: text });
     }
 
@@ -52,23 +63,38 @@ struct OAuthTokenResponse {
     error: Option<String>,
 }
 
+/// Result of a single device-flow poll.
+#[derive(Debug, PartialEq)]
+pub enum DevicePoll {
+    Pending,
+    /// GitHub asked us to poll less often.
+    SlowDown,
+    Authorized(String),
+}
+
 /// Step 2: Poll GitHub for the OAuth token after user authorizes.
 pub async fn poll_device_flow(device_code: &str) -> Result<Option<String>, LlmError> {
+    Ok(match poll_device_flow_status(device_code).await? {
+        DevicePoll::Authorized(token) => Some(token),
+        DevicePoll::Pending | DevicePoll::SlowDown => None,
+    })
+}
+
+pub async fn poll_device_flow_status(device_code: &str) -> Result<DevicePoll, LlmError> {
     let client = Client::new();
-    let resp = client
+    let req = client
         .post("https://github.com/login/oauth/access_token")
         .header("Accept", "application/json")
         .form(&[
             ("client_id", GITHUB_CLIENT_ID),
             ("device_code", device_code),
             ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
-        ])
-        .send()
-        .await?;
+        ]);
+    let resp = debug_log::send(req).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api { status, message: text });
     }
 
@@ -76,13 +102,91 @@ pub async fn poll_device_flow(device_code: &str) -> Result<Option<String>, LlmEr
     let data: OAuthTokenResponse = serde_json::from_str(&raw).map_err(|e| LlmError::Parse(e.to_string()))?;
 
     if let Some(token) = data.access_token {
-        return Ok(Some(token));
+        return Ok(DevicePoll::Authorized(token));
     }
 
     match data.error.as_deref() {
-        Some("authorization_pending") | Some("slow_down") => Ok(None),
+        Some("authorization_pending") | None => Ok(DevicePoll::Pending),
+        Some("slow_down") => Ok(DevicePoll::SlowDown),
         Some(err) => Err(LlmError::Api { status: 400, message: err.to_string() }),
-        None => Ok(None),
+    }
+}
+
+/// Progress reported while waiting for the user to authorize.
+#[derive(Serialize, Clone)]
+pub struct DeviceFlowProgress {
+    pub device_code: String,
+    pub interval: u64,
+    pub remaining_secs: u64,
+}
+
+/// Device flows started by the user. Only the latest may complete: starting
+/// another supersedes it, and a token that arrives for a superseded or
+/// cancelled flow must be discarded rather than saved.
+#[derive(Default)]
+pub struct LoginFlows {
+    pending: std::sync::Mutex<Option<String>>,
+}
+
+impl LoginFlows {
+    /// Make `device_code` the pending flow, superseding any earlier one.
+    pub fn begin(&self, device_code: &str) {
+        *self.pending.lock().unwrap() = Some(device_code.to_string());
+    }
+
+    pub fn is_pending(&self, device_code: &str) -> bool {
+        self.pending.lock().unwrap().as_deref() == Some(device_code)
+    }
+
+    /// Stop tracking `device_code`, whether it was authorized or cancelled.
+    /// Returns false if it isn't the pending flow, in which case its token
+    /// must not be saved.
+    pub fn finish(&self, device_code: &str) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.as_deref() == Some(device_code) {
+            *pending = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Poll until the user authorizes, respecting the server-provided `interval`,
+/// backing off by 5s on `slow_down` (RFC 8628), and giving up after `expires_in`.
+/// Stops with [`LlmError::Cancelled`] once `is_cancelled` returns true.
+pub async fn await_device_flow(
+    device_code: &str,
+    interval: u64,
+    expires_in: u64,
+    on_progress: impl Fn(DeviceFlowProgress),
+    is_cancelled: impl Fn() -> bool,
+) -> Result<String, LlmError> {
+    let started = Instant::now();
+    let deadline = Duration::from_secs(expires_in);
+    let mut interval = interval.max(1);
+
+    loop {
+        let elapsed = started.elapsed();
+        if elapsed >= deadline {
+            return Err(LlmError::Timeout("device code expired before authorization".into()));
+        }
+        on_progress(DeviceFlowProgress {
+            device_code: device_code.to_string(),
+            interval,
+            remaining_secs: (deadline - elapsed).as_secs(),
+        });
+
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        if is_cancelled() {
+            return Err(LlmError::Cancelled("login was cancelled".into()));
+        }
+
+        match poll_device_flow_status(device_code).await? {
+            DevicePoll::Authorized(token) => return Ok(token),
+            DevicePoll::SlowDown => interval += 5,
+            DevicePoll::Pending => {}
+        }
     }
 }
 
@@ -93,33 +197,99 @@ struct CachedToken {
     expires_at: u64,
 }
 
-static TOKEN_CACHE: Mutex<Option<CachedToken>> = Mutex::new(None);
+/// Tokens are refreshed this long before they expire.
+const TOKEN_REFRESH_MARGIN_SECS: u64 = 120;
+
+/// Async mutex held across the exchange, so concurrent callers that miss the
+/// cache queue behind a single refresh instead of all hitting the endpoint.
+static TOKEN_CACHE: Mutex<Option<CachedToken>> = Mutex::const_new(None);
+
+/// Called with every freshly exchanged token and its expiry, so it survives
+/// a restart. Set once at startup.
+type TokenStore = Box<dyn Fn(&str, u64) + Send + Sync>;
+static TOKEN_STORE: OnceLock<TokenStore> = OnceLock::new();
+
+pub fn set_token_store(store: impl Fn(&str, u64) + Send + Sync + 'static) {
+    let _ = TOKEN_STORE.set(Box::new(store));
+}
+
+/// Seed the cache with a token persisted by an earlier run. A token that is
+/// already inside the refresh window is ignored, so the first request
+/// exchanges a new one instead of failing with it.
+pub fn restore_token(token: String, expires_at: u64) {
+    if !is_fresh(expires_at, unix_now()) {
+        return;
+    }
+    if let Ok(mut cache) = TOKEN_CACHE.try_lock() {
+        if cache.is_none() {
+            *cache = Some(CachedToken { token, expires_at });
+        }
+    }
+}
+
+/// Drop the cached token, e.g. when the OAuth token it was exchanged from
+/// is replaced or removed.
+pub async fn forget_token() {
+    *TOKEN_CACHE.lock().await = None;
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Whether a token expiring at `expires_at` can still be used at `now`.
+fn is_fresh(expires_at: u64, now: u64) -> bool {
+    now + TOKEN_REFRESH_MARGIN_SECS < expires_at
+}
 
 /// Exchange OAuth token for a short-lived Copilot API token.
 async fn get_copilot_token(oauth_token: &str) -> Result<String, LlmError> {
-    {
-        let cache = TOKEN_CACHE.lock().unwrap();
-        if let Some(cached) = cache.as_ref() {
-            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            if now + 120 < cached.expires_at {
-                return Ok(cached.token.clone());
-            }
+    cached_or_refresh(&TOKEN_CACHE, || async {
+        let fresh = exchange_token(oauth_token).await?;
+        if let Some(store) = TOKEN_STORE.get() {
+            store(&fresh.token, fresh.expires_at);
+        }
+        Ok(fresh)
+    })
+    .await
+}
+
+/// Return the cached token unless it is about to expire, otherwise run
+/// `refresh` while holding the lock and store its result.
+async fn cached_or_refresh<F, Fut>(
+    cache: &Mutex<Option<CachedToken>>,
+    refresh: F,
+) -> Result<String, LlmError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<CachedToken, LlmError>>,
+{
+    let mut cache = cache.lock().await;
+    if let Some(cached) = cache.as_ref() {
+        if is_fresh(cached.expires_at, unix_now()) {
+            return Ok(cached.token.clone());
         }
     }
 
+    let fresh = refresh().await?;
+    let token = fresh.token.clone();
+    *cache = Some(fresh);
+    Ok(token)
+}
+
+async fn exchange_token(oauth_token: &str) -> Result<CachedToken, LlmError> {
     let client = Client::new();
-    let resp = client
+    let req = client
         .get(TOKEN_AUTH_URL)
         .header("Authorization", format!("token {}", oauth_token))
         .header("Accept", "application/json")
         .header("Editor-Plugin-Version", "copilot/1.0.0")
-        .header("User-Agent", "ai-box/0.1.0")
-        .send()
-        .await?;
+        .header("User-Agent", "ai-box/0.1.0");
+    let resp = debug_log::send(req).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api {
             status,
             message: format!("Copilot token exchange failed: {}", text),
@@ -127,13 +297,12 @@ async fn get_copilot_token(oauth_token: &str) -> Result<String, LlmError> {
     }
 
     let data: CopilotTokenResp = resp.json().await.map_err(|e| LlmError::Parse(e.to_string()))?;
+    Ok(CachedToken { token: data.token, expires_at: data.expires_at })
+}
 
-    {
-        let mut cache = TOKEN_CACHE.lock().unwrap();
-        *cache = Some(CachedToken { token: data.token.clone(), expires_at: data.expires_at });
-    }
-
-    Ok(data.token)
+/// Run a fresh token exchange, bypassing the cache, to check the OAuth token.
+pub async fn ping(oauth_token: &str) -> Result<(), LlmError> {
+    exchange_token(oauth_token).await.map(|_| ())
 }
 
 #[derive(Deserialize)]
@@ -144,17 +313,51 @@ struct CopilotTokenResp {
 
 // ── Chat ──
 
+/// OpenAI-compatible chat body. Unset options are left out entirely so the
+/// endpoint applies its own defaults.
 #[derive(Serialize)]
 struct ChatBody {
     model: String,
     messages: Vec<Msg>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+fn build_body(request: &ChatRequest, stream: bool) -> ChatBody {
+    let messages = replayable_messages(&request.messages).iter()
+        .map(|m| Msg {
+            role: m.role.clone(),
+            content: m.content.clone(),
+            tool_call_id: m.tool_call_id.clone(),
+            tool_calls: m.tool_calls.iter().map(Into::into).collect(),
+        })
+        .collect();
+    ChatBody {
+        model: request.model.clone(),
+        messages,
+        stream,
+        temperature: request.params.temperature,
+        max_tokens: request.params.max_tokens,
+        top_p: request.params.top_p,
+        stop: request.params.stop.clone(),
+    }
+}
+
+#[derive(Serialize)]
 struct Msg {
     role: String,
     content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OpenAiToolCall>,
 }
 
 #[derive(Deserialize)]
@@ -163,7 +366,10 @@ struct ChatResp {
 }
 #[derive(Deserialize)]
 struct ChatChoice {
-    message: Msg,
+    /// Same shape as a stream delta: `content` is null on tool-only replies.
+    message: Delta,
+    #[serde(default)]
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -180,86 +386,123 @@ struct Delta {
     content: Option<String>,
 }
 
-fn copilot_headers(token: &str) -> Vec<(&'static str, String)> {
-    vec![
-        ("Authorization", format!("Bearer {}", token)),
-        ("Copilot-Integration-Id", "vscode-chat".into()),
-        ("Editor-Version", "ai-box/0.1.0".into()),
-    ]
+/// `req` with the auth and client headers every Copilot API call needs.
+fn with_copilot_headers(req: RequestBuilder, token: &str) -> RequestBuilder {
+    req.header("Authorization", format!("Bearer {}", token))
+        .header("Copilot-Integration-Id", "vscode-chat")
+        .header("Editor-Version", "ai-box/0.1.0")
+}
+
+/// The chat completion request for `request`, authorized with `token`.
+pub(crate) fn chat_request(token: &str, request: &ChatRequest, stream: bool) -> RequestBuilder {
+    with_copilot_headers(Client::new().post(COPILOT_CHAT_URL), token)
+        .json(&build_body(request, stream))
 }
 
 pub async fn chat(config: &CopilotConfig, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
     let token = get_copilot_token(&config.oauth_token).await?;
-    let client = Client::new();
-    let messages: Vec<Msg> = request.messages.iter()
-        .map(|m| Msg { role: m.role.clone(), content: m.content.clone() })
-        .collect();
-
-    let body = ChatBody { model: request.model.clone(), messages, stream: false };
-
-    let mut req = client.post(COPILOT_CHAT_URL);
-    for (k, v) in copilot_headers(&token) { req = req.header(k, v); }
-    let resp = req.json(&body).send().await?;
+    let resp = debug_log::send(chat_request(&token, request, false)).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api { status, message: text });
     }
 
-    let data: ChatResp = resp.json().await?;
-    let content = data.choices.first().map(|c| c.message.content.clone()).unwrap_or_default();
-    Ok(ChatResponse { content, model: request.model.clone() })
+    let (content, truncated) = response_content(resp.json().await?)?;
+    Ok(ChatResponse { content, model: request.model.clone(), usage: None, truncated })
+}
+
+/// Text of the first choice and whether it stopped at the token limit,
+/// telling "no choices at all" apart from a choice with blank content.
+fn response_content(data: ChatResp) -> Result<(String, bool), LlmError> {
+    let choice = data.choices.into_iter().next()
+        .ok_or_else(|| LlmError::EmptyResponse("provider returned no choices".into()))?;
+    let truncated = is_length_finish(choice.finish_reason.as_deref());
+    match choice.message.content {
+        Some(content) if !content.trim().is_empty() => Ok((content, truncated)),
+        _ => Err(LlmError::EmptyResponse("first choice has an empty content field".into())),
+    }
 }
 
 pub async fn chat_stream(
     config: &CopilotConfig,
     request: &ChatRequest,
-    on_chunk: impl Fn(StreamChunk) + Send,
+    tx: Sender<StreamChunk>,
 ) -> Result<String, LlmError> {
     let token = get_copilot_token(&config.oauth_token).await?;
-    let client = Client::new();
-    let messages: Vec<Msg> = request.messages.iter()
-        .map(|m| Msg { role: m.role.clone(), content: m.content.clone() })
-        .collect();
-
-    let body = ChatBody { model: request.model.clone(), messages, stream: true };
-
-    let mut req = client.post(COPILOT_CHAT_URL);
-    for (k, v) in copilot_headers(&token) { req = req.header(k, v); }
-    let resp = req.json(&body).send().await?;
+    let resp = debug_log::send(chat_request(&token, request, true)).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api { status, message: text });
     }
 
+    // Endpoint answered without streaming: deliver the whole reply at once
+    if is_json_body(&resp) {
+        let (content, truncated) = response_content(resp.json().await?)?;
+        let _ = tx.send(StreamChunk {
+            delta: content.clone(),
+            done: false,
+            usage: None,
+            truncated: false,
+        }).await;
+        let _ = tx.send(StreamChunk {
+            delta: String::new(),
+            done: true,
+            usage: None,
+            truncated,
+        }).await;
+        return Ok(content);
+    }
+
     let mut full_content = String::new();
     let mut stream = resp.bytes_stream();
-    let mut buffer = String::new();
+    let mut lines = LineBuffer::default();
+    let mut ended = false;
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        buffer.push_str(&String::from_utf8_lossy(&chunk));
+    let deadline = first_token_deadline();
 
-        while let Some(pos) = buffer.find('\n') {
-            let line = buffer[..pos].trim().to_string();
-            buffer = buffer[pos + 1..].to_string();
+    while !ended {
+        match next_chunk(&mut stream, deadline.filter(|_| full_content.is_empty())).await? {
+            Some(chunk) => lines.push(&chunk?),
+            None => {
+                lines.finish();
+                ended = true;
+            }
+        }
 
+        while let Some(line) = lines.next_line() {
             if let Some(data) = line.strip_prefix("data: ") {
                 if data == "[DONE]" {
-                    on_chunk(StreamChunk { delta: String::new(), done: true });
+                    let _ = tx.send(StreamChunk {
+                        delta: String::new(),
+                        done: true,
+                        usage: None,
+                        truncated: false,
+                    }).await;
                     return Ok(full_content);
                 }
                 if let Ok(parsed) = serde_json::from_str::<StreamResp>(data) {
                     if let Some(choice) = parsed.choices.first() {
                         if let Some(content) = &choice.delta.content {
                             full_content.push_str(content);
-                            on_chunk(StreamChunk { delta: content.clone(), done: false });
+                            let _ = tx.send(StreamChunk {
+                                delta: content.clone(),
+                                done: false,
+                                usage: None,
+                                truncated: false,
+                            }).await;
                         }
                         if choice.finish_reason.is_some() {
-                            on_chunk(StreamChunk { delta: String::new(), done: true });
+                            let truncated = is_length_finish(choice.finish_reason.as_deref());
+                            let _ = tx.send(StreamChunk {
+                                delta: String::new(),
+                                done: true,
+                                usage: None,
+                                truncated,
+                            }).await;
                             return Ok(full_content);
                         }
                     }
@@ -268,7 +511,12 @@ pub async fn chat_stream(
         }
     }
 
-    on_chunk(StreamChunk { delta: String::new(), done: true });
+    let _ = tx.send(StreamChunk {
+        delta: String::new(),
+        done: true,
+        usage: None,
+        truncated: false,
+    }).await;
     Ok(full_content)
 }
 
@@ -276,25 +524,37 @@ pub async fn chat_stream(
 
 pub async fn fetch_models(oauth_token: &str) -> Result<Vec<super::ModelInfo>, LlmError> {
     let token = get_copilot_token(oauth_token).await?;
-    let client = Client::new();
-
-    let mut req = client.get(COPILOT_MODELS_URL);
-    for (k, v) in copilot_headers(&token) { req = req.header(k, v); }
-    let resp = req.send().await?;
+    let req = with_copilot_headers(Client::new().get(COPILOT_MODELS_URL), &token);
+    let resp = debug_log::send(req).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api { status, message: format!("Failed to fetch models: {}", text) });
     }
 
     let catalog: ModelCatalog = resp.json().await.map_err(|e| LlmError::Parse(e.to_string()))?;
 
     let models = catalog.data.into_iter()
-        .map(|m| super::ModelInfo {
-            id: format!("copilot/{}", m.id),
-            name: m.id.clone(),
-            provider: m.vendor.unwrap_or_else(|| "Copilot".into()),
+        .map(|m| {
+            let mut info = super::ModelInfo::new(
+                format!("copilot/{}", m.id),
+                m.id,
+                m.vendor.unwrap_or_else(|| "Copilot".into()),
+            );
+            if let Some(capabilities) = m.capabilities {
+                let supports = capabilities.supports;
+                if let Some(tools) = supports.tool_calls {
+                    info.capabilities.supports_tools = tools;
+                }
+                if let Some(streaming) = supports.streaming {
+                    info.capabilities.supports_streaming = streaming;
+                }
+                if let Some(context) = capabilities.limits.max_context_window_tokens {
+                    info.context_length = context;
+                }
+            }
+            info
         })
         .collect();
 
@@ -310,4 +570,126 @@ struct ModelCatalog {
 struct ModelEntry {
     id: String,
     vendor: Option<String>,
+    #[serde(default)]
+    capabilities: Option<CatalogCapabilities>,
+}
+
+/// Vision is reported too, but images aren't sent to Copilot.
+#[derive(Deserialize)]
+struct CatalogCapabilities {
+    #[serde(default)]
+    supports: CatalogSupports,
+    #[serde(default)]
+    limits: CatalogLimits,
+}
+
+#[derive(Deserialize, Default)]
+struct CatalogSupports {
+    tool_calls: Option<bool>,
+    streaming: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+struct CatalogLimits {
+    max_context_window_tokens: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_callers_share_one_exchange() {
+        static CACHE: Mutex<Option<CachedToken>> = Mutex::const_new(None);
+        let exchanges = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..32)
+            .map(|_| {
+                let exchanges = exchanges.clone();
+                tokio::spawn(async move {
+                    cached_or_refresh(&CACHE, || async move {
+                        exchanges.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                        Ok(CachedToken { token: "tok".into(), expires_at: now + 1800 })
+                    })
+                    .await
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.await.unwrap().unwrap(), "tok");
+        }
+        assert_eq!(exchanges.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_token_freshness_honours_refresh_margin() {
+        let now = 1_700_000_000;
+        assert!(is_fresh(now + 1800, now));
+        assert!(is_fresh(now + TOKEN_REFRESH_MARGIN_SECS + 1, now));
+        assert!(!is_fresh(now + TOKEN_REFRESH_MARGIN_SECS, now));
+        assert!(!is_fresh(now - 60, now));
+    }
+
+    #[tokio::test]
+    async fn test_stale_cached_token_is_refreshed() {
+        static CACHE: Mutex<Option<CachedToken>> = Mutex::const_new(None);
+        let now = unix_now();
+        *CACHE.lock().await = Some(CachedToken { token: "old".into(), expires_at: now + 60 });
+
+        let token = cached_or_refresh(&CACHE, || async move {
+            Ok(CachedToken { token: "new".into(), expires_at: now + 1800 })
+        })
+        .await
+        .unwrap();
+        assert_eq!(token, "new");
+
+        // The refreshed token is reused without another exchange
+        let again = cached_or_refresh(&CACHE, || async { panic!("no refresh expected") })
+            .await
+            .unwrap();
+        assert_eq!(again, "new");
+    }
+
+    #[test]
+    fn test_empty_choices_and_content_are_distinct_errors() {
+        let parse = |json: &str| response_content(serde_json::from_str(json).unwrap());
+        let err = parse(r#"{"choices": []}"#).unwrap_err();
+        assert!(err.to_string().contains("no choices"));
+        let err = parse(r#"{"choices": [{"message": {"content": null}}]}"#).unwrap_err();
+        assert!(err.to_string().contains("empty content"));
+        let err = parse(r#"{"choices": [{"message": {"content": " "}}]}"#).unwrap_err();
+        assert!(err.to_string().contains("empty content"));
+        let reply = r#"{"choices": [{"message": {"content": "Hi"}, "finish_reason": "length"}]}"#;
+        assert_eq!(parse(reply).unwrap(), ("Hi".to_string(), true));
+    }
+
+    #[test]
+    fn test_generation_params_serialized_only_when_set() {
+        let mut request = ChatRequest {
+            messages: vec![crate::llm::ChatMessage::user("Hi")],
+            model: "gpt-4o".into(),
+            stream: true,
+            params: Default::default(),
+        };
+        let json = serde_json::to_value(build_body(&request, true)).unwrap();
+        let keys: Vec<&str> = json.as_object().unwrap().keys().map(|k| k.as_str()).collect();
+        assert_eq!(keys.len(), 3, "unexpected keys: {:?}", keys);
+
+        request.params = crate::llm::GenerationParams {
+            temperature: Some(0.5),
+            max_tokens: Some(512),
+            top_p: None,
+            stop: vec!["END".into()],
+        };
+        let json = serde_json::to_value(build_body(&request, true)).unwrap();
+        assert_eq!(json["stop"], serde_json::json!(["END"]));
+        assert_eq!(json["max_tokens"], 512);
+        assert!((json["temperature"].as_f64().unwrap() - 0.5).abs() < 1e-6);
+        assert!(json.get("top_p").is_none());
+    }
 }
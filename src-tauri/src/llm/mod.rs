@@ -1,13 +1,91 @@
 pub mod claude;
 pub mod copilot;
+pub mod ollama;
 pub mod openai;
 
+use crate::debug_log;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use futures::{Stream, StreamExt};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub role: String,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// For `tool` messages, the id of the tool call being answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// For `assistant` messages, the tool calls the reply made.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[cfg(test)]
+impl ChatMessage {
+    pub fn user(content: &str) -> Self {
+        Self {
+            role: "user".into(),
+            content: content.into(),
+            attachments: Vec::new(),
+            tool_call_id: None,
+            tool_calls: Vec::new(),
+        }
+    }
+}
+
+/// A tool invocation requested by an assistant reply.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// Arguments as the JSON text the model produced.
+    pub arguments: String,
+}
+
+/// An image sent alongside a message, base64-encoded for the provider.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    pub media_type: String,
+    pub data: String,
+}
+
+impl Attachment {
+    /// Read and encode an image file. Only formats accepted by both OpenAI
+    /// and Claude are allowed.
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        let media_type = match ext.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            _ => return Err(format!("Unsupported image type: .{}", ext)),
+        };
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Ok(Self {
+            media_type: media_type.to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+        })
+    }
+
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -15,6 +93,47 @@ pub struct ModelInfo {
     pub id: String,
     pub name: String,
     pub provider: String,
+    pub capabilities: ModelCapabilities,
+    /// Context window in tokens.
+    pub context_length: usize,
+}
+
+impl ModelInfo {
+    /// A model described by the built-in capability table. Fetchers override
+    /// the fields their provider reports.
+    pub fn new(id: impl Into<String>, name: impl Into<String>, provider: impl Into<String>) -> Self {
+        let id = id.into();
+        ModelInfo {
+            capabilities: known_capabilities(&id),
+            context_length: known_context_length(&id),
+            id,
+            name: name.into(),
+            provider: provider.into(),
+        }
+    }
+}
+
+/// What a model can do, so the UI can adapt to the selected one.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    pub supports_streaming: bool,
+    pub supports_vision: bool,
+    pub supports_tools: bool,
+}
+
+/// Sampling options shared by every provider. `None` / empty leaves the
+/// provider's default in place.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GenerationParams {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Sequences that end generation as soon as the model produces one.
+    #[serde(default)]
+    pub stop: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -22,18 +141,321 @@ pub struct ChatRequest {
     pub messages: Vec<ChatMessage>,
     pub model: String,
     pub stream: bool,
+    #[serde(default, flatten)]
+    pub params: GenerationParams,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatResponse {
     pub content: String,
     pub model: String,
+    /// Token accounting, for providers that report it.
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
+    /// The reply stopped at the token limit.
+    #[serde(default)]
+    pub truncated: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamChunk {
     pub delta: String,
     pub done: bool,
+    /// Set on the final chunk by providers that report token usage.
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
+    /// Set on the final chunk when the reply stopped at the token limit
+    /// rather than finishing on its own.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/// Whether a provider's finish reason means the reply was cut off by
+/// `max_tokens`: OpenAI-style APIs say "length", Claude "max_tokens".
+pub fn is_length_finish(reason: Option<&str>) -> bool {
+    matches!(reason, Some("length" | "max_tokens"))
+}
+
+/// History as it can be replayed to a provider. Tool results are only valid
+/// straight after the assistant turn that made the call, and every call in
+/// that turn needs its result, so calls without a result are dropped and
+/// results without a call are sent as plain user text.
+pub(crate) fn replayable_messages(messages: &[ChatMessage]) -> Vec<ChatMessage> {
+    let mut replay = Vec::with_capacity(messages.len());
+    let mut i = 0;
+    while i < messages.len() {
+        let m = &messages[i];
+        i += 1;
+        if m.role == "tool" {
+            replay.push(tool_result_as_text(m));
+            continue;
+        }
+        if m.role != "assistant" || m.tool_calls.is_empty() {
+            replay.push(m.clone());
+            continue;
+        }
+        let results = messages[i..].iter().take_while(|r| r.role == "tool").count();
+        let results = &messages[i..i + results];
+        i += results.len();
+        let mut call = m.clone();
+        call.tool_calls
+            .retain(|c| results.iter().any(|r| r.tool_call_id.as_deref() == Some(c.id.as_str())));
+        let answered: Vec<String> = call.tool_calls.iter().map(|c| c.id.clone()).collect();
+        replay.push(call);
+        for result in results {
+            match &result.tool_call_id {
+                Some(id) if answered.contains(id) => replay.push(result.clone()),
+                _ => replay.push(tool_result_as_text(result)),
+            }
+        }
+    }
+    replay
+}
+
+fn tool_result_as_text(m: &ChatMessage) -> ChatMessage {
+    match &m.tool_call_id {
+        Some(id) => eprintln!("Tool result for unknown call {}; sending it as text", id),
+        None => eprintln!("Tool result without a tool_call_id; sending it as text"),
+    }
+    ChatMessage {
+        role: "user".into(),
+        content: format!("Tool result:\n{}", m.content),
+        attachments: m.attachments.clone(),
+        tool_call_id: None,
+        tool_calls: Vec::new(),
+    }
+}
+
+/// Tokens billed for one request. The cache fields are only filled in by
+/// Claude with prompt caching: tokens written to and served from the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub cache_creation_input_tokens: u32,
+    pub cache_read_input_tokens: u32,
+}
+
+/// In-flight requests allowed per provider unless `max_concurrent_requests`
+/// is set.
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+/// Caps concurrent outbound requests per provider or endpoint. Callers over
+/// the cap queue for a slot rather than failing.
+pub struct RequestLimiter {
+    limit: AtomicUsize,
+    semaphores: Mutex<BTreeMap<String, Arc<Semaphore>>>,
+}
+
+/// Shared by chat, streaming and embedding calls.
+pub static REQUEST_LIMITER: RequestLimiter = RequestLimiter::new(DEFAULT_MAX_CONCURRENT_REQUESTS);
+
+impl RequestLimiter {
+    pub const fn new(limit: usize) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit),
+            semaphores: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Requests already holding a slot finish under the old limit; new ones
+    /// queue against the new limit.
+    pub fn set_limit(&self, limit: usize) {
+        let limit = limit.max(1);
+        if self.limit.swap(limit, Ordering::SeqCst) != limit {
+            self.semaphores.lock().unwrap().clear();
+        }
+    }
+
+    /// Wait for a slot under `key`. The slot is released when the permit drops.
+    pub async fn acquire(&self, key: &str) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit.load(Ordering::SeqCst))))
+            .clone();
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("limiter semaphores are never closed")
+    }
+}
+
+/// Chunks a stream may buffer ahead of a slow receiver before the provider
+/// stops reading.
+pub const STREAM_CHANNEL_CAPACITY: usize = 256;
+
+/// How long a stream may stay silent before its first token, unless
+/// `first_token_timeout_secs` is set. Loading a local model can take a while.
+pub const DEFAULT_FIRST_TOKEN_TIMEOUT_SECS: u64 = 60;
+
+static FIRST_TOKEN_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_FIRST_TOKEN_TIMEOUT_SECS);
+
+/// `0` disables the first-token watchdog.
+pub fn set_first_token_timeout(secs: u64) {
+    FIRST_TOKEN_TIMEOUT_SECS.store(secs, Ordering::SeqCst);
+}
+
+/// Deadline for the first token of a stream starting now.
+pub(crate) fn first_token_deadline() -> Option<Instant> {
+    match FIRST_TOKEN_TIMEOUT_SECS.load(Ordering::SeqCst) {
+        0 => None,
+        secs => Some(Instant::now() + Duration::from_secs(secs)),
+    }
+}
+
+/// Next piece of a streamed response body. Until the first token arrives the
+/// wait is bounded by `deadline`; pass `None` once text is flowing so long
+/// generations aren't cut off.
+pub(crate) async fn next_chunk<S>(
+    stream: &mut S,
+    deadline: Option<Instant>,
+) -> Result<Option<S::Item>, LlmError>
+where
+    S: Stream + Unpin,
+{
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, stream.next())
+            .await
+            .map_err(|_| LlmError::FirstTokenTimeout),
+        None => Ok(stream.next().await),
+    }
+}
+
+/// Splits a streamed body into lines. Consumed lines are skipped with a
+/// cursor and only dropped when the next chunk arrives, so a chunk holding
+/// many short lines isn't copied once per line. Works on bytes, so a UTF-8
+/// character split across chunks still decodes.
+#[derive(Debug, Default)]
+pub(crate) struct LineBuffer {
+    buf: Vec<u8>,
+    /// Start of the first unconsumed line in `buf`.
+    start: usize,
+}
+
+impl LineBuffer {
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Mark the end of the body, so a last line without a trailing newline
+    /// is still returned by [`Self::next_line`].
+    pub(crate) fn finish(&mut self) {
+        if self.start < self.buf.len() {
+            self.buf.push(b'\n');
+        }
+    }
+
+    /// The next complete line, trimmed.
+    pub(crate) fn next_line(&mut self) -> Option<String> {
+        let rest = &self.buf[self.start..];
+        let end = rest.iter().position(|&b| b == b'\n')?;
+        let line = String::from_utf8_lossy(&rest[..end]).trim().to_string();
+        self.start += end + 1;
+        Some(line)
+    }
+}
+
+/// Built-in capabilities of a prefixed model id like "openai/gpt-4o", for
+/// providers whose model lists don't report them.
+pub fn known_capabilities(model: &str) -> ModelCapabilities {
+    let model = model.to_lowercase();
+    let (prefix, id) = model.split_once('/').unwrap_or(("", model.as_str()));
+    let (vision, tools) = match prefix {
+        "openai" => (openai_supports_vision(id), openai_supports_tools(id)),
+        "claude" => (claude_supports_vision(id), true),
+        "ollama" => (ollama_supports_vision(id), ollama_supports_tools(id)),
+        "openrouter" => (openrouter_supports_vision(id), openrouter_supports_tools(id)),
+        // Images aren't sent to Copilot
+        "copilot" => (false, openai_supports_tools(id) || id.starts_with("claude") || id.starts_with("gemini")),
+        _ => (false, false),
+    };
+    ModelCapabilities {
+        supports_streaming: true,
+        supports_vision: vision,
+        supports_tools: tools,
+    }
+}
+
+/// Built-in context window of a prefixed model id, matched on the model's
+/// own name (the part after the last `/`).
+pub fn known_context_length(model: &str) -> usize {
+    let name = model.rsplit('/').next().unwrap_or(model);
+    crate::tokens::context_limit(name)
+}
+
+fn openai_supports_vision(id: &str) -> bool {
+    ["gpt-4o", "gpt-4.1", "gpt-4-turbo", "gpt-5", "o1", "o3", "o4"]
+        .iter()
+        .any(|p| id.starts_with(p))
+}
+
+/// Function calling came with GPT-3.5 Turbo; the first o1 previews lacked it.
+fn openai_supports_tools(id: &str) -> bool {
+    ["gpt-4", "gpt-3.5-turbo", "gpt-5", "o1", "o3", "o4"]
+        .iter()
+        .any(|p| id.starts_with(p))
+        && !id.starts_with("o1-mini")
+        && !id.starts_with("o1-preview")
+}
+
+/// Every Claude 3+ model except 3.5 Haiku takes images.
+fn claude_supports_vision(id: &str) -> bool {
+    !id.contains("haiku-3-5") && !id.contains("3-5-haiku")
+}
+
+fn ollama_supports_vision(id: &str) -> bool {
+    ["llava", "vision", "gemma3", "vl", "minicpm-v", "moondream"]
+        .iter()
+        .any(|m| id.contains(m))
+}
+
+fn ollama_supports_tools(id: &str) -> bool {
+    [
+        "llama3.1", "llama3.2", "llama3.3", "llama4", "qwen2.5", "qwen3", "mistral", "mixtral",
+        "command-r", "hermes3", "granite3",
+    ]
+    .iter()
+    .any(|m| id.contains(m))
+}
+
+// OpenRouter ids are "<vendor>/<model>", with dots in versions
+fn openrouter_supports_vision(id: &str) -> bool {
+    match id.split_once('/') {
+        Some(("openai", model)) => openai_supports_vision(model),
+        Some(("anthropic", model)) => claude_supports_vision(&model.replace('.', "-")),
+        Some(("google", model)) => model.starts_with("gemini"),
+        _ => false,
+    }
+}
+
+fn openrouter_supports_tools(id: &str) -> bool {
+    match id.split_once('/') {
+        Some(("openai", model)) => openai_supports_tools(model),
+        Some(("anthropic", _)) => true,
+        Some(("google", model)) => model.starts_with("gemini"),
+        _ => false,
+    }
+}
+
+pub const OPENROUTER_BASE_URL: &str = "https://openrouter.ai/api/v1";
+
+/// OpenRouter uses the optional `HTTP-Referer` / `X-Title` headers to
+/// attribute traffic to the app.
+pub fn openrouter_config(api_key: String) -> openai::OpenAiConfig {
+    openai::OpenAiConfig {
+        api_key,
+        base_url: OPENROUTER_BASE_URL.to_string(),
+        extra_headers: vec![
+            ("HTTP-Referer".into(), "https://github.com/bi-xiaohu/ai-box".into()),
+            ("X-Title".into(), "AI-Box".into()),
+        ],
+    }
 }
 
 /// Unified LLM provider enum — dispatches to OpenAI-compatible or Claude backends.
@@ -42,6 +464,8 @@ pub enum Provider {
     OpenAi(openai::OpenAiConfig),
     Claude(claude::ClaudeConfig),
     Ollama(openai::OpenAiConfig),
+    /// OpenAI-compatible aggregator; model ids are "<vendor>/<model>".
+    OpenRouter(openai::OpenAiConfig),
     Copilot(copilot::CopilotConfig),
 }
 
@@ -50,6 +474,7 @@ impl Provider {
         Provider::OpenAi(openai::OpenAiConfig {
             api_key,
             base_url: "https://api.openai.com/v1".to_string(),
+            extra_headers: Vec::new(),
         })
     }
 
@@ -57,6 +482,7 @@ impl Provider {
         Provider::Claude(claude::ClaudeConfig {
             api_key,
             base_url: "https://api.anthropic.com".to_string(),
+            prompt_caching: false,
         })
     }
 
@@ -64,16 +490,61 @@ impl Provider {
         Provider::Ollama(openai::OpenAiConfig {
             api_key: String::new(),
             base_url: format!("{}/v1", host),
+            extra_headers: Vec::new(),
         })
     }
 
+    pub fn openrouter(api_key: String) -> Self {
+        Provider::OpenRouter(openrouter_config(api_key))
+    }
+
     pub fn copilot(oauth_token: String) -> Self {
         Provider::Copilot(copilot::CopilotConfig { oauth_token })
     }
 
+    /// Whether `model_id` on this provider accepts image inputs.
+    pub fn supports_vision(&self, model_id: &str) -> bool {
+        let id = model_id.to_lowercase();
+        match self {
+            Provider::OpenAi(_) => openai_supports_vision(&id),
+            Provider::Claude(_) => claude_supports_vision(&id),
+            Provider::Ollama(_) => ollama_supports_vision(&id),
+            Provider::OpenRouter(_) => openrouter_supports_vision(&id),
+            Provider::Copilot(_) => false,
+        }
+    }
+
+    /// Key this provider's requests are rate-limited under.
+    fn limit_key(&self) -> &'static str {
+        match self {
+            Provider::OpenAi(_) => "openai",
+            Provider::Claude(_) => "claude",
+            Provider::Ollama(_) => "ollama",
+            Provider::OpenRouter(_) => "openrouter",
+            Provider::Copilot(_) => "copilot",
+        }
+    }
+
+    /// Cheapest request that shows whether the provider is reachable and
+    /// accepts our credentials. Not rate-limited: it's a diagnostic.
+    pub async fn ping(&self) -> Result<(), LlmError> {
+        match self {
+            Provider::OpenAi(config) | Provider::OpenRouter(config) => openai::ping(config).await,
+            Provider::Ollama(config) => {
+                let host = config.base_url.trim_end_matches("/v1");
+                let req = reqwest::Client::new().get(format!("{}/api/tags", host));
+                let resp = debug_log::send(req).await?;
+                check_status(resp).await
+            }
+            Provider::Claude(config) => claude::ping(config).await,
+            Provider::Copilot(config) => copilot::ping(&config.oauth_token).await,
+        }
+    }
+
     pub async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let _permit = REQUEST_LIMITER.acquire(self.limit_key()).await;
         match self {
-            Provider::OpenAi(config) | Provider::Ollama(config) => {
+            Provider::OpenAi(config) | Provider::Ollama(config) | Provider::OpenRouter(config) => {
                 openai::chat(config, request).await
             }
             Provider::Claude(config) => claude::chat(config, request).await,
@@ -81,19 +552,76 @@ impl Provider {
         }
     }
 
-    pub async fn chat_stream(
+    /// The request `chat_stream` would send for `request`, credentials
+    /// redacted. Copilot's short-lived session token isn't exchanged for
+    /// this, so its `Authorization` header holds a placeholder.
+    pub fn inspect_chat(&self, request: &ChatRequest) -> Result<debug_log::InspectedRequest, LlmError> {
+        let builder = match self {
+            Provider::OpenAi(config) | Provider::Ollama(config) | Provider::OpenRouter(config) => {
+                openai::chat_request(config, request, true)
+            }
+            Provider::Claude(config) => claude::chat_request(config, request, true),
+            Provider::Copilot(_) => copilot::chat_request("copilot-session-token", request, true),
+        };
+        Ok(debug_log::inspect(&builder.build()?))
+    }
+
+    /// Stream a reply into `tx`, so the receiver can handle chunks at its own
+    /// pace (e.g. batch deltas) instead of inside the HTTP read loop. A full
+    /// channel pauses reading from the provider until the receiver catches
+    /// up. The sender is dropped when the stream ends; a receiver that hung
+    /// up just stops listening. Returns the full text.
+    pub async fn chat_stream_to(
         &self,
         request: &ChatRequest,
-        on_chunk: impl Fn(StreamChunk) + Send,
+        tx: Sender<StreamChunk>,
     ) -> Result<String, LlmError> {
+        let _permit = REQUEST_LIMITER.acquire(self.limit_key()).await;
         match self {
-            Provider::OpenAi(config) | Provider::Ollama(config) => {
-                openai::chat_stream(config, request, on_chunk).await
+            Provider::OpenAi(config) | Provider::Ollama(config) | Provider::OpenRouter(config) => {
+                openai::chat_stream(config, request, tx).await
             }
-            Provider::Claude(config) => claude::chat_stream(config, request, on_chunk).await,
-            Provider::Copilot(config) => copilot::chat_stream(config, request, on_chunk).await,
+            Provider::Claude(config) => claude::chat_stream(config, request, tx).await,
+            Provider::Copilot(config) => copilot::chat_stream(config, request, tx).await,
         }
     }
+
+    /// Stream a reply, calling `on_chunk` for every chunk: `chat_stream_to`
+    /// with the receiving end drained alongside it.
+    pub async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: impl Fn(StreamChunk) + Send,
+    ) -> Result<String, LlmError> {
+        let (tx, mut rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        let forward = async move {
+            while let Some(chunk) = rx.recv().await {
+                on_chunk(chunk);
+            }
+        };
+        let (result, ()) = futures::future::join(self.chat_stream_to(request, tx), forward).await;
+        result
+    }
+}
+
+/// Whether a response to a streaming request is a single JSON document
+/// rather than an event stream: some endpoints and proxies ignore
+/// `stream: true`.
+fn is_json_body(resp: &reqwest::Response) -> bool {
+    resp.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.trim_start().starts_with("application/json"))
+}
+
+/// Turn a non-2xx response into `LlmError::Api`.
+async fn check_status(resp: reqwest::Response) -> Result<(), LlmError> {
+    if resp.status().is_success() {
+        return Ok(());
+    }
+    let status = resp.status().as_u16();
+    let message = debug_log::error_text(resp).await;
+    Err(LlmError::Api { status, message })
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -104,6 +632,17 @@ pub enum LlmError {
     Api { status: u16, message: String },
     #[error("Parse error: {0}")]
     Parse(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// The stream opened but no token arrived within the configured window.
+    #[error("No response from the model before the first-token timeout")]
+    FirstTokenTimeout,
+    /// The provider answered successfully but produced no text.
+    #[error("Empty response: {0}")]
+    EmptyResponse(String),
+    /// Stopped on request before finishing.
+    #[error("Cancelled: {0}")]
+    Cancelled(String),
 }
 
 impl Serialize for LlmError {
@@ -114,3 +653,172 @@ impl Serialize for LlmError {
         serializer.serialize_str(&self.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_replay_pairs_tool_calls_with_results() {
+        let message = |role: &str, content: &str, tool_call_id: Option<&str>| ChatMessage {
+            role: role.into(),
+            content: content.into(),
+            attachments: Vec::new(),
+            tool_call_id: tool_call_id.map(Into::into),
+            tool_calls: Vec::new(),
+        };
+        let call = |id: &str| ToolCall {
+            id: id.into(),
+            name: "lookup".into(),
+            arguments: "{}".into(),
+        };
+        let mut assistant = message("assistant", "", None);
+        assistant.tool_calls = vec![call("call_1"), call("call_2")];
+        let replay = replayable_messages(&[
+            message("user", "Look these up", None),
+            assistant,
+            message("tool", "one", Some("call_1")),
+            message("tool", "stale", Some("call_9")),
+            message("assistant", "Done", None),
+            message("tool", "orphan", None),
+        ]);
+
+        let roles: Vec<&str> = replay.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "tool", "user", "assistant", "user"]);
+        // call_2 never got a result, so it isn't replayed
+        assert_eq!(replay[1].tool_calls, vec![call("call_1")]);
+        assert_eq!(replay[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(replay[3].content, "Tool result:\nstale");
+        assert_eq!(replay[3].tool_call_id, None);
+        assert_eq!(replay[5].content, "Tool result:\norphan");
+    }
+
+    #[test]
+    fn test_line_buffer_splits_across_chunks() {
+        let mut lines = LineBuffer::default();
+        lines.push(b"data: one\r\ndata: t");
+        assert_eq!(lines.next_line().as_deref(), Some("data: one"));
+        assert_eq!(lines.next_line(), None);
+        // "é" split between chunks
+        lines.push(b"wo \xc3");
+        lines.push(b"\xa9\n\ndata: last");
+        assert_eq!(lines.next_line().as_deref(), Some("data: two é"));
+        assert_eq!(lines.next_line().as_deref(), Some(""));
+        assert_eq!(lines.next_line(), None);
+        lines.finish();
+        assert_eq!(lines.next_line().as_deref(), Some("data: last"));
+        assert_eq!(lines.next_line(), None);
+        lines.finish();
+        assert_eq!(lines.next_line(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_limiter_never_exceeds_limit() {
+        const LIMIT: usize = 3;
+        static LIMITER: RequestLimiter = RequestLimiter::new(LIMIT);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..=LIMIT)
+            .map(|_| {
+                let in_flight = in_flight.clone();
+                let peak = peak.clone();
+                tokio::spawn(async move {
+                    let _permit = LIMITER.acquire("test").await;
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= LIMIT);
+    }
+
+    #[tokio::test]
+    async fn test_limiter_keys_are_independent() {
+        static LIMITER: RequestLimiter = RequestLimiter::new(1);
+        let _openai = LIMITER.acquire("openai").await;
+        let other = LIMITER.acquire("http://localhost:11434/v1");
+        let _other = tokio::time::timeout(Duration::from_secs(1), other)
+            .await
+            .expect("a busy key should not block another");
+    }
+
+    #[test]
+    fn test_openrouter_vision_follows_vendor() {
+        let provider = Provider::openrouter("key".into());
+        assert!(provider.supports_vision("openai/gpt-4o-mini"));
+        assert!(provider.supports_vision("anthropic/claude-sonnet-4"));
+        assert!(!provider.supports_vision("anthropic/claude-3.5-haiku"));
+        assert!(!provider.supports_vision("meta-llama/llama-3.1-70b-instruct"));
+        let Provider::OpenRouter(config) = provider else {
+            unreachable!()
+        };
+        assert_eq!(config.base_url, OPENROUTER_BASE_URL);
+        assert!(config.extra_headers.iter().any(|(name, _)| name == "X-Title"));
+    }
+
+    #[test]
+    fn test_inspect_chat_reflects_params_and_redacts_key() {
+        let request = ChatRequest {
+            messages: vec![ChatMessage::user("Hi")],
+            model: "gpt-4o".into(),
+            stream: true,
+            params: GenerationParams {
+                temperature: Some(0.25),
+                max_tokens: Some(300),
+                ..Default::default()
+            },
+        };
+        let inspected = Provider::openai("sk-proj-abcdef1234567890".into())
+            .inspect_chat(&request)
+            .unwrap();
+        assert_eq!(inspected.method, "POST");
+        assert_eq!(inspected.url, "https://api.openai.com/v1/chat/completions");
+        assert_eq!(inspected.body["temperature"], 0.25);
+        assert_eq!(inspected.body["max_tokens"], 300);
+        assert_eq!(inspected.body["stream"], true);
+        let auth = inspected.headers.iter().find(|h| h.name == "authorization").unwrap();
+        assert_eq!(auth.value, "[REDACTED]");
+        assert!(!format!("{:?}", inspected).contains("abcdef1234567890"));
+    }
+
+    #[test]
+    fn test_known_capabilities() {
+        let gpt4o = ModelInfo::new("openai/gpt-4o", "GPT-4o", "OpenAI");
+        assert!(gpt4o.capabilities.supports_vision);
+        assert!(gpt4o.capabilities.supports_tools);
+        assert!(gpt4o.capabilities.supports_streaming);
+        assert_eq!(gpt4o.context_length, 128_000);
+
+        let haiku = known_capabilities("claude/claude-3-5-haiku-20241022");
+        assert!(!haiku.supports_vision);
+        assert!(haiku.supports_tools);
+        assert!(known_capabilities("openrouter/openai/gpt-4o").supports_vision);
+        assert!(!known_capabilities("ollama/llama3").supports_tools);
+        assert!(known_capabilities("ollama/llama3.1:8b").supports_tools);
+        assert_eq!(known_context_length("openrouter/anthropic/claude-sonnet-4"), 200_000);
+    }
+
+    #[tokio::test]
+    async fn test_next_chunk_times_out_only_before_first_token() {
+        let mut silent = futures::stream::pending::<u8>();
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(matches!(
+            next_chunk(&mut silent, Some(deadline)).await,
+            Err(LlmError::FirstTokenTimeout)
+        ));
+
+        let mut flowing = futures::stream::iter([1u8, 2]);
+        assert_eq!(next_chunk(&mut flowing, Some(deadline)).await.unwrap(), Some(1));
+        // Past the deadline, but text already started
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(next_chunk(&mut flowing, None).await.unwrap(), Some(2));
+    }
+}
@@ -0,0 +1,250 @@
+//! Ollama's native API, for model management. Chat goes through its
+//! OpenAI-compatible `/v1` endpoint instead (see [`super::openai`]).
+
+use super::{LlmError, ModelInfo};
+use crate::debug_log;
+use futures::StreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_HOST: &str = "http://localhost:11434";
+
+/// Tag Ollama assumes when a model name has none.
+const DEFAULT_TAG: &str = "latest";
+
+#[derive(Debug, Deserialize)]
+struct TagsResponse {
+    #[serde(default)]
+    models: Vec<TagModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagModel {
+    name: String,
+}
+
+/// One line of `/api/pull` output. Download lines carry byte counts for the
+/// layer named by `digest`; the rest only have a status.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PullProgress {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PullLine {
+    Error { error: String },
+    Progress(PullProgress),
+}
+
+/// Names of the locally installed models, e.g. "llama3:latest".
+pub async fn installed_models(host: &str) -> Result<Vec<String>, LlmError> {
+    let resp = debug_log::send(Client::new().get(format!("{}/api/tags", host))).await?;
+    if !resp.status().is_success() {
+        let status = resp.status().as_u16();
+        let message = debug_log::error_text(resp).await;
+        return Err(LlmError::Api { status, message });
+    }
+    let tags: TagsResponse = resp
+        .json()
+        .await
+        .map_err(|e| LlmError::Parse(e.to_string()))?;
+    Ok(tags.models.into_iter().map(|m| m.name).collect())
+}
+
+/// What `/api/show` reports about an installed model.
+#[derive(Debug, Default, PartialEq)]
+pub struct ModelDetails {
+    /// E.g. "completion", "vision", "tools". Empty on Ollama versions that
+    /// predate the field.
+    pub capabilities: Vec<String>,
+    pub context_length: Option<usize>,
+}
+
+pub async fn show(host: &str, name: &str) -> Result<ModelDetails, LlmError> {
+    let req = Client::new()
+        .post(format!("{}/api/show", host))
+        .json(&serde_json::json!({ "model": name }));
+    let resp = debug_log::send(req).await?;
+    if !resp.status().is_success() {
+        let status = resp.status().as_u16();
+        let message = debug_log::error_text(resp).await;
+        return Err(LlmError::Api { status, message });
+    }
+    let body: serde_json::Value = resp
+        .json()
+        .await
+        .map_err(|e| LlmError::Parse(e.to_string()))?;
+    Ok(parse_show(&body))
+}
+
+fn parse_show(body: &serde_json::Value) -> ModelDetails {
+    let capabilities = body["capabilities"]
+        .as_array()
+        .map(|caps| caps.iter().filter_map(|c| c.as_str().map(String::from)).collect())
+        .unwrap_or_default();
+    // Keyed by architecture, e.g. "llama.context_length"
+    let context_length = body["model_info"].as_object().and_then(|info| {
+        info.iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64())
+            .map(|n| n as usize)
+    });
+    ModelDetails {
+        capabilities,
+        context_length,
+    }
+}
+
+/// Fill in what Ollama reports about each `ollama/...` model, keeping the
+/// built-in guesses for models it can't describe.
+pub async fn describe_models(host: &str, models: &mut [ModelInfo]) {
+    let details = futures::future::join_all(models.iter().map(|m| {
+        let name = m.id.strip_prefix("ollama/").unwrap_or(&m.id);
+        show(host, name)
+    }))
+    .await;
+    for (model, details) in models.iter_mut().zip(details) {
+        let Ok(details) = details else {
+            continue;
+        };
+        if !details.capabilities.is_empty() {
+            let has = |c: &str| details.capabilities.iter().any(|d| d == c);
+            model.capabilities.supports_vision = has("vision");
+            model.capabilities.supports_tools = has("tools");
+        }
+        if let Some(context_length) = details.context_length {
+            model.context_length = context_length;
+        }
+    }
+}
+
+/// Whether `name` is among `installed`, treating an untagged name as
+/// `:latest` the way Ollama does.
+pub fn is_installed(installed: &[String], name: &str) -> bool {
+    let wanted = with_tag(name);
+    installed.iter().any(|m| with_tag(m) == wanted)
+}
+
+fn with_tag(name: &str) -> String {
+    if name.contains(':') {
+        name.to_string()
+    } else {
+        format!("{}:{}", name, DEFAULT_TAG)
+    }
+}
+
+/// Download `name`, calling `on_progress` for every status line. Returns
+/// once Ollama reports success; an error line ends the pull with an error.
+pub async fn pull(
+    host: &str,
+    name: &str,
+    on_progress: impl Fn(PullProgress) + Send,
+) -> Result<(), LlmError> {
+    let req = Client::new()
+        .post(format!("{}/api/pull", host))
+        .json(&serde_json::json!({ "model": name, "stream": true }));
+    let resp = debug_log::send(req).await?;
+    if !resp.status().is_success() {
+        let status = resp.status().as_u16();
+        let message = debug_log::error_text(resp).await;
+        return Err(LlmError::Api { status, message });
+    }
+
+    // Newline-delimited JSON; a line can span chunks
+    let mut stream = resp.bytes_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut succeeded = false;
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk?);
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            succeeded |= handle_line(&line, &on_progress)?;
+        }
+    }
+    succeeded |= handle_line(&buffer, &on_progress)?;
+    if succeeded {
+        Ok(())
+    } else {
+        Err(LlmError::Parse("Pull ended before Ollama reported success".into()))
+    }
+}
+
+/// Parse one pull line; `true` once it reports success.
+fn handle_line(line: &[u8], on_progress: &impl Fn(PullProgress)) -> Result<bool, LlmError> {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(false);
+    }
+    match serde_json::from_str::<PullLine>(line) {
+        Ok(PullLine::Error { error }) => Err(LlmError::Api {
+            status: 500,
+            message: error,
+        }),
+        Ok(PullLine::Progress(progress)) => {
+            let done = progress.status == "success";
+            on_progress(progress);
+            Ok(done)
+        }
+        Err(e) => Err(LlmError::Parse(format!("Unexpected pull output: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_untagged_names_match_latest() {
+        let installed = vec!["llama3:latest".to_string(), "qwen2.5:7b".to_string()];
+        assert!(is_installed(&installed, "llama3"));
+        assert!(is_installed(&installed, "llama3:latest"));
+        assert!(is_installed(&installed, "qwen2.5:7b"));
+        assert!(!is_installed(&installed, "qwen2.5"));
+        assert!(!is_installed(&installed, "mistral"));
+    }
+
+    #[test]
+    fn test_show_reports_capabilities_and_context() {
+        let body = serde_json::json!({
+            "capabilities": ["completion", "vision"],
+            "model_info": {"general.architecture": "gemma3", "gemma3.context_length": 131072}
+        });
+        assert_eq!(
+            parse_show(&body),
+            ModelDetails {
+                capabilities: vec!["completion".into(), "vision".into()],
+                context_length: Some(131072),
+            }
+        );
+        assert_eq!(parse_show(&serde_json::json!({"modelfile": ""})), ModelDetails::default());
+    }
+
+    #[test]
+    fn test_pull_lines_report_progress_and_errors() {
+        let seen = Mutex::new(Vec::new());
+        let record = |p: PullProgress| seen.lock().unwrap().push(p);
+        let download = br#"{"status":"pulling 6a0746a1ec1a","digest":"sha256:6a07","total":4661211424,"completed":1048576}"#;
+        assert!(!handle_line(download, &record).unwrap());
+        assert!(!handle_line(b"  \n", &record).unwrap());
+        assert!(handle_line(br#"{"status":"success"}"#, &record).unwrap());
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].total, Some(4661211424));
+        assert_eq!(seen[0].completed, Some(1048576));
+
+        match handle_line(br#"{"error":"pull model manifest: file does not exist"}"#, &|_| {}) {
+            Err(LlmError::Api { message, .. }) => assert!(message.contains("does not exist")),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+    }
+}
@@ -1,12 +1,58 @@
-use super::{ChatRequest, ChatResponse, LlmError, StreamChunk};
-use futures::StreamExt;
-use reqwest::Client;
+use super::{
+    first_token_deadline, is_json_body, is_length_finish, next_chunk, ChatRequest, ChatResponse,
+    replayable_messages, LineBuffer, LlmError, ModelInfo, StreamChunk, ToolCall,
+};
+use crate::debug_log;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+
+/// How long a fetched model list is reused before hitting `/models` again.
+const MODELS_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Debug, Clone)]
 pub struct OpenAiConfig {
     pub api_key: String,
     pub base_url: String,
+    /// Sent with every request, for gateways that want e.g. `HTTP-Referer`.
+    pub extra_headers: Vec<(String, String)>,
+}
+
+impl OpenAiConfig {
+    /// Add the bearer token (when set) and any extra headers.
+    pub(crate) fn apply_headers(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if !self.api_key.is_empty() {
+            req = req.header("Authorization", format!("Bearer {}", self.api_key));
+        }
+        for (name, value) in &self.extra_headers {
+            req = req.header(name, value);
+        }
+        req
+    }
+}
+
+/// Parse the `openai_extra_headers` setting, a JSON object of header names to
+/// values. Names and values must be valid HTTP headers; non-ASCII values are
+/// rejected here rather than failing later on send.
+pub fn parse_extra_headers(json: &str) -> Result<Vec<(String, String)>, String> {
+    let map: std::collections::BTreeMap<String, String> = serde_json::from_str(json)
+        .map_err(|e| format!("Extra headers must be a JSON object of strings: {}", e))?;
+    for (name, value) in &map {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name: {:?}", name))?;
+        // `HeaderValue` tolerates raw Latin-1 bytes, which servers decode
+        // inconsistently, so insist on ASCII
+        if !value.is_ascii() || HeaderValue::from_str(value).is_err() {
+            return Err(format!(
+                "Invalid value for header {}: only visible ASCII characters are allowed",
+                name
+            ));
+        }
+    }
+    Ok(map.into_iter().collect())
 }
 
 #[derive(Serialize)]
@@ -14,12 +60,73 @@ struct OpenAiRequest {
     model: String,
     messages: Vec<OpenAiMessage>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 struct OpenAiMessage {
     role: String,
-    content: String,
+    content: OpenAiContent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OpenAiToolCall>,
+}
+
+/// A tool call an assistant turn made, in the shape OpenAI-style APIs take
+/// it back in the history.
+#[derive(Serialize)]
+pub(super) struct OpenAiToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: OpenAiFunctionCall,
+}
+
+#[derive(Serialize)]
+struct OpenAiFunctionCall {
+    name: String,
+    arguments: String,
+}
+
+impl From<&ToolCall> for OpenAiToolCall {
+    fn from(call: &ToolCall) -> Self {
+        Self {
+            id: call.id.clone(),
+            kind: "function",
+            function: OpenAiFunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            },
+        }
+    }
+}
+
+/// Plain text, or a content-part array when the message carries images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OpenAiContent {
+    Text(String),
+    Parts(Vec<OpenAiContentPart>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OpenAiContentPart {
+    Text { text: String },
+    ImageUrl { image_url: OpenAiImageUrl },
+}
+
+#[derive(Serialize)]
+struct OpenAiImageUrl {
+    url: String,
 }
 
 #[derive(Deserialize)]
@@ -29,7 +136,14 @@ struct OpenAiResponse {
 
 #[derive(Deserialize)]
 struct OpenAiChoice {
-    message: OpenAiMessage,
+    message: OpenAiResponseMessage,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiResponseMessage {
+    content: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -48,37 +162,74 @@ struct OpenAiDelta {
     content: Option<String>,
 }
 
-pub async fn chat(config: &OpenAiConfig, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
-    let client = Client::new();
-    let messages: Vec<OpenAiMessage> = request
-        .messages
+fn build_messages(request: &ChatRequest) -> Vec<OpenAiMessage> {
+    replayable_messages(&request.messages)
         .iter()
-        .map(|m| OpenAiMessage {
-            role: m.role.clone(),
-            content: m.content.clone(),
+        .map(|m| {
+            let content = if m.attachments.is_empty() {
+                OpenAiContent::Text(m.content.clone())
+            } else {
+                let mut parts = vec![OpenAiContentPart::Text {
+                    text: m.content.clone(),
+                }];
+                parts.extend(m.attachments.iter().map(|a| OpenAiContentPart::ImageUrl {
+                    image_url: OpenAiImageUrl { url: a.data_url() },
+                }));
+                OpenAiContent::Parts(parts)
+            };
+            OpenAiMessage {
+                role: m.role.clone(),
+                content,
+                tool_call_id: m.tool_call_id.clone(),
+                tool_calls: m.tool_calls.iter().map(Into::into).collect(),
+            }
         })
-        .collect();
+        .collect()
+}
 
-    let body = OpenAiRequest {
+fn build_body(request: &ChatRequest, stream: bool) -> OpenAiRequest {
+    OpenAiRequest {
         model: request.model.clone(),
-        messages,
-        stream: false,
-    };
+        messages: build_messages(request),
+        stream,
+        temperature: request.params.temperature,
+        max_tokens: request.params.max_tokens,
+        top_p: request.params.top_p,
+        stop: request.params.stop.clone(),
+    }
+}
+
+/// Text of the first choice, telling "no choices at all" apart from a choice
+/// whose `content` is null or blank (e.g. a filtered or tool-only reply).
+fn response_content(data: OpenAiResponse) -> Result<String, LlmError> {
+    let choice = data
+        .choices
+        .into_iter()
+        .next()
+        .ok_or_else(|| LlmError::EmptyResponse("provider returned no choices".into()))?;
+    match choice.message.content {
+        Some(content) if !content.trim().is_empty() => Ok(content),
+        _ => Err(LlmError::EmptyResponse(
+            "first choice has an empty content field".into(),
+        )),
+    }
+}
 
-    let mut req = client
+/// `POST /chat/completions` for `request`, with auth and extra headers.
+pub(crate) fn chat_request(config: &OpenAiConfig, request: &ChatRequest, stream: bool) -> RequestBuilder {
+    let req = Client::new()
         .post(format!("{}/chat/completions", config.base_url))
         .header("Content-Type", "application/json")
-        .json(&body);
-
-    if !config.api_key.is_empty() {
-        req = req.header("Authorization", format!("Bearer {}", config.api_key));
-    }
+        .json(&build_body(request, stream));
+    config.apply_headers(req)
+}
 
-    let resp = req.send().await?;
+pub async fn chat(config: &OpenAiConfig, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+    let resp = debug_log::send(chat_request(config, request, false)).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api {
             status,
             message: text,
@@ -86,77 +237,84 @@ pub async fn chat(config: &OpenAiConfig, request: &ChatRequest) -> Result<ChatRe
     }
 
     let data: OpenAiResponse = resp.json().await?;
-    let content = data
+    let truncated = data
         .choices
         .first()
-        .map(|c| c.message.content.clone())
-        .unwrap_or_default();
+        .is_some_and(|c| is_length_finish(c.finish_reason.as_deref()));
+    let content = response_content(data)?;
 
     Ok(ChatResponse {
         content,
         model: request.model.clone(),
+        usage: None,
+        truncated,
     })
 }
 
 pub async fn chat_stream(
     config: &OpenAiConfig,
     request: &ChatRequest,
-    on_chunk: impl Fn(StreamChunk) + Send,
+    tx: Sender<StreamChunk>,
 ) -> Result<String, LlmError> {
-    let client = Client::new();
-    let messages: Vec<OpenAiMessage> = request
-        .messages
-        .iter()
-        .map(|m| OpenAiMessage {
-            role: m.role.clone(),
-            content: m.content.clone(),
-        })
-        .collect();
-
-    let body = OpenAiRequest {
-        model: request.model.clone(),
-        messages,
-        stream: true,
-    };
-
-    let mut req = client
-        .post(format!("{}/chat/completions", config.base_url))
-        .header("Content-Type", "application/json")
-        .json(&body);
-
-    if !config.api_key.is_empty() {
-        req = req.header("Authorization", format!("Bearer {}", config.api_key));
-    }
-
-    let resp = req.send().await?;
+    let resp = debug_log::send(chat_request(config, request, true)).await?;
 
     if !resp.status().is_success() {
         let status = resp.status().as_u16();
-        let text = resp.text().await.unwrap_or_default();
+        let text = debug_log::error_text(resp).await;
         return Err(LlmError::Api {
             status,
             message: text,
         });
     }
 
+    // Endpoint answered without streaming: deliver the whole reply at once
+    if is_json_body(&resp) {
+        let data: OpenAiResponse = resp.json().await.map_err(|e| LlmError::Parse(e.to_string()))?;
+        let truncated = data
+            .choices
+            .first()
+            .is_some_and(|c| is_length_finish(c.finish_reason.as_deref()));
+        let content = response_content(data)?;
+        let _ = tx.send(StreamChunk {
+            delta: content.clone(),
+            done: false,
+            usage: None,
+            truncated: false,
+        }).await;
+        let _ = tx.send(StreamChunk {
+            delta: String::new(),
+            done: true,
+            usage: None,
+            truncated,
+        }).await;
+        return Ok(content);
+    }
+
     let mut full_content = String::new();
     let mut stream = resp.bytes_stream();
-    let mut buffer = String::new();
+    let mut lines = LineBuffer::default();
+    let mut ended = false;
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        buffer.push_str(&String::from_utf8_lossy(&chunk));
+    let deadline = first_token_deadline();
 
-        while let Some(pos) = buffer.find('\n') {
-            let line = buffer[..pos].trim().to_string();
-            buffer = buffer[pos + 1..].to_string();
+    while !ended {
+        match next_chunk(&mut stream, deadline.filter(|_| full_content.is_empty())).await? {
+            Some(chunk) => lines.push(&chunk?),
+            None => {
+                lines.finish();
+                ended = true;
+            }
+        }
 
+        while let Some(line) = lines.next_line() {
             if let Some(data) = line.strip_prefix("data: ") {
                 if data == "[DONE]" {
-                    on_chunk(StreamChunk {
+                    let _ = tx.send(StreamChunk {
                         delta: String::new(),
                         done: true,
-                    });
+                        usage: None,
+                        truncated: false,
+                    }).await;
                     return Ok(full_content);
                 }
 
@@ -164,16 +322,20 @@ pub async fn chat_stream(
                     if let Some(choice) = parsed.choices.first() {
                         if let Some(content) = &choice.delta.content {
                             full_content.push_str(content);
-                            on_chunk(StreamChunk {
+                            let _ = tx.send(StreamChunk {
                                 delta: content.clone(),
                                 done: false,
-                            });
+                                usage: None,
+                                truncated: false,
+                            }).await;
                         }
                         if choice.finish_reason.is_some() {
-                            on_chunk(StreamChunk {
+                            let _ = tx.send(StreamChunk {
                                 delta: String::new(),
                                 done: true,
-                            });
+                                usage: None,
+                                truncated: is_length_finish(choice.finish_reason.as_deref()),
+                            }).await;
                             return Ok(full_content);
                         }
                     }
@@ -182,9 +344,453 @@ pub async fn chat_stream(
         }
     }
 
-    on_chunk(StreamChunk {
+    let _ = tx.send(StreamChunk {
         delta: String::new(),
         done: true,
-    });
+        usage: None,
+        truncated: false,
+    }).await;
     Ok(full_content)
 }
+
+// ── Models ──
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+    /// Reported by OpenRouter, like `architecture` and `supported_parameters`.
+    #[serde(default)]
+    context_length: Option<usize>,
+    #[serde(default)]
+    architecture: Option<ModelArchitecture>,
+    #[serde(default)]
+    supported_parameters: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct ModelArchitecture {
+    #[serde(default)]
+    input_modalities: Vec<String>,
+}
+
+/// `ModelInfo` for a listed model, preferring what the server reports over
+/// the built-in table.
+fn model_info(entry: ModelEntry, prefix: &str, provider: &str) -> ModelInfo {
+    let mut info = ModelInfo::new(format!("{}/{}", prefix, entry.id), entry.id, provider);
+    if let Some(context_length) = entry.context_length {
+        info.context_length = context_length;
+    }
+    if let Some(architecture) = entry.architecture {
+        info.capabilities.supports_vision = architecture.input_modalities.iter().any(|m| m == "image");
+    }
+    if let Some(parameters) = entry.supported_parameters {
+        info.capabilities.supports_tools = parameters.iter().any(|p| p == "tools");
+    }
+    info
+}
+
+struct CachedModels {
+    base_url: String,
+    /// The ids are stored with it, so each provider sharing a server gets
+    /// its own entry.
+    prefix: String,
+    fetched_at: Instant,
+    models: Vec<ModelInfo>,
+}
+
+/// One entry per base URL and prefix, so OpenAI and Ollama lists don't evict
+/// each other.
+static MODELS_CACHE: Mutex<Vec<CachedModels>> = Mutex::new(Vec::new());
+
+/// Model families served by `/models` that can't be used with chat completions.
+const NON_CHAT_MARKERS: &[&str] = &[
+    "embedding",
+    "whisper",
+    "tts",
+    "dall-e",
+    "davinci",
+    "babbage",
+    "moderation",
+    "transcribe",
+    "realtime",
+    "audio",
+    "image",
+];
+
+fn is_chat_model(id: &str) -> bool {
+    let id = id.to_lowercase();
+    !NON_CHAT_MARKERS.iter().any(|m| id.contains(m))
+}
+
+/// Check reachability and credentials with an uncached `GET {base_url}/models`.
+pub async fn ping(config: &OpenAiConfig) -> Result<(), LlmError> {
+    let req = config.apply_headers(Client::new().get(format!("{}/models", config.base_url)));
+    super::check_status(debug_log::send(req).await?).await
+}
+
+/// Drop the cached model lists for `base_url`, e.g. after installing a model.
+pub fn forget_cached_models(base_url: &str) {
+    MODELS_CACHE.lock().unwrap().retain(|c| c.base_url != base_url);
+}
+
+/// Drop the cached model lists mapped to `prefix`, e.g. after its API key
+/// or base URL changed.
+pub fn forget_cached_models_for(prefix: &str) {
+    MODELS_CACHE.lock().unwrap().retain(|c| c.prefix != prefix);
+}
+
+/// Fetch the chat-capable models exposed by `{base_url}/models` as
+/// `openai/<id>` entries.
+pub async fn fetch_openai_models(config: &OpenAiConfig) -> Result<Vec<ModelInfo>, LlmError> {
+    fetch_models(config, "openai", "OpenAI").await
+}
+
+/// Fetch the chat-capable models of any OpenAI-compatible server (OpenAI,
+/// Ollama, LM Studio, vLLM), mapped to `{prefix}/<id>`. Results are cached
+/// per base URL and prefix for `MODELS_CACHE_TTL`.
+pub async fn fetch_models(
+    config: &OpenAiConfig,
+    prefix: &str,
+    provider: &str,
+) -> Result<Vec<ModelInfo>, LlmError> {
+    {
+        let cache = MODELS_CACHE.lock().unwrap();
+        if let Some(cached) = cache
+            .iter()
+            .find(|c| c.base_url == config.base_url && c.prefix == prefix)
+        {
+            if cached.fetched_at.elapsed() < MODELS_CACHE_TTL {
+                return Ok(cached.models.clone());
+            }
+        }
+    }
+
+    let client = Client::new();
+    let mut req = client.get(format!("{}/models", config.base_url));
+    req = config.apply_headers(req);
+    let resp = debug_log::send(req).await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status().as_u16();
+        let text = debug_log::error_text(resp).await;
+        return Err(LlmError::Api {
+            status,
+            message: format!("Failed to fetch models: {}", text),
+        });
+    }
+
+    let list: ModelList = resp.json().await.map_err(|e| LlmError::Parse(e.to_string()))?;
+    let mut models: Vec<ModelInfo> = list
+        .data
+        .into_iter()
+        .filter(|m| is_chat_model(&m.id))
+        .map(|m| model_info(m, prefix, provider))
+        .collect();
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+
+    {
+        let mut cache = MODELS_CACHE.lock().unwrap();
+        cache.retain(|c| c.base_url != config.base_url || c.prefix != prefix);
+        cache.push(CachedModels {
+            base_url: config.base_url.clone(),
+            prefix: prefix.to_string(),
+            fetched_at: Instant::now(),
+            models: models.clone(),
+        });
+    }
+
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{Attachment, ChatMessage, GenerationParams, STREAM_CHANNEL_CAPACITY};
+    use crate::test_util::{serve, Reply};
+
+    /// Run `chat_stream` to the end, collecting the chunks as they arrive.
+    async fn streamed(config: &OpenAiConfig, request: &ChatRequest) -> (String, Vec<StreamChunk>) {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        let collect = async move {
+            let mut chunks = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                chunks.push(chunk);
+            }
+            chunks
+        };
+        let (content, chunks) =
+            futures::future::join(chat_stream(config, request, tx), collect).await;
+        (content.unwrap(), chunks)
+    }
+
+    /// Serve one canned HTTP response on a local port and return its base URL.
+    fn serve_once(content_type: &'static str, body: impl Into<String>) -> String {
+        serve_times(1, content_type, body)
+    }
+
+    /// Like `serve_once`, answering `count` requests with the same response.
+    fn serve_times(count: usize, content_type: &'static str, body: impl Into<String>) -> String {
+        let body = body.into();
+        serve(count, move |_| Reply::ok(content_type, body.clone()))
+    }
+
+    #[tokio::test]
+    async fn test_model_cache_is_per_prefix() {
+        // Three fetches reach the server; any more would be refused
+        let config = OpenAiConfig {
+            api_key: String::new(),
+            base_url: serve_times(3, "application/json", r#"{"data": [{"id": "gpt-x"}]}"#),
+            extra_headers: Vec::new(),
+        };
+        let ids = |models: Vec<ModelInfo>| models.into_iter().map(|m| m.id).collect::<Vec<_>>();
+
+        assert_eq!(ids(fetch_models(&config, "openai", "OpenAI").await.unwrap()), ["openai/gpt-x"]);
+        assert_eq!(ids(fetch_models(&config, "custom", "Custom").await.unwrap()), ["custom/gpt-x"]);
+        forget_cached_models_for("custom");
+        assert_eq!(ids(fetch_models(&config, "custom", "Custom").await.unwrap()), ["custom/gpt-x"]);
+        // Still cached, untouched by forgetting the other prefix
+        assert_eq!(ids(fetch_models(&config, "openai", "OpenAI").await.unwrap()), ["openai/gpt-x"]);
+        assert_eq!(ids(fetch_models(&config, "custom", "Custom").await.unwrap()), ["custom/gpt-x"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_request_answered_with_plain_json() {
+        let base_url = serve_once(
+            "application/json; charset=utf-8",
+            r#"{"choices": [{"message": {"role": "assistant", "content": "Not streamed"}}]}"#,
+        );
+        let config = OpenAiConfig {
+            api_key: String::new(),
+            base_url,
+            extra_headers: Vec::new(),
+        };
+        let request = ChatRequest {
+            messages: vec![ChatMessage::user("Hi")],
+            model: "proxy-model".into(),
+            stream: true,
+            params: Default::default(),
+        };
+
+        let (content, chunks) = streamed(&config, &request).await;
+        assert_eq!(content, "Not streamed");
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].delta, "Not streamed");
+        assert!(!chunks[0].done);
+        assert!(chunks[1].done);
+    }
+
+    #[tokio::test]
+    async fn test_stream_of_many_tiny_deltas() {
+        const DELTAS: usize = 5_000;
+        let mut body: String = (0..DELTAS)
+            .map(|i| format!("data: {{\"choices\": [{{\"delta\": {{\"content\": \"{}\"}}}}]}}\n\n", i % 10))
+            .collect();
+        // No trailing newline after the last event
+        body.push_str(r#"data: {"choices": [{"delta": {"content": "!"}, "finish_reason": "stop"}]}"#);
+        let config = OpenAiConfig {
+            api_key: String::new(),
+            base_url: serve_once("text/event-stream", body),
+            extra_headers: Vec::new(),
+        };
+        let request = ChatRequest {
+            messages: Vec::new(),
+            model: "proxy-model".into(),
+            stream: true,
+            params: Default::default(),
+        };
+
+        let (content, chunks) = streamed(&config, &request).await;
+        let expected: String = (0..DELTAS).map(|i| char::from(b'0' + (i % 10) as u8)).collect();
+        assert_eq!(content, expected + "!");
+        assert_eq!(chunks.len(), DELTAS + 2);
+        assert!(chunks.last().unwrap().done);
+    }
+
+    #[tokio::test]
+    async fn test_closure_stream_sees_every_chunk() {
+        let config = OpenAiConfig {
+            api_key: String::new(),
+            base_url: serve_once(
+                "text/event-stream",
+                "data: {\"choices\": [{\"delta\": {\"content\": \"Hel\"}}]}\n\ndata: {\"choices\": [{\"delta\": {\"content\": \"lo\"}}]}\n\ndata: [DONE]\n\n",
+            ),
+            extra_headers: Vec::new(),
+        };
+        let request = ChatRequest {
+            messages: Vec::new(),
+            model: "proxy-model".into(),
+            stream: true,
+            params: Default::default(),
+        };
+
+        let chunks = Mutex::new(Vec::new());
+        let content = crate::llm::Provider::OpenAi(config)
+            .chat_stream(&request, |chunk| chunks.lock().unwrap().push(chunk))
+            .await
+            .unwrap();
+        assert_eq!(content, "Hello");
+        let chunks = chunks.into_inner().unwrap();
+        let deltas: Vec<&str> = chunks.iter().map(|c| c.delta.as_str()).collect();
+        assert_eq!(deltas, ["Hel", "lo", ""]);
+        assert!(chunks[2].done);
+    }
+
+    #[tokio::test]
+    async fn test_length_finish_marks_stream_truncated() {
+        let base_url = serve_once(
+            "text/event-stream",
+            concat!(
+                "data: {\"choices\": [{\"delta\": {\"content\": \"Once upon\"}, \"finish_reason\": null}]}\n\n",
+                "data: {\"choices\": [{\"delta\": {}, \"finish_reason\": \"length\"}]}\n\n",
+            ),
+        );
+        let config = OpenAiConfig {
+            api_key: String::new(),
+            base_url,
+            extra_headers: Vec::new(),
+        };
+        let request = ChatRequest {
+            messages: vec![ChatMessage::user("Tell me a story")],
+            model: "gpt-4o".into(),
+            stream: true,
+            params: Default::default(),
+        };
+
+        let (content, chunks) = streamed(&config, &request).await;
+        assert_eq!(content, "Once upon");
+        let last = chunks.last().unwrap();
+        assert!(last.done);
+        assert!(last.truncated);
+
+        assert!(is_length_finish(Some("max_tokens")));
+        assert!(!is_length_finish(Some("stop")));
+        assert!(!is_length_finish(None));
+    }
+
+    #[test]
+    fn test_image_message_uses_content_parts() {
+        let request = ChatRequest {
+            messages: vec![ChatMessage {
+                attachments: vec![Attachment {
+                    media_type: "image/png".into(),
+                    data: "AAAA".into(),
+                }],
+                ..ChatMessage::user("What is this?")
+            }],
+            model: "gpt-4o".into(),
+            stream: false,
+            params: Default::default(),
+        };
+        let json = serde_json::to_value(build_messages(&request)).unwrap();
+        assert_eq!(json[0]["content"][0]["type"], "text");
+        assert_eq!(json[0]["content"][1]["type"], "image_url");
+        assert_eq!(json[0]["content"][1]["image_url"]["url"], "data:image/png;base64,AAAA");
+    }
+
+    #[test]
+    fn test_tool_calls_are_replayed_before_results() {
+        let request = ChatRequest {
+            messages: vec![
+                ChatMessage {
+                    role: "assistant".into(),
+                    content: String::new(),
+                    attachments: Vec::new(),
+                    tool_call_id: None,
+                    tool_calls: vec![ToolCall {
+                        id: "call_1".into(),
+                        name: "multiply".into(),
+                        arguments: r#"{"a":6,"b":7}"#.into(),
+                    }],
+                },
+                ChatMessage {
+                    role: "tool".into(),
+                    content: "42".into(),
+                    attachments: Vec::new(),
+                    tool_call_id: Some("call_1".into()),
+                    tool_calls: Vec::new(),
+                },
+            ],
+            model: "gpt-4o".into(),
+            stream: false,
+            params: Default::default(),
+        };
+        let json = serde_json::to_value(build_messages(&request)).unwrap();
+        let call = &json[0]["tool_calls"][0];
+        assert_eq!(call["id"], "call_1");
+        assert_eq!(call["type"], "function");
+        assert_eq!(call["function"]["name"], "multiply");
+        assert_eq!(call["function"]["arguments"], r#"{"a":6,"b":7}"#);
+        assert_eq!(json[1]["role"], "tool");
+        assert_eq!(json[1]["tool_call_id"], "call_1");
+        assert!(json[1].get("tool_calls").is_none());
+    }
+
+    fn text_request(params: GenerationParams) -> ChatRequest {
+        ChatRequest {
+            messages: vec![ChatMessage::user("List three colors as JSON")],
+            model: "gpt-4o".into(),
+            stream: true,
+            params,
+        }
+    }
+
+    #[test]
+    fn test_stop_serialized_only_when_set() {
+        let json = serde_json::to_value(build_body(&text_request(Default::default()), true)).unwrap();
+        assert!(json.get("stop").is_none());
+        assert!(json.get("temperature").is_none());
+
+        let params = GenerationParams {
+            temperature: Some(0.2),
+            stop: vec!["```".into(), "\n\n".into()],
+            ..Default::default()
+        };
+        let json = serde_json::to_value(build_body(&text_request(params), true)).unwrap();
+        assert_eq!(json["stop"], serde_json::json!(["```", "\n\n"]));
+        assert!((json["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+        assert!(json.get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn test_empty_choices_and_content_are_distinct_errors() {
+        let parse = |json: &str| response_content(serde_json::from_str(json).unwrap());
+        let err = parse(r#"{"choices": []}"#).unwrap_err();
+        assert!(err.to_string().contains("no choices"));
+        let err = parse(r#"{"choices": [{"message": {"content": null}}]}"#).unwrap_err();
+        assert!(err.to_string().contains("empty content"));
+        assert_eq!(
+            parse(r#"{"choices": [{"message": {"content": "Hi"}}]}"#).unwrap(),
+            "Hi"
+        );
+    }
+
+    #[test]
+    fn test_parse_extra_headers_validates_names_and_values() {
+        let headers =
+            parse_extra_headers(r#"{"X-Title": "AI-Box", "HTTP-Referer": "https://example.com"}"#)
+                .unwrap();
+        assert_eq!(headers.len(), 2);
+
+        let err = parse_extra_headers(r#"{"X-Title": "Boîte à IA"}"#).unwrap_err();
+        assert!(err.contains("X-Title"), "{}", err);
+        assert!(parse_extra_headers(r#"{"Bad Name": "x"}"#).is_err());
+        assert!(parse_extra_headers(r#"["X-Title"]"#).is_err());
+    }
+
+    #[test]
+    fn test_is_chat_model_filters_non_chat() {
+        assert!(is_chat_model("gpt-4o"));
+        assert!(is_chat_model("o3-mini"));
+        assert!(is_chat_model("qwen2.5-7b-instruct"));
+        assert!(!is_chat_model("text-embedding-3-small"));
+        assert!(!is_chat_model("whisper-1"));
+        assert!(!is_chat_model("tts-1-hd"));
+        assert!(!is_chat_model("dall-e-3"));
+        assert!(!is_chat_model("omni-moderation-latest"));
+    }
+}
@@ -0,0 +1,56 @@
+//! OS keychain storage for API keys and OAuth tokens.
+//!
+//! Secret settings are kept in the platform credential store (Keychain,
+//! Credential Manager, Secret Service); the `settings` row only holds
+//! `KEYCHAIN_REF` as a pointer. When no keychain is available the value
+//! falls back to the SQLite row so the app keeps working.
+
+const SERVICE: &str = "ai-box";
+
+/// Stored in the `settings` table in place of a value that lives in the keychain.
+pub const KEYCHAIN_REF: &str = "keychain:";
+
+/// Whether a setting key holds a credential that belongs in the keychain.
+pub fn is_secret_key(key: &str) -> bool {
+    key.ends_with("_api_key") || key == "copilot_oauth_token" || key == "copilot_api_token"
+}
+
+fn entry(key: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(SERVICE, key)
+}
+
+pub fn store(key: &str, value: &str) -> Result<(), String> {
+    entry(key)
+        .and_then(|e| e.set_password(value))
+        .map_err(|e| e.to_string())
+}
+
+pub fn load(key: &str) -> Result<Option<String>, String> {
+    match entry(key).and_then(|e| e.get_password()) {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn delete(key: &str) -> Result<(), String> {
+    match entry(key).and_then(|e| e.delete_credential()) {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_secret_key() {
+        assert!(is_secret_key("openai_api_key"));
+        assert!(is_secret_key("claude_api_key"));
+        assert!(is_secret_key("copilot_oauth_token"));
+        assert!(is_secret_key("copilot_api_token"));
+        assert!(!is_secret_key("openai_base_url"));
+        assert!(!is_secret_key("default_model"));
+    }
+}
@@ -0,0 +1,724 @@
+//! Optional local HTTP server speaking the OpenAI API, so other tools on the
+//! machine can use the providers and knowledge base configured here. It
+//! serves `GET /v1/models` and `POST /v1/chat/completions` (streamed or not),
+//! and every request must carry `local_server_api_key` as a bearer token.
+//! It binds to localhost unless `local_server_host` says otherwise.
+//!
+//! The HTTP handling is deliberately minimal: one request per connection,
+//! request bodies sized by `Content-Length` and only read once the caller is
+//! authorized, and the connection is closed after the response.
+
+use crate::commands::chat::{resolve_provider, with_system_context};
+use crate::commands::knowledge::rag_context;
+use crate::commands::settings::{available_models, generation_params};
+use crate::db::Database;
+use crate::error::AppError;
+use crate::llm::{
+    Attachment, ChatMessage, ChatRequest, GenerationParams, StreamChunk, TokenUsage, ToolCall,
+    STREAM_CHANNEL_CAPACITY,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 8711;
+/// Shortest `local_server_api_key` accepted, so it can't be guessed quickly.
+pub const MIN_API_KEY_LEN: usize = 16;
+
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+/// Large enough for a few base64 images.
+const MAX_BODY_BYTES: usize = 32 * 1024 * 1024;
+/// Longest a single read or write may stall before the connection is
+/// dropped, so a slow or silent client can't hold it open.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a running server needs to answer requests.
+#[derive(Clone)]
+pub struct ServerContext {
+    /// For RAG, which may embed the query with the local model.
+    pub app: tauri::AppHandle,
+    pub db: Database,
+    pub api_key: String,
+}
+
+/// The running server, if any. Managed as app state.
+#[derive(Default)]
+pub struct LocalServer {
+    running: Mutex<Option<Running>>,
+}
+
+struct Running {
+    addr: SocketAddr,
+    /// Dropping or firing this stops the accept loop.
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl LocalServer {
+    /// Listen on `addr` and serve in the background, replacing any server
+    /// already running. Returns the bound address.
+    pub fn start(&self, addr: (&str, u16), ctx: ServerContext) -> std::io::Result<SocketAddr> {
+        self.stop();
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let (shutdown, stopped) = oneshot::channel();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = serve(listener, ctx, stopped).await {
+                eprintln!("Local API server stopped: {}", e);
+            }
+        });
+        *self.running.lock().unwrap() = Some(Running {
+            addr,
+            _shutdown: shutdown,
+        });
+        Ok(addr)
+    }
+
+    /// Stop accepting connections. Requests already being answered finish.
+    pub fn stop(&self) -> bool {
+        self.running.lock().unwrap().take().is_some()
+    }
+
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.running.lock().unwrap().as_ref().map(|r| r.addr)
+    }
+}
+
+/// Accept connections on `listener` until `stopped` fires or its sender is
+/// dropped, answering each on its own task.
+pub async fn serve(
+    listener: std::net::TcpListener,
+    ctx: ServerContext,
+    mut stopped: oneshot::Receiver<()>,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    loop {
+        match futures::future::select(Box::pin(listener.accept()), &mut stopped).await {
+            futures::future::Either::Left((Ok((socket, _)), _)) => {
+                let ctx = ctx.clone();
+                tokio::spawn(async move { handle_connection(socket, &ctx).await });
+            }
+            futures::future::Either::Left((Err(e), _)) => {
+                eprintln!("Local API server accept failed: {}", e)
+            }
+            futures::future::Either::Right(_) => return Ok(()),
+        }
+    }
+}
+
+async fn handle_connection(mut socket: tokio::net::TcpStream, ctx: &ServerContext) {
+    let result = async {
+        let (mut request, received) = read_head(&mut socket).await?;
+        // Nothing past the headers is read for a caller without the key
+        authorize(&request, &ctx.api_key)?;
+        read_body(&mut socket, &mut request, received).await?;
+        route(&mut socket, &request, ctx).await
+    }
+    .await;
+    // A client that went away can't be told anything
+    if let Err(e) = result {
+        let _ = write_json(&mut socket, e.status, &e.body()).await;
+    }
+    let _ = socket.shutdown().await;
+}
+
+/// An error answered in OpenAI's `{"error": {...}}` shape.
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    kind: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, kind: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            kind,
+            message: message.into(),
+        }
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Self::new(400, "invalid_request_error", message)
+    }
+
+    fn body(&self) -> Value {
+        json!({ "error": { "message": self.message, "type": self.kind, "code": null } })
+    }
+}
+
+impl From<AppError> for ApiError {
+    fn from(e: AppError) -> Self {
+        let status = match &e {
+            AppError::InvalidInput(_) | AppError::NotConfigured(_) => 400,
+            AppError::NotFound(_) => 404,
+            AppError::RateLimited(_) => 429,
+            AppError::Timeout(_) => 504,
+            AppError::Auth(_)
+            | AppError::Network(_)
+            | AppError::Api { .. }
+            | AppError::EmptyResponse(_) => 502,
+            AppError::Cancelled(_) | AppError::Database(_) | AppError::Other(_) => 500,
+        };
+        let kind = if status == 400 { "invalid_request_error" } else { "api_error" };
+        ApiError::new(status, kind, e.to_string())
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        ApiError::new(500, "api_error", e.to_string())
+    }
+}
+
+#[derive(Debug)]
+struct HttpRequest {
+    method: String,
+    /// Without the query string.
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn bearer_token(&self) -> Option<&str> {
+        let value = self.header("authorization")?;
+        let (scheme, token) = value.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+    }
+}
+
+/// One read, giving up after `IO_TIMEOUT`.
+async fn read_some(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    with_timeout(stream.read(buf)).await
+}
+
+async fn with_timeout<T>(
+    io: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(IO_TIMEOUT, io)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "client stalled"))?
+}
+
+/// Read the request line and headers. The request comes back without its
+/// body, alongside whatever body bytes arrived with the headers.
+async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<(HttpRequest, Vec<u8>), ApiError> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let header_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > MAX_HEADER_BYTES {
+            return Err(ApiError::new(
+                431,
+                "invalid_request_error",
+                "Request headers are too large",
+            ));
+        }
+        let n = read_some(stream, &mut chunk).await?;
+        if n == 0 {
+            return Err(ApiError::invalid("Connection closed before the request was complete"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err(ApiError::invalid("Malformed request line"));
+    };
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let request = HttpRequest {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or_default().to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    Ok((request, buf.split_off(header_end + 4)))
+}
+
+/// Read the rest of the `Content-Length` body into `request`, starting from
+/// the bytes `read_head` already `received`.
+async fn read_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut HttpRequest,
+    mut received: Vec<u8>,
+) -> Result<(), ApiError> {
+    let length = match request.header("content-length") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| ApiError::invalid("Invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(ApiError::new(413, "invalid_request_error", "Request body is too large"));
+    }
+    let mut chunk = [0u8; 8192];
+    while received.len() < length {
+        let n = read_some(stream, &mut chunk).await?;
+        if n == 0 {
+            return Err(ApiError::invalid("Connection closed before the body was complete"));
+        }
+        received.extend_from_slice(&chunk[..n]);
+    }
+    received.truncate(length);
+    request.body = received;
+    Ok(())
+}
+
+fn authorize(request: &HttpRequest, api_key: &str) -> Result<(), ApiError> {
+    if request
+        .bearer_token()
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), api_key.as_bytes()))
+    {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        401,
+        "authentication_error",
+        "Missing or incorrect API key. Use the local_server_api_key from ai-box settings.",
+    ))
+}
+
+/// Compares every byte, so the time taken doesn't reveal how much of a
+/// guessed key was right. Only the length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn route(
+    stream: &mut (impl AsyncWrite + Unpin),
+    request: &HttpRequest,
+    ctx: &ServerContext,
+) -> Result<(), ApiError> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/v1/models") => {
+            let models = available_models(&ctx.db).await?;
+            let data: Vec<Value> = models
+                .iter()
+                .map(|m| json!({ "id": m.id, "object": "model", "created": 0, "owned_by": m.provider }))
+                .collect();
+            write_json(stream, 200, &json!({ "object": "list", "data": data })).await?;
+            Ok(())
+        }
+        ("POST", "/v1/chat/completions") => chat_completions(stream, request, ctx).await,
+        (_, "/v1/models" | "/v1/chat/completions") => {
+            Err(ApiError::new(405, "invalid_request_error", "Method not allowed"))
+        }
+        (_, path) => Err(ApiError::new(
+            404,
+            "invalid_request_error",
+            format!("Unknown endpoint {}", path),
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionBody {
+    model: String,
+    messages: Vec<BodyMessage>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    #[serde(alias = "max_completion_tokens")]
+    max_tokens: Option<u32>,
+    top_p: Option<f32>,
+    stop: Option<Stop>,
+    /// Not part of the OpenAI API: answer from the knowledge base. The query
+    /// defaults to the last user message.
+    rag: Option<RagOptions>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RagOptions {
+    query: Option<String>,
+    top_k: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BodyMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<Content>,
+    tool_call_id: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<BodyToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BodyToolCall {
+    id: String,
+    function: BodyFunctionCall,
+}
+
+#[derive(Debug, Deserialize)]
+struct BodyFunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageUrl {
+    url: String,
+}
+
+fn chat_message(message: &BodyMessage) -> Result<ChatMessage, ApiError> {
+    let mut content = String::new();
+    let mut attachments = Vec::new();
+    match &message.content {
+        None => {}
+        Some(Content::Text(text)) => content.push_str(text),
+        Some(Content::Parts(parts)) => {
+            for part in parts {
+                match part {
+                    ContentPart::Text { text } => {
+                        if !content.is_empty() {
+                            content.push('\n');
+                        }
+                        content.push_str(text);
+                    }
+                    ContentPart::ImageUrl { image_url } => {
+                        attachments.push(data_url_attachment(&image_url.url)?)
+                    }
+                }
+            }
+        }
+    }
+    Ok(ChatMessage {
+        role: message.role.clone(),
+        content,
+        attachments,
+        tool_call_id: message.tool_call_id.clone(),
+        tool_calls: message
+            .tool_calls
+            .iter()
+            .map(|call| ToolCall {
+                id: call.id.clone(),
+                name: call.function.name.clone(),
+                arguments: call.function.arguments.clone(),
+            })
+            .collect(),
+    })
+}
+
+/// An image given as a `data:` URL. Remote image URLs aren't fetched.
+fn data_url_attachment(url: &str) -> Result<Attachment, ApiError> {
+    url.strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .map(|(media_type, data)| Attachment {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        })
+        .ok_or_else(|| ApiError::invalid("Only base64 data: URLs are supported for images"))
+}
+
+async fn chat_completions(
+    stream: &mut (impl AsyncWrite + Unpin),
+    request: &HttpRequest,
+    ctx: &ServerContext,
+) -> Result<(), ApiError> {
+    let body: CompletionBody = serde_json::from_slice(&request.body)
+        .map_err(|e| ApiError::invalid(format!("Invalid request body: {}", e)))?;
+    let mut messages = body
+        .messages
+        .iter()
+        .map(chat_message)
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(rag) = &body.rag {
+        let query = match &rag.query {
+            Some(query) => query.clone(),
+            None => messages
+                .iter()
+                .rev()
+                .find(|m| m.role == "user")
+                .map(|m| m.content.clone())
+                .ok_or_else(|| ApiError::invalid("rag needs a query or a user message"))?,
+        };
+        let context = rag_context(&ctx.app, &ctx.db, query, rag.top_k).await?;
+        with_system_context(&mut messages, context);
+    }
+
+    let (provider, model_id) = resolve_provider(&body.model, &ctx.db)?;
+    let request = ChatRequest {
+        messages,
+        model: model_id,
+        stream: body.stream,
+        params: params(&body, generation_params(&ctx.db)),
+    };
+    let id = format!("chatcmpl-{}", uuid::Uuid::new_v4().simple());
+
+    if !body.stream {
+        let response = provider.chat(&request).await.map_err(AppError::from)?;
+        let mut completion = json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": unix_now(),
+            "model": body.model,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": response.content },
+                "finish_reason": if response.truncated { "length" } else { "stop" },
+            }],
+        });
+        if let Some(usage) = response.usage {
+            completion["usage"] = usage_json(&usage);
+        }
+        write_json(stream, 200, &completion).await?;
+        return Ok(());
+    }
+
+    // Headers go out before the provider answers, so errors from here on are
+    // reported as a final event rather than a status code
+    with_timeout(stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+    ))
+    .await?;
+    let event = |delta: Value, finish_reason: Option<&str>| {
+        json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": unix_now(),
+            "model": body.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    };
+    write_event(stream, &event(json!({ "role": "assistant", "content": "" }), None)).await?;
+
+    let (tx, mut rx) = mpsc::channel::<StreamChunk>(STREAM_CHANNEL_CAPACITY);
+    let forward = async {
+        let mut last = None;
+        while let Some(chunk) = rx.recv().await {
+            if !chunk.delta.is_empty() {
+                // The client hung up; let the provider finish unheard
+                let delta = event(json!({ "content": chunk.delta }), None);
+                if write_event(stream, &delta).await.is_err() {
+                    return None;
+                }
+            }
+            if chunk.done {
+                last = Some(chunk);
+            }
+        }
+        Some(last)
+    };
+    let (result, forwarded) =
+        futures::future::join(provider.chat_stream_to(&request, tx), forward).await;
+    let Some(last) = forwarded else {
+        return Ok(());
+    };
+    match result {
+        Ok(_) => {
+            let truncated = last.as_ref().is_some_and(|c| c.truncated);
+            let mut done = event(json!({}), Some(if truncated { "length" } else { "stop" }));
+            if let Some(usage) = last.and_then(|c| c.usage) {
+                done["usage"] = usage_json(&usage);
+            }
+            write_event(stream, &done).await?;
+        }
+        Err(e) => write_event(stream, &ApiError::from(AppError::from(e)).body()).await?,
+    }
+    with_timeout(stream.write_all(b"data: [DONE]\n\n")).await?;
+    Ok(())
+}
+
+/// The body's sampling parameters, falling back to the app's generation
+/// settings for any it leaves out.
+fn params(body: &CompletionBody, defaults: GenerationParams) -> GenerationParams {
+    GenerationParams {
+        temperature: body.temperature.or(defaults.temperature),
+        max_tokens: body.max_tokens.or(defaults.max_tokens),
+        top_p: body.top_p.or(defaults.top_p),
+        stop: match &body.stop {
+            Some(Stop::One(stop)) => vec![stop.clone()],
+            Some(Stop::Many(stops)) => stops.clone(),
+            None => defaults.stop,
+        },
+    }
+}
+
+fn usage_json(usage: &TokenUsage) -> Value {
+    let prompt = usage.input_tokens + usage.cache_creation_input_tokens + usage.cache_read_input_tokens;
+    json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": usage.output_tokens,
+        "total_tokens": prompt + usage.output_tokens,
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+async fn write_json(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    body: &Value,
+) -> std::io::Result<()> {
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status),
+        body.len()
+    );
+    with_timeout(stream.write_all(head.as_bytes())).await?;
+    with_timeout(stream.write_all(body.as_bytes())).await
+}
+
+async fn write_event(stream: &mut (impl AsyncWrite + Unpin), event: &Value) -> std::io::Result<()> {
+    with_timeout(stream.write_all(format!("data: {}\n\n", event).as_bytes())).await?;
+    with_timeout(stream.flush()).await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_request(mut raw: &[u8]) -> Result<HttpRequest, ApiError> {
+        let (mut request, received) = read_head(&mut raw).await?;
+        read_body(&mut raw, &mut request, received).await?;
+        Ok(request)
+    }
+
+    #[tokio::test]
+    async fn test_reads_request_and_checks_bearer_token() {
+        let raw = b"POST /v1/chat/completions?x=1 HTTP/1.1\r\nHost: localhost\r\nauthorization: bearer sk-local\r\nContent-Length: 11\r\n\r\n{\"a\": true}trailing";
+        let request = read_request(raw).await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/chat/completions");
+        assert_eq!(request.body, br#"{"a": true}"#);
+        assert!(authorize(&request, "sk-local").is_ok());
+        assert_eq!(authorize(&request, "sk-other").unwrap_err().status, 401);
+        assert_eq!(authorize(&request, "sk-local-longer").unwrap_err().status, 401);
+        assert!(!constant_time_eq(b"sk-local", b"sk-locaL"));
+
+        let anonymous = read_request(b"GET /v1/models HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(authorize(&anonymous, "sk-local").unwrap_err().status, 401);
+
+        let short = b"POST /v1/chat/completions HTTP/1.1\r\nContent-Length: 50\r\n\r\n{}";
+        assert_eq!(read_request(short).await.unwrap_err().status, 400);
+        let huge = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1);
+        assert_eq!(read_request(huge.as_bytes()).await.unwrap_err().status, 413);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unauthorized_body_is_never_read() {
+        // The client sends headers and then stalls mid-body
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST /v1/chat/completions HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n{")
+            .await
+            .unwrap();
+        let (mut request, received) = read_head(&mut server).await.unwrap();
+        assert_eq!(received, b"{");
+        assert_eq!(authorize(&request, "sk-local").unwrap_err().status, 401);
+
+        // An authorized read of the same body gives up once the client stalls
+        let err = read_body(&mut server, &mut request, received).await.unwrap_err();
+        assert_eq!(err.status, 500);
+        assert!(err.message.contains("stalled"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_completion_body_maps_to_chat_request() {
+        let body: CompletionBody = serde_json::from_value(json!({
+            "model": "openai/gpt-4o",
+            "stream": true,
+            "temperature": 0.2,
+            "stop": "END",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}}
+                ]}
+            ]
+        }))
+        .unwrap();
+        let messages: Vec<ChatMessage> = body.messages.iter().map(|m| chat_message(m).unwrap()).collect();
+        assert_eq!(messages[0].content, "Be brief.");
+        assert_eq!(messages[1].content, "What is this?");
+        assert_eq!(messages[1].attachments[0].media_type, "image/png");
+        assert_eq!(messages[1].attachments[0].data, "iVBORw0KGgo=");
+
+        let defaults = GenerationParams {
+            temperature: Some(0.9),
+            max_tokens: Some(512),
+            ..Default::default()
+        };
+        let params = params(&body, defaults);
+        assert_eq!(params.temperature, Some(0.2));
+        assert_eq!(params.max_tokens, Some(512));
+        assert_eq!(params.stop, vec!["END"]);
+
+        let remote: BodyMessage = serde_json::from_value(json!({
+            "role": "user",
+            "content": [{"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}}]
+        }))
+        .unwrap();
+        assert_eq!(chat_message(&remote).unwrap_err().status, 400);
+    }
+}
@@ -0,0 +1,66 @@
+//! Helpers shared by unit tests in different modules.
+
+use std::io::{Read, Write};
+
+/// A canned HTTP response for `serve`.
+pub struct Reply {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn ok(content_type: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body: body.into(),
+        }
+    }
+}
+
+/// Answer `count` HTTP requests on a local port with whatever `respond`
+/// returns for each request body, and return the base URL. Connections after
+/// the last one are refused.
+pub fn serve(count: usize, mut respond: impl FnMut(&str) -> Reply + Send + 'static) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for _ in 0..count {
+            let (mut socket, _) = listener.accept().unwrap();
+            let reply = respond(&read_body(&mut socket));
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.status,
+                reply.content_type,
+                reply.body.len(),
+                reply.body
+            );
+            socket.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    format!("http://{}", addr)
+}
+
+/// Read a request up to the end of its body, as given by `Content-Length`.
+fn read_body(socket: &mut impl Read) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = socket.read(&mut buf).unwrap();
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request);
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length: usize = head
+                .to_lowercase()
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:").and_then(|v| v.trim().parse().ok()))
+                .unwrap_or(0);
+            if body.len() >= length || n == 0 {
+                return body.to_string();
+            }
+        } else if n == 0 {
+            return String::new();
+        }
+    }
+}
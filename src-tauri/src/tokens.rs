@@ -0,0 +1,107 @@
+//! Prompt-size estimates, cheap enough to run on every keystroke.
+//!
+//! OpenAI models are counted with their own BPE. Claude, Llama and the rest
+//! don't ship a public Rust tokenizer, so they are approximated with
+//! `cl100k_base`, which lands within a few percent for English text.
+
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton};
+
+/// Chat formatting adds a few tokens per message (role, separators) and a
+/// few to prime the reply.
+const TOKENS_PER_MESSAGE: usize = 4;
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Models on the newer `o200k_base` vocabulary.
+fn uses_o200k(model_id: &str) -> bool {
+    let id = model_id.to_lowercase();
+    ["gpt-4o", "gpt-4.1", "gpt-4.5", "gpt-5", "o1", "o3", "o4", "chatgpt-4o"]
+        .iter()
+        .any(|p| id.starts_with(p))
+}
+
+/// Token count of `text` for `model_id` (without the provider prefix).
+pub fn count_tokens(model_id: &str, text: &str) -> usize {
+    let bpe = if uses_o200k(model_id) {
+        o200k_base_singleton()
+    } else {
+        cl100k_base_singleton()
+    };
+    let bpe = bpe.lock();
+    bpe.encode_ordinary(text).len()
+}
+
+/// Prompt tokens for a chat history given as `(role, content)` pairs.
+pub fn count_chat_tokens<'a>(
+    model_id: &str,
+    messages: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> usize {
+    messages
+        .into_iter()
+        .map(|(role, content)| {
+            TOKENS_PER_MESSAGE + count_tokens(model_id, role) + count_tokens(model_id, content)
+        })
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+/// Context window of `model_id`, matched on its id. Unknown models get a
+/// conservative 8k.
+pub fn context_limit(model_id: &str) -> usize {
+    let id = model_id.to_lowercase();
+    if id.starts_with("gpt-4.1") {
+        1_047_576
+    } else if id.starts_with("gpt-5") {
+        400_000
+    } else if id.starts_with("o1") || id.starts_with("o3") || id.starts_with("o4") {
+        200_000
+    } else if id.starts_with("gpt-4o") || id.starts_with("gpt-4-turbo") || id.starts_with("gpt-4.5") {
+        128_000
+    } else if id.starts_with("gpt-4-32k") {
+        32_768
+    } else if id.starts_with("gpt-4") {
+        8_192
+    } else if id.starts_with("gpt-3.5") {
+        16_385
+    } else if id.contains("claude") || id.contains("sonnet") || id.contains("opus") || id.contains("haiku") {
+        200_000
+    } else if id.starts_with("gemini") {
+        1_000_000
+    } else if id.contains("llama3.1") || id.contains("llama3.2") || id.contains("llama-3.1") {
+        128_000
+    } else if id.contains("qwen2.5") || id.contains("mistral-nemo") {
+        32_768
+    } else {
+        8_192
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_tokens_per_family() {
+        assert_eq!(count_tokens("gpt-4o", "hello world"), 2);
+        assert_eq!(count_tokens("gpt-4", "hello world"), 2);
+        assert_eq!(count_tokens("claude-sonnet-4-20250514", ""), 0);
+        // The newer vocabulary packs this sentence more tightly
+        let text = "Tokenizers differ between model generations.";
+        assert!(count_tokens("gpt-4o", text) <= count_tokens("gpt-4", text));
+    }
+
+    #[test]
+    fn test_chat_tokens_include_message_overhead() {
+        let content = count_tokens("gpt-4o", "Hi there");
+        let role = count_tokens("gpt-4o", "user");
+        let total = count_chat_tokens("gpt-4o", [("user", "Hi there")]);
+        assert_eq!(total, TOKENS_PER_MESSAGE + role + content + TOKENS_PER_REPLY);
+    }
+
+    #[test]
+    fn test_context_limits() {
+        assert_eq!(context_limit("gpt-4o-mini"), 128_000);
+        assert_eq!(context_limit("gpt-4-0613"), 8_192);
+        assert_eq!(context_limit("claude-3-5-haiku-20241022"), 200_000);
+        assert_eq!(context_limit("some-local-model"), 8_192);
+    }
+}
@@ -8,7 +8,6 @@ import {
   Conversation,
   ModelInfo,
   getAvailableModels,
-  fetchCopilotModels,
   listConversations,
 } from "./lib/api";
 
@@ -28,14 +27,8 @@ function App() {
 
   async function loadModels() {
     try {
-      let m = await getAvailableModels();
-      // Dynamically fetch Copilot models from catalog API
-      try {
-        const copilotModels = await fetchCopilotModels();
-        m = [...m, ...copilotModels];
-      } catch {
-        // Copilot key not configured or fetch failed — ignore
-      }
+      // Served from the backend model cache (includes Copilot's catalog)
+      const m = await getAvailableModels();
       setModels(m);
       if (m.length > 0 && !m.find((x) => x.id === currentModel)) {
         setCurrentModel(m[0].id);
@@ -4,10 +4,14 @@ import ReactMarkdown from "react-markdown";
 import remarkGfm from "remark-gfm";
 import {
   ChatStreamEvent,
+  continueMessage,
+  estimateTokens,
   getMessages,
   Message,
   ModelInfo,
   sendMessage,
+  errorMessage,
+  TokenEstimate,
 } from "../lib/api";
 
 interface ChatViewProps {
@@ -27,6 +31,8 @@ export default function ChatView({
   const [input, setInput] = useState("");
   const [streaming, setStreaming] = useState(false);
   const [streamContent, setStreamContent] = useState("");
+  const streamMessageId = useRef<string | null>(null);
+  const [tokenEstimate, setTokenEstimate] = useState<TokenEstimate | null>(null);
   const bottomRef = useRef<HTMLDivElement>(null);
   const textareaRef = useRef<HTMLTextAreaElement>(null);
 
@@ -44,10 +50,18 @@ export default function ChatView({
     let unlisten: UnlistenFn | null = null;
 
     listen<ChatStreamEvent>("chat-stream", (event) => {
-      const { conversation_id, delta, done } = event.payload;
+      const { conversation_id, message_id, delta, done, error } = event.payload;
       if (conversation_id !== conversationId) return;
 
-      if (done) {
+      // A new reply (e.g. after a regenerate) replaces the one being shown
+      const isNewReply = streamMessageId.current !== message_id;
+      streamMessageId.current = done ? null : message_id;
+
+      if (done && error) {
+        // Nothing was saved; sendMessage rejects with the reason
+        setStreamContent("");
+        setStreaming(false);
+      } else if (done) {
         // Stream complete — reload messages from DB
         if (conversationId) {
           getMessages(conversationId).then((msgs) => {
@@ -57,7 +71,7 @@ export default function ChatView({
           });
         }
       } else {
-        setStreamContent((prev) => prev + delta);
+        setStreamContent((prev) => (isNewReply ? delta : prev + delta));
       }
     }).then((fn) => {
       unlisten = fn;
@@ -68,6 +82,20 @@ export default function ChatView({
     };
   }, [conversationId]);
 
+  // Re-estimate prompt size shortly after typing stops
+  useEffect(() => {
+    if (!conversationId || !currentModel) {
+      setTokenEstimate(null);
+      return;
+    }
+    const timer = setTimeout(() => {
+      estimateTokens(conversationId, currentModel, input)
+        .then(setTokenEstimate)
+        .catch(() => setTokenEstimate(null));
+    }, 300);
+    return () => clearTimeout(timer);
+  }, [conversationId, currentModel, input, messages]);
+
   // Auto-scroll to bottom
   useEffect(() => {
     bottomRef.current?.scrollIntoView({ behavior: "smooth" });
@@ -92,7 +120,7 @@ export default function ChatView({
 
     // Optimistically add user message
     const userMsg: Message = {
-      id: "temp-" + Date.now(),
+      id: crypto.randomUUID(),
       conversation_id: conversationId,
       role: "user",
       content,
@@ -101,7 +129,13 @@ export default function ChatView({
     setMessages((prev) => [...prev, userMsg]);
 
     try {
-      await sendMessage(conversationId, content, currentModel);
+      await sendMessage(
+        conversationId,
+        content,
+        currentModel,
+        undefined,
+        userMsg.id
+      );
     } catch (e) {
       console.error("Send failed:", e);
       setStreaming(false);
@@ -113,13 +147,26 @@ export default function ChatView({
           id: "error-" + Date.now(),
           conversation_id: conversationId,
           role: "assistant",
-          content: `⚠️ Error: ${e}`,
+          content: `⚠️ Error: ${errorMessage(e)}`,
           created_at: new Date().toISOString(),
         },
       ]);
     }
   }
 
+  async function handleContinue(messageId: string) {
+    if (streaming) return;
+    setStreaming(true);
+    setStreamContent("");
+    try {
+      await continueMessage(messageId);
+    } catch (e) {
+      console.error("Continue failed:", e);
+      setStreaming(false);
+      setStreamContent("");
+    }
+  }
+
   function handleKeyDown(e: React.KeyboardEvent) {
     if (e.key === "Enter" && !e.shiftKey) {
       e.preventDefault();
@@ -159,7 +206,11 @@ export default function ChatView({
       {/* Messages area */}
       <div className="flex-1 overflow-y-auto px-4 py-4 space-y-4">
         {messages.map((msg) => (
-          <MessageBubble key={msg.id} message={msg} />
+          <MessageBubble
+            key={msg.id}
+            message={msg}
+            onContinue={streaming ? undefined : () => handleContinue(msg.id)}
+          />
         ))}
 
         {/* Streaming indicator */}
@@ -206,12 +257,30 @@ export default function ChatView({
             Send
           </button>
         </div>
+        {tokenEstimate &&
+          tokenEstimate.prompt_tokens > tokenEstimate.context_limit * 0.8 && (
+            <p
+              className={`max-w-4xl mx-auto mt-1 text-xs ${
+                tokenEstimate.remaining < 0 ? "text-red-400" : "text-yellow-400"
+              }`}
+            >
+              ~{tokenEstimate.prompt_tokens.toLocaleString()} of{" "}
+              {tokenEstimate.context_limit.toLocaleString()} context tokens
+              {tokenEstimate.remaining < 0 && " — this message won't fit"}
+            </p>
+          )}
       </div>
     </div>
   );
 }
 
-function MessageBubble({ message }: { message: Message }) {
+function MessageBubble({
+  message,
+  onContinue,
+}: {
+  message: Message;
+  onContinue?: () => void;
+}) {
   const isUser = message.role === "user";
   return (
     <div className={`flex ${isUser ? "justify-end" : "justify-start"}`}>
@@ -231,6 +300,17 @@ function MessageBubble({ message }: { message: Message }) {
             </ReactMarkdown>
           </div>
         )}
+        {!isUser && message.model && (
+          <div className="mt-2 text-xs text-gray-500">{message.model}</div>
+        )}
+        {message.truncated && onContinue && (
+          <button
+            onClick={onContinue}
+            className="mt-2 text-xs text-blue-400 hover:text-blue-300"
+          >
+            Cut off at the token limit — continue
+          </button>
+        )}
       </div>
     </div>
   );
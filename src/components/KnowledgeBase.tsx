@@ -1,10 +1,16 @@
 import { useEffect, useState } from "react";
 import { open } from "@tauri-apps/plugin-dialog";
+import { listen } from "@tauri-apps/api/event";
 import {
+  cancelUpload,
   DocumentInfo,
+  EmbeddingProgress,
+  reembedDocument,
   listDocuments,
   uploadDocument,
+  updateDocument,
   deleteDocument,
+  errorMessage,
 } from "../lib/api";
 
 interface KnowledgeBaseProps {
@@ -19,6 +25,9 @@ export default function KnowledgeBase({
   const [documents, setDocuments] = useState<DocumentInfo[]>([]);
   const [uploading, setUploading] = useState(false);
   const [error, setError] = useState("");
+  const [progress, setProgress] = useState<EmbeddingProgress | null>(null);
+  // Documents whose upload was cancelled and can be resumed
+  const [pendingIds, setPendingIds] = useState<string[]>([]);
 
   useEffect(() => {
     if (isOpen) {
@@ -26,6 +35,22 @@ export default function KnowledgeBase({
     }
   }, [isOpen]);
 
+  useEffect(() => {
+    const unlisten = listen<EmbeddingProgress>("embedding-progress", (event) => {
+      const { embedded, total } = event.payload;
+      // Uploads are indexed in the background; refresh once one finishes
+      if (embedded === total) {
+        setProgress(null);
+        loadDocuments();
+      } else {
+        setProgress(event.payload);
+      }
+    });
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
+
   async function loadDocuments() {
     try {
       const docs = await listDocuments();
@@ -43,7 +68,7 @@ export default function KnowledgeBase({
         filters: [
           {
             name: "Documents",
-            extensions: ["txt", "md", "pdf"],
+            extensions: ["txt", "md", "pdf", "epub"],
           },
         ],
       });
@@ -54,7 +79,43 @@ export default function KnowledgeBase({
       await uploadDocument(selected);
       await loadDocuments();
     } catch (e) {
-      setError(`Upload failed: ${e}`);
+      setError(`Upload failed: ${errorMessage(e)}`);
+    } finally {
+      setUploading(false);
+    }
+  }
+
+  async function handleCancel() {
+    if (!progress) return;
+    if (await cancelUpload(progress.document_id)) {
+      setPendingIds((prev) => [...prev, progress.document_id]);
+    }
+  }
+
+  async function handleResume(id: string) {
+    setError("");
+    setUploading(true);
+    try {
+      const remaining = await reembedDocument(id);
+      if (remaining === 0) {
+        setPendingIds((prev) => prev.filter((p) => p !== id));
+      }
+    } catch (e) {
+      setError(`Resume failed: ${errorMessage(e)}`);
+    } finally {
+      setUploading(false);
+      setProgress(null);
+    }
+  }
+
+  async function handleRefresh(doc: DocumentInfo) {
+    setError("");
+    setUploading(true);
+    try {
+      const updated = await updateDocument(doc.id, doc.file_path);
+      setDocuments((prev) => prev.map((d) => (d.id === doc.id ? updated : d)));
+    } catch (e) {
+      setError(`Update failed: ${errorMessage(e)}`);
     } finally {
       setUploading(false);
     }
@@ -65,7 +126,7 @@ export default function KnowledgeBase({
       await deleteDocument(id);
       setDocuments((prev) => prev.filter((d) => d.id !== id));
     } catch (e) {
-      setError(`Delete failed: ${e}`);
+      setError(`Delete failed: ${errorMessage(e)}`);
     }
   }
 
@@ -101,9 +162,19 @@ export default function KnowledgeBase({
             className="w-full py-3 border-2 border-dashed border-gray-700 hover:border-blue-500 rounded-lg text-sm text-gray-400 hover:text-blue-400 transition-colors disabled:opacity-50 cursor-pointer mb-4"
           >
             {uploading
-              ? "Uploading & generating embeddings..."
-              : "📄 Click to upload document (txt, md, pdf)"}
+              ? "Uploading..."
+              : progress
+                ? `Generating embeddings... ${progress.embedded}/${progress.total}`
+                : "📄 Click to upload document (txt, md, pdf, epub)"}
           </button>
+          {progress && (
+            <button
+              onClick={handleCancel}
+              className="w-full -mt-2 mb-4 py-1.5 text-xs text-gray-400 hover:text-red-400 transition-colors cursor-pointer"
+            >
+              Cancel embedding
+            </button>
+          )}
 
           {error && (
             <p className="text-red-400 text-sm mb-3">{error}</p>
@@ -128,8 +199,42 @@ export default function KnowledgeBase({
                     <p className="text-xs text-gray-500">
                       {doc.file_type.toUpperCase()} · {formatSize(doc.file_size)}{" "}
                       · {new Date(doc.created_at).toLocaleDateString()}
+                      {doc.index_status && doc.index_status !== "ready" && (
+                        <> · {doc.index_status}</>
+                      )}
+                      {doc.index_status !== "indexing" &&
+                        doc.embedded_chunks < doc.chunk_count && (
+                          <span
+                            className="text-yellow-400"
+                            title="Some chunks have no embedding and won't show up in search"
+                          >
+                            {" "}
+                            · {doc.embedded_chunks}/{doc.chunk_count} indexed
+                          </span>
+                        )}
                     </p>
                   </div>
+                  {(pendingIds.includes(doc.id) ||
+                    doc.index_status === "failed" ||
+                    doc.index_status === "partial" ||
+                    doc.index_status === "cancelled") && (
+                    <button
+                      onClick={() => handleResume(doc.id)}
+                      disabled={uploading}
+                      title="Embed the remaining chunks"
+                      className="text-xs text-yellow-400 hover:text-yellow-300 ml-3 cursor-pointer disabled:opacity-50"
+                    >
+                      Resume
+                    </button>
+                  )}
+                  <button
+                    onClick={() => handleRefresh(doc)}
+                    disabled={uploading}
+                    title="Re-index from file"
+                    className="opacity-0 group-hover:opacity-100 text-gray-500 hover:text-blue-400 ml-3 transition-opacity cursor-pointer disabled:opacity-50"
+                  >
+                    ↻
+                  </button>
                   <button
                     onClick={() => handleDelete(doc.id)}
                     className="opacity-0 group-hover:opacity-100 text-gray-500 hover:text-red-400 ml-3 transition-opacity cursor-pointer"
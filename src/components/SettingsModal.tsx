@@ -5,10 +5,21 @@ import {
   deleteSetting,
   copilotStartLogin,
   copilotPollLogin,
+  copilotCancelLogin,
   copilotIsLoggedIn,
   copilotLogout,
+  backupDatabase,
+  restoreDatabase,
+  compactDatabase,
+  errorMessage,
+  isAppError,
+  startLocalServer,
+  stopLocalServer,
+  localServerStatus,
+  LocalServerStatus,
 } from "../lib/api";
 import { openUrl } from "@tauri-apps/plugin-opener";
+import { open as openDialog, save as saveDialog } from "@tauri-apps/plugin-dialog";
 
 interface SettingsModalProps {
   open: boolean;
@@ -48,12 +59,144 @@ const FIELDS: SettingField[] = [
     placeholder: "https://api.anthropic.com",
     secret: false,
   },
+  {
+    key: "openai_extra_headers",
+    label: "OpenAI Extra Headers",
+    placeholder: '{"HTTP-Referer": "https://...", "X-Title": "AI-Box"}',
+    secret: false,
+  },
+  {
+    key: "openrouter_api_key",
+    label: "OpenRouter API Key",
+    placeholder: "sk-or-...",
+    secret: true,
+  },
   {
     key: "ollama_host",
     label: "Ollama Host",
     placeholder: "http://localhost:11434",
     secret: false,
   },
+  {
+    key: "embedding_provider",
+    label: "Embedding Provider",
+    placeholder: "openai or local",
+    secret: false,
+  },
+  {
+    key: "embedding_model",
+    label: "Embedding Model",
+    placeholder: "text-embedding-3-small",
+    secret: false,
+  },
+  {
+    key: "embedding_dimensions",
+    label: "Embedding Dimensions",
+    placeholder: "Model default (e.g. 512 for text-embedding-3)",
+    secret: false,
+  },
+  {
+    key: "embedding_base_url",
+    label: "Embedding Base URL",
+    placeholder: "Defaults to the OpenAI Base URL",
+    secret: false,
+  },
+  {
+    key: "dedup_upload_threshold",
+    label: "Upload Dedup Threshold",
+    placeholder: "0.98 (1 disables)",
+    secret: false,
+  },
+  {
+    key: "dedup_search_threshold",
+    label: "Search Dedup Threshold",
+    placeholder: "0.95 (1 disables)",
+    secret: false,
+  },
+  {
+    key: "duplicate_uploads",
+    label: "Duplicate Uploads",
+    placeholder: "reject or reuse (returns the indexed copy)",
+    secret: false,
+  },
+  {
+    key: "default_system_prompt",
+    label: "Default System Prompt",
+    placeholder: "Applied to new conversations",
+    secret: false,
+  },
+  {
+    key: "temperature",
+    label: "Temperature",
+    placeholder: "Provider default (0–2)",
+    secret: false,
+  },
+  {
+    key: "max_tokens",
+    label: "Max Tokens",
+    placeholder: "Provider default",
+    secret: false,
+  },
+  {
+    key: "top_p",
+    label: "Top P",
+    placeholder: "Provider default (0–1)",
+    secret: false,
+  },
+  {
+    key: "stop_sequences",
+    label: "Stop Sequences",
+    placeholder: '["</answer>", "###"]',
+    secret: false,
+  },
+  {
+    key: "max_concurrent_requests",
+    label: "Max Concurrent Requests",
+    placeholder: "4 per provider",
+    secret: false,
+  },
+  {
+    key: "first_token_timeout_secs",
+    label: "First Token Timeout (seconds)",
+    placeholder: "60 (0 disables)",
+    secret: false,
+  },
+  {
+    key: "stream_save_interval_ms",
+    label: "Save Streaming Replies Every (ms)",
+    placeholder: "Off — saved when the reply finishes",
+    secret: false,
+  },
+  {
+    key: "claude_prompt_caching",
+    label: "Claude Prompt Caching",
+    placeholder: "false (true caches system prompt and long context)",
+    secret: false,
+  },
+  {
+    key: "debug_logging",
+    label: "Log Provider Requests",
+    placeholder: "false (true writes redacted requests to the debug log)",
+    secret: false,
+  },
+  {
+    key: "local_server_host",
+    label: "Local API Server Host",
+    placeholder: "127.0.0.1 (localhost only)",
+    secret: false,
+  },
+  {
+    key: "local_server_port",
+    label: "Local API Server Port",
+    placeholder: "8711",
+    secret: false,
+  },
+  {
+    key: "local_server_api_key",
+    label: "Local API Server Key",
+    placeholder: "Generated when the server first starts",
+    secret: true,
+  },
 ];
 
 export default function SettingsModal({
@@ -69,15 +212,23 @@ export default function SettingsModal({
   // Copilot login state
   const [copilotLoggedIn, setCopilotLoggedIn] = useState(false);
   const [copilotLoggingIn, setCopilotLoggingIn] = useState(false);
-  const [, setDeviceCode] = useState("");
+  const deviceCodeRef = useRef("");
   const [userCode, setUserCode] = useState("");
   const pollRef = useRef<ReturnType<typeof setTimeout> | null>(null);
+  const [server, setServer] = useState<LocalServerStatus | null>(null);
 
-  const stopPolling = useCallback(() => {
+  // Also runs when the dialog closes, abandoning a login in progress
+  const cancelLogin = useCallback(() => {
     if (pollRef.current) {
       clearTimeout(pollRef.current);
       pollRef.current = null;
     }
+    if (deviceCodeRef.current) {
+      copilotCancelLogin(deviceCodeRef.current).catch(console.error);
+      deviceCodeRef.current = "";
+    }
+    setCopilotLoggingIn(false);
+    setUserCode("");
   }, []);
 
   useEffect(() => {
@@ -90,16 +241,17 @@ export default function SettingsModal({
         })
         .catch(console.error);
       copilotIsLoggedIn().then(setCopilotLoggedIn).catch(console.error);
+      localServerStatus().then(setServer).catch(console.error);
     }
-    return stopPolling;
-  }, [isOpen, stopPolling]);
+    return cancelLogin;
+  }, [isOpen, cancelLogin]);
 
   async function handleCopilotLogin() {
     setCopilotLoggingIn(true);
     setMessage("");
     try {
       const resp = await copilotStartLogin();
-      setDeviceCode(resp.device_code);
+      deviceCodeRef.current = resp.device_code;
       setUserCode(resp.user_code);
       // Open browser for user to authorize
       await openUrl(resp.verification_uri);
@@ -111,7 +263,7 @@ export default function SettingsModal({
           if (token) {
             setCopilotLoggedIn(true);
             setCopilotLoggingIn(false);
-            setDeviceCode("");
+            deviceCodeRef.current = "";
             setUserCode("");
             setMessage("GitHub Copilot connected!");
             onSaved();
@@ -119,7 +271,9 @@ export default function SettingsModal({
           }
         } catch (err) {
           setCopilotLoggingIn(false);
-          setMessage("Error: Login failed or expired. Try again.");
+          if (!isAppError(err) || err.code !== "cancelled") {
+            setMessage("Error: Login failed or expired. Try again.");
+          }
           return;
         }
         pollRef.current = setTimeout(poll, interval);
@@ -127,7 +281,7 @@ export default function SettingsModal({
       pollRef.current = setTimeout(poll, interval);
     } catch (e) {
       setCopilotLoggingIn(false);
-      setMessage(`Error: ${e}`);
+      setMessage(`Error: ${errorMessage(e)}`);
     }
   }
 
@@ -138,25 +292,81 @@ export default function SettingsModal({
     onSaved();
   }
 
+  async function handleBackup() {
+    setMessage("");
+    try {
+      const dest = await saveDialog({
+        defaultPath: "ai-box-backup.db",
+        filters: [{ name: "SQLite database", extensions: ["db"] }],
+      });
+      if (!dest) return;
+      await backupDatabase(dest);
+      setMessage("Backup saved!");
+    } catch (e) {
+      setMessage(`Error: ${errorMessage(e)}`);
+    }
+  }
+
+  async function handleRestore() {
+    setMessage("");
+    try {
+      const src = await openDialog({
+        multiple: false,
+        filters: [{ name: "SQLite database", extensions: ["db"] }],
+      });
+      if (!src) return;
+      await restoreDatabase(src);
+      setMessage("Backup restored!");
+      onSaved();
+      setValues(await getSettings());
+    } catch (e) {
+      setMessage(`Error: ${errorMessage(e)}`);
+    }
+  }
+
+  async function handleToggleServer() {
+    setMessage("");
+    try {
+      const status = server?.running ? await stopLocalServer() : await startLocalServer();
+      setServer(status);
+      // Starting may have generated the key
+      setValues(await getSettings());
+    } catch (e) {
+      setMessage(`Error: ${errorMessage(e)}`);
+    }
+  }
+
+  async function handleCompact() {
+    setMessage("");
+    try {
+      const result = await compactDatabase();
+      setMessage(`Compacted, freed ${(result.freed_bytes / (1024 * 1024)).toFixed(1)} MB`);
+    } catch (e) {
+      setMessage(`Error: ${errorMessage(e)}`);
+    }
+  }
+
   async function handleSave() {
     setSaving(true);
     setMessage("");
     try {
+      const warnings: string[] = [];
       for (const [key, value] of Object.entries(editValues)) {
         if (value.trim()) {
-          await setSetting(key, value.trim());
+          const warning = await setSetting(key, value.trim());
+          if (warning) warnings.push(warning);
         } else {
           await deleteSetting(key);
         }
       }
-      setMessage("Settings saved!");
+      setMessage(warnings.length ? `Settings saved. ${warnings.join(" ")}` : "Settings saved!");
       onSaved();
       // Reload settings to show masked values
       const s = await getSettings();
       setValues(s);
       setEditValues({});
     } catch (e) {
-      setMessage(`Error: ${e}`);
+      setMessage(`Error: ${errorMessage(e)}`);
     } finally {
       setSaving(false);
     }
@@ -229,6 +439,12 @@ export default function SettingsModal({
                   in the browser window
                 </p>
                 <p className="text-xs text-gray-500">Waiting for authorization...</p>
+                <button
+                  onClick={cancelLogin}
+                  className="px-3 py-1 bg-gray-800 hover:bg-gray-700 border border-gray-600 rounded-lg text-xs transition-colors cursor-pointer"
+                >
+                  Cancel
+                </button>
               </div>
             ) : (
               <button
@@ -239,6 +455,49 @@ export default function SettingsModal({
               </button>
             )}
           </div>
+
+          {/* Local API server */}
+          <div className="pt-2 border-t border-gray-800">
+            <label className="block text-sm text-gray-400 mb-2">Local API Server</label>
+            <div className="flex items-center gap-3">
+              <button
+                onClick={handleToggleServer}
+                className="px-4 py-2 bg-gray-800 hover:bg-gray-700 border border-gray-600 rounded-lg text-sm transition-colors cursor-pointer"
+              >
+                {server?.running ? "Stop" : "Start"}
+              </button>
+              <span className="text-xs text-gray-500">
+                {server?.running
+                  ? `OpenAI-compatible API at ${server.base_url}`
+                  : "Lets other apps on this machine use your providers"}
+              </span>
+            </div>
+          </div>
+
+          {/* Backup & restore */}
+          <div className="pt-2 border-t border-gray-800">
+            <label className="block text-sm text-gray-400 mb-2">Data</label>
+            <div className="flex gap-2">
+              <button
+                onClick={handleBackup}
+                className="px-4 py-2 bg-gray-800 hover:bg-gray-700 border border-gray-600 rounded-lg text-sm transition-colors cursor-pointer"
+              >
+                Back up database
+              </button>
+              <button
+                onClick={handleRestore}
+                className="px-4 py-2 bg-gray-800 hover:bg-gray-700 border border-gray-600 rounded-lg text-sm transition-colors cursor-pointer"
+              >
+                Restore from backup
+              </button>
+              <button
+                onClick={handleCompact}
+                className="px-4 py-2 bg-gray-800 hover:bg-gray-700 border border-gray-600 rounded-lg text-sm transition-colors cursor-pointer"
+              >
+                Compact
+              </button>
+            </div>
+          </div>
         </div>
 
         {/* Footer */}
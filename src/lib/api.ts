@@ -2,32 +2,122 @@ import { invoke } from "@tauri-apps/api/core";
 
 // ── Types ──
 
+export type AppErrorCode =
+  | "auth"
+  | "rate_limited"
+  | "timeout"
+  | "network"
+  | "not_configured"
+  | "not_found"
+  | "invalid_input"
+  | "empty_response"
+  | "cancelled"
+  | "api"
+  | "database"
+  | "other";
+
+/** Shape of every error rejected by a backend command. */
+export interface AppError {
+  code: AppErrorCode;
+  message: string;
+}
+
+export function isAppError(e: unknown): e is AppError {
+  return typeof e === "object" && e !== null && "code" in e && "message" in e;
+}
+
+export function errorMessage(e: unknown): string {
+  return isAppError(e) ? e.message : String(e);
+}
+
 export interface Conversation {
   id: string;
   title: string;
   model: string | null;
   created_at: string;
   updated_at: string;
+  deleted_at?: string | null;
+  /** Sent ahead of the history with every request. */
+  system_prompt?: string | null;
+  tags?: string[];
+}
+
+export interface PromptTemplate {
+  id: string;
+  name: string;
+  /** May contain `{{variable}}` placeholders. */
+  content: string;
+  created_at: string;
+  updated_at: string;
+}
+
+/** A stored template plus values for its placeholders. */
+export interface TemplateRef {
+  id: string;
+  variables?: Record<string, string>;
 }
 
 export interface Message {
   id: string;
   conversation_id: string;
-  role: "user" | "assistant" | "system";
+  role: "user" | "assistant" | "system" | "tool";
   content: string;
   created_at: string;
+  attachments?: string[];
+  /** Model that produced an assistant reply, e.g. "openai/gpt-4o". */
+  model?: string | null;
+  /** The reply stopped at the token limit; see `continueMessage`. */
+  truncated?: boolean;
+  /** For `tool` messages, the id of the tool call this answers. */
+  tool_call_id?: string | null;
+  /** For assistant replies, the tool calls they made. */
+  tool_calls?: ToolCall[];
+}
+
+export interface ToolCall {
+  id: string;
+  name: string;
+  /** Arguments as the JSON text the model produced. */
+  arguments: string;
+}
+
+export interface ModelCapabilities {
+  supports_streaming: boolean;
+  supports_vision: boolean;
+  supports_tools: boolean;
 }
 
 export interface ModelInfo {
   id: string;
   name: string;
   provider: string;
+  capabilities: ModelCapabilities;
+  /** Context window in tokens. */
+  context_length: number;
 }
 
 export interface ChatStreamEvent {
+  /** Empty for knowledge-base answers from `ragQuery`. */
   conversation_id: string;
+  /** Id the assistant message is saved under. */
+  message_id: string;
+  model: string;
   delta: string;
   done: boolean;
+  /** Final event of a reply that failed; nothing was saved. */
+  error?: boolean;
+  /** Final event of a reply that stopped at the token limit. */
+  truncated?: boolean;
+  /** Token counts on the final event, for providers that report them. */
+  usage?: TokenUsage;
+}
+
+export interface TokenUsage {
+  input_tokens: number;
+  output_tokens: number;
+  /** Claude prompt caching: tokens written to / served from the cache. */
+  cache_creation_input_tokens: number;
+  cache_read_input_tokens: number;
 }
 
 // ── Chat API ──
@@ -39,12 +129,59 @@ export async function createConversation(
   return invoke("create_conversation", { title, model });
 }
 
-export async function listConversations(): Promise<Conversation[]> {
-  return invoke("list_conversations");
+export async function listConversations(
+  includeTags?: boolean
+): Promise<Conversation[]> {
+  return invoke("list_conversations", { includeTags });
+}
+
+export async function addTag(conversationId: string, tag: string): Promise<void> {
+  return invoke("add_tag", { conversationId, tag });
+}
+
+export async function removeTag(
+  conversationId: string,
+  tag: string
+): Promise<void> {
+  return invoke("remove_tag", { conversationId, tag });
+}
+
+export async function listTags(): Promise<string[]> {
+  return invoke("list_tags");
+}
+
+export async function listConversationsByTag(
+  tag: string
+): Promise<Conversation[]> {
+  return invoke("list_conversations_by_tag", { tag });
+}
+
+/** Moves to the trash unless `permanent` is set. */
+export async function deleteConversation(
+  id: string,
+  permanent?: boolean
+): Promise<void> {
+  return invoke("delete_conversation", { id, permanent });
+}
+
+/** Permanently delete every conversation, trash included. */
+export async function deleteAllConversations(): Promise<{
+  conversations: number;
+  messages: number;
+}> {
+  return invoke("delete_all_conversations");
+}
+
+export async function listTrash(): Promise<Conversation[]> {
+  return invoke("list_trash");
 }
 
-export async function deleteConversation(id: string): Promise<void> {
-  return invoke("delete_conversation", { id });
+export async function restoreConversation(id: string): Promise<void> {
+  return invoke("restore_conversation", { id });
+}
+
+export async function purgeTrash(olderThanDays?: number): Promise<number> {
+  return invoke("purge_trash", { olderThanDays });
 }
 
 export async function renameConversation(
@@ -54,16 +191,172 @@ export async function renameConversation(
   return invoke("rename_conversation", { id, title });
 }
 
+export async function forkConversation(
+  conversationId: string,
+  upToMessageId?: string
+): Promise<Conversation> {
+  return invoke("fork_conversation", { conversationId, upToMessageId });
+}
+
 export async function getMessages(conversationId: string): Promise<Message[]> {
   return invoke("get_messages", { conversationId });
 }
 
+/** Without `model`, uses the conversation's model, then `default_model`. */
+/** Pass a client-generated `messageId` to make retries safe: the user
+ * message is stored once and a retry only regenerates the reply. */
 export async function sendMessage(
   conversationId: string,
   content: string,
-  model: string
+  model?: string,
+  attachments?: string[],
+  messageId?: string,
+  systemTemplate?: TemplateRef,
+  contextFiles?: string[]
 ): Promise<Message> {
-  return invoke("send_message", { conversationId, content, model });
+  return invoke("send_message", {
+    conversationId,
+    content,
+    model,
+    attachments,
+    messageId,
+    systemTemplate,
+    contextFiles,
+  });
+}
+
+export async function deleteMessage(
+  messageId: string,
+  cascade: boolean
+): Promise<number> {
+  return invoke("delete_message", { messageId, cascade });
+}
+
+export async function editMessage(
+  messageId: string,
+  newContent: string,
+  regenerate?: boolean,
+  model?: string
+): Promise<Message | null> {
+  return invoke("edit_message", { messageId, newContent, regenerate, model });
+}
+
+/** Extend a truncated assistant reply; the continuation streams under the same message id. */
+export async function continueMessage(messageId: string): Promise<Message> {
+  return invoke("continue_message", { messageId });
+}
+
+export interface TokenEstimate {
+  prompt_tokens: number;
+  context_limit: number;
+  /** Negative once the prompt no longer fits. */
+  remaining: number;
+}
+
+/** Cheap enough to call on keystroke; debounce it. */
+export async function estimateTokens(
+  conversationId: string,
+  model: string,
+  draft?: string
+): Promise<TokenEstimate> {
+  return invoke("estimate_tokens", { conversationId, model, draft });
+}
+
+export interface InspectedRequest {
+  method: string;
+  url: string;
+  headers: { name: string; value: string }[];
+  body: unknown;
+}
+
+export async function inspectChatRequest(
+  conversationId: string,
+  model?: string,
+  ragQuery?: string,
+  topK?: number
+): Promise<InspectedRequest> {
+  return invoke("inspect_chat_request", { conversationId, model, ragQuery, topK });
+}
+
+// ── Prompt Templates API ──
+
+export async function listPromptTemplates(): Promise<PromptTemplate[]> {
+  return invoke("list_prompt_templates");
+}
+
+export async function createPromptTemplate(name: string, content: string): Promise<PromptTemplate> {
+  return invoke("create_prompt_template", { name, content });
+}
+
+export async function updatePromptTemplate(id: string, name: string, content: string): Promise<void> {
+  return invoke("update_prompt_template", { id, name, content });
+}
+
+export async function deletePromptTemplate(id: string): Promise<void> {
+  return invoke("delete_prompt_template", { id });
+}
+
+/** Set literal `content` or a rendered template as the conversation's system prompt; neither clears it. */
+export async function setSystemPrompt(
+  conversationId: string,
+  content?: string,
+  template?: TemplateRef
+): Promise<string | null> {
+  return invoke("set_system_prompt", { conversationId, content, template });
+}
+
+// ── Export API ──
+
+export type ExportFormat = "markdown" | "json";
+
+export async function exportConversation(
+  conversationId: string,
+  format: ExportFormat,
+  destPath?: string
+): Promise<string> {
+  return invoke("export_conversation", { conversationId, format, destPath });
+}
+
+export async function exportAll(
+  format: ExportFormat,
+  destPath: string
+): Promise<number> {
+  return invoke("export_all", { format, destPath });
+}
+
+export async function importConversation(json: string): Promise<Conversation> {
+  return invoke("import_conversation", { json });
+}
+
+// ── Database API ──
+
+export async function backupDatabase(destPath: string): Promise<void> {
+  return invoke("backup_database", { destPath });
+}
+
+export async function restoreDatabase(srcPath: string): Promise<void> {
+  return invoke("restore_database", { srcPath });
+}
+
+export interface DatabaseStats {
+  total_bytes: number;
+  free_bytes: number;
+  table_rows: Record<string, number>;
+  embedding_bytes: number;
+}
+
+export interface CompactResult {
+  before_bytes: number;
+  after_bytes: number;
+  freed_bytes: number;
+}
+
+export async function databaseStats(): Promise<DatabaseStats> {
+  return invoke("database_stats");
+}
+
+export async function compactDatabase(): Promise<CompactResult> {
+  return invoke("compact_database");
 }
 
 // ── Settings API ──
@@ -72,7 +365,12 @@ export async function getSettings(): Promise<Record<string, string>> {
   return invoke("get_settings");
 }
 
-export async function setSetting(key: string, value: string): Promise<void> {
+export async function getSettingRaw(key: string): Promise<string | null> {
+  return invoke("get_setting_raw", { key });
+}
+
+/** Resolves to a warning when a base URL was saved but looks wrong. */
+export async function setSetting(key: string, value: string): Promise<string | null> {
   return invoke("set_setting", { key, value });
 }
 
@@ -80,10 +378,50 @@ export async function deleteSetting(key: string): Promise<void> {
   return invoke("delete_setting", { key });
 }
 
+/** Non-secret settings as JSON, for moving a setup to another machine. */
+export async function exportSettings(): Promise<string> {
+  return invoke("export_settings");
+}
+
+/** Apply a file from `exportSettings`; returns how many settings were set. */
+export async function importSettings(json: string): Promise<number> {
+  return invoke("import_settings", { json });
+}
+
+/** Path of the request log written while `debug_logging` is on. */
+export async function getLogPath(): Promise<string> {
+  return invoke("get_log_path");
+}
+
+export async function clearLogs(): Promise<void> {
+  return invoke("clear_logs");
+}
+
 export async function getAvailableModels(): Promise<ModelInfo[]> {
   return invoke("get_available_models");
 }
 
+export async function getCachedModels(): Promise<ModelInfo[]> {
+  return invoke("get_cached_models");
+}
+
+export async function refreshModels(): Promise<ModelInfo[]> {
+  return invoke("refresh_models");
+}
+
+export interface ProviderHealth {
+  provider: string;
+  reachable: boolean;
+  /** null when the provider takes no credentials or the check couldn't tell */
+  credentials_valid: boolean | null;
+  latency_ms: number;
+  error: string | null;
+}
+
+export async function providerHealth(): Promise<ProviderHealth[]> {
+  return invoke("provider_health");
+}
+
 export async function fetchCopilotModels(): Promise<ModelInfo[]> {
   return invoke("fetch_copilot_models");
 }
@@ -93,18 +431,41 @@ export interface DeviceCodeResponse {
   user_code: string;
   verification_uri: string;
   interval: number;
+  expires_in: number;
+}
+
+export interface CopilotLoginProgress {
+  device_code: string;
+  interval: number;
+  remaining_secs: number;
 }
 
 export async function copilotStartLogin(): Promise<DeviceCodeResponse> {
   return invoke("copilot_start_login");
 }
 
+/** Abandon a pending login; its token will not be saved. */
+export async function copilotCancelLogin(deviceCode: string): Promise<boolean> {
+  return invoke("copilot_cancel_login", { deviceCode });
+}
+
 export async function copilotPollLogin(
   deviceCode: string
 ): Promise<string | null> {
   return invoke("copilot_poll_login", { deviceCode });
 }
 
+/** Recommended login path: resolves with the token once the user authorizes. */
+export async function copilotAwaitLogin(
+  login: DeviceCodeResponse
+): Promise<string> {
+  return invoke("copilot_await_login", {
+    deviceCode: login.device_code,
+    interval: login.interval,
+    expiresIn: login.expires_in,
+  });
+}
+
 export async function copilotIsLoggedIn(): Promise<boolean> {
   return invoke("copilot_is_logged_in");
 }
@@ -113,6 +474,46 @@ export async function copilotLogout(): Promise<void> {
   return invoke("copilot_logout");
 }
 
+// ── Local API server ──
+
+export interface LocalServerStatus {
+  running: boolean;
+  /** OpenAI-compatible base URL, e.g. "http://127.0.0.1:8711/v1". */
+  base_url: string | null;
+}
+
+/** Start (or restart) the server; clients authenticate with local_server_api_key. */
+export async function startLocalServer(): Promise<LocalServerStatus> {
+  return invoke("start_local_server");
+}
+
+export async function stopLocalServer(): Promise<LocalServerStatus> {
+  return invoke("stop_local_server");
+}
+
+export async function localServerStatus(): Promise<LocalServerStatus> {
+  return invoke("local_server_status");
+}
+
+// ── Ollama API ──
+
+/** Payload of `ollama-pull-progress` events. */
+export interface OllamaPullProgress {
+  name: string;
+  status: string;
+  digest?: string;
+  total?: number;
+  completed?: number;
+}
+
+export async function ollamaModelInstalled(name: string): Promise<boolean> {
+  return invoke("ollama_model_installed", { name });
+}
+
+export async function pullOllamaModel(name: string): Promise<void> {
+  return invoke("pull_ollama_model", { name });
+}
+
 // ── Knowledge Base API ──
 
 export interface DocumentInfo {
@@ -122,30 +523,216 @@ export interface DocumentInfo {
   file_path: string;
   file_size: number | null;
   created_at: string;
+  chunk_size?: number | null;
+  chunk_overlap?: number | null;
+  /** Status of the background embedding job, if the document has one. */
+  index_status?: JobStatus | null;
+  /** Chunks with an embedding, out of `chunk_count`. */
+  embedded_chunks: number;
+  chunk_count: number;
+}
+
+export type JobStatus = "indexing" | "ready" | "failed" | "partial" | "cancelled";
+
+/** Background embedding of one document's pending chunks. */
+export interface Job {
+  document_id: string;
+  status: JobStatus;
+  embedded: number;
+  total: number;
+  attempts: number;
+  error: string | null;
+  created_at: string;
+  updated_at: string;
+}
+
+export async function listJobs(): Promise<Job[]> {
+  return invoke("list_jobs");
+}
+
+export async function jobStatus(documentId: string): Promise<Job | null> {
+  return invoke("job_status", { documentId });
 }
 
 export interface ChunkInfo {
   id: string;
   content: string;
   chunk_index: number;
+  /** 1-based page range the chunk was cut from (PDFs only). */
+  page_start?: number | null;
+  page_end?: number | null;
   score: number | null;
+  rerank_score: number | null;
+  /** 0–1 position of the score within this result set. */
+  relevance?: number | null;
 }
 
 export async function listDocuments(): Promise<DocumentInfo[]> {
   return invoke("list_documents");
 }
 
-export async function uploadDocument(filePath: string): Promise<DocumentInfo> {
-  return invoke("upload_document", { filePath });
+export interface ChunkingOptions {
+  /** Characters per chunk (default 512). */
+  chunkSize?: number;
+  /** Characters shared between neighbouring chunks (default 64). */
+  overlap?: number;
+}
+
+export async function uploadDocument(
+  filePath: string,
+  options: ChunkingOptions = {}
+): Promise<DocumentInfo> {
+  return invoke("upload_document", { filePath, ...options });
+}
+
+export interface DocumentEstimate {
+  chunk_count: number;
+  /** Tokens sent for embedding, overlap included. */
+  total_tokens: number;
+  min_chunk_tokens: number;
+  max_chunk_tokens: number;
+  avg_chunk_tokens: number;
+}
+
+/** Chunk and token counts an upload would produce; nothing is stored. */
+export async function estimateDocument(
+  filePath: string,
+  options: ChunkingOptions = {}
+): Promise<DocumentEstimate> {
+  return invoke("estimate_document", { filePath, ...options });
+}
+
+export async function updateDocument(
+  id: string,
+  filePath: string
+): Promise<DocumentInfo> {
+  return invoke("update_document", { id, filePath });
+}
+
+export interface EmbeddingProgress {
+  document_id: string;
+  embedded: number;
+  total: number;
+}
+
+/** Stops an upload's embedding loop; the rest of the chunks stay pending. */
+export async function cancelUpload(documentId: string): Promise<boolean> {
+  return invoke("cancel_upload", { documentId });
+}
+
+/** Embeds pending chunks; resolves with how many are still pending. */
+export async function reembedDocument(id: string): Promise<number> {
+  return invoke("reembed_document", { id });
+}
+
+export interface ReembedSummary {
+  documents: number;
+  embedded: number;
+  remaining: number;
+  failed: number;
+}
+
+/** Embed every chunk still missing an embedding, across all documents. */
+export async function reembedPending(): Promise<ReembedSummary> {
+  return invoke("reembed_pending");
 }
 
 export async function deleteDocument(id: string): Promise<void> {
   return invoke("delete_document", { id });
 }
 
+export interface ClearedKnowledgeBase {
+  documents: number;
+  chunks: number;
+  files_deleted: number;
+}
+
+/** Delete every document and chunk; chats are kept. */
+export async function clearKnowledgeBase(deleteFiles?: boolean): Promise<ClearedKnowledgeBase> {
+  return invoke("clear_knowledge_base", { deleteFiles });
+}
+
+export interface Chunk {
+  id: string;
+  document_id: string;
+  content: string;
+  chunk_index: number;
+  page_start: number | null;
+  page_end: number | null;
+  has_embedding: boolean;
+  created_at: string;
+}
+
+export async function listChunks(
+  documentId: string,
+  offset?: number,
+  limit?: number,
+): Promise<Chunk[]> {
+  return invoke("list_chunks", { documentId, offset, limit });
+}
+
+export async function deleteChunk(chunkId: string): Promise<void> {
+  return invoke("delete_chunk", { chunkId });
+}
+
+export async function renormalizeEmbeddings(): Promise<number> {
+  return invoke("renormalize_embeddings");
+}
+
+export interface SearchOptions {
+  topK?: number;
+  /** Rerank candidates with a local cross-encoder. */
+  rerank?: boolean;
+  /** Candidates retrieved before reranking down to `topK`. */
+  rerankTopN?: number;
+  /** Only search chunks from these documents. */
+  documentIds?: string[];
+  /** Drop chunks whose cosine score is below this. */
+  minScore?: number;
+}
+
 export async function searchKnowledgeBase(
   query: string,
-  topK?: number
+  options: SearchOptions = {}
 ): Promise<ChunkInfo[]> {
-  return invoke("search_knowledge_base", { query, topK });
+  return invoke("search_knowledge_base", { query, ...options });
+}
+
+export interface RagAnswer {
+  answer: string;
+  sources: ChunkInfo[];
+}
+
+/**
+ * Answer from the knowledge base; the text also streams on "chat-stream" with
+ * an empty `conversation_id`.
+ */
+export async function ragQuery(
+  query: string,
+  model: string,
+  topK?: number
+): Promise<RagAnswer> {
+  return invoke("rag_query", { query, model, topK });
+}
+
+export interface PreviewChunk extends ChunkInfo {
+  /** Source label as it appears in the context, e.g. "[2] (page 4)". */
+  label: string;
+  tokens: number;
+}
+
+export interface RagPreview {
+  /** System prompt `ragQuery` would send, sources included. */
+  context: string;
+  total_tokens: number;
+  chunks: PreviewChunk[];
+}
+
+/** The context `ragQuery` would inject, without calling a model. */
+export async function previewRagContext(
+  query: string,
+  topK?: number,
+  model?: string
+): Promise<RagPreview> {
+  return invoke("preview_rag_context", { query, topK, model });
 }